@@ -12,6 +12,7 @@ use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::Duration;
 use utils::{
     auth::{encode_from_key_file, Claims, Scope},
     id::{NodeId, TenantId, TenantTimelineId, TimelineId},
@@ -20,6 +21,9 @@ use utils::{
 
 use crate::safekeeper::SafekeeperNode;
 
+// Long enough for a local environment to outlive any test run.
+const SAFEKEEPER_DATA_TOKEN_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
 pub const DEFAULT_PG_VERSION: u32 = 14;
 
 //
@@ -180,6 +184,8 @@ pub struct SafekeeperConf {
     pub remote_storage: Option<String>,
     pub backup_threads: Option<u32>,
     pub auth_enabled: bool,
+    // check tokens on the postgres protocol connections too, requires auth_enabled
+    pub pg_auth_enabled: bool,
 }
 
 impl Default for SafekeeperConf {
@@ -192,6 +198,7 @@ impl Default for SafekeeperConf {
             remote_storage: None,
             backup_threads: None,
             auth_enabled: false,
+            pg_auth_enabled: false,
         }
     }
 }
@@ -406,6 +413,13 @@ impl LocalEnv {
         encode_from_key_file(claims, &key_data)
     }
 
+    /// Token of the safekeeperdata scope, which must have an expiration time.
+    pub fn generate_safekeeper_data_token(&self) -> anyhow::Result<String> {
+        self.generate_auth_token(
+            &Claims::new(None, Scope::SafekeeperData).expiring_in(SAFEKEEPER_DATA_TOKEN_VALIDITY),
+        )
+    }
+
     //
     // Initialize a new Neon repository
     //
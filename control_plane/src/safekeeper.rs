@@ -152,6 +152,14 @@ impl SafekeeperNode {
             cmd.arg("--auth-validation-public-key-path");
             // PathBuf is better be passed as is, not via `String`.
             cmd.arg(self.env.base_data_dir.join("auth_public_key.pem"));
+            if self.conf.pg_auth_enabled {
+                cmd.arg("--enable-pg-auth");
+            }
+            // Token for the connections to the peers.
+            cmd.env(
+                "SAFEKEEPER_AUTH_TOKEN",
+                self.env.generate_safekeeper_data_token()?,
+            );
         }
 
         fill_aws_secrets_vars(&mut cmd);
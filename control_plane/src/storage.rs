@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Write};
-use std::num::NonZeroU64;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
@@ -238,6 +238,13 @@ impl PageServerNode {
         let mut cmd = Command::new(self.env.pageserver_bin()?);
         let mut filled_cmd = fill_rust_env_vars(cmd.args(&args).arg("--daemonize"));
         filled_cmd = fill_aws_secrets_vars(filled_cmd);
+        if self.env.safekeepers.iter().any(|sk| sk.auth_enabled) {
+            // Token for the WAL streaming connections to the safekeepers.
+            filled_cmd = filled_cmd.env(
+                "SAFEKEEPER_AUTH_TOKEN",
+                self.env.generate_safekeeper_data_token()?,
+            );
+        }
 
         if !filled_cmd.status()?.success() {
             bail!(
@@ -419,6 +426,11 @@ impl PageServerNode {
                 .map(|x| x.parse::<NonZeroU64>())
                 .transpose()
                 .context("Failed to parse 'max_lsn_wal_lag' as non zero integer")?,
+            walreceiver_catchup_streams: settings
+                .remove("walreceiver_catchup_streams")
+                .map(|x| x.parse::<NonZeroUsize>())
+                .transpose()
+                .context("Failed to parse 'walreceiver_catchup_streams' as non zero integer")?,
         };
         if !settings.is_empty() {
             bail!("Unrecognized tenant settings: {settings:?}")
@@ -481,6 +493,11 @@ impl PageServerNode {
                     .map(|x| x.parse::<NonZeroU64>())
                     .transpose()
                     .context("Failed to parse 'max_lsn_wal_lag' as non zero integer")?,
+                walreceiver_catchup_streams: settings
+                    .get("walreceiver_catchup_streams")
+                    .map(|x| x.parse::<NonZeroUsize>())
+                    .transpose()
+                    .context("Failed to parse 'walreceiver_catchup_streams' as non zero integer")?,
             })
             .send()?
             .error_from_body()?;
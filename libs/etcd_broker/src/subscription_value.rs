@@ -29,6 +29,15 @@ pub struct SkTimelineInfo {
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default)]
     pub peer_horizon_lsn: Option<Lsn>,
+    /// LSN of the earliest WAL, still present on the safekeeper locally.
+    /// Older WAL might only be available in the safekeepers' WAL archive in the remote storage.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub local_start_lsn: Option<Lsn>,
+    /// LSN up to which the WAL of the current, incomplete segment is backed up as a partial segment.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub partial_backup_lsn: Option<Lsn>,
     /// A connection string to use for WAL receiving.
     #[serde(default)]
     pub safekeeper_connstr: Option<String>,
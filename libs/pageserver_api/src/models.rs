@@ -1,4 +1,7 @@
-use std::num::NonZeroU64;
+use std::{
+    collections::HashMap,
+    num::{NonZeroU64, NonZeroUsize},
+};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
@@ -52,6 +55,7 @@ pub struct TenantCreateRequest {
     pub walreceiver_connect_timeout: Option<String>,
     pub lagging_wal_timeout: Option<String>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub walreceiver_catchup_streams: Option<NonZeroUsize>,
 }
 
 #[serde_as]
@@ -59,6 +63,29 @@ pub struct TenantCreateRequest {
 #[serde(transparent)]
 pub struct TenantCreateResponse(#[serde_as(as = "DisplayFromStr")] pub TenantId);
 
+/// Creates a new tenant, referencing the remote layers of the source tenant's timelines at given LSNs.
+/// Timelines, missing in `timeline_lsns`, are cut at their latest uploaded state.
+#[serde_as]
+#[derive(Serialize, Deserialize, Default)]
+pub struct TenantSnapshotRequest {
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub new_tenant_id: Option<TenantId>,
+    #[serde(default)]
+    #[serde_as(as = "HashMap<DisplayFromStr, DisplayFromStr>")]
+    pub timeline_lsns: HashMap<TimelineId, Lsn>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct TenantSnapshotResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub tenant_id: TenantId,
+    /// The actual LSNs the timelines were cut at, that might be before the requested ones.
+    #[serde_as(as = "HashMap<DisplayFromStr, DisplayFromStr>")]
+    pub timeline_lsns: HashMap<TimelineId, Lsn>,
+}
+
 #[derive(Serialize)]
 pub struct StatusResponse {
     pub id: NodeId,
@@ -91,6 +118,7 @@ pub struct TenantConfigRequest {
     pub walreceiver_connect_timeout: Option<String>,
     pub lagging_wal_timeout: Option<String>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub walreceiver_catchup_streams: Option<NonZeroUsize>,
 }
 
 impl TenantConfigRequest {
@@ -109,6 +137,7 @@ impl TenantConfigRequest {
             walreceiver_connect_timeout: None,
             lagging_wal_timeout: None,
             max_lsn_wal_lag: None,
+            walreceiver_catchup_streams: None,
         }
     }
 }
@@ -16,9 +16,11 @@ use std::{
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{bail, Context};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 
 use tokio::io;
 use toml_edit::Item;
@@ -38,6 +40,19 @@ pub const DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS: u32 = 10;
 /// ~3500 PUT/COPY/POST/DELETE or 5500 GET/HEAD S3 requests
 /// https://aws.amazon.com/premiumsupport/knowledge-center/s3-request-limit-avoid-throttling/
 pub const DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT: usize = 100;
+/// S3 allows a single PUT request to carry no more than 5GB, larger objects have to be uploaded in parts.
+/// Multipart upload is also used for smaller files, since it allows to upload parts concurrently and
+/// to retry a failed upload without sending the already uploaded parts again.
+/// https://docs.aws.amazon.com/AmazonS3/latest/userguide/mpuoverview.html
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_THRESHOLD: usize = 64 * 1024 * 1024;
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE: usize = 16 * 1024 * 1024;
+/// Every part except the last one has to be at least 5MB in size.
+pub const MIN_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE: usize = 5 * 1024 * 1024;
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY: usize = 4;
+/// Incomplete multipart uploads are kept to be resumed by the upload retries.
+/// The ones that are older than that were not completed by any of the retries and are aborted on startup.
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_ABANDONED_AFTER: Duration =
+    Duration::from_secs(24 * 60 * 60);
 
 const REMOTE_STORAGE_PREFIX_SEPARATOR: char = '/';
 
@@ -93,17 +108,38 @@ pub trait RemoteStorage: Send + Sync + 'static {
     /// Gets the download path of the given storage file.
     fn local_path(&self, remote_object_id: &RemoteObjectId) -> anyhow::Result<PathBuf>;
 
-    /// Lists all items the storage has right now.
-    async fn list(&self) -> anyhow::Result<Vec<RemoteObjectId>>;
-
-    /// Lists all top level subdirectories for a given prefix
+    /// Lists a single page of the storage items under the prefix given, or under the storage root, if no prefix is passed.
+    /// Listing starts from the beginning, or right after the previous page, if the `continuation_token` of that page is given.
     /// Note: here we assume that if the prefix is passed it was obtained via remote_object_id
     /// which already takes into account any kind of global prefix (prefix_in_bucket for S3 or storage_root for LocalFS)
     /// so this method doesnt need to.
-    async fn list_prefixes(
+    async fn list_page(
         &self,
         prefix: Option<&RemoteObjectId>,
-    ) -> anyhow::Result<Vec<RemoteObjectId>>;
+        mode: ListingMode,
+        continuation_token: Option<String>,
+    ) -> anyhow::Result<ListingPage>;
+
+    /// Streams the storage items page by page, see [`RemoteStorage::list_page`] for the parameters.
+    /// Only one page of the items is kept in memory at a time.
+    fn list_stream<'a>(
+        &'a self,
+        prefix: Option<&'a RemoteObjectId>,
+        mode: ListingMode,
+    ) -> BoxStream<'a, anyhow::Result<RemoteObjectId>> {
+        // `None` means there are no more pages to request, `Some(None)` requests the first page.
+        stream::try_unfold(Some(None), move |next_page| async move {
+            let continuation_token = match next_page {
+                Some(continuation_token) => continuation_token,
+                None => return Ok(None),
+            };
+            let page = self.list_page(prefix, mode, continuation_token).await?;
+            let items = stream::iter(page.items.into_iter().map(Ok));
+            anyhow::Ok(Some((items, page.continuation_token.map(Some))))
+        })
+        .try_flatten()
+        .boxed()
+    }
 
     /// Streams the local file contents into remote into the remote storage entry.
     async fn upload(
@@ -131,12 +167,36 @@ pub trait RemoteStorage: Send + Sync + 'static {
 
     async fn delete(&self, path: &RemoteObjectId) -> anyhow::Result<()>;
 
+    /// Removes the storage-specific leftovers of the uploads that were started long ago and never finished,
+    /// such as incomplete S3 multipart uploads. Supposed to be called once on startup.
+    async fn cleanup_abandoned_uploads(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     /// Downcast to LocalFs implementation. For tests.
     fn as_local(&self) -> Option<&LocalFs> {
         None
     }
 }
 
+/// Which storage items to list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingMode {
+    /// Every object under the prefix, including the ones in its subdirectories.
+    Objects,
+    /// Top level subdirectories under the prefix.
+    Prefixes,
+}
+
+/// A part of the storage listing, returned by a single list request.
+#[derive(Debug, Default)]
+pub struct ListingPage {
+    pub items: Vec<RemoteObjectId>,
+    /// Pass this to [`RemoteStorage::list_page`] to get the next page.
+    /// `None` if there are no more items to list.
+    pub continuation_token: Option<String>,
+}
+
 pub struct Download {
     pub download_stream: Pin<Box<dyn io::AsyncRead + Unpin + Send>>,
     /// Extra key-value data, associated with the current remote file.
@@ -212,6 +272,7 @@ impl GenericRemoteStorage {
 
     /// Takes storage object contents and its size and uploads to remote storage,
     /// mapping `from_path` to the corresponding remote object id in the storage.
+    /// `metadata` is stored along with the object and returned on its download.
     ///
     /// The storage object does not have to be present on the `from_path`,
     /// this path is used for the remote object id conversion only.
@@ -220,6 +281,7 @@ impl GenericRemoteStorage {
         from: Box<dyn tokio::io::AsyncRead + Unpin + Send + Sync + 'static>,
         from_size_bytes: usize,
         from_path: &Path,
+        metadata: Option<StorageMetadata>,
     ) -> anyhow::Result<()> {
         let target_storage_path = self.remote_object_id(from_path).with_context(|| {
             format!(
@@ -228,7 +290,7 @@ impl GenericRemoteStorage {
             )
         })?;
 
-        self.upload(from, from_size_bytes, &target_storage_path, None)
+        self.upload(from, from_size_bytes, &target_storage_path, metadata)
             .await
             .with_context(|| {
                 format!(
@@ -271,6 +333,16 @@ impl GenericRemoteStorage {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StorageMetadata(HashMap<String, String>);
 
+impl StorageMetadata {
+    pub fn new(entries: HashMap<String, String>) -> Self {
+        Self(entries)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+}
+
 fn strip_path_prefix<'a>(prefix: &'a Path, path: &'a Path) -> anyhow::Result<&'a Path> {
     if prefix == path {
         anyhow::bail!(
@@ -329,6 +401,15 @@ pub struct S3Config {
     /// AWS S3 has various limits on its API calls, we need not to exceed those.
     /// See [`DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT`] for more details.
     pub concurrency_limit: NonZeroUsize,
+    /// Files of this size and bigger are uploaded with S3 multipart upload.
+    /// See [`DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_THRESHOLD`] for more details.
+    pub multipart_upload_threshold: NonZeroUsize,
+    /// Size of a single part in multipart upload, not less than [`MIN_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE`].
+    pub multipart_upload_part_size: NonZeroUsize,
+    /// Max number of parts of a single file that are uploaded concurrently.
+    pub multipart_upload_concurrency: NonZeroUsize,
+    /// Incomplete multipart uploads older than that are aborted on startup.
+    pub multipart_upload_abandoned_after: Duration,
 }
 
 impl Debug for S3Config {
@@ -338,6 +419,22 @@ impl Debug for S3Config {
             .field("bucket_region", &self.bucket_region)
             .field("prefix_in_bucket", &self.prefix_in_bucket)
             .field("concurrency_limit", &self.concurrency_limit)
+            .field(
+                "multipart_upload_threshold",
+                &self.multipart_upload_threshold,
+            )
+            .field(
+                "multipart_upload_part_size",
+                &self.multipart_upload_part_size,
+            )
+            .field(
+                "multipart_upload_concurrency",
+                &self.multipart_upload_concurrency,
+            )
+            .field(
+                "multipart_upload_abandoned_after",
+                &self.multipart_upload_abandoned_after,
+            )
             .finish()
     }
 }
@@ -366,6 +463,41 @@ impl RemoteStorageConfig {
         )
         .context("Failed to parse 'concurrency_limit' as a positive integer")?;
 
+        let multipart_upload_threshold = NonZeroUsize::new(
+            parse_optional_integer("multipart_upload_threshold", toml)?
+                .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_THRESHOLD),
+        )
+        .context("Failed to parse 'multipart_upload_threshold' as a positive integer")?;
+
+        let multipart_upload_part_size = NonZeroUsize::new(
+            parse_optional_integer("multipart_upload_part_size", toml)?
+                .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE),
+        )
+        .context("Failed to parse 'multipart_upload_part_size' as a positive integer")?;
+        if multipart_upload_part_size.get() < MIN_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE {
+            bail!(
+                "'multipart_upload_part_size' should be at least {MIN_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE} bytes"
+            );
+        }
+
+        let multipart_upload_concurrency = NonZeroUsize::new(
+            parse_optional_integer("multipart_upload_concurrency", toml)?
+                .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY),
+        )
+        .context("Failed to parse 'multipart_upload_concurrency' as a positive integer")?;
+
+        let multipart_upload_abandoned_after = toml
+            .get("multipart_upload_abandoned_after")
+            .map(|item| {
+                parse_toml_string("multipart_upload_abandoned_after", item).and_then(|s| {
+                    humantime::parse_duration(&s).with_context(|| {
+                        format!("Failed to parse 'multipart_upload_abandoned_after' value '{s}' as a duration")
+                    })
+                })
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_ABANDONED_AFTER);
+
         let storage = match (local_path, bucket_name, bucket_region) {
             (None, None, None) => bail!("no 'local_path' nor 'bucket_name' option"),
             (_, Some(_), None) => {
@@ -386,6 +518,10 @@ impl RemoteStorageConfig {
                     .map(|endpoint| parse_toml_string("endpoint", endpoint))
                     .transpose()?,
                 concurrency_limit,
+                multipart_upload_threshold,
+                multipart_upload_part_size,
+                multipart_upload_concurrency,
+                multipart_upload_abandoned_after,
             }),
             (Some(local_path), None, None) => RemoteStorageKind::LocalFs(PathBuf::from(
                 parse_toml_string("local_path", local_path)?,
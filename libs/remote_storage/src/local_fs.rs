@@ -11,6 +11,7 @@ use std::{
 };
 
 use anyhow::{bail, ensure, Context};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 use tokio::{
     fs,
     io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
@@ -18,11 +19,15 @@ use tokio::{
 use tracing::*;
 use utils::crashsafe_dir::path_with_suffix_extension;
 
-use crate::{Download, DownloadError, RemoteObjectId};
+use crate::{Download, DownloadError, ListingMode, ListingPage, RemoteObjectId};
 
 use super::{strip_path_prefix, RemoteStorage, StorageMetadata};
 
 const LOCAL_FS_TEMP_FILE_SUFFIX: &str = "___temp";
+/// Local file system has no paginated listing, so every page walks the directories in the sorted order,
+/// starting after the last item of the previous page, used as a continuation token.
+/// [`LocalFs::list_stream`] walks the directories once instead, rather than once per page.
+const LOCAL_FS_LIST_PAGE_SIZE: usize = 1000;
 
 /// Convert a Path in the remote storage into a RemoteObjectId
 fn remote_object_id_from_path(path: &Path) -> anyhow::Result<RemoteObjectId> {
@@ -99,6 +104,31 @@ impl LocalFs {
             Ok(None)
         }
     }
+
+    /// Reads the listing under the prefix, sorted the same way S3 sorts its keys,
+    /// starting after the `start_after` item and up to `limit` items.
+    async fn list_sorted(
+        &self,
+        prefix: Option<&RemoteObjectId>,
+        mode: ListingMode,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<RemoteObjectId>> {
+        let path = match prefix {
+            Some(prefix) => Path::new(&prefix.0),
+            None => &self.storage_root,
+        };
+        let mut items = Vec::new();
+        list_sorted_after(
+            path,
+            mode == ListingMode::Objects,
+            start_after,
+            limit,
+            &mut items,
+        )
+        .await?;
+        Ok(items)
+    }
 }
 
 #[async_trait::async_trait]
@@ -119,19 +149,45 @@ impl RemoteStorage for LocalFs {
         Ok(self.working_directory.join(relative_path))
     }
 
-    async fn list(&self) -> anyhow::Result<Vec<RemoteObjectId>> {
-        get_all_files(&self.storage_root, true).await
-    }
-
-    async fn list_prefixes(
+    async fn list_page(
         &self,
         prefix: Option<&RemoteObjectId>,
-    ) -> anyhow::Result<Vec<RemoteObjectId>> {
-        let path = match prefix {
-            Some(prefix) => Path::new(&prefix.0),
-            None => &self.storage_root,
+        mode: ListingMode,
+        continuation_token: Option<String>,
+    ) -> anyhow::Result<ListingPage> {
+        // One extra item tells whether there are more pages.
+        let mut items = self
+            .list_sorted(
+                prefix,
+                mode,
+                continuation_token.as_deref(),
+                LOCAL_FS_LIST_PAGE_SIZE + 1,
+            )
+            .await?;
+
+        let continuation_token = if items.len() > LOCAL_FS_LIST_PAGE_SIZE {
+            items.truncate(LOCAL_FS_LIST_PAGE_SIZE);
+            items.last().map(|item| item.0.clone())
+        } else {
+            None
         };
-        get_all_files(path, false).await
+        Ok(ListingPage {
+            items,
+            continuation_token,
+        })
+    }
+
+    fn list_stream<'a>(
+        &'a self,
+        prefix: Option<&'a RemoteObjectId>,
+        mode: ListingMode,
+    ) -> BoxStream<'a, anyhow::Result<RemoteObjectId>> {
+        stream::once(async move {
+            let items = self.list_sorted(prefix, mode, None, usize::MAX).await?;
+            anyhow::Ok(stream::iter(items.into_iter().map(Ok)))
+        })
+        .try_flatten()
+        .boxed()
     }
 
     async fn upload(
@@ -329,41 +385,61 @@ fn storage_metadata_path(original_path: &Path) -> PathBuf {
     path_with_suffix_extension(original_path, "metadata")
 }
 
-fn get_all_files<'a, P>(
-    directory_path: P,
+/// Collects the items under the directory into `items` in the order of their paths, the way S3 sorts its keys,
+/// skipping the items up to and including `start_after`, and stops once `limit` items are collected.
+/// Subdirectories, which contain no items past `start_after`, are not read.
+fn list_sorted_after<'a>(
+    directory_path: &'a Path,
     recursive: bool,
-) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<RemoteObjectId>>> + Send + Sync + 'a>>
-where
-    P: AsRef<Path> + Send + Sync + 'a,
-{
+    start_after: Option<&'a str>,
+    limit: usize,
+    items: &'a mut Vec<RemoteObjectId>,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
     Box::pin(async move {
-        let directory_path = directory_path.as_ref();
-        if directory_path.exists() {
-            if directory_path.is_dir() {
-                let mut paths = Vec::new();
-                let mut dir_contents = fs::read_dir(directory_path).await?;
-                while let Some(dir_entry) = dir_contents.next_entry().await? {
-                    let file_type = dir_entry.file_type().await?;
-                    let entry_path = dir_entry.path();
-                    if file_type.is_symlink() {
-                        debug!("{:?} us a symlink, skipping", entry_path)
-                    } else if file_type.is_dir() {
-                        if recursive {
-                            paths.extend(get_all_files(&entry_path, true).await?.into_iter())
-                        } else {
-                            paths.push(remote_object_id_from_path(&dir_entry.path())?)
-                        }
-                    } else {
-                        paths.push(remote_object_id_from_path(&dir_entry.path())?);
-                    }
-                }
-                Ok(paths)
+        if !directory_path.exists() {
+            return Ok(());
+        }
+        if !directory_path.is_dir() {
+            bail!("Path '{}' is not a directory", directory_path.display())
+        }
+
+        let mut entries = Vec::new();
+        let mut dir_contents = fs::read_dir(directory_path).await?;
+        while let Some(dir_entry) = dir_contents.next_entry().await? {
+            let file_type = dir_entry.file_type().await?;
+            let entry_path = dir_entry.path();
+            if file_type.is_symlink() {
+                debug!("{:?} us a symlink, skipping", entry_path);
+                continue;
+            }
+            let path = remote_object_id_from_path(&entry_path)?.0;
+            let descend = recursive && file_type.is_dir();
+            // Subdirectory contents go right after the paths, that sort before the subdirectory path with a separator.
+            let sort_key = if descend {
+                format!("{path}/")
             } else {
-                bail!("Path '{}' is not a directory", directory_path.display())
+                path.clone()
+            };
+            entries.push((sort_key, path, descend));
+        }
+        entries.sort();
+
+        for (sort_key, path, descend) in entries {
+            if items.len() >= limit {
+                break;
+            }
+            if descend {
+                let listed_before = start_after.map_or(false, |start_after| {
+                    sort_key.as_str() < start_after && !start_after.starts_with(&sort_key)
+                });
+                if !listed_before {
+                    list_sorted_after(Path::new(&path), true, start_after, limit, items).await?;
+                }
+            } else if start_after.map_or(true, |start_after| path.as_str() > start_after) {
+                items.push(RemoteObjectId(path));
             }
-        } else {
-            Ok(Vec::new())
         }
+        Ok(())
     })
 }
 
@@ -565,6 +641,7 @@ mod pure_tests {
 mod fs_tests {
     use super::*;
 
+    use futures::TryStreamExt;
     use std::{collections::HashMap, io::Write};
     use tempfile::tempdir;
 
@@ -620,11 +697,11 @@ mod fs_tests {
                 assert!(message.contains("does not belong to the current storage"));
             }
         }
-        assert!(storage.list().await?.is_empty());
+        assert!(list_files_sorted(&storage).await?.is_empty());
 
         let target_path_1 = upload_dummy_file(&workdir, &storage, "upload_1", None).await?;
         assert_eq!(
-            storage.list().await?,
+            list_files_sorted(&storage).await?,
             vec![target_path_1.clone()],
             "Should list a single file after first upload"
         );
@@ -639,6 +716,89 @@ mod fs_tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn list_in_pages() -> anyhow::Result<()> {
+        let workdir = tempdir()?.path().to_owned();
+        let storage = create_storage()?;
+
+        let mut uploaded_files = Vec::new();
+        for i in 0..LOCAL_FS_LIST_PAGE_SIZE + 1 {
+            uploaded_files.push(
+                upload_dummy_file(&workdir, &storage, &format!("upload_{i:05}"), None).await?,
+            );
+        }
+
+        let first_page = storage.list_page(None, ListingMode::Objects, None).await?;
+        assert_eq!(
+            first_page.items,
+            uploaded_files[..LOCAL_FS_LIST_PAGE_SIZE],
+            "First page should contain the first files in the sorted order"
+        );
+        let continuation_token = first_page
+            .continuation_token
+            .expect("Should have more pages to list");
+
+        let second_page = storage
+            .list_page(None, ListingMode::Objects, Some(continuation_token))
+            .await?;
+        assert_eq!(
+            second_page.items,
+            uploaded_files[LOCAL_FS_LIST_PAGE_SIZE..],
+            "Second page should contain the remaining files"
+        );
+        assert_eq!(
+            second_page.continuation_token, None,
+            "Should have no more pages to list"
+        );
+
+        assert_eq!(
+            list_files_sorted(&storage).await?,
+            uploaded_files,
+            "Stream should return files from all pages"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_nested_in_pages() -> anyhow::Result<()> {
+        let workdir = tempdir()?.path().to_owned();
+        let storage = create_storage()?;
+
+        let mut uploaded_files = Vec::new();
+        for name in ["b/c/d", "a0", "a/d", "a/b/c", "a.b/c", "a-"] {
+            uploaded_files.push(upload_dummy_file(&workdir, &storage, name, None).await?);
+        }
+        uploaded_files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut listed_files = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let page = storage
+                .list_sorted(None, ListingMode::Objects, continuation_token.as_deref(), 2)
+                .await?;
+            match page.last() {
+                Some(last) => continuation_token = Some(last.0.clone()),
+                None => break,
+            }
+            listed_files.extend(page);
+        }
+        assert_eq!(
+            listed_files, uploaded_files,
+            "Pages should go through the nested directories in the sorted order"
+        );
+        assert_eq!(
+            storage
+                .list_stream(None, ListingMode::Objects)
+                .try_collect::<Vec<_>>()
+                .await?,
+            uploaded_files,
+            "Stream should list the files in the sorted order"
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn upload_file_negatives() -> anyhow::Result<()> {
         let storage = create_storage()?;
@@ -819,7 +979,7 @@ mod fs_tests {
         let upload_target = upload_dummy_file(&workdir, &storage, upload_name, None).await?;
 
         storage.delete(&upload_target).await?;
-        assert!(storage.list().await?.is_empty());
+        assert!(list_files_sorted(&storage).await?.is_empty());
 
         match storage.delete(&upload_target).await {
             Ok(()) => panic!("Should not allow deleting non-existing storage files"),
@@ -925,7 +1085,10 @@ mod fs_tests {
     }
 
     async fn list_files_sorted(storage: &LocalFs) -> anyhow::Result<Vec<RemoteObjectId>> {
-        let mut files = storage.list().await?;
+        let mut files = storage
+            .list_stream(None, ListingMode::Objects)
+            .try_collect::<Vec<_>>()
+            .await?;
         files.sort_by(|a, b| a.0.cmp(&b.0));
         Ok(files)
     }
@@ -4,24 +4,34 @@
 //! allowing multiple api users to independently work with the same S3 bucket, if
 //! their bucket prefixes are both specified and different.
 
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 use rusoto_core::{
     credential::{InstanceMetadataProvider, StaticProvider},
     HttpClient, Region, RusotoError,
 };
 use rusoto_s3::{
-    DeleteObjectRequest, GetObjectError, GetObjectRequest, ListObjectsV2Request, PutObjectRequest,
-    S3Client, StreamingBody, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError,
+    GetObjectRequest, ListMultipartUploadsRequest, ListObjectsV2Request, ListPartsRequest,
+    PutObjectRequest, S3Client, StreamingBody, UploadPartRequest, S3,
+};
+use tokio::{
+    io::{self, AsyncReadExt},
+    sync::Semaphore,
 };
-use tokio::{io, sync::Semaphore};
 use tokio_util::io::ReaderStream;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 use crate::{
-    strip_path_prefix, Download, DownloadError, RemoteObjectId, RemoteStorage, S3Config,
-    REMOTE_STORAGE_PREFIX_SEPARATOR,
+    strip_path_prefix, Download, DownloadError, ListingMode, ListingPage, RemoteObjectId,
+    RemoteStorage, S3Config, REMOTE_STORAGE_PREFIX_SEPARATOR,
 };
 
 use super::StorageMetadata;
@@ -89,6 +99,56 @@ pub(super) mod metrics {
             .with_label_values(&["list_objects"])
             .inc();
     }
+
+    /// Multipart upload consists of several request types, those are distinguished by the `request_type` given.
+    pub fn inc_multipart_upload(request_type: &str) {
+        S3_REQUESTS_COUNT.with_label_values(&[request_type]).inc();
+    }
+
+    pub fn inc_multipart_upload_fail(request_type: &str) {
+        S3_REQUESTS_FAIL_COUNT
+            .with_label_values(&[request_type])
+            .inc();
+    }
+}
+
+/// S3 does not allow more parts than that in a single multipart upload.
+const MAX_MULTIPART_UPLOAD_PARTS: usize = 10_000;
+
+/// Multipart upload settings, see the corresponding [`S3Config`] fields for details.
+struct MultipartUploadConfig {
+    threshold: usize,
+    part_size: usize,
+    concurrency: usize,
+    abandoned_after: Duration,
+}
+
+impl MultipartUploadConfig {
+    /// Part size to use for the file of the size given:
+    /// the configured one, unless the file is too big to fit into [`MAX_MULTIPART_UPLOAD_PARTS`] parts of that size.
+    fn part_size(&self, file_size: usize) -> usize {
+        let min_part_size_to_fit =
+            (file_size + MAX_MULTIPART_UPLOAD_PARTS - 1) / MAX_MULTIPART_UPLOAD_PARTS;
+        self.part_size.max(min_part_size_to_fit)
+    }
+}
+
+/// Checks the expected metadata against the one of an incomplete multipart upload.
+/// S3 doesn't report the metadata of such uploads, so only no metadata (or an empty one) is known to match.
+fn metadata_matches(expected: Option<&StorageMetadata>) -> bool {
+    expected.map_or(true, |metadata| metadata.0.is_empty())
+}
+
+/// A part, uploaded to S3 as a part of some incomplete multipart upload earlier.
+struct UploadedPart {
+    e_tag: String,
+    size: usize,
+}
+
+/// S3 returns MD5 sums of the uploaded parts as their ETags, unless the bucket uses SSE-KMS or SSE-C encryption.
+/// Parts with a different ETag are uploaded again, so the mismatch costs an extra upload only.
+fn part_e_tag_matches(e_tag: &str, part_contents: &[u8]) -> bool {
+    e_tag.trim_matches('"') == format!("{:x}", md5::compute(part_contents))
 }
 
 fn download_destination(
@@ -123,6 +183,7 @@ pub struct S3Bucket {
     // Same goes to IAM, which is queried before every S3 request, if enabled. IAM has even lower RPS threshold.
     // The helps to ensure we don't exceed the thresholds.
     concurrency_limiter: Semaphore,
+    multipart_upload: MultipartUploadConfig,
 }
 
 impl S3Bucket {
@@ -189,6 +250,12 @@ impl S3Bucket {
             bucket_name: aws_config.bucket_name.clone(),
             prefix_in_bucket,
             concurrency_limiter: Semaphore::new(aws_config.concurrency_limit.get()),
+            multipart_upload: MultipartUploadConfig {
+                threshold: aws_config.multipart_upload_threshold.get(),
+                part_size: aws_config.multipart_upload_part_size.get(),
+                concurrency: aws_config.multipart_upload_concurrency.get(),
+                abandoned_after: aws_config.multipart_upload_abandoned_after,
+            },
         })
     }
 
@@ -224,132 +291,470 @@ impl S3Bucket {
             }
         }
     }
-}
 
-#[async_trait::async_trait]
-impl RemoteStorage for S3Bucket {
-    fn remote_object_id(&self, local_path: &Path) -> anyhow::Result<RemoteObjectId> {
-        let relative_path = strip_path_prefix(&self.workdir, local_path)?;
-        let mut key = self.prefix_in_bucket.clone().unwrap_or_default();
-        for segment in relative_path {
-            key.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
-            key.push_str(&segment.to_string_lossy());
+    /// Uploads the file in parts, resuming the incomplete multipart upload for the same key, if there is one.
+    ///
+    /// On failure, the multipart upload is left incomplete: the next attempt to upload the same file
+    /// will reuse the parts uploaded already. Uploads, never completed, are aborted by [`RemoteStorage::cleanup_abandoned_uploads`].
+    ///
+    /// S3 does not report the metadata of an incomplete upload, it is set when the upload gets created
+    /// and applied when the upload gets completed, overwriting the object. So the metadata has to be known to match
+    /// before the upload is resumed: only the uploads without metadata are resumed, for the uploads with metadata,
+    /// the incomplete upload is aborted and a new one is created instead.
+    async fn upload_multipart(
+        &self,
+        from: Box<(dyn io::AsyncRead + Unpin + Send + Sync + 'static)>,
+        from_size_bytes: usize,
+        to: &RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        let incomplete_upload = match self.find_incomplete_upload(to).await? {
+            Some(upload_id) if !metadata_matches(metadata.as_ref()) => {
+                warn!("Aborting multipart upload {upload_id} for {to:?}, its metadata cannot be checked against the requested one");
+                self.abort_multipart_upload(to.0.clone(), upload_id).await?;
+                None
+            }
+            incomplete_upload => incomplete_upload,
+        };
+        let (upload_id, mut uploaded_parts) = match incomplete_upload {
+            Some(upload_id) => {
+                let uploaded_parts = self.list_uploaded_parts(to, &upload_id).await?;
+                info!(
+                    "Resuming multipart upload {upload_id} for {to:?}, {} parts uploaded already",
+                    uploaded_parts.len()
+                );
+                (upload_id, uploaded_parts)
+            }
+            None => (
+                self.create_multipart_upload(to, metadata).await?,
+                HashMap::new(),
+            ),
+        };
+
+        let part_size = self.multipart_upload.part_size(from_size_bytes);
+        // Parts are read one by one, while the ones read already are uploaded concurrently.
+        let parts = stream::try_unfold(
+            (from, 0, 0),
+            |(mut from, bytes_read, part_number): (_, usize, i64)| async move {
+                if bytes_read >= from_size_bytes {
+                    return Ok(None);
+                }
+                let part_number = part_number + 1;
+                let mut part_contents =
+                    Vec::with_capacity(part_size.min(from_size_bytes - bytes_read));
+                (&mut from)
+                    .take(part_size as u64)
+                    .read_to_end(&mut part_contents)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to read part {part_number} of the file to upload")
+                    })?;
+                ensure!(
+                    !part_contents.is_empty(),
+                    "Upload source ended after {bytes_read} bytes, expected {from_size_bytes} bytes"
+                );
+                let bytes_read = bytes_read + part_contents.len();
+                ensure!(
+                    bytes_read <= from_size_bytes,
+                    "Upload source size mismatch: read {bytes_read} bytes, expected {from_size_bytes} bytes"
+                );
+                Ok(Some((
+                    (part_number, part_contents),
+                    (from, bytes_read, part_number),
+                )))
+            },
+        );
+
+        let upload_id = &upload_id;
+        let mut completed_parts: Vec<CompletedPart> = parts
+            .map_ok(|(part_number, part_contents)| {
+                let uploaded_part = uploaded_parts.remove(&part_number);
+                async move {
+                    match uploaded_part {
+                        Some(uploaded_part)
+                            if uploaded_part.size == part_contents.len()
+                                && part_e_tag_matches(&uploaded_part.e_tag, &part_contents) =>
+                        {
+                            debug!("Part {part_number} of {to:?} is uploaded already, skipping");
+                            Ok(CompletedPart {
+                                e_tag: Some(uploaded_part.e_tag),
+                                part_number: Some(part_number),
+                            })
+                        }
+                        _ => {
+                            self.upload_part(to, upload_id, part_number, part_contents)
+                                .await
+                        }
+                    }
+                }
+            })
+            .try_buffer_unordered(self.multipart_upload.concurrency)
+            .try_collect()
+            .await?;
+
+        completed_parts.sort_by_key(|part| part.part_number);
+        self.complete_multipart_upload(to, upload_id, completed_parts)
+            .await
+    }
+
+    /// Returns the id of the most recent incomplete multipart upload for the key given, if any.
+    async fn find_incomplete_upload(&self, key: &RemoteObjectId) -> anyhow::Result<Option<String>> {
+        let mut latest_upload: Option<(String, String)> = None;
+        let mut uploads = self.list_multipart_uploads(Some(key.0.clone()));
+        while let Some(upload) = uploads.try_next().await? {
+            // the prefix may match other keys too
+            if upload.key.as_deref() != Some(key.0.as_str()) {
+                continue;
+            }
+            if let (Some(upload_id), Some(initiated)) = (upload.upload_id, upload.initiated) {
+                // RFC 3339 timestamps in the same timezone are ordered lexicographically
+                if latest_upload
+                    .as_ref()
+                    .map_or(true, |(_, latest_initiated)| &initiated > latest_initiated)
+                {
+                    latest_upload = Some((upload_id, initiated));
+                }
+            }
         }
-        Ok(RemoteObjectId(key))
+        Ok(latest_upload.map(|(upload_id, _)| upload_id))
     }
 
-    fn local_path(&self, storage_path: &RemoteObjectId) -> anyhow::Result<PathBuf> {
-        Ok(download_destination(
-            storage_path,
-            &self.workdir,
-            self.prefix_in_bucket.as_deref(),
-        ))
+    /// Streams the incomplete multipart uploads page by page, only one page is kept in memory at a time.
+    fn list_multipart_uploads(
+        &self,
+        prefix: Option<String>,
+    ) -> BoxStream<'_, anyhow::Result<rusoto_s3::MultipartUpload>> {
+        // `None` means there are no more pages to request, `Some((None, None))` requests the first page.
+        stream::try_unfold(Some((None, None)), move |markers| {
+            let prefix = prefix.clone();
+            async move {
+                let (key_marker, upload_id_marker) = match markers {
+                    Some(markers) => markers,
+                    None => return Ok(None),
+                };
+                let (uploads, next_markers) = self
+                    .list_multipart_uploads_page(prefix, key_marker, upload_id_marker)
+                    .await?;
+                let uploads = stream::iter(uploads.into_iter().map(Ok));
+                anyhow::Ok(Some((uploads, next_markers)))
+            }
+        })
+        .try_flatten()
+        .boxed()
     }
 
-    async fn list(&self) -> anyhow::Result<Vec<RemoteObjectId>> {
-        let mut document_keys = Vec::new();
+    /// Returns a page of the incomplete multipart uploads and the markers to request the next page with, if any.
+    #[allow(clippy::type_complexity)]
+    async fn list_multipart_uploads_page(
+        &self,
+        prefix: Option<String>,
+        key_marker: Option<String>,
+        upload_id_marker: Option<String>,
+    ) -> anyhow::Result<(
+        Vec<rusoto_s3::MultipartUpload>,
+        Option<(Option<String>, Option<String>)>,
+    )> {
+        let _guard =
+            self.concurrency_limiter.acquire().await.context(
+                "Concurrency limiter semaphore got closed during S3 multipart uploads list",
+            )?;
+
+        metrics::inc_multipart_upload("list_multipart_uploads");
+
+        let fetch_response = self
+            .client
+            .list_multipart_uploads(ListMultipartUploadsRequest {
+                bucket: self.bucket_name.clone(),
+                prefix,
+                key_marker,
+                upload_id_marker,
+                ..ListMultipartUploadsRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("list_multipart_uploads");
+                e
+            })?;
 
-        let mut continuation_token = None;
+        let next_markers = if fetch_response.is_truncated == Some(true) {
+            Some((
+                fetch_response.next_key_marker,
+                fetch_response.next_upload_id_marker,
+            ))
+        } else {
+            None
+        };
+        Ok((fetch_response.uploads.unwrap_or_default(), next_markers))
+    }
+
+    async fn list_uploaded_parts(
+        &self,
+        key: &RemoteObjectId,
+        upload_id: &str,
+    ) -> anyhow::Result<HashMap<i64, UploadedPart>> {
+        let mut parts = HashMap::new();
+
+        let mut part_number_marker = None;
         loop {
             let _guard = self
                 .concurrency_limiter
                 .acquire()
                 .await
-                .context("Concurrency limiter semaphore got closed during S3 list")?;
+                .context("Concurrency limiter semaphore got closed during S3 parts list")?;
 
-            metrics::inc_list_objects();
+            metrics::inc_multipart_upload("list_parts");
 
             let fetch_response = self
                 .client
-                .list_objects_v2(ListObjectsV2Request {
+                .list_parts(ListPartsRequest {
                     bucket: self.bucket_name.clone(),
-                    prefix: self.prefix_in_bucket.clone(),
-                    continuation_token,
-                    ..ListObjectsV2Request::default()
+                    key: key.0.clone(),
+                    upload_id: upload_id.to_string(),
+                    part_number_marker,
+                    ..ListPartsRequest::default()
                 })
                 .await
                 .map_err(|e| {
-                    metrics::inc_list_objects_fail();
+                    metrics::inc_multipart_upload_fail("list_parts");
                     e
                 })?;
-            document_keys.extend(
+            parts.extend(
                 fetch_response
-                    .contents
+                    .parts
                     .unwrap_or_default()
                     .into_iter()
-                    .filter_map(|o| Some(RemoteObjectId(o.key?))),
+                    .filter_map(|part| {
+                        Some((
+                            part.part_number?,
+                            UploadedPart {
+                                e_tag: part.e_tag?,
+                                size: usize::try_from(part.size?).ok()?,
+                            },
+                        ))
+                    }),
             );
 
-            match fetch_response.continuation_token {
-                Some(new_token) => continuation_token = Some(new_token),
-                None => break,
+            if fetch_response.is_truncated != Some(true) {
+                break;
             }
+            part_number_marker = fetch_response.next_part_number_marker;
         }
 
-        Ok(document_keys)
+        Ok(parts)
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        key: &RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<String> {
+        let _guard = self.concurrency_limiter.acquire().await.context(
+            "Concurrency limiter semaphore got closed during S3 multipart upload creation",
+        )?;
+
+        metrics::inc_multipart_upload("create_multipart_upload");
+        let response = self
+            .client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                bucket: self.bucket_name.clone(),
+                key: key.0.to_owned(),
+                metadata: metadata.map(|m| m.0),
+                ..CreateMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("create_multipart_upload");
+                e
+            })?;
+        response
+            .upload_id
+            .context("Got no upload id for the created S3 multipart upload")
+    }
+
+    async fn upload_part(
+        &self,
+        key: &RemoteObjectId,
+        upload_id: &str,
+        part_number: i64,
+        part_contents: Vec<u8>,
+    ) -> anyhow::Result<CompletedPart> {
+        let _guard = self
+            .concurrency_limiter
+            .acquire()
+            .await
+            .context("Concurrency limiter semaphore got closed during S3 part upload")?;
+
+        metrics::inc_multipart_upload("upload_part");
+        let content_length = part_contents.len() as i64;
+        let response = self
+            .client
+            .upload_part(UploadPartRequest {
+                body: Some(StreamingBody::from(part_contents)),
+                content_length: Some(content_length),
+                bucket: self.bucket_name.clone(),
+                key: key.0.to_owned(),
+                upload_id: upload_id.to_string(),
+                part_number,
+                ..UploadPartRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("upload_part");
+                e
+            })
+            .with_context(|| format!("Failed to upload part {part_number} of {key:?}"))?;
+
+        Ok(CompletedPart {
+            e_tag: Some(
+                response
+                    .e_tag
+                    .with_context(|| format!("Got no ETag for the uploaded part {part_number}"))?,
+            ),
+            part_number: Some(part_number),
+        })
     }
 
-    /// See the doc for `RemoteStorage::list_prefixes`
+    async fn complete_multipart_upload(
+        &self,
+        key: &RemoteObjectId,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> anyhow::Result<()> {
+        let _guard = self.concurrency_limiter.acquire().await.context(
+            "Concurrency limiter semaphore got closed during S3 multipart upload completion",
+        )?;
+
+        metrics::inc_multipart_upload("complete_multipart_upload");
+        self.client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: self.bucket_name.clone(),
+                key: key.0.to_owned(),
+                upload_id: upload_id.to_string(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                ..CompleteMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("complete_multipart_upload");
+                e
+            })?;
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(&self, key: String, upload_id: String) -> anyhow::Result<()> {
+        let _guard =
+            self.concurrency_limiter.acquire().await.context(
+                "Concurrency limiter semaphore got closed during S3 multipart upload abort",
+            )?;
+
+        metrics::inc_multipart_upload("abort_multipart_upload");
+        self.client
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: self.bucket_name.clone(),
+                key,
+                upload_id,
+                ..AbortMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("abort_multipart_upload");
+                e
+            })?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for S3Bucket {
+    fn remote_object_id(&self, local_path: &Path) -> anyhow::Result<RemoteObjectId> {
+        let relative_path = strip_path_prefix(&self.workdir, local_path)?;
+        let mut key = self.prefix_in_bucket.clone().unwrap_or_default();
+        for segment in relative_path {
+            key.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+            key.push_str(&segment.to_string_lossy());
+        }
+        Ok(RemoteObjectId(key))
+    }
+
+    fn local_path(&self, storage_path: &RemoteObjectId) -> anyhow::Result<PathBuf> {
+        Ok(download_destination(
+            storage_path,
+            &self.workdir,
+            self.prefix_in_bucket.as_deref(),
+        ))
+    }
+
+    /// See the doc for `RemoteStorage::list_page`
     /// Note: it wont include empty "directories"
-    async fn list_prefixes(
+    async fn list_page(
         &self,
         prefix: Option<&RemoteObjectId>,
-    ) -> anyhow::Result<Vec<RemoteObjectId>> {
+        mode: ListingMode,
+        continuation_token: Option<String>,
+    ) -> anyhow::Result<ListingPage> {
         // get the passed prefix or if it is not set use prefix_in_bucket value
         let list_prefix = prefix
             .map(|p| p.0.clone())
-            .or_else(|| self.prefix_in_bucket.clone())
-            .map(|mut p| {
-                // required to end with a separator
-                // otherwise request will return only the entry of a prefix
-                if !p.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
-                    p.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
-                }
-                p
-            });
-
-        let mut document_keys = Vec::new();
+            .or_else(|| self.prefix_in_bucket.clone());
+        let (list_prefix, delimiter) = match mode {
+            ListingMode::Objects => (list_prefix, None),
+            ListingMode::Prefixes => (
+                list_prefix.map(|mut p| {
+                    // required to end with a separator
+                    // otherwise request will return only the entry of a prefix
+                    if !p.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
+                        p.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+                    }
+                    p
+                }),
+                Some(REMOTE_STORAGE_PREFIX_SEPARATOR.to_string()),
+            ),
+        };
 
-        let mut continuation_token = None;
-        loop {
-            let _guard = self
-                .concurrency_limiter
-                .acquire()
-                .await
-                .context("Concurrency limiter semaphore got closed during S3 list")?;
+        let _guard = self
+            .concurrency_limiter
+            .acquire()
+            .await
+            .context("Concurrency limiter semaphore got closed during S3 list")?;
 
-            metrics::inc_list_objects();
+        metrics::inc_list_objects();
 
-            let fetch_response = self
-                .client
-                .list_objects_v2(ListObjectsV2Request {
-                    bucket: self.bucket_name.clone(),
-                    prefix: list_prefix.clone(),
-                    continuation_token,
-                    delimiter: Some(REMOTE_STORAGE_PREFIX_SEPARATOR.to_string()),
-                    ..ListObjectsV2Request::default()
-                })
-                .await
-                .map_err(|e| {
-                    metrics::inc_list_objects_fail();
-                    e
-                })?;
-
-            document_keys.extend(
-                fetch_response
-                    .common_prefixes
-                    .unwrap_or_default()
-                    .into_iter()
-                    .filter_map(|o| Some(RemoteObjectId(o.prefix?))),
-            );
+        let fetch_response = self
+            .client
+            .list_objects_v2(ListObjectsV2Request {
+                bucket: self.bucket_name.clone(),
+                prefix: list_prefix,
+                continuation_token,
+                delimiter,
+                ..ListObjectsV2Request::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_list_objects_fail();
+                e
+            })?;
 
-            match fetch_response.continuation_token {
-                Some(new_token) => continuation_token = Some(new_token),
-                None => break,
-            }
-        }
+        let items = match mode {
+            ListingMode::Objects => fetch_response
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|o| Some(RemoteObjectId(o.key?)))
+                .collect(),
+            ListingMode::Prefixes => fetch_response
+                .common_prefixes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|o| Some(RemoteObjectId(o.prefix?)))
+                .collect(),
+        };
 
-        Ok(document_keys)
+        Ok(ListingPage {
+            items,
+            // `continuation_token` in the response is the one from the request,
+            // the token for the next page is returned separately
+            continuation_token: fetch_response.next_continuation_token,
+        })
     }
 
     async fn upload(
@@ -359,6 +764,12 @@ impl RemoteStorage for S3Bucket {
         to: &RemoteObjectId,
         metadata: Option<StorageMetadata>,
     ) -> anyhow::Result<()> {
+        if from_size_bytes >= self.multipart_upload.threshold {
+            return self
+                .upload_multipart(from, from_size_bytes, to, metadata)
+                .await;
+        }
+
         let _guard = self
             .concurrency_limiter
             .acquire()
@@ -439,6 +850,44 @@ impl RemoteStorage for S3Bucket {
             })?;
         Ok(())
     }
+
+    async fn cleanup_abandoned_uploads(&self) -> anyhow::Result<()> {
+        let abandoned_before = SystemTime::now()
+            .checked_sub(self.multipart_upload.abandoned_after)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        // There might be plenty of the uploads, process them page by page instead of loading the entire listing
+        let mut uploads = self.list_multipart_uploads(self.prefix_in_bucket.clone());
+        let mut aborted = 0;
+        while let Some(upload) = uploads
+            .try_next()
+            .await
+            .context("Failed to list incomplete S3 multipart uploads")?
+        {
+            let (key, upload_id, initiated) = match (upload.key, upload.upload_id, upload.initiated)
+            {
+                (Some(key), Some(upload_id), Some(initiated)) => (key, upload_id, initiated),
+                _ => continue,
+            };
+            match humantime::parse_rfc3339_weak(&initiated) {
+                Ok(initiated) if initiated <= abandoned_before => {}
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("Failed to parse the initiation time '{initiated}' of multipart upload {upload_id} for key {key}: {e}");
+                    continue;
+                }
+            }
+
+            info!("Aborting multipart upload {upload_id} for key {key}, initiated at {initiated}");
+            self.abort_multipart_upload(key, upload_id).await?;
+            aborted += 1;
+        }
+
+        if aborted > 0 {
+            info!("Aborted {aborted} abandoned S3 multipart upload(s)");
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -597,6 +1046,52 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn multipart_upload_part_size() {
+        let storage = dummy_storage(PathBuf::new());
+        let configured_part_size = storage.multipart_upload.part_size;
+
+        assert_eq!(storage.multipart_upload.part_size(0), configured_part_size);
+        assert_eq!(
+            storage
+                .multipart_upload
+                .part_size(configured_part_size * 3 + 1),
+            configured_part_size,
+            "Files that fit into the part number limit should use the configured part size"
+        );
+
+        let huge_file_size = configured_part_size * MAX_MULTIPART_UPLOAD_PARTS + 1;
+        let part_size = storage.multipart_upload.part_size(huge_file_size);
+        assert!(part_size > configured_part_size);
+        assert!(
+            (huge_file_size + part_size - 1) / part_size <= MAX_MULTIPART_UPLOAD_PARTS,
+            "Huge file should not be split into more parts than S3 allows"
+        );
+    }
+
+    #[test]
+    fn resumed_upload_metadata() {
+        let metadata = StorageMetadata::new(HashMap::from([(
+            "Compression".to_string(),
+            "zstd".to_string(),
+        )]));
+
+        assert!(metadata_matches(None));
+        assert!(metadata_matches(Some(
+            &StorageMetadata::new(HashMap::new())
+        )));
+        assert!(!metadata_matches(Some(&metadata)));
+    }
+
+    #[test]
+    fn part_e_tag_comparison() {
+        let contents = b"some part contents";
+        let e_tag = format!("\"{:x}\"", md5::compute(contents));
+
+        assert!(part_e_tag_matches(&e_tag, contents));
+        assert!(!part_e_tag_matches(&e_tag, b"other part contents"));
+    }
+
     fn dummy_storage(workdir: PathBuf) -> S3Bucket {
         S3Bucket {
             workdir,
@@ -604,6 +1099,12 @@ mod tests {
             bucket_name: "dummy-bucket".to_string(),
             prefix_in_bucket: Some("dummy_prefix/".to_string()),
             concurrency_limiter: Semaphore::new(1),
+            multipart_upload: MultipartUploadConfig {
+                threshold: crate::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_THRESHOLD,
+                part_size: crate::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE,
+                concurrency: crate::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY,
+                abandoned_after: crate::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_ABANDONED_AFTER,
+            },
         }
     }
 
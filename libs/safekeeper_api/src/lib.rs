@@ -8,3 +8,43 @@ pub const DEFAULT_PG_LISTEN_ADDR: &str = formatcp!("127.0.0.1:{DEFAULT_PG_LISTEN
 
 pub const DEFAULT_HTTP_LISTEN_PORT: u16 = 7676;
 pub const DEFAULT_HTTP_LISTEN_ADDR: &str = formatcp!("127.0.0.1:{DEFAULT_HTTP_LISTEN_PORT}");
+
+/// Metadata key of the WAL segments in the remote storage, naming the codec the segment is compressed with.
+/// Segments without it are stored as is.
+pub const WAL_COMPRESSION_METADATA_KEY: &str = "neon-wal-compression";
+
+/// Codec of the WAL segments, backed up to the remote storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalCompression {
+    None,
+    Zstd,
+}
+
+impl WalCompression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WalCompression::None => "none",
+            WalCompression::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::str::FromStr for WalCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(WalCompression::None),
+            "zstd" => Ok(WalCompression::Zstd),
+            _ => Err(format!(
+                "unknown WAL compression '{s}', expected 'none' or 'zstd'"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for WalCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
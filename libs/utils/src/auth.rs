@@ -6,6 +6,7 @@
 use serde;
 use std::fs;
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Result};
 use jsonwebtoken::{
@@ -21,8 +22,13 @@ const JWT_ALGORITHM: Algorithm = Algorithm::RS256;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Scope {
+    // Provides access to all data for a specific tenant (specified in `struct Claims` below)
     Tenant,
+    // Provides blanket access to all tenants on the pageserver plus pageserver-wide APIs.
     PageServerApi,
+    // Provides blanket access to all data on the safekeeper plus safekeeper-wide APIs,
+    // e.g. to the storage control plane and to the peer safekeepers.
+    SafekeeperData,
 }
 
 #[serde_as]
@@ -32,11 +38,29 @@ pub struct Claims {
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub tenant_id: Option<TenantId>,
     pub scope: Scope,
+    /// Expiration time, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
 }
 
 impl Claims {
     pub fn new(tenant_id: Option<TenantId>, scope: Scope) -> Self {
-        Self { tenant_id, scope }
+        Self {
+            tenant_id,
+            scope,
+            exp: None,
+        }
+    }
+
+    /// Makes the token expire after `valid_for` from now.
+    pub fn expiring_in(self, valid_for: Duration) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before the Unix epoch");
+        Self {
+            exp: Some((now + valid_for).as_secs()),
+            ..self
+        }
     }
 }
 
@@ -53,6 +77,9 @@ pub fn check_permission(claims: &Claims, tenant_id: Option<TenantId>) -> Result<
         }
         (Scope::PageServerApi, None) => Ok(()), // access to management api for PageServerApi scope
         (Scope::PageServerApi, Some(_)) => Ok(()), // access to tenant api using PageServerApi scope
+        (Scope::SafekeeperData, _) => {
+            bail!("SafekeeperData scope makes no sense for Pageserver. Permission denied")
+        }
     }
 }
 
@@ -65,7 +92,8 @@ impl JwtAuth {
     pub fn new(decoding_key: DecodingKey) -> Self {
         let mut validation = Validation::new(JWT_ALGORITHM);
         // The default 'required_spec_claims' is 'exp'. But we don't want to require
-        // expiration.
+        // expiration for all the scopes, see `decode`. The expiration is still
+        // validated if present.
         validation.required_spec_claims = [].into();
         Self {
             decoding_key,
@@ -79,7 +107,15 @@ impl JwtAuth {
     }
 
     pub fn decode(&self, token: &str) -> Result<TokenData<Claims>> {
-        Ok(decode(token, &self.decoding_key, &self.validation)?)
+        let token_data = decode::<Claims>(token, &self.decoding_key, &self.validation)?;
+        // Safekeeperdata tokens give access to all the data of the safekeepers and are
+        // stored by the peers, so they must not be valid forever.
+        if matches!(token_data.claims.scope, Scope::SafekeeperData)
+            && token_data.claims.exp.is_none()
+        {
+            bail!("Token of safekeeperdata scope has no expiration time")
+        }
+        Ok(token_data)
     }
 }
 
@@ -96,3 +132,39 @@ pub fn encode_from_key_file(claims: &Claims, key_data: &[u8]) -> Result<String>
     let key = EncodingKey::from_rsa_pem(key_data)?;
     Ok(encode(&Header::new(JWT_ALGORITHM), claims, &key)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &[u8] = include_bytes!("../tests/key.pem");
+    const PUBLIC_KEY: &[u8] = include_bytes!("../tests/public_key.pem");
+
+    #[test]
+    fn test_safekeeper_data_expiration() {
+        let auth = JwtAuth::new(DecodingKey::from_rsa_pem(PUBLIC_KEY).unwrap());
+        let encode = |claims: &Claims| encode_from_key_file(claims, PRIVATE_KEY).unwrap();
+
+        let claims = Claims::new(None, Scope::SafekeeperData);
+        assert!(auth.decode(&encode(&claims)).is_err());
+
+        let expired = Claims {
+            exp: Some(1),
+            ..claims.clone()
+        };
+        assert!(auth.decode(&encode(&expired)).is_err());
+
+        let valid = claims.expiring_in(Duration::from_secs(3600));
+        let decoded = auth.decode(&encode(&valid)).unwrap();
+        assert_eq!(decoded.claims.exp, valid.exp);
+
+        // Other scopes do not require the expiration time, but it is checked if present.
+        let tenant = Claims::new(Some(TenantId::generate()), Scope::Tenant);
+        assert!(auth.decode(&encode(&tenant)).is_ok());
+        let expired_tenant = Claims {
+            exp: Some(1),
+            ..tenant
+        };
+        assert!(auth.decode(&encode(&expired_tenant)).is_err());
+    }
+}
@@ -174,6 +174,7 @@ impl AddAssign<u64> for Lsn {
 }
 
 /// An [`Lsn`] that can be accessed atomically.
+#[derive(Debug)]
 pub struct AtomicLsn {
     inner: AtomicU64,
 }
@@ -354,6 +354,71 @@ impl FeStartupPacket {
     }
 }
 
+/// A regular (non-startup) protocol message of either side, kept in its wire format.
+/// Unlike [`FeMessage`], it doesn't interpret the body, so it's suitable for
+/// inspecting the messages while forwarding them as is, e.g. in a connection pooler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    /// The whole message: the tag, the length and the body.
+    frame: Bytes,
+}
+
+impl RawMessage {
+    /// Length of the tag and the length fields preceding the body.
+    const HEADER_LEN: usize = 5;
+
+    /// Upper bound of the message length, the same as postgres has for the large messages
+    /// (PQ_LARGE_MESSAGE_LIMIT), so a bogus length doesn't make the reader buffer up to 4GB.
+    const MAX_LEN: u32 = 0x3fff_fffe;
+
+    /// Split the first message off the buffer.
+    /// This function returns `Ok(None)` if the message hasn't been received completely yet,
+    /// so it can be used with the cancellation-safe reads like [`tokio::io::AsyncReadExt::read_buf`].
+    pub fn parse(buf: &mut BytesMut) -> Result<Option<RawMessage>> {
+        match Self::frame_len(buf)? {
+            Some(len) if buf.len() >= len => Ok(Some(RawMessage {
+                frame: buf.split_to(len).freeze(),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Total length of the first message in the buffer, if its header has been received.
+    fn frame_len(buf: &[u8]) -> Result<Option<usize>> {
+        if buf.len() < Self::HEADER_LEN {
+            return Ok(None);
+        }
+
+        // The message length includes itself, so it better be at least 4.
+        let len = u32::from_be_bytes(buf[1..Self::HEADER_LEN].try_into().unwrap());
+        ensure!(len >= 4, "invalid message length");
+        ensure!(
+            len <= Self::MAX_LEN,
+            "message length {len} exceeds the limit"
+        );
+
+        Ok(Some(len as usize + 1))
+    }
+
+    /// Check whether the buffer starts with a complete message.
+    pub fn is_complete(buf: &[u8]) -> bool {
+        matches!(Self::frame_len(buf), Ok(Some(len)) if buf.len() >= len)
+    }
+
+    pub fn tag(&self) -> u8 {
+        self.frame[0]
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.frame[Self::HEADER_LEN..]
+    }
+
+    /// The message in its wire format.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.frame
+    }
+}
+
 impl FeParseMessage {
     fn parse(mut buf: Bytes) -> anyhow::Result<FeMessage> {
         // FIXME: the rust-postgres driver uses a named prepared statement
@@ -1025,6 +1090,51 @@ mod tests {
         assert_eq!(rf, rf_parsed);
     }
 
+    #[test]
+    fn test_raw_message_parse() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'Q');
+        buf.put_u32(4 + 9);
+        buf.put_slice(b"select 1\0");
+        buf.put_u8(b'S');
+        buf.put_u32(4);
+        buf.put_u8(b'X');
+
+        let frame_len = 1 + 4 + 9;
+        assert!(RawMessage::is_complete(&buf));
+        let query = RawMessage::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(query.tag(), b'Q');
+        assert_eq!(query.body(), b"select 1\0");
+        assert_eq!(query.as_bytes().len(), frame_len);
+
+        let sync = RawMessage::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(sync.tag(), b'S');
+        assert!(sync.body().is_empty());
+
+        // Only the tag of the last message has been received so far.
+        assert!(!RawMessage::is_complete(&buf));
+        assert_eq!(RawMessage::parse(&mut buf).unwrap(), None);
+        buf.put_u32(4);
+        assert_eq!(RawMessage::parse(&mut buf).unwrap().unwrap().tag(), b'X');
+        assert!(buf.is_empty());
+
+        buf.put_u8(b'Q');
+        buf.put_u32(3);
+        assert!(RawMessage::parse(&mut buf).is_err());
+
+        // A huge length is rejected right away, instead of waiting for the body.
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'D');
+        buf.put_u32(RawMessage::MAX_LEN + 1);
+        assert!(!RawMessage::is_complete(&buf));
+        assert!(RawMessage::parse(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'D');
+        buf.put_u32(RawMessage::MAX_LEN);
+        assert_eq!(RawMessage::parse(&mut buf).unwrap(), None);
+    }
+
     #[test]
     fn test_startup_message_params_options_escaped() {
         fn split_options(params: &StartupMessageParams) -> Vec<Cow<'_, str>> {
@@ -289,6 +289,11 @@ fn start_pageserver(conf: &'static PageServerConf, daemonize: bool) -> Result<()
     let profiler_guard = profiling::init_profiler(conf);
 
     WALRECEIVER_RUNTIME.block_on(pageserver::walreceiver::init_etcd_client(conf))?;
+    pageserver::walreceiver::init_safekeeper_wal_storage(conf)?;
+    // Safekeepers may require a token with the safekeeperdata scope for WAL streaming, see docs/authentication.md
+    pageserver::walreceiver::init_safekeeper_auth_token(
+        std::env::var("SAFEKEEPER_AUTH_TOKEN").ok(),
+    );
 
     // initialize authentication for incoming connections
     let auth = match &conf.auth_type {
@@ -75,6 +75,8 @@ pub mod defaults {
 
 # [remote_storage]
 
+# [safekeeper_wal_storage]
+
 "###
     );
 }
@@ -114,6 +116,9 @@ pub struct PageServerConf {
 
     pub auth_validation_public_key_path: Option<PathBuf>,
     pub remote_storage_config: Option<RemoteStorageConfig>,
+    /// Remote storage with the WAL, archived by safekeepers.
+    /// Allows timelines to catch up from the archive, when safekeepers have removed the WAL needed already.
+    pub safekeeper_wal_storage_config: Option<RemoteStorageConfig>,
 
     pub profiling: ProfilingConfig,
     pub default_tenant_conf: TenantConf,
@@ -184,6 +189,7 @@ struct PageServerConfigBuilder {
     //
     auth_validation_public_key_path: BuilderValue<Option<PathBuf>>,
     remote_storage_config: BuilderValue<Option<RemoteStorageConfig>>,
+    safekeeper_wal_storage_config: BuilderValue<Option<RemoteStorageConfig>>,
 
     id: BuilderValue<NodeId>,
 
@@ -213,6 +219,7 @@ impl Default for PageServerConfigBuilder {
             auth_type: Set(AuthType::Trust),
             auth_validation_public_key_path: Set(None),
             remote_storage_config: Set(None),
+            safekeeper_wal_storage_config: Set(None),
             id: NotSet,
             profiling: Set(ProfilingConfig::Disabled),
             broker_etcd_prefix: Set(etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string()),
@@ -273,6 +280,13 @@ impl PageServerConfigBuilder {
         self.remote_storage_config = BuilderValue::Set(remote_storage_config)
     }
 
+    pub fn safekeeper_wal_storage_config(
+        &mut self,
+        safekeeper_wal_storage_config: Option<RemoteStorageConfig>,
+    ) {
+        self.safekeeper_wal_storage_config = BuilderValue::Set(safekeeper_wal_storage_config)
+    }
+
     pub fn broker_endpoints(&mut self, broker_endpoints: Vec<Url>) {
         self.broker_endpoints = BuilderValue::Set(broker_endpoints)
     }
@@ -325,6 +339,9 @@ impl PageServerConfigBuilder {
             remote_storage_config: self
                 .remote_storage_config
                 .ok_or(anyhow!("missing remote_storage_config"))?,
+            safekeeper_wal_storage_config: self
+                .safekeeper_wal_storage_config
+                .ok_or(anyhow!("missing safekeeper_wal_storage_config"))?,
             id: self.id.ok_or(anyhow!("missing id"))?,
             profiling: self.profiling.ok_or(anyhow!("missing profiling"))?,
             // TenantConf is handled separately
@@ -371,6 +388,26 @@ impl PageServerConf {
             .join(METADATA_FILE_NAME)
     }
 
+    /// Points to a place in pageserver's local directory, that mirrors safekeepers' working directory
+    /// and is used to derive the remote WAL segment paths in the safekeeper WAL archive.
+    /// Nothing is stored there, the WAL segments are streamed into the timelines directly.
+    pub fn safekeeper_wal_path(&self) -> PathBuf {
+        self.workdir.join("safekeeper_wal")
+    }
+
+    /// Points to a place in [`PageServerConf::safekeeper_wal_path`], which corresponds to a certain WAL segment file of a safekeeper timeline.
+    pub fn safekeeper_wal_segment_path(
+        &self,
+        tenant_id: &TenantId,
+        timeline_id: &TimelineId,
+        segment_file_name: &str,
+    ) -> PathBuf {
+        self.safekeeper_wal_path()
+            .join(tenant_id.to_string())
+            .join(timeline_id.to_string())
+            .join(segment_file_name)
+    }
+
     //
     // Postgres distribution paths
     //
@@ -430,6 +467,8 @@ impl PageServerConf {
                 "remote_storage" => {
                     builder.remote_storage_config(Some(RemoteStorageConfig::from_toml(item)?))
                 }
+                "safekeeper_wal_storage" => builder
+                    .safekeeper_wal_storage_config(Some(RemoteStorageConfig::from_toml(item)?)),
                 "tenant_config" => {
                     t_conf = Self::parse_toml_tenant_conf(item)?;
                 }
@@ -529,6 +568,12 @@ impl PageServerConf {
         if let Some(max_lsn_wal_lag) = item.get("max_lsn_wal_lag") {
             t_conf.max_lsn_wal_lag = Some(parse_toml_from_str("max_lsn_wal_lag", max_lsn_wal_lag)?);
         }
+        if let Some(walreceiver_catchup_streams) = item.get("walreceiver_catchup_streams") {
+            t_conf.walreceiver_catchup_streams = Some(parse_toml_from_str(
+                "walreceiver_catchup_streams",
+                walreceiver_catchup_streams,
+            )?);
+        }
 
         Ok(t_conf)
     }
@@ -554,6 +599,7 @@ impl PageServerConf {
             auth_type: AuthType::Trust,
             auth_validation_public_key_path: None,
             remote_storage_config: None,
+            safekeeper_wal_storage_config: None,
             profiling: ProfilingConfig::Disabled,
             default_tenant_conf: TenantConf::dummy_conf(),
             broker_endpoints: Vec::new(),
@@ -685,6 +731,7 @@ id = 10
                 auth_type: AuthType::Trust,
                 auth_validation_public_key_path: None,
                 remote_storage_config: None,
+                safekeeper_wal_storage_config: None,
                 profiling: ProfilingConfig::Disabled,
                 default_tenant_conf: TenantConf::default(),
                 broker_endpoints: vec![broker_endpoint
@@ -729,6 +776,7 @@ id = 10
                 auth_type: AuthType::Trust,
                 auth_validation_public_key_path: None,
                 remote_storage_config: None,
+                safekeeper_wal_storage_config: None,
                 profiling: ProfilingConfig::Disabled,
                 default_tenant_conf: TenantConf::default(),
                 broker_endpoints: vec![broker_endpoint
@@ -859,6 +907,20 @@ broker_endpoints = ['{broker_endpoint}']
                         prefix_in_bucket: Some(prefix_in_bucket.clone()),
                         endpoint: Some(endpoint.clone()),
                         concurrency_limit: s3_concurrency_limit,
+                        multipart_upload_threshold: NonZeroUsize::new(
+                            remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_THRESHOLD
+                        )
+                        .unwrap(),
+                        multipart_upload_part_size: NonZeroUsize::new(
+                            remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_PART_SIZE
+                        )
+                        .unwrap(),
+                        multipart_upload_concurrency: NonZeroUsize::new(
+                            remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY
+                        )
+                        .unwrap(),
+                        multipart_upload_abandoned_after:
+                            remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_ABANDONED_AFTER,
                     }),
                 },
                 "Remote storage config should correctly parse the S3 config"
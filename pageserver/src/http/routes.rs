@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context, Result};
@@ -10,11 +11,11 @@ use tracing::*;
 use super::models::{LocalTimelineInfo, RemoteTimelineInfo, TimelineInfo};
 use super::models::{
     StatusResponse, TenantConfigRequest, TenantCreateRequest, TenantCreateResponse, TenantInfo,
-    TimelineCreateRequest,
+    TenantSnapshotRequest, TenantSnapshotResponse, TimelineCreateRequest,
 };
 use crate::storage_sync;
 use crate::storage_sync::index::{RemoteIndex, RemoteTimeline};
-use crate::tenant::{TenantState, Timeline};
+use crate::tenant::{Tenant, TenantState, Timeline};
 use crate::tenant_config::TenantConfOpt;
 use crate::{config::PageServerConf, tenant_mgr};
 use utils::{
@@ -415,6 +416,132 @@ async fn tenant_attach_handler(request: Request<Body>) -> Result<Response<Body>,
     json_response(StatusCode::ACCEPTED, ())
 }
 
+/// Creates a new tenant with the source tenant's config and timelines, cut at the requested LSNs.
+/// The new timelines get copies of the remote layers of the source tenant and get downloaded the same way as on attach.
+async fn tenant_snapshot_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let source_tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, None)?;
+
+    let request_data: TenantSnapshotRequest = json_request(&mut request).await?;
+    let target_tenant_id = request_data
+        .new_tenant_id
+        .unwrap_or_else(TenantId::generate);
+    if target_tenant_id == source_tenant_id {
+        return Err(ApiError::BadRequest(anyhow!(
+            "Snapshot tenant id should differ from the source one"
+        )));
+    }
+
+    info!("Handling tenant {source_tenant_id} snapshot into tenant {target_tenant_id}");
+
+    let state = get_state(&request);
+    let conf = state.conf;
+    let remote_index = &state.remote_index;
+    let storage = state.remote_storage.as_ref().ok_or_else(|| {
+        ApiError::BadRequest(anyhow!(
+            "Tenant snapshots require the remote storage to be configured"
+        ))
+    })?;
+
+    let tenant_conf = tokio::task::spawn_blocking(move || {
+        if tenant_mgr::get_tenant(target_tenant_id, false).is_ok() {
+            return Err(ApiError::Conflict(format!(
+                "Tenant {target_tenant_id} already exists"
+            )));
+        }
+        match tenant_mgr::get_tenant(source_tenant_id, false) {
+            Ok(tenant) => Ok(tenant.get_tenant_conf()),
+            Err(_) => Tenant::load_tenant_config(conf, source_tenant_id)
+                .map_err(ApiError::InternalServerError),
+        }
+    })
+    .await
+    .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))??;
+
+    let mut snapshot_timelines = {
+        let index_accessor = remote_index.read().await;
+        if index_accessor.tenant_entry(&target_tenant_id).is_some() {
+            return Err(ApiError::Conflict(format!(
+                "Tenant {target_tenant_id} is already present in the remote index"
+            )));
+        }
+        let source_tenant = index_accessor
+            .tenant_entry(&source_tenant_id)
+            .ok_or_else(|| {
+                ApiError::NotFound(anyhow!(
+                    "Tenant {source_tenant_id} is not found in the remote index"
+                ))
+            })?;
+        storage_sync::snapshot::plan_tenant_snapshot(
+            conf,
+            source_tenant_id,
+            source_tenant,
+            target_tenant_id,
+            &request_data.timeline_lsns,
+        )
+        .map_err(ApiError::BadRequest)?
+    };
+
+    // Create the tenant first: it reserves the tenant id, so nothing gets uploaded into the remote prefix of a tenant
+    // created concurrently with the same id.
+    let remote_index_for_tenant = remote_index.clone();
+    let new_tenant_id = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_snapshot", tenant = ?target_tenant_id).entered();
+        tenant_mgr::create_tenant(conf, tenant_conf, target_tenant_id, remote_index_for_tenant)
+            .map_err(ApiError::InternalServerError)
+    })
+    .await
+    .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))??;
+    if new_tenant_id.is_none() {
+        return Err(ApiError::Conflict(format!(
+            "Tenant {target_tenant_id} already exists"
+        )));
+    }
+
+    let populate_result = async {
+        for timeline_id in snapshot_timelines.keys() {
+            tokio::fs::create_dir_all(conf.timeline_path(timeline_id, &target_tenant_id))
+                .await
+                .context("Failed to create new timeline directory")?;
+        }
+        storage_sync::snapshot::upload_snapshot_timelines(
+            conf,
+            storage,
+            target_tenant_id,
+            &mut snapshot_timelines,
+        )
+        .await
+    }
+    .await;
+    if let Err(e) = populate_result {
+        // Don't leave a tenant without the snapshot timelines behind, the uploaded objects are removed already
+        if let Err(detach_error) = tenant_mgr::detach_tenant(conf, target_tenant_id).await {
+            error!("Failed to remove snapshot tenant {target_tenant_id} after a failed snapshot: {detach_error:?}");
+        }
+        return Err(ApiError::InternalServerError(e));
+    }
+
+    let mut timeline_lsns = HashMap::with_capacity(snapshot_timelines.len());
+    let mut index_accessor = remote_index.write().await;
+    let tenant_entry = index_accessor.add_tenant_entry(target_tenant_id);
+    // populate remote index with the snapshot timelines
+    for (timeline_id, mut remote_timeline) in snapshot_timelines {
+        timeline_lsns.insert(timeline_id, remote_timeline.metadata.disk_consistent_lsn());
+        remote_timeline.awaits_download = true;
+        tenant_entry.insert(timeline_id, remote_timeline);
+        // schedule actual download
+        storage_sync::schedule_layer_download(target_tenant_id, timeline_id);
+    }
+
+    json_response(
+        StatusCode::CREATED,
+        TenantSnapshotResponse {
+            tenant_id: target_tenant_id,
+            timeline_lsns,
+        },
+    )
+}
+
 /// Note: is expensive from s3 access perspective,
 /// for details see comment to `storage_sync::gather_tenant_timelines_index_parts`
 async fn gather_tenant_timelines_index_parts(
@@ -617,6 +744,9 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
     if let Some(max_lsn_wal_lag) = request_data.max_lsn_wal_lag {
         tenant_conf.max_lsn_wal_lag = Some(max_lsn_wal_lag);
     }
+    if let Some(walreceiver_catchup_streams) = request_data.walreceiver_catchup_streams {
+        tenant_conf.walreceiver_catchup_streams = Some(walreceiver_catchup_streams);
+    }
 
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     if let Some(checkpoint_timeout) = request_data.checkpoint_timeout {
@@ -704,6 +834,9 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
     if let Some(max_lsn_wal_lag) = request_data.max_lsn_wal_lag {
         tenant_conf.max_lsn_wal_lag = Some(max_lsn_wal_lag);
     }
+    if let Some(walreceiver_catchup_streams) = request_data.walreceiver_catchup_streams {
+        tenant_conf.walreceiver_catchup_streams = Some(walreceiver_catchup_streams);
+    }
 
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     if let Some(checkpoint_timeout) = request_data.checkpoint_timeout {
@@ -904,6 +1037,7 @@ pub fn make_router(
         .post("/v1/tenant/:tenant_id/timeline", timeline_create_handler)
         .post("/v1/tenant/:tenant_id/attach", tenant_attach_handler)
         .post("/v1/tenant/:tenant_id/detach", tenant_detach_handler)
+        .post("/v1/tenant/:tenant_id/snapshot", tenant_snapshot_handler)
         .get(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_detail_handler,
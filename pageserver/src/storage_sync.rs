@@ -6,6 +6,7 @@
 //! Synchronization internals are split into submodules
 //!     * [`storage_sync::index`] to keep track of remote tenant files, the metadata and their mappings to local files
 //!     * [`storage_sync::upload`] and [`storage_sync::download`] to manage archive creation and upload; download and extraction, respectively
+//!     * [`storage_sync::snapshot`] to create tenants, referencing the remote layers of other tenants at certain LSNs
 //!
 //! * public API via to interact with the external world:
 //!     * [`start_local_timeline_sync`] to launch a background async loop to handle the synchronization
@@ -141,6 +142,7 @@
 mod delete;
 mod download;
 pub mod index;
+pub mod snapshot;
 mod upload;
 
 use std::{
@@ -608,6 +610,10 @@ pub fn spawn_storage_sync_task(
         }
     }
 
+    if let Err(e) = BACKGROUND_RUNTIME.block_on(storage.cleanup_abandoned_uploads()) {
+        warn!("Failed to clean up abandoned remote storage uploads: {e:?}");
+    }
+
     let applicable_index_parts = BACKGROUND_RUNTIME.block_on(download_index_parts(
         conf,
         &storage,
@@ -1066,6 +1072,16 @@ async fn delete_timeline_data(
     let timeline_delete = &mut new_delete_data.data;
 
     if !timeline_delete.deletion_registered {
+        let foreign_layers = match index.read().await.timeline_entry(&sync_id) {
+            Some(remote_timeline) => timeline_delete
+                .layers_to_delete
+                .iter()
+                .filter(|layer| remote_timeline.foreign_layer_owner(layer).is_some())
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+
         if let Err(e) = update_remote_data(
             conf,
             storage,
@@ -1081,6 +1097,12 @@ async fn delete_timeline_data(
             register_sync_status(sync_id, sync_start, TASK_NAME, Some(false));
             return;
         }
+
+        // Foreign layers belong to other timelines' remote directories, only drop the references to them
+        for layer in foreign_layers {
+            timeline_delete.layers_to_delete.remove(&layer);
+            timeline_delete.deleted_layers.insert(layer);
+        }
     }
     timeline_delete.deletion_registered = true;
 
@@ -1412,6 +1434,8 @@ fn register_sync_status(
 
 #[cfg(test)]
 mod test_utils {
+    use futures::TryStreamExt;
+    use remote_storage::{ListingMode, LocalFs, RemoteObjectId, RemoteStorage};
     use utils::lsn::Lsn;
 
     use crate::tenant::harness::TenantHarness;
@@ -1447,6 +1471,16 @@ mod test_utils {
         })
     }
 
+    /// Lists all the objects of the remote storage in the tests.
+    pub(super) async fn list_remote_objects(
+        storage: &LocalFs,
+    ) -> anyhow::Result<Vec<RemoteObjectId>> {
+        storage
+            .list_stream(None, ListingMode::Objects)
+            .try_collect()
+            .await
+    }
+
     pub(super) fn dummy_contents(name: &str) -> String {
         format!("contents for {name}")
     }
@@ -112,7 +112,7 @@ mod tests {
     use utils::lsn::Lsn;
 
     use crate::{
-        storage_sync::test_utils::{create_local_timeline, dummy_metadata},
+        storage_sync::test_utils::{create_local_timeline, dummy_metadata, list_remote_objects},
         tenant::harness::{TenantHarness, TIMELINE_ID},
     };
     use remote_storage::{LocalFs, RemoteStorage};
@@ -181,8 +181,7 @@ mod tests {
             fs::copy(&local_path, &remote_path).await?;
         }
         assert_eq!(
-            local_storage
-                .list()
+            list_remote_objects(local_storage)
                 .await?
                 .into_iter()
                 .map(|remote_path| local_storage.local_path(&remote_path).unwrap())
@@ -218,8 +217,7 @@ mod tests {
         assert!(deleted, "Should be able to delete timeline files");
 
         assert_eq!(
-            local_storage
-                .list()
+            list_remote_objects(local_storage)
                 .await?
                 .into_iter()
                 .map(|remote_path| local_storage.local_path(&remote_path).unwrap())
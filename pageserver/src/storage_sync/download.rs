@@ -9,7 +9,7 @@ use std::{
 
 use anyhow::Context;
 use futures::stream::{FuturesUnordered, StreamExt};
-use remote_storage::{DownloadError, GenericRemoteStorage};
+use remote_storage::{DownloadError, GenericRemoteStorage, ListingMode};
 use tokio::{
     fs,
     io::{self, AsyncWriteExt},
@@ -261,7 +261,18 @@ pub(super) async fn download_timeline_layers<'a>(
                         )
                     })?;
 
-                let mut layer_download = storage.download_storage_object(None, &layer_destination_path)
+                // Foreign layers are stored under the same file name, but in the remote directory of another timeline
+                let layer_source_path = match (
+                    remote_timeline.foreign_layer_owner(&layer_destination_path),
+                    layer_destination_path.file_name(),
+                ) {
+                    (Some(owner), Some(file_name)) => conf
+                        .timeline_path(&owner.timeline_id, &owner.tenant_id)
+                        .join(file_name),
+                    _ => layer_destination_path.clone(),
+                };
+
+                let mut layer_download = storage.download_storage_object(None, &layer_source_path)
                     .await
                     .with_context(|| {
                         format!(
@@ -376,22 +387,17 @@ async fn get_timeline_sync_ids(
         )
     })?;
 
-    let timelines = storage
-        .list_prefixes(Some(&tenant_storage_path))
-        .await
-        .with_context(|| {
+    // Tenants may have plenty of timelines, process them page by page instead of loading the entire listing
+    let mut timelines = storage.list_stream(Some(&tenant_storage_path), ListingMode::Prefixes);
+    let mut sync_ids = HashSet::new();
+
+    while let Some(timeline_remote_storage_key) = timelines.next().await {
+        let timeline_remote_storage_key = timeline_remote_storage_key.with_context(|| {
             format!(
                 "Failed to list tenant storage path {tenant_storage_path:?} to get remote timelines to download"
             )
         })?;
 
-    if timelines.is_empty() {
-        anyhow::bail!("no timelines found on the remote storage")
-    }
-
-    let mut sync_ids = HashSet::new();
-
-    for timeline_remote_storage_key in timelines {
         let object_name = timeline_remote_storage_key.object_name().ok_or_else(|| {
             anyhow::anyhow!("failed to get timeline id for remote tenant {tenant_id}")
         })?;
@@ -406,6 +412,10 @@ async fn get_timeline_sync_ids(
         });
     }
 
+    if sync_ids.is_empty() {
+        anyhow::bail!("no timelines found on the remote storage")
+    }
+
     Ok(sync_ids)
 }
 
@@ -214,6 +214,8 @@ impl RemoteTimelineIndex {
 pub struct RemoteTimeline {
     timeline_layers: HashSet<PathBuf>,
     missing_layers: HashSet<PathBuf>,
+    /// A subset of the timeline layers, that are stored in the remote directories of other timelines.
+    foreign_layers: HashMap<PathBuf, TenantTimelineId>,
 
     pub metadata: TimelineMetadata,
     pub awaits_download: bool,
@@ -224,6 +226,7 @@ impl RemoteTimeline {
         Self {
             timeline_layers: HashSet::new(),
             missing_layers: HashSet::new(),
+            foreign_layers: HashMap::new(),
             metadata,
             awaits_download: false,
         }
@@ -233,6 +236,17 @@ impl RemoteTimeline {
         self.timeline_layers.extend(new_layers.into_iter());
     }
 
+    /// Adds layers, stored in the remote directories of the timelines given, without copying them.
+    pub fn add_foreign_layers(
+        &mut self,
+        new_layers: impl IntoIterator<Item = (PathBuf, TenantTimelineId)>,
+    ) {
+        for (layer, owner) in new_layers {
+            self.timeline_layers.insert(layer.clone());
+            self.foreign_layers.insert(layer, owner);
+        }
+    }
+
     pub fn add_upload_failures(&mut self, upload_failures: impl IntoIterator<Item = PathBuf>) {
         self.missing_layers.extend(upload_failures.into_iter());
     }
@@ -242,6 +256,19 @@ impl RemoteTimeline {
             .retain(|layer| !layers_to_remove.contains(layer));
         self.missing_layers
             .retain(|layer| !layers_to_remove.contains(layer));
+        self.foreign_layers
+            .retain(|layer, _| !layers_to_remove.contains(layer));
+    }
+
+    /// Returns the timeline whose remote directory stores the layer, if it is not the current timeline.
+    pub fn foreign_layer_owner(&self, layer: &Path) -> Option<TenantTimelineId> {
+        self.foreign_layers.get(layer).copied()
+    }
+
+    /// Makes the foreign layers regular timeline layers, e.g. after copying them into the timeline's remote directory.
+    /// Returns the former owners of the layers.
+    pub fn take_foreign_layers(&mut self) -> HashMap<PathBuf, TenantTimelineId> {
+        std::mem::take(&mut self.foreign_layers)
     }
 
     /// Lists all layer files in the given remote timeline. Omits the metadata file.
@@ -254,6 +281,11 @@ impl RemoteTimeline {
         Ok(Self {
             timeline_layers: to_local_paths(timeline_path, index_part.timeline_layers),
             missing_layers: to_local_paths(timeline_path, index_part.missing_layers),
+            foreign_layers: index_part
+                .foreign_layers
+                .into_iter()
+                .map(|(layer, source)| (layer.as_path(timeline_path), source.into()))
+                .collect(),
             metadata,
             awaits_download: false,
         })
@@ -272,11 +304,40 @@ pub struct IndexPart {
     /// Such "holes" might appear if any upload task was evicted on an error threshold:
     /// the this layer will only be rescheduled for upload on pageserver restart.
     missing_layers: HashSet<RelativePath>,
+    /// A subset of the timeline layers, that are stored in the remote directories of other timelines,
+    /// e.g. for a timeline of a tenant, created as a snapshot of another tenant.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    foreign_layers: HashMap<RelativePath, ForeignLayerSource>,
     #[serde_as(as = "DisplayFromStr")]
     disk_consistent_lsn: Lsn,
     metadata_bytes: Vec<u8>,
 }
 
+/// The timeline, whose remote directory contains a foreign layer of another timeline.
+#[serde_as]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ForeignLayerSource {
+    #[serde_as(as = "DisplayFromStr")]
+    tenant_id: TenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    timeline_id: TimelineId,
+}
+
+impl From<TenantTimelineId> for ForeignLayerSource {
+    fn from(id: TenantTimelineId) -> Self {
+        Self {
+            tenant_id: id.tenant_id,
+            timeline_id: id.timeline_id,
+        }
+    }
+}
+
+impl From<ForeignLayerSource> for TenantTimelineId {
+    fn from(source: ForeignLayerSource) -> Self {
+        TenantTimelineId::new(source.tenant_id, source.timeline_id)
+    }
+}
+
 impl IndexPart {
     pub const FILE_NAME: &'static str = "index_part.json";
 
@@ -290,6 +351,7 @@ impl IndexPart {
         Self {
             timeline_layers,
             missing_layers,
+            foreign_layers: HashMap::new(),
             disk_consistent_lsn,
             metadata_bytes,
         }
@@ -309,6 +371,12 @@ impl IndexPart {
                 .context("Failed to convert timeline layers' paths to relative ones")?,
             missing_layers: to_relative_paths(timeline_path, remote_timeline.missing_layers)
                 .context("Failed to convert missing layers' paths to relative ones")?,
+            foreign_layers: remote_timeline
+                .foreign_layers
+                .into_iter()
+                .map(|(layer, owner)| Ok((RelativePath::new(timeline_path, layer)?, owner.into())))
+                .collect::<anyhow::Result<_>>()
+                .context("Failed to convert foreign layers' paths to relative ones")?,
             disk_consistent_lsn: remote_timeline.metadata.disk_consistent_lsn(),
             metadata_bytes,
         })
@@ -365,6 +433,7 @@ mod tests {
                 timeline_path.join("missing_1"),
                 timeline_path.join("missing_2"),
             ]),
+            foreign_layers: HashMap::new(),
             metadata: metadata.clone(),
             awaits_download: false,
         };
@@ -468,6 +537,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn index_part_foreign_layers_conversion() {
+        let harness = TenantHarness::create("index_part_foreign_layers_conversion").unwrap();
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let metadata = TimelineMetadata::new(
+            Lsn(5).align(),
+            Some(Lsn(4)),
+            None,
+            Lsn(3),
+            Lsn(2),
+            Lsn(1),
+            DEFAULT_PG_VERSION,
+        );
+        let owner = TenantTimelineId::generate();
+
+        let mut remote_timeline = RemoteTimeline::new(metadata);
+        remote_timeline.add_timeline_layers([timeline_path.join("layer_1")]);
+        remote_timeline.add_foreign_layers([(timeline_path.join("foreign_1"), owner)]);
+
+        let index_part = IndexPart::from_remote_timeline(&timeline_path, remote_timeline)
+            .expect("Correct remote timeline should be convertible to index part");
+        assert_eq!(
+            index_part.timeline_layers.iter().collect::<BTreeSet<_>>(),
+            BTreeSet::from([
+                &RelativePath("foreign_1".to_string()),
+                &RelativePath("layer_1".to_string())
+            ]),
+            "Index part should list foreign layers among the timeline layers"
+        );
+        assert_eq!(
+            index_part.foreign_layers,
+            HashMap::from([(RelativePath("foreign_1".to_string()), owner.into())]),
+            "Index part should keep the owners of the foreign layers"
+        );
+
+        let serialized = serde_json::to_string(&index_part).unwrap();
+        let deserialized: IndexPart = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(index_part, deserialized);
+
+        let restored_timeline = RemoteTimeline::from_index_part(&timeline_path, deserialized)
+            .expect("Correct index part should be convertible to remote timeline");
+        assert_eq!(
+            restored_timeline.foreign_layer_owner(&timeline_path.join("foreign_1")),
+            Some(owner)
+        );
+        assert_eq!(
+            restored_timeline.foreign_layer_owner(&timeline_path.join("layer_1")),
+            None
+        );
+    }
+
     #[test]
     fn index_part_conversion_negatives() {
         let harness = TenantHarness::create("index_part_conversion_negatives").unwrap();
@@ -493,6 +613,7 @@ mod tests {
                     timeline_path.join("missing_1"),
                     timeline_path.join("missing_2"),
                 ]),
+                foreign_layers: HashMap::new(),
                 metadata: metadata.clone(),
                 awaits_download: false,
             },
@@ -510,6 +631,7 @@ mod tests {
                     PathBuf::from("bad_path"),
                     timeline_path.join("missing_2"),
                 ]),
+                foreign_layers: HashMap::new(),
                 metadata,
                 awaits_download: false,
             },
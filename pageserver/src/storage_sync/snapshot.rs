@@ -0,0 +1,713 @@
+//! Tenant snapshots: a new tenant, whose timelines get the remote layers of another tenant's timelines, cut at certain LSNs.
+//!
+//! The snapshot is planned with the layers marked as foreign ones, pointing at the remote directories of the timelines
+//! that have uploaded them originally. Before the [`IndexPart`] of a snapshot timeline is uploaded, its layers are copied
+//! into its own remote directory, so the snapshot does not depend on the source tenant, which is free to remove its layers
+//! by GC, compaction or deletion. The regular download machinery fetches the snapshot layers afterwards.
+//! The branch structure is preserved, since the snapshot timelines keep the ids of their source counterparts.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+use remote_storage::GenericRemoteStorage;
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+};
+use tracing::*;
+use utils::{
+    crashsafe_dir::path_with_suffix_extension,
+    id::{TenantId, TenantTimelineId, TimelineId},
+    lsn::Lsn,
+};
+
+use super::{
+    index::{IndexPart, RemoteTimeline, TenantEntry},
+    upload::upload_index_part,
+};
+use crate::{
+    config::PageServerConf,
+    tenant::{
+        filename::{DeltaFileName, ImageFileName},
+        metadata::TimelineMetadata,
+    },
+    TEMP_FILE_SUFFIX,
+};
+
+/// Computes the remote timelines of the snapshot tenant, for every timeline of the source tenant.
+///
+/// Each timeline is cut at the LSN requested in `timeline_lsns`, or at its remote `disk_consistent_lsn`, if not specified.
+/// Since no WAL is available for the new tenant, the cut snaps down to the closest LSN that the remote layers are consistent at:
+/// the resulting `disk_consistent_lsn` of the snapshot timeline contains the actual LSN.
+pub fn plan_tenant_snapshot(
+    conf: &'static PageServerConf,
+    source_tenant_id: TenantId,
+    source_tenant: &TenantEntry,
+    target_tenant_id: TenantId,
+    timeline_lsns: &HashMap<TimelineId, Lsn>,
+) -> anyhow::Result<HashMap<TimelineId, RemoteTimeline>> {
+    if let Some(unknown_timeline_id) = timeline_lsns
+        .keys()
+        .find(|timeline_id| !source_tenant.contains_key(timeline_id))
+    {
+        bail!("Timeline {unknown_timeline_id} is not found in the remote index of tenant {source_tenant_id}");
+    }
+
+    let mut snapshot_timelines = HashMap::with_capacity(source_tenant.len());
+    for (timeline_id, source_timeline) in source_tenant.iter() {
+        let snapshot_timeline = snapshot_timeline(
+            conf,
+            TenantTimelineId::new(source_tenant_id, *timeline_id),
+            source_timeline,
+            target_tenant_id,
+            timeline_lsns.get(timeline_id).copied(),
+        )
+        .with_context(|| format!("Failed to snapshot timeline {timeline_id}"))?;
+        snapshot_timelines.insert(*timeline_id, snapshot_timeline);
+    }
+
+    for (timeline_id, snapshot_timeline) in &snapshot_timelines {
+        let metadata = &snapshot_timeline.metadata;
+        if let Some(ancestor_id) = metadata.ancestor_timeline() {
+            let ancestor = snapshot_timelines.get(&ancestor_id).with_context(|| {
+                format!("Ancestor {ancestor_id} of timeline {timeline_id} is not found in the remote index of tenant {source_tenant_id}")
+            })?;
+            let ancestor_lsn = ancestor.metadata.disk_consistent_lsn();
+            ensure!(
+                ancestor_lsn >= metadata.ancestor_lsn(),
+                "Ancestor {ancestor_id} is cut at {ancestor_lsn}, before timeline {timeline_id} branches off it at {}",
+                metadata.ancestor_lsn()
+            );
+        }
+    }
+
+    Ok(snapshot_timelines)
+}
+
+/// Uploads the snapshot timelines into the remote storage: copies their layers from the remote directories of the source
+/// timelines and uploads the index parts, after which the snapshot timelines own all their layers.
+/// On failure, removes everything uploaded already, so no partial snapshot is left in the remote storage.
+///
+/// Expects the local directories of the snapshot timelines to exist, the layers are copied through temporary files there.
+pub async fn upload_snapshot_timelines(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    target_tenant_id: TenantId,
+    snapshot_timelines: &mut HashMap<TimelineId, RemoteTimeline>,
+) -> anyhow::Result<()> {
+    let mut uploaded = Vec::new();
+    for (timeline_id, snapshot_timeline) in snapshot_timelines.iter_mut() {
+        let sync_id = TenantTimelineId::new(target_tenant_id, *timeline_id);
+        if let Err(e) =
+            upload_snapshot_timeline(conf, storage, sync_id, snapshot_timeline, &mut uploaded).await
+        {
+            remove_snapshot_objects(storage, &uploaded).await;
+            return Err(e);
+        }
+        debug!("Uploaded snapshot timeline {sync_id}");
+    }
+    Ok(())
+}
+
+/// Copies the foreign layers of the snapshot timeline and uploads its index part,
+/// adding the local paths of the uploaded objects to `uploaded`.
+async fn upload_snapshot_timeline(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    sync_id: TenantTimelineId,
+    snapshot_timeline: &mut RemoteTimeline,
+    uploaded: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    for (layer, owner) in snapshot_timeline.take_foreign_layers() {
+        copy_foreign_layer(conf, storage, &layer, owner)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to copy layer '{}' of timeline {owner} into snapshot timeline {sync_id}",
+                    layer.display()
+                )
+            })?;
+        uploaded.push(layer);
+    }
+
+    let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+    let index_part = IndexPart::from_remote_timeline(&timeline_path, snapshot_timeline.clone())
+        .with_context(|| format!("Failed to create index part for snapshot timeline {sync_id}"))?;
+    upload_index_part(conf, storage, sync_id, index_part).await?;
+    uploaded.push(timeline_path.join(IndexPart::FILE_NAME));
+    Ok(())
+}
+
+/// Copies the layer from the remote directory of its owner into the remote path of `layer`,
+/// so that removing the layer from the owner's directory (e.g. by GC, compaction or timeline deletion) does not affect the copy.
+async fn copy_foreign_layer(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    layer: &Path,
+    owner: TenantTimelineId,
+) -> anyhow::Result<()> {
+    let file_name = layer
+        .file_name()
+        .with_context(|| format!("Layer path '{}' has no file name", layer.display()))?;
+    let source_path = conf
+        .timeline_path(&owner.timeline_id, &owner.tenant_id)
+        .join(file_name);
+    let temp_file_path = path_with_suffix_extension(layer, TEMP_FILE_SUFFIX);
+
+    let copy_result = async {
+        let mut download = storage
+            .download_storage_object(None, &source_path)
+            .await
+            .context("Failed to download the source layer")?;
+        let mut temp_file = fs::File::create(&temp_file_path)
+            .await
+            .context("Failed to create a temporary layer file")?;
+        io::copy(&mut download.download_stream, &mut temp_file)
+            .await
+            .context("Failed to download the source layer into a temporary file")?;
+        temp_file.flush().await?;
+        drop(temp_file);
+
+        let temp_file = fs::File::open(&temp_file_path).await?;
+        let temp_file_size = temp_file.metadata().await?.len() as usize;
+        storage
+            .upload_storage_object(Box::new(temp_file), temp_file_size, layer, None)
+            .await
+    }
+    .await;
+
+    // The regular download of the snapshot timeline fetches the layer again, with all the durability precautions.
+    if let Err(e) = fs::remove_file(&temp_file_path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!(
+                "Failed to remove temporary layer file '{}': {e}",
+                temp_file_path.display()
+            );
+        }
+    }
+    copy_result
+}
+
+/// Removes the uploaded objects of the snapshot timelines, if the snapshot could not be uploaded entirely.
+/// Best effort: failures are logged, since the snapshot has failed already.
+async fn remove_snapshot_objects(storage: &GenericRemoteStorage, uploaded: &[PathBuf]) {
+    for path in uploaded {
+        let removal = async {
+            let remote_object_id = storage.remote_object_id(path)?;
+            storage.delete(&remote_object_id).await
+        }
+        .await;
+        match removal {
+            Ok(()) => debug!("Removed snapshot object '{}'", path.display()),
+            Err(e) => error!(
+                "Failed to remove snapshot object '{}': {e:?}",
+                path.display()
+            ),
+        }
+    }
+}
+
+enum LayerName {
+    Image(ImageFileName),
+    Delta(DeltaFileName),
+}
+
+fn snapshot_timeline(
+    conf: &'static PageServerConf,
+    source_id: TenantTimelineId,
+    source_timeline: &RemoteTimeline,
+    target_tenant_id: TenantId,
+    requested_lsn: Option<Lsn>,
+) -> anyhow::Result<RemoteTimeline> {
+    let source_metadata = &source_timeline.metadata;
+    let remote_lsn = source_metadata.disk_consistent_lsn();
+    let lower_bound = source_metadata
+        .ancestor_lsn()
+        .max(source_metadata.latest_gc_cutoff_lsn());
+
+    let mut cut_lsn = requested_lsn.unwrap_or(remote_lsn);
+    ensure!(
+        cut_lsn <= remote_lsn,
+        "Requested lsn {cut_lsn} is ahead of the remote disk consistent lsn {remote_lsn}"
+    );
+    ensure!(
+        cut_lsn >= lower_bound,
+        "Requested lsn {cut_lsn} is behind the ancestor lsn or the gc cutoff lsn {lower_bound}"
+    );
+
+    let source_path = conf.timeline_path(&source_id.timeline_id, &source_id.tenant_id);
+    let mut layers = Vec::with_capacity(source_timeline.stored_files().len());
+    for layer in source_timeline.stored_files() {
+        let file_name = layer
+            .strip_prefix(&source_path)
+            .ok()
+            .and_then(|file_name| file_name.to_str())
+            .with_context(|| format!("Unexpected remote layer path '{}'", layer.display()))?;
+        let layer_name = if let Some(image) = ImageFileName::parse_str(file_name) {
+            LayerName::Image(image)
+        } else if let Some(delta) = DeltaFileName::parse_str(file_name) {
+            LayerName::Delta(delta)
+        } else {
+            bail!("Unexpected remote layer file name '{file_name}'");
+        };
+        let owner = source_timeline
+            .foreign_layer_owner(layer)
+            .unwrap_or(source_id);
+        layers.push((file_name, layer_name, owner));
+    }
+
+    // A delta layer, crossing the cut, holds the WAL records right after the cut and cannot be included partially:
+    // move the cut before such layers, until none are left.
+    while let Some(crossing_start) = layers
+        .iter()
+        .filter_map(|(_, layer_name, _)| match layer_name {
+            LayerName::Delta(delta)
+                if delta.lsn_range.start <= cut_lsn && delta.lsn_range.end > cut_lsn + 1 =>
+            {
+                Some(delta.lsn_range.start)
+            }
+            _ => None,
+        })
+        .min()
+    {
+        cut_lsn = crossing_start
+            .checked_sub(1u64)
+            .with_context(|| format!("Delta layer starting at {crossing_start} crosses the cut"))?;
+    }
+
+    let target_path = conf.timeline_path(&source_id.timeline_id, &target_tenant_id);
+    let mut disk_consistent_lsn = source_metadata.ancestor_lsn();
+    let mut foreign_layers = Vec::new();
+    for (file_name, layer_name, owner) in layers {
+        let layer_lsn = match layer_name {
+            LayerName::Image(image) => image.lsn,
+            LayerName::Delta(delta) => match delta.lsn_range.end.checked_sub(1u64) {
+                Some(last_lsn) => last_lsn,
+                None => continue,
+            },
+        };
+        if layer_lsn <= cut_lsn {
+            disk_consistent_lsn = disk_consistent_lsn.max(layer_lsn);
+            foreign_layers.push((target_path.join(file_name), owner));
+        }
+    }
+
+    ensure!(
+        disk_consistent_lsn >= lower_bound,
+        "No consistent remote state found between the ancestor lsn or the gc cutoff lsn {lower_bound} and the requested lsn {}",
+        requested_lsn.unwrap_or(remote_lsn)
+    );
+    info!(
+        "Snapshot of timeline {source_id} is cut at lsn {disk_consistent_lsn}, with {} layers",
+        foreign_layers.len()
+    );
+
+    let mut snapshot_timeline = RemoteTimeline::new(TimelineMetadata::new(
+        disk_consistent_lsn,
+        None,
+        source_metadata.ancestor_timeline(),
+        source_metadata.ancestor_lsn(),
+        source_metadata.latest_gc_cutoff_lsn(),
+        source_metadata.initdb_lsn(),
+        source_metadata.pg_version(),
+    ));
+    snapshot_timeline.add_foreign_layers(foreign_layers);
+    Ok(snapshot_timeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use remote_storage::LocalFs;
+    use tempfile::tempdir;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        repository::Key,
+        storage_sync::test_utils::list_remote_objects,
+        tenant::harness::{TenantHarness, NEW_TIMELINE_ID, TIMELINE_ID},
+        DEFAULT_PG_VERSION,
+    };
+
+    fn image(lsn: u64) -> String {
+        ImageFileName {
+            key_range: Key::MIN..Key::MAX,
+            lsn: Lsn(lsn),
+        }
+        .to_string()
+    }
+
+    fn delta(start: u64, end: u64) -> String {
+        DeltaFileName {
+            key_range: Key::MIN..Key::MAX,
+            lsn_range: Lsn(start)..Lsn(end),
+        }
+        .to_string()
+    }
+
+    fn remote_timeline(
+        timeline_path: &std::path::Path,
+        metadata: TimelineMetadata,
+        layers: &[String],
+    ) -> RemoteTimeline {
+        let mut remote_timeline = RemoteTimeline::new(metadata);
+        remote_timeline.add_timeline_layers(layers.iter().map(|layer| timeline_path.join(layer)));
+        remote_timeline
+    }
+
+    fn layer_names(remote_timeline: &RemoteTimeline) -> HashSet<String> {
+        remote_timeline
+            .stored_files()
+            .iter()
+            .map(|layer| layer.file_name().unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_cuts_timelines_at_layer_boundaries() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("snapshot_cuts_timelines_at_layer_boundaries")?;
+        let target_tenant_id = TenantId::generate();
+        let source = TenantEntry::from(HashMap::from([(
+            TIMELINE_ID,
+            remote_timeline(
+                &harness.timeline_path(&TIMELINE_ID),
+                TimelineMetadata::new(
+                    Lsn(0x5f),
+                    Some(Lsn(0x5e)),
+                    None,
+                    Lsn(0),
+                    Lsn(0x10),
+                    Lsn(0x10),
+                    DEFAULT_PG_VERSION,
+                ),
+                &[image(0x10), delta(0x10, 0x30), delta(0x30, 0x60)],
+            ),
+        )]));
+
+        let snapshot = plan_tenant_snapshot(
+            harness.conf,
+            harness.tenant_id,
+            &source,
+            target_tenant_id,
+            &HashMap::new(),
+        )?;
+        let timeline = &snapshot[&TIMELINE_ID];
+        assert_eq!(timeline.metadata.disk_consistent_lsn(), Lsn(0x5f));
+        assert_eq!(timeline.metadata.prev_record_lsn(), None);
+        assert_eq!(layer_names(timeline).len(), 3);
+
+        let snapshot = plan_tenant_snapshot(
+            harness.conf,
+            harness.tenant_id,
+            &source,
+            target_tenant_id,
+            &HashMap::from([(TIMELINE_ID, Lsn(0x40))]),
+        )?;
+        let timeline = &snapshot[&TIMELINE_ID];
+        assert_eq!(
+            timeline.metadata.disk_consistent_lsn(),
+            Lsn(0x2f),
+            "Cut should move before the delta layer crossing it"
+        );
+        assert_eq!(
+            layer_names(timeline),
+            HashSet::from([image(0x10), delta(0x10, 0x30)])
+        );
+
+        let target_timeline_path = harness.conf.timeline_path(&TIMELINE_ID, &target_tenant_id);
+        for layer in timeline.stored_files() {
+            assert!(layer.starts_with(&target_timeline_path));
+            assert_eq!(
+                timeline.foreign_layer_owner(layer),
+                Some(TenantTimelineId::new(harness.tenant_id, TIMELINE_ID)),
+                "Every snapshot layer should reference the source timeline"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_of_snapshot_keeps_layer_owners() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("snapshot_of_snapshot_keeps_layer_owners")?;
+        let original_owner = TenantTimelineId::generate();
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+
+        let mut source_timeline = RemoteTimeline::new(TimelineMetadata::new(
+            Lsn(0x2f),
+            None,
+            None,
+            Lsn(0),
+            Lsn(0x10),
+            Lsn(0x10),
+            DEFAULT_PG_VERSION,
+        ));
+        source_timeline.add_foreign_layers([(timeline_path.join(image(0x10)), original_owner)]);
+        source_timeline.add_timeline_layers([timeline_path.join(delta(0x10, 0x30))]);
+        let source = TenantEntry::from(HashMap::from([(TIMELINE_ID, source_timeline)]));
+
+        let target_tenant_id = TenantId::generate();
+        let snapshot = plan_tenant_snapshot(
+            harness.conf,
+            harness.tenant_id,
+            &source,
+            target_tenant_id,
+            &HashMap::new(),
+        )?;
+        let target_timeline_path: PathBuf =
+            harness.conf.timeline_path(&TIMELINE_ID, &target_tenant_id);
+        let timeline = &snapshot[&TIMELINE_ID];
+        assert_eq!(
+            timeline.foreign_layer_owner(&target_timeline_path.join(image(0x10))),
+            Some(original_owner)
+        );
+        assert_eq!(
+            timeline.foreign_layer_owner(&target_timeline_path.join(delta(0x10, 0x30))),
+            Some(TenantTimelineId::new(harness.tenant_id, TIMELINE_ID))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_negatives() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("snapshot_negatives")?;
+        let target_tenant_id = TenantId::generate();
+        let source = TenantEntry::from(HashMap::from([
+            (
+                TIMELINE_ID,
+                remote_timeline(
+                    &harness.timeline_path(&TIMELINE_ID),
+                    TimelineMetadata::new(
+                        Lsn(0x5f),
+                        None,
+                        None,
+                        Lsn(0),
+                        Lsn(0x20),
+                        Lsn(0x10),
+                        DEFAULT_PG_VERSION,
+                    ),
+                    &[image(0x20), delta(0x20, 0x40), delta(0x40, 0x60)],
+                ),
+            ),
+            (
+                NEW_TIMELINE_ID,
+                remote_timeline(
+                    &harness.timeline_path(&NEW_TIMELINE_ID),
+                    TimelineMetadata::new(
+                        Lsn(0x4f),
+                        None,
+                        Some(TIMELINE_ID),
+                        Lsn(0x45),
+                        Lsn(0x45),
+                        Lsn(0x10),
+                        DEFAULT_PG_VERSION,
+                    ),
+                    &[delta(0x45, 0x50)],
+                ),
+            ),
+        ]));
+
+        for (timeline_lsns, reason) in [
+            (
+                HashMap::from([(TimelineId::generate(), Lsn(0x30))]),
+                "unknown timeline",
+            ),
+            (
+                HashMap::from([(TIMELINE_ID, Lsn(0x70))]),
+                "lsn ahead of the remote one",
+            ),
+            (
+                HashMap::from([(TIMELINE_ID, Lsn(0x10))]),
+                "lsn behind the gc cutoff",
+            ),
+            (
+                HashMap::from([(TIMELINE_ID, Lsn(0x50))]),
+                "ancestor cut before the branch point",
+            ),
+        ] {
+            assert!(
+                plan_tenant_snapshot(
+                    harness.conf,
+                    harness.tenant_id,
+                    &source,
+                    target_tenant_id,
+                    &timeline_lsns,
+                )
+                .is_err(),
+                "Snapshot should fail for {reason}"
+            );
+        }
+
+        let snapshot = plan_tenant_snapshot(
+            harness.conf,
+            harness.tenant_id,
+            &source,
+            target_tenant_id,
+            &HashMap::new(),
+        )?;
+        let branch = &snapshot[&NEW_TIMELINE_ID];
+        assert_eq!(branch.metadata.ancestor_timeline(), Some(TIMELINE_ID));
+        assert_eq!(branch.metadata.ancestor_lsn(), Lsn(0x45));
+        assert_eq!(branch.metadata.disk_consistent_lsn(), Lsn(0x4f));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_outlives_source_layers() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("snapshot_outlives_source_layers")?;
+        let target_tenant_id = TenantId::generate();
+        let storage_root = tempdir()?;
+        let storage = GenericRemoteStorage::new(LocalFs::new(
+            storage_root.path().to_owned(),
+            harness.conf.workdir.clone(),
+        )?);
+
+        let source_timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let layers = [image(0x10), delta(0x10, 0x30)];
+        for layer in &layers {
+            let contents = layer.as_bytes().to_vec();
+            storage
+                .upload_storage_object(
+                    Box::new(std::io::Cursor::new(contents.clone())),
+                    contents.len(),
+                    &source_timeline_path.join(layer),
+                    None,
+                )
+                .await?;
+        }
+        let source = TenantEntry::from(HashMap::from([(
+            TIMELINE_ID,
+            remote_timeline(
+                &source_timeline_path,
+                TimelineMetadata::new(
+                    Lsn(0x2f),
+                    None,
+                    None,
+                    Lsn(0),
+                    Lsn(0x10),
+                    Lsn(0x10),
+                    DEFAULT_PG_VERSION,
+                ),
+                &layers,
+            ),
+        )]));
+
+        let mut snapshot = plan_tenant_snapshot(
+            harness.conf,
+            harness.tenant_id,
+            &source,
+            target_tenant_id,
+            &HashMap::new(),
+        )?;
+        let target_timeline_path = harness.conf.timeline_path(&TIMELINE_ID, &target_tenant_id);
+        std::fs::create_dir_all(&target_timeline_path)?;
+        upload_snapshot_timelines(harness.conf, &storage, target_tenant_id, &mut snapshot).await?;
+        assert!(
+            std::fs::read_dir(&target_timeline_path)?.next().is_none(),
+            "No temporary files should be left after copying the layers"
+        );
+
+        // GC, compaction or deletion of the source timeline removes its remote layers
+        for layer in &layers {
+            let source_layer = storage.remote_object_id(&source_timeline_path.join(layer))?;
+            storage.delete(&source_layer).await?;
+        }
+
+        let timeline = &snapshot[&TIMELINE_ID];
+        assert_eq!(layer_names(timeline), HashSet::from(layers.clone()));
+        for layer in timeline.stored_files() {
+            assert_eq!(
+                timeline.foreign_layer_owner(layer),
+                None,
+                "Snapshot timeline should own its layers after the upload"
+            );
+            let mut download = storage.download_storage_object(None, layer).await?;
+            let mut contents = String::new();
+            download
+                .download_stream
+                .read_to_string(&mut contents)
+                .await?;
+            assert_eq!(
+                contents,
+                layer.file_name().unwrap().to_string_lossy(),
+                "Snapshot layer should be readable after the source one is removed"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_objects_removal() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("snapshot_objects_removal")?;
+        let target_tenant_id = TenantId::generate();
+        let storage_root = tempdir()?;
+        let storage = GenericRemoteStorage::new(LocalFs::new(
+            storage_root.path().to_owned(),
+            harness.conf.workdir.clone(),
+        )?);
+        let local_storage = storage.as_local().unwrap();
+
+        let metadata = TimelineMetadata::new(
+            Lsn(0x2f),
+            None,
+            None,
+            Lsn(0),
+            Lsn(0x10),
+            Lsn(0x10),
+            DEFAULT_PG_VERSION,
+        );
+        let timeline_path = harness.conf.timeline_path(&TIMELINE_ID, &target_tenant_id);
+        let mut snapshot_timelines = HashMap::from([(
+            TIMELINE_ID,
+            remote_timeline(&timeline_path, metadata.clone(), &[image(0x10)]),
+        )]);
+        upload_snapshot_timelines(
+            harness.conf,
+            &storage,
+            target_tenant_id,
+            &mut snapshot_timelines,
+        )
+        .await?;
+        assert_eq!(list_remote_objects(local_storage).await?.len(), 1);
+
+        remove_snapshot_objects(&storage, &[timeline_path.join(IndexPart::FILE_NAME)]).await;
+        assert!(
+            list_remote_objects(local_storage).await?.is_empty(),
+            "No index parts of the snapshot should be left after the removal"
+        );
+
+        // A layer, removed from the source before it got copied, fails the snapshot
+        let branch_path = harness
+            .conf
+            .timeline_path(&NEW_TIMELINE_ID, &target_tenant_id);
+        std::fs::create_dir_all(&branch_path)?;
+        let mut branch = RemoteTimeline::new(metadata);
+        branch.add_foreign_layers([(
+            branch_path.join(image(0x10)),
+            TenantTimelineId::new(harness.tenant_id, TIMELINE_ID),
+        )]);
+        snapshot_timelines.insert(NEW_TIMELINE_ID, branch);
+        assert!(upload_snapshot_timelines(
+            harness.conf,
+            &storage,
+            target_tenant_id,
+            &mut snapshot_timelines,
+        )
+        .await
+        .is_err());
+        assert!(
+            list_remote_objects(local_storage).await?.is_empty(),
+            "No objects of a failed snapshot should be left in the remote storage"
+        );
+
+        Ok(())
+    }
+}
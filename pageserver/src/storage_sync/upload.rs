@@ -37,6 +37,7 @@ pub(super) async fn upload_index_part(
             Box::new(index_part_bytes),
             index_part_size,
             &index_part_path,
+            None,
         )
         .await
         .with_context(|| format!("Failed to upload index part for '{sync_id}'"))
@@ -122,7 +123,7 @@ pub(super) async fn upload_timeline_layers<'a>(
                 .len() as usize;
 
             match storage
-                .upload_storage_object(Box::new(source_file), source_size, &source_path)
+                .upload_storage_object(Box::new(source_file), source_size, &source_path, None)
                 .await
                 .with_context(|| format!("Failed to upload layer file for {sync_id}"))
             {
@@ -203,7 +204,7 @@ mod tests {
     use crate::{
         storage_sync::{
             index::RelativePath,
-            test_utils::{create_local_timeline, dummy_metadata},
+            test_utils::{create_local_timeline, dummy_metadata, list_remote_objects},
         },
         tenant::harness::{TenantHarness, TIMELINE_ID},
     };
@@ -230,7 +231,7 @@ mod tests {
         timeline_upload.metadata = None;
 
         assert!(
-            local_storage.list().await?.is_empty(),
+            list_remote_objects(local_storage).await?.is_empty(),
             "Storage should be empty before any uploads are made"
         );
 
@@ -276,7 +277,7 @@ mod tests {
             "Successful upload without metadata should not have it returned either"
         );
 
-        let storage_files = local_storage.list().await?;
+        let storage_files = list_remote_objects(local_storage).await?;
         assert_eq!(
             storage_files.len(),
             layer_files.len(),
@@ -323,7 +324,7 @@ mod tests {
             create_local_timeline(&harness, TIMELINE_ID, &layers_to_upload, metadata.clone())
                 .await?;
         assert!(
-            local_storage.list().await?.is_empty(),
+            list_remote_objects(local_storage).await?.is_empty(),
             "Storage should be empty before any uploads are made"
         );
 
@@ -372,7 +373,7 @@ mod tests {
             "Successful upload should not change its metadata"
         );
 
-        let storage_files = local_storage.list().await?;
+        let storage_files = list_remote_objects(local_storage).await?;
         assert_eq!(
             storage_files.len(),
             layer_files.len(),
@@ -420,12 +421,12 @@ mod tests {
         );
 
         assert!(
-            local_storage.list().await?.is_empty(),
+            list_remote_objects(local_storage).await?.is_empty(),
             "Storage should be empty before any uploads are made"
         );
         upload_index_part(harness.conf, &storage, sync_id, index_part.clone()).await?;
 
-        let storage_files = local_storage.list().await?;
+        let storage_files = list_remote_objects(local_storage).await?;
         assert_eq!(
             storage_files.len(),
             1,
@@ -24,7 +24,7 @@ use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::num::NonZeroU64;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::ops::Bound::Included;
 use std::path::Path;
 use std::process::Command;
@@ -59,7 +59,7 @@ pub mod block_io;
 mod delta_layer;
 mod disk_btree;
 pub(crate) mod ephemeral_file;
-mod filename;
+pub(crate) mod filename;
 mod image_layer;
 mod inmemory_layer;
 mod layer_map;
@@ -593,6 +593,17 @@ impl Tenant {
             .unwrap_or(self.conf.default_tenant_conf.max_lsn_wal_lag)
     }
 
+    pub fn get_walreceiver_catchup_streams(&self) -> NonZeroUsize {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .walreceiver_catchup_streams
+            .unwrap_or(self.conf.default_tenant_conf.walreceiver_catchup_streams)
+    }
+
+    pub fn get_tenant_conf(&self) -> TenantConfOpt {
+        *self.tenant_conf.read().unwrap()
+    }
+
     pub fn update_tenant_config(&self, new_tenant_conf: TenantConfOpt) {
         self.tenant_conf.write().unwrap().update(&new_tenant_conf);
     }
@@ -1204,6 +1215,7 @@ pub mod harness {
                 walreceiver_connect_timeout: Some(tenant_conf.walreceiver_connect_timeout),
                 lagging_wal_timeout: Some(tenant_conf.lagging_wal_timeout),
                 max_lsn_wal_lag: Some(tenant_conf.max_lsn_wal_lag),
+                walreceiver_catchup_streams: Some(tenant_conf.walreceiver_catchup_streams),
             }
         }
     }
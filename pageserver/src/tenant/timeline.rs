@@ -625,6 +625,9 @@ impl Timeline {
         let max_lsn_wal_lag = tenant_conf_guard
             .max_lsn_wal_lag
             .unwrap_or(self.conf.default_tenant_conf.max_lsn_wal_lag);
+        let catchup_streams = tenant_conf_guard
+            .walreceiver_catchup_streams
+            .unwrap_or(self.conf.default_tenant_conf.walreceiver_catchup_streams);
         drop(tenant_conf_guard);
         let self_clone = Arc::clone(self);
         spawn_connection_manager_task(
@@ -633,6 +636,7 @@ impl Timeline {
             walreceiver_connect_timeout,
             lagging_wal_timeout,
             max_lsn_wal_lag,
+            catchup_streams,
         )?;
 
         Ok(())
@@ -9,7 +9,7 @@
 //! may lead to a data loss.
 //!
 use serde::{Deserialize, Serialize};
-use std::num::NonZeroU64;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::time::Duration;
 
 pub mod defaults {
@@ -34,6 +34,7 @@ pub mod defaults {
     pub const DEFAULT_WALRECEIVER_CONNECT_TIMEOUT: &str = "2 seconds";
     pub const DEFAULT_WALRECEIVER_LAGGING_WAL_TIMEOUT: &str = "3 seconds";
     pub const DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG: u64 = 10 * 1024 * 1024;
+    pub const DEFAULT_WALRECEIVER_CATCHUP_STREAMS: usize = 1;
 }
 
 /// Per-tenant configuration options
@@ -82,6 +83,9 @@ pub struct TenantConf {
     /// A lagging safekeeper will be changed after `lagging_wal_timeout` time elapses since the last WAL update,
     /// to avoid eager reconnects.
     pub max_lsn_wal_lag: NonZeroU64,
+    /// Number of safekeeper connections to fetch WAL over concurrently, when the timeline is far behind the safekeepers.
+    /// Only one connection is used when set to 1, as well as after the timeline catches up.
+    pub walreceiver_catchup_streams: NonZeroUsize,
 }
 
 /// Same as TenantConf, but this struct preserves the information about
@@ -105,6 +109,7 @@ pub struct TenantConfOpt {
     #[serde(with = "humantime_serde")]
     pub lagging_wal_timeout: Option<Duration>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub walreceiver_catchup_streams: Option<NonZeroUsize>,
 }
 
 impl TenantConfOpt {
@@ -138,6 +143,9 @@ impl TenantConfOpt {
                 .lagging_wal_timeout
                 .unwrap_or(global_conf.lagging_wal_timeout),
             max_lsn_wal_lag: self.max_lsn_wal_lag.unwrap_or(global_conf.max_lsn_wal_lag),
+            walreceiver_catchup_streams: self
+                .walreceiver_catchup_streams
+                .unwrap_or(global_conf.walreceiver_catchup_streams),
         }
     }
 
@@ -178,6 +186,9 @@ impl TenantConfOpt {
         if let Some(max_lsn_wal_lag) = other.max_lsn_wal_lag {
             self.max_lsn_wal_lag = Some(max_lsn_wal_lag);
         }
+        if let Some(walreceiver_catchup_streams) = other.walreceiver_catchup_streams {
+            self.walreceiver_catchup_streams = Some(walreceiver_catchup_streams);
+        }
     }
 }
 
@@ -207,6 +218,8 @@ impl TenantConf {
                 .expect("cannot parse default walreceiver lagging wal timeout"),
             max_lsn_wal_lag: NonZeroU64::new(DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG)
                 .expect("cannot parse default max walreceiver Lsn wal lag"),
+            walreceiver_catchup_streams: NonZeroUsize::new(DEFAULT_WALRECEIVER_CATCHUP_STREAMS)
+                .expect("cannot parse default walreceiver catchup streams"),
         }
     }
 
@@ -232,6 +245,10 @@ impl TenantConf {
             .unwrap(),
             max_lsn_wal_lag: NonZeroU64::new(defaults::DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG)
                 .unwrap(),
+            walreceiver_catchup_streams: NonZeroUsize::new(
+                defaults::DEFAULT_WALRECEIVER_CATCHUP_STREAMS,
+            )
+            .unwrap(),
         }
     }
 }
@@ -12,6 +12,9 @@
 //!
 //! Only one active WAL streaming connection is allowed at a time.
 //! The connection is supposed to be updated periodically, based on safekeeper timeline data.
+//! Timelines that lag far behind the safekeepers may optionally fetch the missing WAL from several safekeepers concurrently instead,
+//! falling back to a single connection after catching up.
+//! If the WAL needed was removed from all safekeepers already, it is ingested from the safekeepers' WAL archive in the remote storage, when configured.
 //!
 //! * handle the actual connection and WAL streaming
 //!
@@ -20,7 +23,9 @@
 //!
 //! The current module contains high-level primitives used in the submodules; general synchronization, timeline acknowledgement and shutdown logic.
 
+mod archive_catchup;
 mod connection_manager;
+mod parallel_catchup;
 mod walreceiver_connection;
 
 use crate::config::PageServerConf;
@@ -30,11 +35,14 @@ use anyhow::{ensure, Context};
 use etcd_broker::Client;
 use itertools::Itertools;
 use once_cell::sync::OnceCell;
+use remote_storage::GenericRemoteStorage;
 use std::future::Future;
+use std::sync::Arc;
 use tokio::sync::watch;
 use tracing::*;
 use url::Url;
 
+use archive_catchup::SafekeeperWalArchive;
 pub use connection_manager::spawn_connection_manager_task;
 
 static ETCD_CLIENT: OnceCell<Client> = OnceCell::new();
@@ -79,6 +87,53 @@ pub fn is_etcd_client_initialized() -> bool {
     ETCD_CLIENT.get().is_some()
 }
 
+static SAFEKEEPER_WAL_ARCHIVE: OnceCell<Option<SafekeeperWalArchive>> = OnceCell::new();
+
+///
+/// Initialize the access to the WAL, archived by safekeepers, if configured. This must be called once at page server startup.
+///
+pub fn init_safekeeper_wal_storage(conf: &'static PageServerConf) -> anyhow::Result<()> {
+    let wal_archive = match &conf.safekeeper_wal_storage_config {
+        Some(storage_config) => Some(SafekeeperWalArchive::new(
+            conf,
+            GenericRemoteStorage::from_config(conf.safekeeper_wal_path(), storage_config)
+                .context("Failed to create safekeeper WAL storage")?,
+        )),
+        None => None,
+    };
+
+    if SAFEKEEPER_WAL_ARCHIVE.set(wal_archive).is_err() {
+        panic!("safekeeper WAL storage already initialized");
+    }
+    Ok(())
+}
+
+///
+/// Get a handle to the safekeeper WAL archive, if configured
+///
+fn get_safekeeper_wal_archive() -> Option<&'static SafekeeperWalArchive> {
+    SAFEKEEPER_WAL_ARCHIVE.get().and_then(Option::as_ref)
+}
+
+static SAFEKEEPER_AUTH_TOKEN: OnceCell<Option<Arc<String>>> = OnceCell::new();
+
+///
+/// Initialize the JWT token presented to the safekeepers which check the tokens of the WAL streaming connections.
+/// This must be called once at page server startup.
+///
+pub fn init_safekeeper_auth_token(auth_token: Option<String>) {
+    if SAFEKEEPER_AUTH_TOKEN.set(auth_token.map(Arc::new)).is_err() {
+        panic!("safekeeper auth token already initialized");
+    }
+}
+
+///
+/// Get the JWT token to present to safekeepers, if any
+///
+fn get_safekeeper_auth_token() -> Option<Arc<String>> {
+    SAFEKEEPER_AUTH_TOKEN.get().cloned().flatten()
+}
+
 /// A handle of an asynchronous task.
 /// The task has a channel that it can use to communicate its lifecycle events in a certain form, see [`TaskEvent`]
 /// and a cancellation channel that it can listen to for earlier interrupts.
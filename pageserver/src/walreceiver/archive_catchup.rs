@@ -0,0 +1,264 @@
+//! WAL catch-up from the safekeepers' WAL archive in the remote storage.
+//!
+//! Safekeepers remove the WAL that is backed up to the remote storage and processed by pageservers already.
+//! A timeline that fell behind that point (e.g. was detached for long or restored from an old remote state) cannot get its WAL
+//! streamed from any safekeeper, so instead, it downloads the WAL segments that safekeepers had archived, decodes and ingests them.
+//!
+//! The catch-up task ends after reaching the WAL present on safekeepers, and the connection manager switches to the regular streaming then.
+
+use std::{ops::Range, sync::Arc, time::SystemTime};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use async_compression::tokio::bufread::ZstdDecoder;
+use chrono::Utc;
+use postgres_ffi::{waldecoder::WalStreamDecoder, XLogFileName, XLogSegNo};
+use postgres_ffi::{PG_TLI, WAL_SEGMENT_SIZE};
+use remote_storage::{DownloadError, GenericRemoteStorage};
+use safekeeper_api::{WalCompression, WAL_COMPRESSION_METADATA_KEY};
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::{select, sync::watch};
+use tracing::*;
+use utils::{id::TenantTimelineId, lsn::Lsn};
+
+use super::{
+    walreceiver_connection::{ingest_wal_data, WalConnectionStatus},
+    TaskStateUpdate,
+};
+use crate::{
+    config::PageServerConf,
+    tenant::{Timeline, WalReceiverInfo},
+    walingest::WalIngest,
+};
+
+/// The WAL, archived by safekeepers in the remote storage.
+/// Safekeepers store every segment under the same path that the segment has in their working directories,
+/// possibly compressed with the codec named in the segment's metadata.
+/// The beginning of the segment that is not complete yet might be archived under the segment's `.partial` name.
+#[derive(Clone)]
+pub(super) struct SafekeeperWalArchive {
+    conf: &'static PageServerConf,
+    storage: GenericRemoteStorage,
+}
+
+impl SafekeeperWalArchive {
+    pub(super) fn new(conf: &'static PageServerConf, storage: GenericRemoteStorage) -> Self {
+        Self { conf, storage }
+    }
+
+    /// Downloads and decompresses the entire WAL segment, or the archived beginning of it, if the segment is partial.
+    /// Fails if neither is present in the archive.
+    async fn download_segment(
+        &self,
+        id: TenantTimelineId,
+        segno: XLogSegNo,
+    ) -> anyhow::Result<ArchivedSegment> {
+        let segment_name = XLogFileName(PG_TLI, segno, WAL_SEGMENT_SIZE);
+        let partial_segment_name = format!("{segment_name}.partial");
+        let mut is_partial = false;
+        let mut download = None;
+        for name in [&segment_name, &partial_segment_name] {
+            let segment_path =
+                self.conf
+                    .safekeeper_wal_segment_path(&id.tenant_id, &id.timeline_id, name);
+            match self
+                .storage
+                .download_storage_object(None, &segment_path)
+                .await
+            {
+                Ok(segment_download) => {
+                    download = Some(segment_download);
+                    break;
+                }
+                Err(DownloadError::NotFound) => is_partial = true,
+                Err(e) => {
+                    return Err(anyhow::Error::new(e)
+                        .context(format!("Failed to download WAL segment {name}")))
+                }
+            }
+        }
+        let mut download = match download {
+            Some(download) => download,
+            None => bail!("WAL segment {segment_name} of timeline {id} is not archived"),
+        };
+
+        let compression = match download
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get(WAL_COMPRESSION_METADATA_KEY))
+        {
+            Some(codec) => codec.parse().map_err(|e: String| anyhow!(e))?,
+            None => WalCompression::None,
+        };
+
+        let mut segment = Vec::with_capacity(WAL_SEGMENT_SIZE);
+        match compression {
+            WalCompression::None => download.download_stream.read_to_end(&mut segment).await,
+            WalCompression::Zstd => {
+                ZstdDecoder::new(BufReader::new(download.download_stream))
+                    .read_to_end(&mut segment)
+                    .await
+            }
+        }
+        .with_context(|| format!("Failed to read WAL segment {segment_name}"))?;
+        if is_partial {
+            ensure!(
+                segment.len() <= WAL_SEGMENT_SIZE,
+                "partial WAL segment {segment_name} has size {}, more than {WAL_SEGMENT_SIZE}",
+                segment.len()
+            );
+        } else {
+            ensure!(
+                segment.len() == WAL_SEGMENT_SIZE,
+                "WAL segment {segment_name} has size {}, expected {WAL_SEGMENT_SIZE}",
+                segment.len()
+            );
+        }
+        Ok(ArchivedSegment {
+            data: segment,
+            is_partial,
+        })
+    }
+}
+
+struct ArchivedSegment {
+    data: Vec<u8>,
+    /// Whether only the beginning of the segment is archived.
+    is_partial: bool,
+}
+
+/// Downloads the archived WAL segments from the timeline's last record until `target_lsn` and ingests them.
+pub(super) async fn handle_archive_catchup(
+    timeline: Arc<Timeline>,
+    wal_archive: SafekeeperWalArchive,
+    target_lsn: Lsn,
+    events_sender: watch::Sender<TaskStateUpdate<WalConnectionStatus>>,
+    mut cancellation: watch::Receiver<()>,
+) -> anyhow::Result<()> {
+    let id = TenantTimelineId {
+        tenant_id: timeline.tenant_id,
+        timeline_id: timeline.timeline_id,
+    };
+    let mut last_rec_lsn = timeline.get_last_record_lsn();
+    let mut startpoint = last_rec_lsn;
+    if startpoint == Lsn(0) {
+        bail!("No previous WAL position");
+    }
+    // There might be some padding after the last full record, skip it.
+    startpoint += startpoint.calc_padding(8u32);
+
+    let segments = archive_segments_to_ingest(startpoint, target_lsn);
+    info!(
+        "last_record_lsn {last_rec_lsn} catching up from {startpoint} to {target_lsn} with archived WAL segments {segments:?}"
+    );
+
+    let now = Utc::now().naive_utc();
+    let mut connection_status = WalConnectionStatus {
+        is_connected: true,
+        has_processed_wal: false,
+        latest_connection_update: now,
+        latest_wal_update: now,
+        streaming_lsn: None,
+        commit_lsn: Some(target_lsn),
+    };
+    if let Err(e) = events_sender.send(TaskStateUpdate::Progress(connection_status.clone())) {
+        warn!("Wal connection event listener dropped right after archive catch-up start, aborting it: {e}");
+        return Ok(());
+    }
+
+    let mut waldecoder = WalStreamDecoder::new(startpoint, timeline.pg_version);
+    let mut walingest = WalIngest::new(timeline.as_ref(), startpoint)?;
+
+    for segno in segments {
+        let segment = select! {
+            _ = cancellation.changed() => {
+                info!("archive catch-up interrupted");
+                return Ok(());
+            }
+            segment = wal_archive.download_segment(id, segno) => segment?,
+        };
+
+        let segment_start = Lsn(segno * WAL_SEGMENT_SIZE as u64);
+        let segment_end = segment_start + segment.data.len() as u64;
+        // The first segment might be partially ingested already.
+        let data_start = (startpoint.max(segment_start).0 - segment_start.0) as usize;
+        let data = &segment.data[data_start.min(segment.data.len())..];
+        trace!("ingesting archived WAL between {segment_start} and {segment_end}");
+
+        if let Some(lsn) = ingest_wal_data(
+            &timeline,
+            &mut waldecoder,
+            &mut walingest,
+            data,
+            segment_end,
+        )? {
+            last_rec_lsn = lsn;
+            connection_status.has_processed_wal = true;
+        }
+
+        let now = Utc::now().naive_utc();
+        connection_status.latest_connection_update = now;
+        connection_status.latest_wal_update = now;
+        connection_status.streaming_lsn = Some(segment_end);
+        if let Err(e) = events_sender.send(TaskStateUpdate::Progress(connection_status.clone())) {
+            warn!("Wal connection event listener dropped, aborting the archive catch-up: {e}");
+            return Ok(());
+        }
+
+        timeline.check_checkpoint_distance().with_context(|| {
+            format!(
+                "Failed to check checkpoint distance for timeline {}",
+                timeline.timeline_id
+            )
+        })?;
+
+        *timeline.last_received_wal.lock().unwrap() = Some(WalReceiverInfo {
+            wal_source_connstr: "safekeeper WAL archive".to_string(),
+            last_received_msg_lsn: segment_end,
+            last_received_msg_ts: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Received message time should be before UNIX EPOCH!")
+                .as_micros(),
+        });
+
+        if segment.is_partial {
+            // No WAL is archived after the partial segment.
+            info!("archived WAL ends at {segment_end} in partial segment {segno}");
+            break;
+        }
+    }
+
+    info!("archive catch-up finished, last_record_lsn {last_rec_lsn}, target {target_lsn}");
+    Ok(())
+}
+
+/// Numbers of the WAL segments, that contain the WAL between `start` and `target`.
+fn archive_segments_to_ingest(start: Lsn, target: Lsn) -> Range<XLogSegNo> {
+    let first_segment = start.segment_number(WAL_SEGMENT_SIZE);
+    let segment_after_target = (target.0 + WAL_SEGMENT_SIZE as u64 - 1) / WAL_SEGMENT_SIZE as u64;
+    first_segment..segment_after_target.max(first_segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_segments_cover_the_missing_wal() {
+        let segment_size = WAL_SEGMENT_SIZE as u64;
+
+        assert_eq!(
+            archive_segments_to_ingest(Lsn(segment_size + 100), Lsn(3 * segment_size)),
+            1..3,
+            "Segments up to the target should be ingested, including the partially ingested one"
+        );
+        assert_eq!(
+            archive_segments_to_ingest(Lsn(segment_size + 100), Lsn(3 * segment_size + 1)),
+            1..4,
+            "The segment with the target should be ingested"
+        );
+        assert!(
+            archive_segments_to_ingest(Lsn(3 * segment_size), Lsn(2 * segment_size)).is_empty(),
+            "Nothing should be ingested, if the target is reached already"
+        );
+    }
+}
@@ -11,7 +11,7 @@
 
 use std::{
     collections::{hash_map, HashMap},
-    num::NonZeroU64,
+    num::{NonZeroU64, NonZeroUsize},
     sync::Arc,
     time::Duration,
 };
@@ -38,7 +38,12 @@ use utils::{
     lsn::Lsn,
 };
 
-use super::{walreceiver_connection::WalConnectionStatus, TaskEvent, TaskHandle};
+use super::{
+    archive_catchup::SafekeeperWalArchive,
+    parallel_catchup::{CatchupSource, FailedCatchupSource, CATCHUP_RANGE_SIZE},
+    walreceiver_connection::WalConnectionStatus,
+    TaskEvent, TaskHandle,
+};
 
 /// Spawns the loop to take care of the timeline's WAL streaming connection.
 pub fn spawn_connection_manager_task(
@@ -47,6 +52,7 @@ pub fn spawn_connection_manager_task(
     wal_connect_timeout: Duration,
     lagging_wal_timeout: Duration,
     max_lsn_wal_lag: NonZeroU64,
+    catchup_streams: NonZeroUsize,
 ) -> anyhow::Result<()> {
     let mut etcd_client = get_etcd_client().clone();
 
@@ -70,6 +76,7 @@ pub fn spawn_connection_manager_task(
                 wal_connect_timeout,
                 lagging_wal_timeout,
                 max_lsn_wal_lag,
+                catchup_streams,
             );
             loop {
                 select! {
@@ -163,7 +170,13 @@ async fn connection_manager_loop_step(
                     TaskEvent::End(walreceiver_task_result) => {
                         match walreceiver_task_result {
                             Ok(()) => debug!("WAL receiving task finished"),
-                            Err(e) => error!("wal receiver task finished with an error: {e:?}"),
+                            Err(e) => {
+                                error!("wal receiver task finished with an error: {e:?}");
+                                // Back off from the safekeeper that has failed the catch-up, not from the catch-up's main one.
+                                if let Some(FailedCatchupSource(sk_id)) = e.downcast_ref() {
+                                    wal_connection.sk_id = *sk_id;
+                                }
+                            }
                         }
                         walreceiver_state.drop_old_connection(false).await;
                     },
@@ -205,7 +218,15 @@ async fn connection_manager_loop_step(
             }
         }
 
-        if let Some(new_candidate) = walreceiver_state.next_connection_candidate() {
+        if let Some(archive_candidate) = walreceiver_state.next_archive_catchup_candidate() {
+            info!("Starting WAL catch-up from the safekeeper WAL archive: {archive_candidate:?}");
+            walreceiver_state
+                .start_archive_catchup(archive_candidate)
+                .await
+        } else if let Some(catchup_candidate) = walreceiver_state.next_catchup_candidate() {
+            info!("Starting parallel WAL catch-up: {catchup_candidate:?}");
+            walreceiver_state.start_catchup(catchup_candidate).await
+        } else if let Some(new_candidate) = walreceiver_state.next_connection_candidate() {
             info!("Switching to new connection candidate: {new_candidate:?}");
             walreceiver_state
                 .change_connection(
@@ -288,6 +309,13 @@ struct WalreceiverState {
     lagging_wal_timeout: Duration,
     /// The Lsn lag to use to determine when the current connection is lagging to much behind and reconnect to the other one.
     max_lsn_wal_lag: NonZeroU64,
+    /// The number of safekeeper connections to fetch the WAL with concurrently, when the timeline is far behind them.
+    /// Parallel catch-up is disabled, if the value is 1.
+    catchup_streams: NonZeroUsize,
+    /// The WAL archived by safekeepers, to catch up from when no safekeeper has the WAL needed anymore.
+    wal_archive: Option<SafekeeperWalArchive>,
+    /// JWT token to present to the safekeepers, set as the password of the connection and kept out of its connection string.
+    auth_token: Option<Arc<String>>,
     /// Current connection to safekeeper for WAL streaming.
     wal_connection: Option<WalConnection>,
     /// Info about retries and unsuccessful attempts to connect to safekeepers.
@@ -309,6 +337,9 @@ struct WalConnection {
     connection_task: TaskHandle<WalConnectionStatus>,
     /// Have we discovered that other safekeeper has more recent WAL than we do?
     discovered_new_wal: Option<NewCommittedWAL>,
+    /// For the catch-up connections (parallel or from the WAL archive), the Lsn to fetch the WAL up to.
+    /// Such connections are not switched until they finish, after which a regular single safekeeper connection is established.
+    catchup_target: Option<Lsn>,
 }
 
 /// Notion of a new committed WAL, which exists on other safekeeper.
@@ -342,6 +373,7 @@ impl WalreceiverState {
         wal_connect_timeout: Duration,
         lagging_wal_timeout: Duration,
         max_lsn_wal_lag: NonZeroU64,
+        catchup_streams: NonZeroUsize,
     ) -> Self {
         let id = TenantTimelineId {
             tenant_id: timeline.tenant_id,
@@ -353,6 +385,9 @@ impl WalreceiverState {
             wal_connect_timeout,
             lagging_wal_timeout,
             max_lsn_wal_lag,
+            catchup_streams,
+            wal_archive: super::get_safekeeper_wal_archive().cloned(),
+            auth_token: super::get_safekeeper_auth_token(),
             wal_connection: None,
             wal_stream_candidates: HashMap::new(),
             wal_connection_retries: HashMap::new(),
@@ -366,11 +401,13 @@ impl WalreceiverState {
         let id = self.id;
         let connect_timeout = self.wal_connect_timeout;
         let timeline = Arc::clone(&self.timeline);
+        let auth_token = self.auth_token.clone();
         let connection_handle = TaskHandle::spawn(move |events_sender, cancellation| {
             async move {
                 super::walreceiver_connection::handle_walreceiver_connection(
                     timeline,
                     new_wal_source_connstr,
+                    auth_token,
                     events_sender,
                     cancellation,
                     connect_timeout,
@@ -395,6 +432,96 @@ impl WalreceiverState {
             },
             connection_task: connection_handle,
             discovered_new_wal: None,
+            catchup_target: None,
+        });
+    }
+
+    /// Shuts down the current connection (if any) and starts ingesting the WAL from the safekeeper WAL archive.
+    async fn start_archive_catchup(&mut self, candidate: ArchiveCatchupCandidate) {
+        self.drop_old_connection(true).await;
+
+        let id = self.id;
+        let timeline = Arc::clone(&self.timeline);
+        let wal_archive = self.wal_archive.clone().expect(
+            "Archive catch-up candidates are selected only with the WAL archive configured",
+        );
+        let target_lsn = candidate.target_lsn;
+        let connection_handle = TaskHandle::spawn(move |events_sender, cancellation| {
+            async move {
+                super::archive_catchup::handle_archive_catchup(
+                    timeline,
+                    wal_archive,
+                    target_lsn,
+                    events_sender,
+                    cancellation,
+                )
+                .await
+                .context("walreceiver archive catch-up failure")
+            }
+            .instrument(info_span!("walreceiver_archive_catchup", id = %id))
+        });
+
+        let now = Utc::now().naive_utc();
+        self.wal_connection = Some(WalConnection {
+            started_at: now,
+            sk_id: candidate.safekeeper_id,
+            status: WalConnectionStatus {
+                is_connected: false,
+                has_processed_wal: false,
+                latest_connection_update: now,
+                latest_wal_update: now,
+                streaming_lsn: None,
+                commit_lsn: None,
+            },
+            connection_task: connection_handle,
+            discovered_new_wal: None,
+            catchup_target: Some(target_lsn),
+        });
+    }
+
+    /// Shuts down the current connection (if any) and starts fetching the WAL from the given safekeepers concurrently.
+    async fn start_catchup(&mut self, candidate: CatchupCandidate) {
+        self.drop_old_connection(true).await;
+
+        let id = self.id;
+        let connect_timeout = self.wal_connect_timeout;
+        let streams = self.catchup_streams;
+        let timeline = Arc::clone(&self.timeline);
+        let sources = candidate.sources;
+        let main_sk_id = sources[0].sk_id;
+        let auth_token = self.auth_token.clone();
+        let connection_handle = TaskHandle::spawn(move |events_sender, cancellation| {
+            async move {
+                super::parallel_catchup::handle_parallel_catchup(
+                    timeline,
+                    sources,
+                    auth_token,
+                    streams,
+                    events_sender,
+                    cancellation,
+                    connect_timeout,
+                )
+                .await
+                .context("walreceiver parallel catch-up failure")
+            }
+            .instrument(info_span!("walreceiver_catchup", id = %id))
+        });
+
+        let now = Utc::now().naive_utc();
+        self.wal_connection = Some(WalConnection {
+            started_at: now,
+            sk_id: main_sk_id,
+            status: WalConnectionStatus {
+                is_connected: false,
+                has_processed_wal: false,
+                latest_connection_update: now,
+                latest_wal_update: now,
+                streaming_lsn: None,
+                commit_lsn: None,
+            },
+            connection_task: connection_handle,
+            discovered_new_wal: None,
+            catchup_target: Some(candidate.target_lsn),
         });
     }
 
@@ -507,6 +634,10 @@ impl WalreceiverState {
         self.cleanup_old_candidates();
 
         match &self.wal_connection {
+            Some(existing_wal_connection) if existing_wal_connection.catchup_target.is_some() => {
+                // Parallel catch-up handles its connections on its own, and falls back to a regular connection after it ends.
+                return None;
+            }
             Some(existing_wal_connection) => {
                 let connected_sk_node = existing_wal_connection.sk_id;
 
@@ -652,6 +783,110 @@ impl WalreceiverState {
         None
     }
 
+    /// Checks whether the timeline needs the WAL that was removed from all safekeepers already and has to be downloaded from their WAL archive.
+    ///
+    /// The catch-up is started, if:
+    /// * the WAL archive is configured for the pageserver
+    /// * there is no catch-up running already
+    /// * every known safekeeper has its local WAL starting after the timeline's last record
+    /// * the archive has some WAL past the timeline's last record
+    ///
+    /// The archived WAL is ingested up to the earliest WAL present on safekeepers, to switch to streaming from there.
+    fn next_archive_catchup_candidate(&mut self) -> Option<ArchiveCatchupCandidate> {
+        self.wal_archive.as_ref()?;
+        if let Some(wal_connection) = &self.wal_connection {
+            if wal_connection.catchup_target.is_some() {
+                return None;
+            }
+        }
+        self.cleanup_old_candidates();
+
+        let last_record_lsn = self.timeline.get_last_record_lsn();
+        if last_record_lsn == Lsn(0) {
+            return None;
+        }
+
+        // Retry cooldowns are not considered: the WAL is downloaded from the archive, not from the safekeepers.
+        let mut archived_up_to = Lsn(0);
+        let mut earliest_local_wal: Option<(NodeId, Lsn)> = None;
+        for (sk_id, etcd_info) in &self.wal_stream_candidates {
+            // Safekeepers that do not report their local WAL start are assumed to have the WAL needed.
+            let local_start_lsn = etcd_info.timeline.local_start_lsn?;
+            if local_start_lsn <= last_record_lsn {
+                return None;
+            }
+            archived_up_to = archived_up_to.max(etcd_info.timeline.backup_lsn.unwrap_or(Lsn(0)));
+            if earliest_local_wal.map_or(true, |(_, earliest_lsn)| local_start_lsn < earliest_lsn) {
+                earliest_local_wal = Some((*sk_id, local_start_lsn));
+            }
+        }
+
+        let (safekeeper_id, local_start_lsn) = earliest_local_wal?;
+        let target_lsn = local_start_lsn.min(archived_up_to);
+        if target_lsn <= last_record_lsn {
+            warn!("No safekeeper has the WAL after last_record_lsn {last_record_lsn}, and the WAL archive has it only up to {archived_up_to}");
+            return None;
+        }
+
+        Some(ArchiveCatchupCandidate {
+            safekeeper_id,
+            target_lsn,
+        })
+    }
+
+    /// Checks whether the timeline is far enough behind the safekeepers to fetch the WAL from several of them concurrently.
+    ///
+    /// The catch-up is started, if:
+    /// * it is enabled for the tenant, with more than one stream configured
+    /// * there is no catch-up running already
+    /// * at least two safekeepers have the WAL the timeline lacks
+    /// * the timeline lags behind for at least one WAL range per stream
+    ///
+    /// The parallel catch-up interrupts any regular connection, since that one is lagging too much.
+    fn next_catchup_candidate(&mut self) -> Option<CatchupCandidate> {
+        if self.catchup_streams.get() < 2 {
+            return None;
+        }
+        if let Some(wal_connection) = &self.wal_connection {
+            if wal_connection.catchup_target.is_some() {
+                return None;
+            }
+        }
+        self.cleanup_old_candidates();
+
+        let last_record_lsn = self.timeline.get_last_record_lsn();
+        if last_record_lsn == Lsn(0) {
+            return None;
+        }
+        let mut sources = self
+            .applicable_connection_candidates()
+            .filter_map(|(sk_id, info, wal_source_connstr)| {
+                let commit_lsn = info.commit_lsn?;
+                (commit_lsn > last_record_lsn).then(|| CatchupSource {
+                    sk_id,
+                    wal_source_connstr,
+                    commit_lsn,
+                })
+            })
+            .collect::<Vec<_>>();
+        if sources.len() < 2 {
+            return None;
+        }
+        // The most advanced safekeeper goes first, so it gets the first WAL range and is reported as the connected one.
+        sources.sort_by(|a, b| b.commit_lsn.cmp(&a.commit_lsn));
+
+        let target_lsn = sources[0].commit_lsn;
+        let lag = target_lsn.0 - last_record_lsn.0;
+        if lag < self.catchup_streams.get() as u64 * CATCHUP_RANGE_SIZE {
+            return None;
+        }
+
+        Some(CatchupCandidate {
+            sources,
+            target_lsn,
+        })
+    }
+
     /// Selects the best possible candidate, based on the data collected from etcd updates about the safekeepers.
     /// Optionally, omits the given node, to support gracefully switching from a healthy safekeeper to another.
     ///
@@ -733,6 +968,21 @@ struct NewWalConnectionCandidate {
     reason: ReconnectReason,
 }
 
+/// The WAL to ingest from the safekeeper WAL archive.
+#[derive(Debug, PartialEq, Eq)]
+struct ArchiveCatchupCandidate {
+    /// The safekeeper with the earliest local WAL, to stream from after the catch-up.
+    safekeeper_id: NodeId,
+    target_lsn: Lsn,
+}
+
+/// Safekeepers to fetch the lagging timeline WAL from, concurrently.
+#[derive(Debug, PartialEq, Eq)]
+struct CatchupCandidate {
+    sources: Vec<CatchupSource>,
+    target_lsn: Lsn,
+}
+
 /// Stores the reason why WAL connection was switched, for furter debugging purposes.
 #[derive(Debug, PartialEq, Eq)]
 enum ReconnectReason {
@@ -780,6 +1030,7 @@ fn wal_stream_connection_string(
 mod tests {
     use super::*;
     use crate::tenant::harness::{TenantHarness, TIMELINE_ID};
+    use remote_storage::{GenericRemoteStorage, LocalFs};
 
     #[test]
     fn no_connection_no_candidate() -> anyhow::Result<()> {
@@ -802,6 +1053,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: None,
                     },
                     etcd_version: 0,
@@ -818,6 +1071,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some("no commit_lsn".to_string()),
                     },
                     etcd_version: 0,
@@ -834,6 +1089,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some("no commit_lsn".to_string()),
                     },
                     etcd_version: 0,
@@ -850,6 +1107,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: None,
                     },
                     etcd_version: 0,
@@ -897,6 +1156,7 @@ mod tests {
                 Ok(())
             }),
             discovered_new_wal: None,
+            catchup_target: None,
         });
         state.wal_stream_candidates = HashMap::from([
             (
@@ -909,6 +1169,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                     },
                     etcd_version: 0,
@@ -925,6 +1187,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some("not advanced Lsn".to_string()),
                     },
                     etcd_version: 0,
@@ -941,6 +1205,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some("not enough advanced Lsn".to_string()),
                     },
                     etcd_version: 0,
@@ -975,6 +1241,8 @@ mod tests {
                     backup_lsn: None,
                     remote_consistent_lsn: None,
                     peer_horizon_lsn: None,
+                    local_start_lsn: None,
+                    partial_backup_lsn: None,
                     safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                 },
                 etcd_version: 0,
@@ -1007,6 +1275,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some("smaller commit_lsn".to_string()),
                     },
                     etcd_version: 0,
@@ -1023,6 +1293,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                     },
                     etcd_version: 0,
@@ -1039,6 +1311,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: None,
                     },
                     etcd_version: 0,
@@ -1084,6 +1358,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                     },
                     etcd_version: 0,
@@ -1100,6 +1376,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                     },
                     etcd_version: 0,
@@ -1157,6 +1435,7 @@ mod tests {
                 Ok(())
             }),
             discovered_new_wal: None,
+            catchup_target: None,
         });
         state.wal_stream_candidates = HashMap::from([
             (
@@ -1169,6 +1448,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                     },
                     etcd_version: 0,
@@ -1185,6 +1466,8 @@ mod tests {
                         backup_lsn: None,
                         remote_consistent_lsn: None,
                         peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
                         safekeeper_connstr: Some("advanced by Lsn safekeeper".to_string()),
                     },
                     etcd_version: 0,
@@ -1245,6 +1528,7 @@ mod tests {
                 Ok(())
             }),
             discovered_new_wal: None,
+            catchup_target: None,
         });
         state.wal_stream_candidates = HashMap::from([(
             NodeId(0),
@@ -1256,6 +1540,8 @@ mod tests {
                     backup_lsn: None,
                     remote_consistent_lsn: None,
                     peer_horizon_lsn: None,
+                    local_start_lsn: None,
+                    partial_backup_lsn: None,
                     safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                 },
                 etcd_version: 0,
@@ -1316,6 +1602,7 @@ mod tests {
                 discovered_at: time_over_threshold,
                 lsn: new_lsn,
             }),
+            catchup_target: None,
         });
         state.wal_stream_candidates = HashMap::from([(
             NodeId(0),
@@ -1327,6 +1614,8 @@ mod tests {
                     backup_lsn: None,
                     remote_consistent_lsn: None,
                     peer_horizon_lsn: None,
+                    local_start_lsn: None,
+                    partial_backup_lsn: None,
                     safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
                 },
                 etcd_version: 0,
@@ -1363,6 +1652,198 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn parallel_catchup_for_lagging_timeline() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("parallel_catchup_for_lagging_timeline")?;
+        let mut state = dummy_state(&harness);
+        let last_record_lsn = Lsn(0x10);
+        state.timeline.writer().finish_write(last_record_lsn);
+        let now = Utc::now().naive_utc();
+
+        let lagging_lsn = last_record_lsn + CATCHUP_RANGE_SIZE;
+        let advanced_lsn = last_record_lsn + 3 * CATCHUP_RANGE_SIZE;
+        state.wal_stream_candidates = HashMap::from([
+            (
+                NodeId(0),
+                EtcdSkTimeline {
+                    timeline: SkTimelineInfo {
+                        last_log_term: None,
+                        flush_lsn: None,
+                        commit_lsn: Some(lagging_lsn),
+                        backup_lsn: None,
+                        remote_consistent_lsn: None,
+                        peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
+                        safekeeper_connstr: Some("lagging safekeeper".to_string()),
+                    },
+                    etcd_version: 0,
+                    latest_update: now,
+                },
+            ),
+            (
+                NodeId(1),
+                EtcdSkTimeline {
+                    timeline: SkTimelineInfo {
+                        last_log_term: None,
+                        flush_lsn: None,
+                        commit_lsn: Some(advanced_lsn),
+                        backup_lsn: None,
+                        remote_consistent_lsn: None,
+                        peer_horizon_lsn: None,
+                        local_start_lsn: None,
+                        partial_backup_lsn: None,
+                        safekeeper_connstr: Some("advanced safekeeper".to_string()),
+                    },
+                    etcd_version: 0,
+                    latest_update: now,
+                },
+            ),
+        ]);
+
+        assert!(
+            state.next_catchup_candidate().is_none(),
+            "Should not catch up in parallel, if only one stream is configured"
+        );
+
+        state.catchup_streams = NonZeroUsize::new(2).unwrap();
+        let catchup_candidate = state
+            .next_catchup_candidate()
+            .expect("Expected a parallel catch-up for the lagging timeline, but got none");
+        assert_eq!(catchup_candidate.target_lsn, advanced_lsn);
+        assert_eq!(
+            catchup_candidate
+                .sources
+                .iter()
+                .map(|source| (source.sk_id, source.commit_lsn))
+                .collect::<Vec<_>>(),
+            vec![(NodeId(1), advanced_lsn), (NodeId(0), lagging_lsn)],
+            "Should fetch the WAL from all safekeepers ahead of the timeline, most advanced first"
+        );
+
+        state.catchup_streams = NonZeroUsize::new(4).unwrap();
+        assert!(
+            state.next_catchup_candidate().is_none(),
+            "Should not catch up in parallel, if the lag is smaller than a WAL range per stream"
+        );
+
+        state.catchup_streams = NonZeroUsize::new(2).unwrap();
+        state.wal_connection = Some(WalConnection {
+            started_at: now,
+            sk_id: NodeId(1),
+            status: WalConnectionStatus {
+                is_connected: true,
+                has_processed_wal: false,
+                latest_connection_update: now,
+                latest_wal_update: now,
+                commit_lsn: Some(advanced_lsn),
+                streaming_lsn: None,
+            },
+            connection_task: TaskHandle::spawn(move |_, _| async move { Ok(()) }),
+            discovered_new_wal: None,
+            catchup_target: Some(advanced_lsn),
+        });
+        assert!(
+            state.next_catchup_candidate().is_none(),
+            "Should not start another parallel catch-up while one is running"
+        );
+        assert!(
+            state.next_connection_candidate().is_none(),
+            "Should not switch connections while the parallel catch-up is running"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_catchup_for_removed_wal() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("archive_catchup_for_removed_wal")?;
+        let mut state = dummy_state(&harness);
+        let last_record_lsn = Lsn(0x10);
+        state.timeline.writer().finish_write(last_record_lsn);
+        let now = Utc::now().naive_utc();
+
+        let safekeeper_info = |local_start_lsn: Lsn, backup_lsn: Lsn| EtcdSkTimeline {
+            timeline: SkTimelineInfo {
+                last_log_term: None,
+                flush_lsn: None,
+                commit_lsn: Some(Lsn(0x30_000_000)),
+                backup_lsn: Some(backup_lsn),
+                remote_consistent_lsn: None,
+                peer_horizon_lsn: None,
+                local_start_lsn: Some(local_start_lsn),
+                partial_backup_lsn: None,
+                safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
+            },
+            etcd_version: 0,
+            latest_update: now,
+        };
+        state.wal_stream_candidates = HashMap::from([
+            (
+                NodeId(0),
+                safekeeper_info(Lsn(0x2_000_000), Lsn(0x3_000_000)),
+            ),
+            (
+                NodeId(1),
+                safekeeper_info(Lsn(0x1_000_000), Lsn(0x3_000_000)),
+            ),
+        ]);
+
+        assert!(
+            state.next_archive_catchup_candidate().is_none(),
+            "Should not catch up from the archive, if it is not configured"
+        );
+
+        let archive_root = tempfile::tempdir()?;
+        state.wal_archive = Some(SafekeeperWalArchive::new(
+            harness.conf,
+            GenericRemoteStorage::new(LocalFs::new(
+                archive_root.path().to_owned(),
+                harness.conf.safekeeper_wal_path(),
+            )?),
+        ));
+        assert_eq!(
+            state.next_archive_catchup_candidate(),
+            Some(ArchiveCatchupCandidate {
+                safekeeper_id: NodeId(1),
+                target_lsn: Lsn(0x1_000_000),
+            }),
+            "Should catch up from the archive up to the earliest WAL present on safekeepers"
+        );
+
+        state.wal_stream_candidates.insert(
+            NodeId(2),
+            safekeeper_info(last_record_lsn, Lsn(0x3_000_000)),
+        );
+        assert!(
+            state.next_archive_catchup_candidate().is_none(),
+            "Should not catch up from the archive, if any safekeeper has the WAL needed"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn wal_stream_connection_string_auth() -> anyhow::Result<()> {
+        let id = TenantTimelineId::generate();
+        let auth_token = "secret_safekeeper_token";
+
+        let connstr = wal_stream_connection_string(id, "127.0.0.1:5454")?;
+        assert!(!connstr.contains("password"), "{connstr}");
+        assert!(!connstr.contains(auth_token), "{connstr}");
+
+        let config = crate::walreceiver::walreceiver_connection::replication_connect_config(
+            &connstr,
+            Some(auth_token),
+        )?;
+        assert_eq!(config.get_password(), Some(auth_token.as_bytes()));
+        assert_eq!(
+            utils::connstring::connection_host_port(&config),
+            ("127.0.0.1".to_owned(), 5454)
+        );
+        Ok(())
+    }
+
     const DUMMY_SAFEKEEPER_CONNSTR: &str = "safekeeper_connstr";
 
     fn dummy_state(harness: &TenantHarness<'_>) -> WalreceiverState {
@@ -1378,6 +1859,9 @@ mod tests {
             wal_connect_timeout: Duration::from_secs(1),
             lagging_wal_timeout: Duration::from_secs(1),
             max_lsn_wal_lag: NonZeroU64::new(1024 * 1024).unwrap(),
+            catchup_streams: NonZeroUsize::new(1).unwrap(),
+            wal_archive: None,
+            auth_token: None,
             wal_connection: None,
             wal_stream_candidates: HashMap::new(),
             wal_connection_retries: HashMap::new(),
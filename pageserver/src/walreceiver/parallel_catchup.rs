@@ -0,0 +1,344 @@
+//! Parallel WAL catch-up for timelines that are far behind the safekeepers, e.g. after a pageserver restart.
+//!
+//! A single replication connection limits the catch-up speed by one TCP stream, so instead, the WAL the timeline misses
+//! is split into consecutive ranges, fetched from several safekeepers concurrently and ingested strictly in order.
+//! Every range is requested from a safekeeper that has it committed already, with a `START_REPLICATION` command,
+//! limited by the range end.
+//!
+//! The catch-up task ends after all ranges are ingested, and the connection manager falls back to a single safekeeper stream then.
+
+use std::{
+    fmt,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context};
+use chrono::Utc;
+use futures::StreamExt;
+use postgres_ffi::waldecoder::WalStreamDecoder;
+use postgres_protocol::message::backend::ReplicationMessage;
+use std::num::NonZeroUsize;
+use tokio::{pin, select, sync::watch, time};
+use tokio_postgres::replication::ReplicationStream;
+use tracing::*;
+use utils::{id::NodeId, lsn::Lsn};
+
+use super::{
+    walreceiver_connection::{ingest_wal_data, replication_connect_config, WalConnectionStatus},
+    TaskStateUpdate,
+};
+use crate::{
+    task_mgr::WALRECEIVER_RUNTIME,
+    tenant::{Timeline, WalReceiverInfo},
+    walingest::WalIngest,
+};
+
+/// Size of a WAL range, fetched by a single replication connection.
+/// Ranges are aligned to the size, so that every range covers a single WAL segment at most.
+pub(super) const CATCHUP_RANGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Maximum time to wait for the next message of a WAL range from the safekeeper.
+const WAL_RANGE_MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A safekeeper to fetch WAL ranges from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct CatchupSource {
+    pub sk_id: NodeId,
+    pub wal_source_connstr: String,
+    pub commit_lsn: Lsn,
+}
+
+/// The safekeeper that failed to send its WAL range, attached to the catch-up error as a context,
+/// so that the connection manager backs off from that safekeeper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct FailedCatchupSource(pub NodeId);
+
+impl fmt::Display for FailedCatchupSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Catch-up source safekeeper {} failed", self.0)
+    }
+}
+
+/// Fetches the WAL from the timeline's last record up to the most advanced source's commit_lsn,
+/// using up to `streams` connections concurrently, and ingests it.
+pub(super) async fn handle_parallel_catchup(
+    timeline: Arc<Timeline>,
+    sources: Vec<CatchupSource>,
+    auth_token: Option<Arc<String>>,
+    streams: NonZeroUsize,
+    events_sender: watch::Sender<TaskStateUpdate<WalConnectionStatus>>,
+    mut cancellation: watch::Receiver<()>,
+    connect_timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut last_rec_lsn = timeline.get_last_record_lsn();
+    let mut startpoint = last_rec_lsn;
+    if startpoint == Lsn(0) {
+        bail!("No previous WAL position");
+    }
+    // There might be some padding after the last full record, skip it.
+    startpoint += startpoint.calc_padding(8u32);
+
+    let ranges = plan_catchup_ranges(startpoint, &sources);
+    let target_lsn = ranges
+        .last()
+        .map(|(range, _)| range.end)
+        .unwrap_or(startpoint);
+    info!(
+        "last_record_lsn {last_rec_lsn} catching up from {startpoint} to {target_lsn} with {} WAL ranges from {} safekeepers",
+        ranges.len(),
+        sources.len()
+    );
+
+    let now = Utc::now().naive_utc();
+    let mut connection_status = WalConnectionStatus {
+        is_connected: true,
+        has_processed_wal: false,
+        latest_connection_update: now,
+        latest_wal_update: now,
+        streaming_lsn: None,
+        commit_lsn: Some(target_lsn),
+    };
+    if let Err(e) = events_sender.send(TaskStateUpdate::Progress(connection_status.clone())) {
+        warn!("Wal connection event listener dropped right after catch-up start, aborting it: {e}");
+        return Ok(());
+    }
+
+    let mut waldecoder = WalStreamDecoder::new(startpoint, timeline.pg_version);
+    let mut walingest = WalIngest::new(timeline.as_ref(), startpoint)?;
+
+    // `buffered` runs up to `streams` range fetches at once, yet returns their results in the original order.
+    let fetched_ranges = futures::stream::iter(ranges)
+        .map(|(range, source_index)| {
+            let source = &sources[source_index];
+            let sk_id = source.sk_id;
+            let wal_source_connstr = source.wal_source_connstr.clone();
+            let auth_token = auth_token.clone();
+            async move {
+                let data = fetch_wal_range(
+                    &wal_source_connstr,
+                    auth_token.as_deref().map(String::as_str),
+                    range.clone(),
+                    connect_timeout,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to fetch WAL range {}..{} from safekeeper {sk_id}",
+                        range.start, range.end
+                    )
+                })
+                .context(FailedCatchupSource(sk_id))?;
+                anyhow::Ok((range, wal_source_connstr, data))
+            }
+        })
+        .buffered(streams.get());
+    pin!(fetched_ranges);
+
+    while let Some(fetched_range) = {
+        select! {
+            _ = cancellation.changed() => {
+                info!("parallel catch-up interrupted");
+                None
+            }
+            fetched_range = fetched_ranges.next() => fetched_range,
+        }
+    } {
+        let (range, wal_source_connstr, data) = fetched_range?;
+        trace!(
+            "fetched WAL range between {} and {}",
+            range.start,
+            range.end
+        );
+
+        if let Some(lsn) =
+            ingest_wal_data(&timeline, &mut waldecoder, &mut walingest, &data, range.end)?
+        {
+            last_rec_lsn = lsn;
+            connection_status.has_processed_wal = true;
+        }
+
+        let now = Utc::now().naive_utc();
+        connection_status.latest_connection_update = now;
+        connection_status.latest_wal_update = now;
+        connection_status.streaming_lsn = Some(range.end);
+        if let Err(e) = events_sender.send(TaskStateUpdate::Progress(connection_status.clone())) {
+            warn!("Wal connection event listener dropped, aborting the catch-up: {e}");
+            return Ok(());
+        }
+
+        timeline.check_checkpoint_distance().with_context(|| {
+            format!(
+                "Failed to check checkpoint distance for timeline {}",
+                timeline.timeline_id
+            )
+        })?;
+
+        *timeline.last_received_wal.lock().unwrap() = Some(WalReceiverInfo {
+            wal_source_connstr,
+            last_received_msg_lsn: range.end,
+            last_received_msg_ts: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Received message time should be before UNIX EPOCH!")
+                .as_micros(),
+        });
+    }
+
+    info!("parallel catch-up finished, last_record_lsn {last_rec_lsn}, target {target_lsn}");
+    Ok(())
+}
+
+/// Splits the WAL between `start` and the most advanced source's commit_lsn into consecutive ranges, aligned to [`CATCHUP_RANGE_SIZE`].
+/// Every range is assigned to one of the sources that have it committed, round-robin, returning the index of the source along with the range.
+pub(super) fn plan_catchup_ranges(
+    start: Lsn,
+    sources: &[CatchupSource],
+) -> Vec<(Range<Lsn>, usize)> {
+    let target = match sources.iter().map(|source| source.commit_lsn).max() {
+        Some(target) => target,
+        None => return Vec::new(),
+    };
+
+    let mut ranges = Vec::new();
+    let mut range_start = start;
+    let mut next_source = 0;
+    while range_start < target {
+        let range_end =
+            Lsn((range_start.0 / CATCHUP_RANGE_SIZE + 1) * CATCHUP_RANGE_SIZE).min(target);
+        // the most advanced source has every range committed, so there's always a source to pick
+        let source = (0..sources.len())
+            .map(|offset| (next_source + offset) % sources.len())
+            .find(|&index| sources[index].commit_lsn >= range_end)
+            .expect("The most advanced source should have all WAL ranges committed");
+
+        ranges.push((range_start..range_end, source));
+        next_source = (source + 1) % sources.len();
+        range_start = range_end;
+    }
+    ranges
+}
+
+/// Opens a replication connection to the safekeeper and reads the WAL of the given range, that has to be committed there.
+async fn fetch_wal_range(
+    wal_source_connstr: &str,
+    auth_token: Option<&str>,
+    range: Range<Lsn>,
+    connect_timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    let connect_cfg = replication_connect_config(wal_source_connstr, auth_token)?;
+    let (replication_client, connection) =
+        time::timeout(connect_timeout, connect_cfg.connect(postgres::NoTls))
+            .await
+            .context("Timed out while waiting for WAL range connection to open")?
+            .context("Failed to open WAL range connection")?;
+
+    // The connection finishes after the client is dropped.
+    WALRECEIVER_RUNTIME.spawn(async move {
+        if let Err(connection_error) = connection.await {
+            if !connection_error.is_closed() {
+                debug!("WAL range connection aborted: {connection_error}")
+            }
+        }
+    });
+
+    let query = format!(
+        "START_REPLICATION PHYSICAL {} STOP {}",
+        range.start, range.end
+    );
+    let copy_stream = replication_client.copy_both_simple(&query).await?;
+    let physical_stream = ReplicationStream::new(copy_stream);
+    pin!(physical_stream);
+
+    let range_size = (range.end.0 - range.start.0) as usize;
+    let mut data = Vec::with_capacity(range_size);
+    while data.len() < range_size {
+        let replication_message = time::timeout(WAL_RANGE_MESSAGE_TIMEOUT, physical_stream.next())
+            .await
+            .context("Timed out while waiting for the WAL range data")?
+            .context("Replication stream ended before the end of the WAL range")??;
+
+        if let ReplicationMessage::XLogData(xlog_data) = replication_message {
+            let expected_start = range.start + data.len() as u64;
+            let wal_start = Lsn::from(xlog_data.wal_start());
+            ensure!(
+                wal_start == expected_start,
+                "Expected WAL data starting at {expected_start}, got {wal_start}"
+            );
+            // Safekeepers that do not support the range end keep streaming after it
+            let bytes_left = range_size - data.len();
+            let xlog_data = xlog_data.data();
+            data.extend_from_slice(&xlog_data[..xlog_data.len().min(bytes_left)]);
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(id: u64, commit_lsn: u64) -> CatchupSource {
+        CatchupSource {
+            sk_id: NodeId(id),
+            wal_source_connstr: format!("source {id}"),
+            commit_lsn: Lsn(commit_lsn),
+        }
+    }
+
+    #[test]
+    fn catchup_ranges_are_aligned_and_consecutive() {
+        let start = Lsn(CATCHUP_RANGE_SIZE / 2);
+        let end = CATCHUP_RANGE_SIZE * 3 + 100;
+        let ranges = plan_catchup_ranges(start, &[source(0, end), source(1, end)]);
+
+        assert_eq!(
+            ranges,
+            vec![
+                (start..Lsn(CATCHUP_RANGE_SIZE), 0),
+                (Lsn(CATCHUP_RANGE_SIZE)..Lsn(CATCHUP_RANGE_SIZE * 2), 1),
+                (Lsn(CATCHUP_RANGE_SIZE * 2)..Lsn(CATCHUP_RANGE_SIZE * 3), 0),
+                (Lsn(CATCHUP_RANGE_SIZE * 3)..Lsn(end), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn catchup_ranges_are_fetched_from_sources_with_committed_wal() {
+        let start = Lsn(CATCHUP_RANGE_SIZE);
+        let ranges = plan_catchup_ranges(
+            start,
+            &[
+                source(0, CATCHUP_RANGE_SIZE * 5),
+                source(1, CATCHUP_RANGE_SIZE * 2),
+                source(2, CATCHUP_RANGE_SIZE * 3 + 1),
+            ],
+        );
+
+        assert_eq!(
+            ranges.iter().map(|(_, source)| *source).collect::<Vec<_>>(),
+            vec![0, 2, 0, 0],
+            "Sources should only get the ranges they have committed"
+        );
+        assert_eq!(ranges.last().unwrap().0.end, Lsn(CATCHUP_RANGE_SIZE * 5));
+    }
+
+    #[test]
+    fn failed_source_is_found_in_the_error_chain() {
+        let error = anyhow::anyhow!("connection refused")
+            .context(FailedCatchupSource(NodeId(2)))
+            .context("walreceiver parallel catch-up failure");
+
+        assert_eq!(
+            error.downcast_ref::<FailedCatchupSource>(),
+            Some(&FailedCatchupSource(NodeId(2)))
+        );
+    }
+
+    #[test]
+    fn no_catchup_ranges_when_caught_up() {
+        assert!(plan_catchup_ranges(Lsn(100), &[source(0, 100), source(1, 50)]).is_empty());
+        assert!(plan_catchup_ranges(Lsn(100), &[]).is_empty());
+    }
+}
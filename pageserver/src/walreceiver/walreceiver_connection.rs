@@ -50,26 +50,46 @@ pub struct WalConnectionStatus {
     pub commit_lsn: Option<Lsn>,
 }
 
+/// Creates the replication connection config for the safekeeper connection string.
+/// The auth token is set as the password here, so that it never gets into the connection string, that is logged and shown in the mgmt API.
+pub(super) fn replication_connect_config(
+    wal_source_connstr: &str,
+    auth_token: Option<&str>,
+) -> anyhow::Result<tokio_postgres::Config> {
+    let mut connect_cfg =
+        format!("{wal_source_connstr} application_name=pageserver replication=true")
+            .parse::<tokio_postgres::Config>()
+            .with_context(|| {
+                format!("Failed to parse safekeeper connection string '{wal_source_connstr}'")
+            })?;
+    if let Some(auth_token) = auth_token {
+        connect_cfg.password(auth_token);
+    }
+    Ok(connect_cfg)
+}
+
 /// Open a connection to the given safekeeper and receive WAL, sending back progress
 /// messages as we go.
 pub async fn handle_walreceiver_connection(
     timeline: Arc<Timeline>,
     wal_source_connstr: String,
+    auth_token: Option<Arc<String>>,
     events_sender: watch::Sender<TaskStateUpdate<WalConnectionStatus>>,
     mut cancellation: watch::Receiver<()>,
     connect_timeout: Duration,
 ) -> anyhow::Result<()> {
     // Connect to the database in replication mode.
     info!("connecting to {wal_source_connstr}");
-    let connect_cfg = format!("{wal_source_connstr} application_name=pageserver replication=true");
+    let connect_cfg = replication_connect_config(
+        &wal_source_connstr,
+        auth_token.as_deref().map(String::as_str),
+    )?;
 
-    let (mut replication_client, connection) = time::timeout(
-        connect_timeout,
-        tokio_postgres::connect(&connect_cfg, postgres::NoTls),
-    )
-    .await
-    .context("Timed out while waiting for walreceiver connection to open")?
-    .context("Failed to open walreceiver connection")?;
+    let (mut replication_client, connection) =
+        time::timeout(connect_timeout, connect_cfg.connect(postgres::NoTls))
+            .await
+            .context("Timed out while waiting for walreceiver connection to open")?
+            .context("Failed to open walreceiver connection")?;
 
     info!("connected!");
     let mut connection_status = WalConnectionStatus {
@@ -215,27 +235,10 @@ pub async fn handle_walreceiver_connection(
 
                 trace!("received XLogData between {startlsn} and {endlsn}");
 
-                waldecoder.feed_bytes(data);
-
+                if let Some(lsn) =
+                    ingest_wal_data(&timeline, &mut waldecoder, &mut walingest, data, endlsn)?
                 {
-                    let mut decoded = DecodedWALRecord::default();
-                    let mut modification = timeline.begin_modification(endlsn);
-                    while let Some((lsn, recdata)) = waldecoder.poll_decode()? {
-                        // let _enter = info_span!("processing record", lsn = %lsn).entered();
-
-                        // It is important to deal with the aligned records as lsn in getPage@LSN is
-                        // aligned and can be several bytes bigger. Without this alignment we are
-                        // at risk of hitting a deadlock.
-                        ensure!(lsn.is_aligned());
-
-                        walingest
-                            .ingest_record(recdata, lsn, &mut modification, &mut decoded)
-                            .context("could not ingest record at {lsn}")?;
-
-                        fail_point!("walreceiver-after-ingest");
-
-                        last_rec_lsn = lsn;
-                    }
+                    last_rec_lsn = lsn;
                 }
 
                 if !caught_up && endlsn >= end_of_wal {
@@ -341,6 +344,39 @@ pub async fn handle_walreceiver_connection(
     Ok(())
 }
 
+/// Feeds the WAL bytes, ending at `endlsn`, into the decoder and ingests all complete records decoded.
+/// Returns the LSN of the last record ingested, if any.
+pub(super) fn ingest_wal_data(
+    timeline: &Timeline,
+    waldecoder: &mut WalStreamDecoder,
+    walingest: &mut WalIngest<'_>,
+    data: &[u8],
+    endlsn: Lsn,
+) -> anyhow::Result<Option<Lsn>> {
+    waldecoder.feed_bytes(data);
+
+    let mut last_rec_lsn = None;
+    let mut decoded = DecodedWALRecord::default();
+    let mut modification = timeline.begin_modification(endlsn);
+    while let Some((lsn, recdata)) = waldecoder.poll_decode()? {
+        // let _enter = info_span!("processing record", lsn = %lsn).entered();
+
+        // It is important to deal with the aligned records as lsn in getPage@LSN is
+        // aligned and can be several bytes bigger. Without this alignment we are
+        // at risk of hitting a deadlock.
+        ensure!(lsn.is_aligned());
+
+        walingest
+            .ingest_record(recdata, lsn, &mut modification, &mut decoded)
+            .context("could not ingest record at {lsn}")?;
+
+        fail_point!("walreceiver-after-ingest");
+
+        last_rec_lsn = Some(lsn);
+    }
+    Ok(last_rec_lsn)
+}
+
 /// Data returned from the postgres `IDENTIFY_SYSTEM` command
 ///
 /// See the [postgres docs] for more details.
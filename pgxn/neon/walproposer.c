@@ -92,6 +92,13 @@ static XLogRecPtr lastSentCommitLsn;	/* last commitLsn broadcast to*
 										 * safekeepers */
 static ProposerGreeting greetRequest;
 static VoteRequest voteRequest; /* Vote request for safekeeper */
+static StringInfoData logicalSlots;	/* serialized logical replication slots */
+static uint64 logicalSlotsVersion;	/* bumped whenever the slots change */
+static char *neon_auth_token;	/* JWT token presented to safekeepers, if
+								 * any */
+static MembershipConfiguration mconf;	/* membership configuration of the
+										 * highest generation among the
+										 * safekeepers */
 static WaitEventSet *waitEvents;
 static AppendResponse quorumFeedback;
 /*
@@ -113,7 +120,7 @@ static XLogRecPtr propEpochStartLsn;	/* epoch start lsn of the proposer */
 static term_t donorEpoch;		/* Most advanced acceptor epoch */
 static int	donor;				/* Most advanced acceptor */
 static XLogRecPtr timelineStartLsn; /* timeline globally starts at this LSN */
-static int	n_votes = 0;
+static bool elected = false;
 static int	n_connected = 0;
 static TimestampTz last_reconnect_attempt;
 
@@ -150,13 +157,27 @@ static void SendMessageToNode(Safekeeper *sk);
 static void BroadcastAppendRequest(void);
 static void HandleActiveState(Safekeeper *sk, uint32 events);
 static bool SendAppendRequests(Safekeeper *sk);
+static void UpdateLogicalSlots(void);
 static bool RecvAppendResponses(Safekeeper *sk);
 static void CombineHotStanbyFeedbacks(HotStandbyFeedback * hs);
 static XLogRecPtr CalculateMinFlushLsn(void);
 static XLogRecPtr GetAcknowledgedByQuorumWALPosition(void);
+static XLogRecPtr GetAcknowledgedByMemberSetWALPosition(MemberSet * set);
+static XLogRecPtr GetAcknowledgedFlushLsn(Safekeeper *sk);
+static Safekeeper *FindMember(NNodeId id);
+static bool HasQuorum(bool (*counted) (Safekeeper *sk));
+static bool HasMemberSetQuorum(MemberSet * set, bool (*counted) (Safekeeper *sk));
+static bool HasVoted(Safekeeper *sk);
+static bool IsSynced(Safekeeper *sk);
 static void HandleSafekeeperResponse(void);
 static bool AsyncRead(Safekeeper *sk, char **buf, int *buf_size);
 static bool AsyncReadMessage(Safekeeper *sk, AcceptorProposerMessage * anymsg);
+static int	FormatSafekeeperConnInfo(char *buf, size_t size, char *host, char *port);
+static void ReadConfiguration(StringInfo s, MembershipConfiguration * conf);
+static void ReadMemberSet(StringInfo s, MemberSet * set);
+static void ReadLogicalSlots(StringInfo s, VoteResponse * msg);
+static void ReadName(StringInfo s, NameData *name);
+static void RestoreLogicalSlots(VoteResponse * vr);
 static bool BlockingWrite(Safekeeper *sk, void *msg, size_t msg_size, SafekeeperState success_state);
 static bool AsyncWrite(Safekeeper *sk, void *msg, size_t msg_size, SafekeeperState flush_state);
 static bool AsyncFlush(Safekeeper *sk);
@@ -381,6 +402,7 @@ WalProposerPoll(void)
 			 */
 			if (availableLsn != InvalidXLogRecPtr)
 			{
+				UpdateLogicalSlots();
 				BroadcastAppendRequest();
 			}
 
@@ -446,6 +468,12 @@ WalProposerInitImpl(XLogRecPtr flushRecPtr, uint64 systemId)
 	if (WalReceiverFunctions == NULL)
 		elog(ERROR, "libpqwalreceiver didn't initialize correctly");
 
+	/*
+	 * Safekeepers checking the tokens accept the tenant token the compute
+	 * uses for the pageserver, see docs/authentication.md.
+	 */
+	neon_auth_token = getenv("ZENITH_AUTH_TOKEN");
+
 	for (host = wal_acceptors_list; host != NULL && *host != '\0'; host = sep)
 	{
 		port = strchr(host, ':');
@@ -478,6 +506,7 @@ WalProposerInitImpl(XLogRecPtr flushRecPtr, uint64 systemId)
 		safekeeper[n_safekeepers].flushWrite = false;
 		safekeeper[n_safekeepers].startStreamingAt = InvalidXLogRecPtr;
 		safekeeper[n_safekeepers].streamingAt = InvalidXLogRecPtr;
+		safekeeper[n_safekeepers].logicalSlotsSent = 0;
 		n_safekeepers += 1;
 	}
 	if (n_safekeepers < 1)
@@ -511,6 +540,10 @@ WalProposerInitImpl(XLogRecPtr flushRecPtr, uint64 systemId)
 #endif
 	greetRequest.walSegSize = wal_segment_size;
 
+	/* No slots to send until some are created */
+	initStringInfo(&logicalSlots);
+	pq_sendint32_le(&logicalSlots, 0);
+
 	InitEventSet();
 }
 
@@ -649,9 +682,7 @@ ResetConnection(Safekeeper *sk)
 	{
 		int			written = 0;
 
-		written = snprintf((char *) &sk->conninfo, MAXCONNINFO,
-						   "host=%s port=%s dbname=replication options='-c timeline_id=%s tenant_id=%s'",
-						   sk->host, sk->port, neon_timeline_walproposer, neon_tenant_walproposer);
+		written = FormatSafekeeperConnInfo((char *) &sk->conninfo, MAXCONNINFO, sk->host, sk->port);
 
 		/*
 		 * currently connection string is not that long, but once we pass
@@ -997,7 +1028,19 @@ SendProposerGreeting(Safekeeper *sk)
 	 * On failure, logging & resetting the connection is handled. We just need
 	 * to handle the control flow.
 	 */
-	BlockingWrite(sk, &greetRequest, sizeof(greetRequest), SS_HANDSHAKE_RECV);
+	resetStringInfo(&sk->outbuf);
+	appendBinaryStringInfo(&sk->outbuf, (char *) &greetRequest, sizeof(greetRequest));
+
+	/*
+	 * We don't know the membership configuration of the timeline, so send the
+	 * empty one: generation 0, no members and no joint configuration. The
+	 * safekeepers reply with theirs.
+	 */
+	pq_sendint32_le(&sk->outbuf, 0);
+	pq_sendint32_le(&sk->outbuf, 0);
+	pq_sendbyte(&sk->outbuf, 0);
+
+	BlockingWrite(sk, sk->outbuf.data, sk->outbuf.len, SS_HANDSHAKE_RECV);
 }
 
 static void
@@ -1021,6 +1064,14 @@ RecvAcceptorGreeting(Safekeeper *sk)
 		/* We're still collecting terms from the majority. */
 		propTerm = Max(sk->greetResponse.term, propTerm);
 
+		/*
+		 * Ask for the votes in the latest configuration, the safekeepers of
+		 * the other generations refuse to vote. The election and the commits
+		 * need a quorum of its member sets then, see HasQuorum.
+		 */
+		if (sk->greetResponse.mconf.generation > mconf.generation)
+			mconf = sk->greetResponse.mconf;
+
 		/* Quorum is acquried, prepare the vote request. */
 		if (n_connected == quorum)
 		{
@@ -1030,7 +1081,8 @@ RecvAcceptorGreeting(Safekeeper *sk)
 			voteRequest = (VoteRequest)
 			{
 				.tag = 'v',
-					.term = propTerm
+					.term = propTerm,
+					.generation = mconf.generation
 			};
 			memcpy(voteRequest.proposerId.data, greetRequest.proposerId.data, UUID_LEN);
 		}
@@ -1106,7 +1158,7 @@ RecvVoteResponse(Safekeeper *sk)
 	 * we are not elected yet and thus need the vote.
 	 */
 	if ((!sk->voteResponse.voteGiven) &&
-		(sk->voteResponse.term > propTerm || n_votes < quorum))
+		(sk->voteResponse.term > propTerm || !elected))
 	{
 		elog(FATAL, "WAL acceptor %s:%s with term " INT64_FORMAT " rejects our connection request with term " INT64_FORMAT "",
 			 sk->host, sk->port,
@@ -1115,24 +1167,94 @@ RecvVoteResponse(Safekeeper *sk)
 	Assert(sk->voteResponse.term == propTerm);
 
 	/* Handshake completed, do we have quorum? */
-	n_votes++;
-	if (n_votes < quorum)
-	{
-		sk->state = SS_IDLE;	/* can't do much yet, no quorum */
-	}
-	else if (n_votes > quorum)
+	if (elected)
 	{
 		/* recovery already performed, just start streaming */
 		SendProposerElected(sk);
+		return;
 	}
-	else
+
+	sk->state = SS_IDLE;
+	if (HasQuorum(HasVoted))
 	{
-		sk->state = SS_IDLE;
+		elected = true;
 		UpdateEventSet(sk, WL_SOCKET_READABLE); /* Idle states wait for
 												 * read-ready */
 
 		HandleElectedProposer();
 	}
+	/* otherwise, can't do much yet, no quorum */
+}
+
+/*
+ * Whether the safekeepers counted make up a quorum. Without a membership
+ * configuration, that is a majority of neon.safekeepers. Otherwise, that is
+ * a majority of the configuration members and, while the configuration is
+ * joint, a majority of its new members too: a proposer elected or a commit
+ * made by one of the member sets alone could be lost after the member change.
+ */
+static bool
+HasQuorum(bool (*counted) (Safekeeper *sk))
+{
+	int			n_counted = 0;
+
+	if (mconf.generation != 0)
+		return HasMemberSetQuorum(&mconf.members, counted) &&
+			(!mconf.joint || HasMemberSetQuorum(&mconf.newMembers, counted));
+
+	for (int i = 0; i < n_safekeepers; i++)
+	{
+		if (counted(&safekeeper[i]))
+			n_counted++;
+	}
+	return n_counted >= quorum;
+}
+
+/*
+ * Whether the majority of the member set is counted. The members missing in
+ * neon.safekeepers are never counted.
+ */
+static bool
+HasMemberSetQuorum(MemberSet * set, bool (*counted) (Safekeeper *sk))
+{
+	uint32		n_counted = 0;
+
+	for (uint32 i = 0; i < set->n_members; i++)
+	{
+		Safekeeper *sk = FindMember(set->ids[i]);
+
+		if (sk != NULL && counted(sk))
+			n_counted++;
+	}
+	return n_counted >= set->n_members / 2 + 1;
+}
+
+/*
+ * The safekeeper with the node id given. Node ids are known from the
+ * greetings, so the safekeepers which haven't greeted us are not found.
+ */
+static Safekeeper *
+FindMember(NNodeId id)
+{
+	for (int i = 0; i < n_safekeepers; i++)
+	{
+		if (safekeeper[i].greetResponse.nodeId == id)
+			return &safekeeper[i];
+	}
+	return NULL;
+}
+
+/* Voters wait in SS_IDLE until the proposer is elected. */
+static bool
+HasVoted(Safekeeper *sk)
+{
+	return sk->state == SS_IDLE;
+}
+
+static bool
+IsSynced(Safekeeper *sk)
+{
+	return sk->appendResponse.commitLsn >= propEpochStartLsn;
 }
 
 /*
@@ -1195,6 +1317,8 @@ HandleElectedProposer(void)
 		return;
 	}
 
+	RestoreLogicalSlots(&safekeeper[donor].voteResponse);
+
 	WalProposerStartStreaming(propEpochStartLsn);
 	/* Should not return here */
 }
@@ -1361,12 +1485,15 @@ static bool
 WalProposerRecovery(int donor, TimeLineID timeline, XLogRecPtr startpos, XLogRecPtr endpos)
 {
 	char		conninfo[MAXCONNINFO];
+	int			written;
 	char	   *err;
 	WalReceiverConn *wrconn;
 	WalRcvStreamOptions options;
 
-	sprintf(conninfo, "host=%s port=%s dbname=replication options='-c timeline_id=%s tenant_id=%s'",
-			safekeeper[donor].host, safekeeper[donor].port, neon_timeline_walproposer, neon_tenant_walproposer);
+	written = FormatSafekeeperConnInfo(conninfo, MAXCONNINFO, safekeeper[donor].host, safekeeper[donor].port);
+	if (written > MAXCONNINFO || written < 0)
+		elog(FATAL, "could not create connection string for safekeeper %s:%s",
+			 safekeeper[donor].host, safekeeper[donor].port);
 	wrconn = walrcv_connect(conninfo, false, "wal_proposer_recovery", &err);
 	if (!wrconn)
 	{
@@ -1602,6 +1729,8 @@ StartStreaming(Safekeeper *sk)
 	 */
 	sk->state = SS_ACTIVE;
 	sk->streamingAt = sk->startStreamingAt;
+	/* the safekeeper might have missed the slots of this term */
+	sk->logicalSlotsSent = 0;
 
 	/* event set will be updated inside SendMessageToNode */
 	SendMessageToNode(sk);
@@ -1714,6 +1843,36 @@ SendAppendRequests(Safekeeper *sk)
 		sk->flushWrite = false;
 	}
 
+	/* Send the changed logical replication slots before the WAL */
+	if (sk->logicalSlotsSent != logicalSlotsVersion)
+	{
+		resetStringInfo(&sk->outbuf);
+		pq_sendint64_le(&sk->outbuf, 'l');
+		pq_sendint64_le(&sk->outbuf, propTerm);
+		appendBinaryStringInfo(&sk->outbuf, logicalSlots.data, logicalSlots.len);
+
+		writeResult = walprop_async_write(sk->conn, sk->outbuf.data, sk->outbuf.len);
+		sk->logicalSlotsSent = logicalSlotsVersion;
+
+		switch (writeResult)
+		{
+			case PG_ASYNC_WRITE_SUCCESS:
+				break;
+			case PG_ASYNC_WRITE_TRY_FLUSH:
+				sk->flushWrite = true;
+				return true;
+			case PG_ASYNC_WRITE_FAIL:
+				elog(WARNING, "Failed to send to node %s:%s in %s state: %s",
+					 sk->host, sk->port, FormatSafekeeperState(sk->state),
+					 walprop_error_message(sk->conn));
+				ShutdownConnection(sk);
+				return false;
+			default:
+				Assert(false);
+				return false;
+		}
+	}
+
 	while (sk->streamingAt != availableLsn || !sentAnything)
 	{
 		sentAnything = true;
@@ -1797,6 +1956,134 @@ SendAppendRequests(Safekeeper *sk)
 	return true;
 }
 
+/*
+ * Serialize the logical replication slots of the compute as n_slots followed by
+ * (name length, name, plugin length, plugin, database, catalog_xmin,
+ * restart_lsn, confirmed_flush_lsn) entries. If they have
+ * changed, bump the version to send them to the safekeepers, which keep the WAL
+ * needed by the slots.
+ */
+static void
+UpdateLogicalSlots(void)
+{
+	StringInfoData buf;
+	uint32		n_slots = 0;
+
+	if (syncSafekeepers || max_replication_slots == 0)
+		return;
+
+	initStringInfo(&buf);
+	pq_sendint32_le(&buf, 0);	/* n_slots, set below */
+
+	LWLockAcquire(ReplicationSlotControlLock, LW_SHARED);
+	for (int i = 0; i < max_replication_slots; i++)
+	{
+		ReplicationSlot *s = &ReplicationSlotCtl->replication_slots[i];
+		NameData	name;
+		NameData	plugin;
+		Oid			database;
+		TransactionId catalog_xmin;
+		XLogRecPtr	restart_lsn;
+		XLogRecPtr	confirmed_flush;
+
+		if (!s->in_use || !SlotIsLogical(s))
+			continue;
+
+		SpinLockAcquire(&s->mutex);
+		name = s->data.name;
+		plugin = s->data.plugin;
+		database = s->data.database;
+		catalog_xmin = s->data.catalog_xmin;
+		restart_lsn = s->data.restart_lsn;
+		confirmed_flush = s->data.confirmed_flush;
+		SpinLockRelease(&s->mutex);
+
+		pq_sendint32_le(&buf, strlen(NameStr(name)));
+		appendBinaryStringInfo(&buf, NameStr(name), strlen(NameStr(name)));
+		pq_sendint32_le(&buf, strlen(NameStr(plugin)));
+		appendBinaryStringInfo(&buf, NameStr(plugin), strlen(NameStr(plugin)));
+		pq_sendint32_le(&buf, database);
+		pq_sendint32_le(&buf, catalog_xmin);
+		pq_sendint64_le(&buf, restart_lsn);
+		pq_sendint64_le(&buf, confirmed_flush);
+		n_slots++;
+	}
+	LWLockRelease(ReplicationSlotControlLock);
+	memcpy(buf.data, &n_slots, sizeof(n_slots));
+
+	if (buf.len != logicalSlots.len || memcmp(buf.data, logicalSlots.data, buf.len) != 0)
+	{
+		resetStringInfo(&logicalSlots);
+		appendBinaryStringInfo(&logicalSlots, buf.data, buf.len);
+		logicalSlotsVersion++;
+	}
+	pfree(buf.data);
+}
+
+/*
+ * Create the logical replication slots kept by the safekeepers that the compute
+ * doesn't have: computes are ephemeral, and the slots are not in the
+ * basebackup. The donor has the most advanced WAL, so it most likely got the
+ * slots from the last elected proposer as well.
+ */
+static void
+RestoreLogicalSlots(VoteResponse * vr)
+{
+	for (uint32 i = 0; i < vr->nLogicalSlots; i++)
+	{
+		LogicalSlotState *state = &vr->logicalSlots[i];
+		ReplicationSlot *slot;
+		bool		have_free_slot = false;
+
+		if (SearchNamedReplicationSlot(NameStr(state->name), true) != NULL)
+			continue;
+
+		/* ReplicationSlotCreate errors out if there is no free slot */
+		LWLockAcquire(ReplicationSlotControlLock, LW_SHARED);
+		for (int j = 0; j < max_replication_slots; j++)
+		{
+			if (!ReplicationSlotCtl->replication_slots[j].in_use)
+			{
+				have_free_slot = true;
+				break;
+			}
+		}
+		LWLockRelease(ReplicationSlotControlLock);
+		if (!have_free_slot)
+		{
+			elog(WARNING, "cannot restore logical replication slot \"%s\": all replication slots are in use, increase max_replication_slots",
+				 NameStr(state->name));
+			continue;
+		}
+
+#if PG_VERSION_NUM >= 150000
+		ReplicationSlotCreate(NameStr(state->name), true, RS_PERSISTENT, false);
+#else
+		ReplicationSlotCreate(NameStr(state->name), true, RS_PERSISTENT);
+#endif
+		slot = MyReplicationSlot;
+
+		SpinLockAcquire(&slot->mutex);
+		slot->data.database = state->database;
+		slot->data.plugin = state->plugin;
+		slot->data.catalog_xmin = state->catalogXmin;
+		slot->effective_catalog_xmin = state->catalogXmin;
+		slot->data.restart_lsn = state->restartLsn;
+		slot->data.confirmed_flush = state->confirmedFlushLsn;
+		SpinLockRelease(&slot->mutex);
+
+		ReplicationSlotMarkDirty();
+		ReplicationSlotSave();
+		ReplicationSlotsComputeRequiredXmin(false);
+		ReplicationSlotsComputeRequiredLSN();
+		ReplicationSlotRelease();
+
+		elog(LOG, "restored logical replication slot \"%s\", restart_lsn %X/%X, confirmed_flush_lsn %X/%X",
+			 NameStr(state->name), LSN_FORMAT_ARGS(state->restartLsn),
+			 LSN_FORMAT_ARGS(state->confirmedFlushLsn));
+	}
+}
+
 /*
  * Receive and process all available feedback.
  *
@@ -1983,24 +2270,27 @@ CalculateMinFlushLsn(void)
 }
 
 /*
- * Calculate WAL position acknowledged by quorum
+ * Calculate WAL position acknowledged by quorum, see HasQuorum.
  */
 static XLogRecPtr
 GetAcknowledgedByQuorumWALPosition(void)
 {
 	XLogRecPtr	responses[MAX_SAFEKEEPERS];
 
+	if (mconf.generation != 0)
+	{
+		XLogRecPtr	lsn = GetAcknowledgedByMemberSetWALPosition(&mconf.members);
+
+		if (mconf.joint)
+			lsn = Min(lsn, GetAcknowledgedByMemberSetWALPosition(&mconf.newMembers));
+		return lsn;
+	}
+
 	/*
 	 * Sort acknowledged LSNs
 	 */
 	for (int i = 0; i < n_safekeepers; i++)
-	{
-		/*
-		 * Like in Raft, we aren't allowed to commit entries from previous
-		 * terms, so ignore reported LSN until it gets to epochStartLsn.
-		 */
-		responses[i] = safekeeper[i].appendResponse.flushLsn >= propEpochStartLsn ? safekeeper[i].appendResponse.flushLsn : 0;
-	}
+		responses[i] = GetAcknowledgedFlushLsn(&safekeeper[i]);
 	qsort(responses, n_safekeepers, sizeof(XLogRecPtr), CompareLsn);
 
 	/*
@@ -2009,6 +2299,39 @@ GetAcknowledgedByQuorumWALPosition(void)
 	return responses[n_safekeepers - quorum];
 }
 
+/*
+ * Calculate WAL position acknowledged by the majority of the member set. The
+ * members missing in neon.safekeepers haven't acknowledged anything.
+ */
+static XLogRecPtr
+GetAcknowledgedByMemberSetWALPosition(MemberSet * set)
+{
+	XLogRecPtr	responses[MAX_SAFEKEEPERS];
+
+	if (set->n_members == 0)
+		return InvalidXLogRecPtr;
+
+	for (uint32 i = 0; i < set->n_members; i++)
+	{
+		Safekeeper *sk = FindMember(set->ids[i]);
+
+		responses[i] = sk != NULL ? GetAcknowledgedFlushLsn(sk) : InvalidXLogRecPtr;
+	}
+	qsort(responses, set->n_members, sizeof(XLogRecPtr), CompareLsn);
+
+	return responses[set->n_members - (set->n_members / 2 + 1)];
+}
+
+static XLogRecPtr
+GetAcknowledgedFlushLsn(Safekeeper *sk)
+{
+	/*
+	 * Like in Raft, we aren't allowed to commit entries from previous terms,
+	 * so ignore reported LSN until it gets to epochStartLsn.
+	 */
+	return sk->appendResponse.flushLsn >= propEpochStartLsn ? sk->appendResponse.flushLsn : 0;
+}
+
 /*
  * ReplicationFeedbackShmemSize --- report amount of shared memory space needed
  */
@@ -2185,21 +2508,15 @@ HandleSafekeeperResponse(void)
 	 */
 	if (syncSafekeepers)
 	{
-		int			n_synced;
-
-		n_synced = 0;
 		for (int i = 0; i < n_safekeepers; i++)
 		{
 			Safekeeper *sk = &safekeeper[i];
-			bool		synced = sk->appendResponse.commitLsn >= propEpochStartLsn;
 
 			/* alive safekeeper which is not synced yet; wait for it */
-			if (sk->state != SS_OFFLINE && !synced)
+			if (sk->state != SS_OFFLINE && !IsSynced(sk))
 				return;
-			if (synced)
-				n_synced++;
 		}
-		if (n_synced >= quorum)
+		if (HasQuorum(IsSynced))
 		{
 			/* All safekeepers synced! */
 			fprintf(stdout, "%X/%X\n", LSN_FORMAT_ARGS(propEpochStartLsn));
@@ -2277,6 +2594,7 @@ AsyncReadMessage(Safekeeper *sk, AcceptorProposerMessage * anymsg)
 
 				msg->term = pq_getmsgint64_le(&s);
 				msg->nodeId = pq_getmsgint64_le(&s);
+				ReadConfiguration(&s, &msg->mconf);
 				pq_getmsgend(&s);
 				return true;
 			}
@@ -2297,6 +2615,8 @@ AsyncReadMessage(Safekeeper *sk, AcceptorProposerMessage * anymsg)
 					msg->termHistory.entries[i].lsn = pq_getmsgint64_le(&s);
 				}
 				msg->timelineStartLsn = pq_getmsgint64_le(&s);
+				ReadConfiguration(&s, &msg->mconf);
+				ReadLogicalSlots(&s, msg);
 				pq_getmsgend(&s);
 				return true;
 			}
@@ -2325,6 +2645,92 @@ AsyncReadMessage(Safekeeper *sk, AcceptorProposerMessage * anymsg)
 	}
 }
 
+/*
+ * Build the connection string to the safekeeper, with the auth token as the
+ * password if there is one. Returns the snprintf result.
+ */
+static int
+FormatSafekeeperConnInfo(char *buf, size_t size, char *host, char *port)
+{
+	if (neon_auth_token)
+		return snprintf(buf, size,
+						"host=%s port=%s dbname=replication password=%s options='-c timeline_id=%s tenant_id=%s'",
+						host, port, neon_auth_token, neon_timeline_walproposer, neon_tenant_walproposer);
+
+	return snprintf(buf, size,
+					"host=%s port=%s dbname=replication options='-c timeline_id=%s tenant_id=%s'",
+					host, port, neon_timeline_walproposer, neon_tenant_walproposer);
+}
+
+/*
+ * Read the membership configuration of the safekeeper from the message.
+ */
+static void
+ReadConfiguration(StringInfo s, MembershipConfiguration * conf)
+{
+	conf->generation = pq_getmsgint32_le(s);
+	ReadMemberSet(s, &conf->members);
+	/* joint configuration flag, followed by the new members */
+	conf->joint = pq_getmsgbyte(s) != 0;
+	if (conf->joint)
+		ReadMemberSet(s, &conf->newMembers);
+	else
+		conf->newMembers.n_members = 0;
+}
+
+static void
+ReadMemberSet(StringInfo s, MemberSet * set)
+{
+	set->n_members = pq_getmsgint32_le(s);
+	if (set->n_members > MAX_SAFEKEEPERS)
+		elog(FATAL, "membership configuration has too many members: %u", set->n_members);
+
+	for (uint32 i = 0; i < set->n_members; i++)
+	{
+		set->ids[i] = pq_getmsgint64_le(s);
+		pq_getmsgbytes(s, pq_getmsgint32_le(s));	/* postgres address, unused */
+	}
+}
+
+/*
+ * Read the logical replication slots kept by the safekeeper, serialized the
+ * same way UpdateLogicalSlots does.
+ */
+static void
+ReadLogicalSlots(StringInfo s, VoteResponse * msg)
+{
+	/* name length, plugin length, database, catalog_xmin and two LSNs */
+	const int	minSlotSize = 4 + 4 + 4 + 4 + 8 + 8;
+
+	msg->nLogicalSlots = pq_getmsgint32_le(s);
+	if (msg->nLogicalSlots > (s->len - s->cursor) / minSlotSize)
+		elog(FATAL, "vote response has too many logical slots: %u", msg->nLogicalSlots);
+
+	msg->logicalSlots = palloc(sizeof(LogicalSlotState) * msg->nLogicalSlots);
+	for (uint32 i = 0; i < msg->nLogicalSlots; i++)
+	{
+		LogicalSlotState *slot = &msg->logicalSlots[i];
+
+		ReadName(s, &slot->name);
+		ReadName(s, &slot->plugin);
+		slot->database = pq_getmsgint32_le(s);
+		slot->catalogXmin = pq_getmsgint32_le(s);
+		slot->restartLsn = pq_getmsgint64_le(s);
+		slot->confirmedFlushLsn = pq_getmsgint64_le(s);
+	}
+}
+
+static void
+ReadName(StringInfo s, NameData *name)
+{
+	uint32		len = pq_getmsgint32_le(s);
+
+	if (len >= NAMEDATALEN)
+		elog(FATAL, "logical slot name of %u bytes is too long", len);
+	memset(name, 0, sizeof(NameData));
+	memcpy(NameStr(*name), pq_getmsgbytes(s, len), len);
+}
+
 /*
  * Blocking equivalent to AsyncWrite.
  *
@@ -11,7 +11,7 @@
 #include "replication/walreceiver.h"
 
 #define SK_MAGIC 0xCafeCeefu
-#define SK_PROTOCOL_VERSION 2
+#define SK_PROTOCOL_VERSION 3
 
 #define MAX_SAFEKEEPERS 32
 #define MAX_SEND_SIZE (XLOG_BLCKSZ * 16)	/* max size of a single* WAL
@@ -158,6 +158,38 @@ typedef uint64 term_t;
 /* neon storage node id */
 typedef uint64 NNodeId;
 
+/* Safekeepers of a membership configuration, identified by their node ids. */
+typedef struct MemberSet
+{
+	uint32		n_members;
+	NNodeId		ids[MAX_SAFEKEEPERS];
+}			MemberSet;
+
+/*
+ * Membership configuration of the timeline safekeepers. While the
+ * configuration is joint, both the old and the new member sets have to agree
+ * to elect the proposer and to commit WAL. Generation 0 stands for the
+ * timelines without a configuration, where neon.safekeepers is the member set.
+ */
+typedef struct MembershipConfiguration
+{
+	uint32		generation;
+	MemberSet	members;
+	bool		joint;
+	MemberSet	newMembers;		/* valid only if joint */
+}			MembershipConfiguration;
+
+/* Logical replication slot of the compute, as kept by the safekeepers. */
+typedef struct LogicalSlotState
+{
+	NameData	name;
+	NameData	plugin;
+	Oid			database;
+	TransactionId catalogXmin;
+	XLogRecPtr	restartLsn;
+	XLogRecPtr	confirmedFlushLsn;
+}			LogicalSlotState;
+
 /*
  * Proposer <-> Acceptor messaging.
  */
@@ -189,6 +221,7 @@ typedef struct AcceptorGreeting
 	AcceptorProposerMessage apm;
 	term_t		term;
 	NNodeId		nodeId;
+	MembershipConfiguration mconf;	/* acceptor's membership configuration */
 }			AcceptorGreeting;
 
 /*
@@ -199,6 +232,7 @@ typedef struct VoteRequest
 	uint64		tag;
 	term_t		term;
 	pg_uuid_t	proposerId;		/* for monitoring/debugging */
+	uint32		generation;		/* membership configuration generation */
 }			VoteRequest;
 
 /* Element of term switching chain. */
@@ -230,6 +264,9 @@ typedef struct VoteResponse
 								 * recovery of some safekeeper */
 	TermHistory termHistory;
 	XLogRecPtr	timelineStartLsn;	/* timeline globally starts at this LSN */
+	MembershipConfiguration mconf;	/* acceptor's membership configuration */
+	uint32		nLogicalSlots;
+	LogicalSlotState *logicalSlots; /* logical slots to restore on the compute */
 }			VoteResponse;
 
 /*
@@ -367,6 +404,8 @@ typedef struct Safekeeper
 								 * to flush pending messages */
 	XLogRecPtr	streamingAt;	/* current streaming position */
 	AppendRequestHeader appendRequest;	/* request for sending to safekeeper */
+	uint64		logicalSlotsSent;	/* version of the logical slots sent to*
+									 * the safekeeper */
 
 	int			eventPos;		/* position in wait event set. Equal to -1 if*
 								 * no event */
@@ -62,6 +62,34 @@ impl AuthError {
     pub fn bad_auth_method(name: impl Into<Box<str>>) -> Self {
         AuthErrorImpl::BadAuthMethod(name.into()).into()
     }
+
+    /// Whether the client failed to prove that it knows the password.
+    pub fn is_auth_failed(&self) -> bool {
+        matches!(
+            self.0.as_ref(),
+            AuthErrorImpl::Sasl(crate::sasl::Error::AuthenticationFailed(_))
+        )
+    }
+
+    /// Whether the request was rejected due to the console request rate limits.
+    pub fn is_rate_limited(&self) -> bool {
+        use AuthErrorImpl::*;
+        matches!(
+            self.0.as_ref(),
+            GetAuthInfo(backend::GetAuthInfoError::RateLimited(_))
+                | WakeCompute(backend::WakeComputeError::RateLimited(_))
+        )
+    }
+
+    /// Whether the error is caused by the client's request
+    /// rather than by the console or the proxy itself.
+    pub fn is_client_error(&self) -> bool {
+        use AuthErrorImpl::*;
+        matches!(
+            self.0.as_ref(),
+            BadAuthMethod(_) | MalformedPassword(_) | MissingProjectName
+        )
+    }
 }
 
 impl<E: Into<AuthErrorImpl>> From<E> for AuthError {
@@ -4,7 +4,10 @@ mod link;
 pub use link::LinkAuthError;
 
 mod console;
-pub use console::{GetAuthInfoError, WakeComputeError};
+pub use console::{
+    configure_caches, invalidate_auth_info, invalidate_node_info, GetAuthInfoError,
+    WakeComputeError,
+};
 
 use crate::{
     auth::{self, AuthFlow, ClientCredentials},
@@ -80,6 +83,8 @@ pub struct ConsoleReqExtra<'a> {
     pub session_id: uuid::Uuid,
     /// Name of client application, if set.
     pub application_name: Option<&'a str>,
+    /// Client's address, used to throttle the requests; it isn't sent to the console.
+    pub peer_ip: Option<std::net::IpAddr>,
 }
 
 /// This type serves two purposes:
@@ -158,15 +163,24 @@ impl<'a, T, E> BackendType<'a, Result<T, E>> {
 }
 
 impl BackendType<'_, ClientCredentials<'_>> {
+    /// The project name, if the client has provided it before the authentication.
+    pub fn project(&self) -> Option<&str> {
+        use BackendType::*;
+        match self {
+            Console(_, creds) | Postgres(_, creds) => creds.project(),
+            Link(_) => None,
+        }
+    }
+
     /// Authenticate the client via the requested backend, possibly using credentials.
     pub async fn authenticate(
-        mut self,
+        &mut self,
         extra: &ConsoleReqExtra<'_>,
-        client: &mut stream::PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
+        client: &mut stream::PqStream<stream::Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
     ) -> super::Result<compute::NodeInfo> {
         use BackendType::*;
 
-        if let Console(_, creds) | Postgres(_, creds) = &mut self {
+        if let Console(_, creds) | Postgres(_, creds) = self {
             // If there's no project so far, that entails that client doesn't
             // support SNI or other means of passing the project name.
             // We now expect to see a very specific payload in the place of password.
@@ -181,41 +195,92 @@ impl BackendType<'_, ClientCredentials<'_>> {
                 // TODO: add missing type safety to ClientCredentials.
                 creds.project = Some(payload.project.into());
 
-                let mut config = match &self {
-                    Console(endpoint, creds) => {
-                        console::Api::new(endpoint, extra, creds)
-                            .wake_compute()
-                            .await?
-                    }
-                    Postgres(endpoint, creds) => {
-                        postgres::Api::new(endpoint, creds).wake_compute().await?
-                    }
-                    _ => unreachable!("see the patterns above"),
-                };
-
                 // We should use a password from payload as well.
-                config.password(payload.password);
-
-                return Ok(compute::NodeInfo {
-                    reported_auth_ok: false,
-                    config,
-                });
+                return self
+                    .wake_compute_with_password(extra, &payload.password)
+                    .await;
             }
         }
 
-        match self {
+        match &*self {
             Console(endpoint, creds) => {
-                console::Api::new(&endpoint, extra, &creds)
+                console::Api::new(endpoint, extra, creds)
                     .handle_user(client)
                     .await
             }
             Postgres(endpoint, creds) => {
-                postgres::Api::new(&endpoint, &creds)
+                postgres::Api::new(endpoint, creds)
                     .handle_user(client)
                     .await
             }
             // NOTE: this auth backend doesn't use client credentials.
-            Link(url) => link::handle_user(&url, client).await,
+            Link(url) => link::handle_user(url, client).await,
         }
     }
+
+    /// Ask for the compute address once again, bypassing the cache, e.g. if we
+    /// can't connect to the compute node. Returns [`None`] if the backend can't
+    /// tell us the address without the client's involvement.
+    pub async fn wake_compute(
+        &self,
+        extra: &ConsoleReqExtra<'_>,
+    ) -> Result<Option<compute::ComputeConnCfg>, WakeComputeError> {
+        use BackendType::*;
+
+        match self {
+            Console(endpoint, creds) => match creds.project() {
+                Some(project) => {
+                    invalidate_node_info(project);
+                    let config = console::Api::new(endpoint, extra, creds)
+                        .wake_compute()
+                        .await?;
+                    Ok(Some(config))
+                }
+                None => Ok(None),
+            },
+            Postgres(endpoint, creds) => {
+                let config = postgres::Api::new(endpoint, creds).wake_compute().await?;
+                Ok(Some(config))
+            }
+            Link(_) => Ok(None),
+        }
+    }
+
+    /// Wake up the compute node for a client which has already supplied its
+    /// cleartext password. The proxy doesn't check the password: the compute
+    /// node will, since we pass it on in the connection config.
+    pub async fn wake_compute_with_password(
+        &self,
+        extra: &ConsoleReqExtra<'_>,
+        password: &[u8],
+    ) -> super::Result<compute::NodeInfo> {
+        use BackendType::*;
+
+        let (mut config, project) = match self {
+            Console(endpoint, creds) => {
+                let project = creds
+                    .project()
+                    .ok_or(auth::AuthErrorImpl::MissingProjectName)?;
+                let config = console::Api::new(endpoint, extra, creds)
+                    .wake_compute()
+                    .await?;
+                (config, Some(project.to_owned()))
+            }
+            Postgres(endpoint, creds) => {
+                let config = postgres::Api::new(endpoint, creds).wake_compute().await?;
+                (config, None)
+            }
+            // The user is supposed to authenticate in the browser instead.
+            Link(_) => return Err(auth::AuthError::bad_auth_method("password")),
+        };
+
+        config.password(password);
+
+        Ok(compute::NodeInfo {
+            reported_auth_ok: false,
+            config,
+            project,
+            address: None,
+        })
+    }
 }
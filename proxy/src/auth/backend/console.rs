@@ -3,11 +3,15 @@
 use super::ConsoleReqExtra;
 use crate::{
     auth::{self, AuthFlow, ClientCredentials},
+    cache::{CacheOptions, TimedCache},
     compute::{self, ComputeConnCfg},
     error::{io_error, UserFacingError},
-    http, scram,
-    stream::PqStream,
+    http,
+    rate_limit::{self, RateLimitError},
+    scram,
+    stream::{PqStream, Stream},
 };
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use thiserror::Error;
@@ -53,6 +57,9 @@ pub enum GetAuthInfoError {
 
     #[error(transparent)]
     Transport(TransportError),
+
+    #[error(transparent)]
+    RateLimited(RateLimitError),
 }
 
 impl UserFacingError for GetAuthInfoError {
@@ -61,6 +68,7 @@ impl UserFacingError for GetAuthInfoError {
         match self {
             BadSecret => REQUEST_FAILED.to_owned(),
             Transport(e) => e.to_string_client(),
+            RateLimited(e) => e.to_string_client(),
         }
     }
 }
@@ -79,6 +87,9 @@ pub enum WakeComputeError {
 
     #[error(transparent)]
     Transport(TransportError),
+
+    #[error(transparent)]
+    RateLimited(RateLimitError),
 }
 
 impl UserFacingError for WakeComputeError {
@@ -87,6 +98,7 @@ impl UserFacingError for WakeComputeError {
         match self {
             BadComputeAddress(_) => REQUEST_FAILED.to_owned(),
             Transport(e) => e.to_string_client(),
+            RateLimited(e) => e.to_string_client(),
         }
     }
 }
@@ -110,6 +122,7 @@ struct GetWakeComputeResponse {
 }
 
 /// Auth secret which is managed by the cloud.
+#[derive(Clone)]
 pub enum AuthInfo {
     /// Md5 hash of user's password.
     Md5([u8; 16]),
@@ -118,6 +131,47 @@ pub enum AuthInfo {
     Scram(scram::ServerSecret),
 }
 
+/// Caches of the console responses, keyed by project and role.
+/// They save the round trips to the console on connection storms.
+struct ApiCaches {
+    auth_info: TimedCache<(String, String), AuthInfo>,
+    /// Compute addresses, without the database and the user.
+    node_info: TimedCache<String, ComputeConnCfg>,
+}
+
+static API_CACHES: OnceCell<ApiCaches> = OnceCell::new();
+
+/// Enable the caches of the console responses. Without this, every connection
+/// asks the console for the auth secret and for the compute address.
+pub fn configure_caches(auth_info: CacheOptions, node_info: CacheOptions) -> anyhow::Result<()> {
+    let caches = ApiCaches {
+        auth_info: TimedCache::new("auth_info", auth_info),
+        node_info: TimedCache::new("node_info", node_info),
+    };
+    API_CACHES
+        .set(caches)
+        .map_err(|_| anyhow::anyhow!("console caches are already configured"))
+}
+
+/// Drop the cached compute address of the project, e.g. if it's no longer reachable.
+pub fn invalidate_node_info(project: &str) -> bool {
+    match API_CACHES.get() {
+        Some(caches) => caches.node_info.invalidate(&project.to_owned()),
+        None => false,
+    }
+}
+
+/// Drop the cached auth secret of the role, or of all roles of the project if `role`
+/// is `None`. Returns the number of dropped entries.
+pub fn invalidate_auth_info(project: &str, role: Option<&str>) -> usize {
+    match API_CACHES.get() {
+        Some(caches) => caches
+            .auth_info
+            .invalidate_matching(|(p, r)| p == project && role.map_or(true, |role| r == role)),
+        None => 0,
+    }
+}
+
 #[must_use]
 pub(super) struct Api<'a> {
     endpoint: &'a http::Endpoint,
@@ -142,12 +196,49 @@ impl<'a> Api<'a> {
     /// Authenticate the existing user or throw an error.
     pub(super) async fn handle_user(
         self,
-        client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
+        client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
     ) -> auth::Result<compute::NodeInfo> {
-        handle_user(client, &self, Self::get_auth_info, Self::wake_compute).await
+        let project = self.creds.project().expect("impossible");
+        let mut node = handle_user(client, &self, Self::get_auth_info, Self::wake_compute)
+            .await
+            .map_err(|e| {
+                // The cached secret may be outdated if the password has been changed.
+                if e.is_auth_failed() {
+                    invalidate_auth_info(project, Some(self.creds.user));
+                }
+                e
+            })?;
+        node.project = Some(project.to_owned());
+        Ok(node)
+    }
+
+    /// Throttle the requests which actually reach the console, i.e. the cache misses.
+    fn check_rate_limit(&self) -> Result<(), RateLimitError> {
+        let project = self.creds.project().expect("impossible");
+        rate_limit::check_console_request(project, self.extra.peer_ip)
     }
 
     async fn get_auth_info(&self) -> Result<AuthInfo, GetAuthInfoError> {
+        let caches = API_CACHES.get();
+        let key = (
+            self.creds.project().expect("impossible").to_owned(),
+            self.creds.user.to_owned(),
+        );
+        if let Some(auth_info) = caches.and_then(|caches| caches.auth_info.get(&key)) {
+            return Ok(auth_info);
+        }
+
+        self.check_rate_limit()
+            .map_err(GetAuthInfoError::RateLimited)?;
+        let auth_info = self.do_get_auth_info().await?;
+        if let Some(caches) = caches {
+            caches.auth_info.insert(key, auth_info.clone());
+        }
+
+        Ok(auth_info)
+    }
+
+    async fn do_get_auth_info(&self) -> Result<AuthInfo, GetAuthInfoError> {
         let req = self
             .endpoint
             .get("proxy_get_role_secret")
@@ -177,6 +268,27 @@ impl<'a> Api<'a> {
 
     /// Wake up the compute node and return the corresponding connection info.
     pub(super) async fn wake_compute(&self) -> Result<ComputeConnCfg, WakeComputeError> {
+        let caches = API_CACHES.get();
+        let key = self.creds.project().expect("impossible").to_owned();
+        let mut config = match caches.and_then(|caches| caches.node_info.get(&key)) {
+            Some(config) => config,
+            None => {
+                self.check_rate_limit()
+                    .map_err(WakeComputeError::RateLimited)?;
+                let config = self.do_wake_compute().await?;
+                if let Some(caches) = caches {
+                    caches.node_info.insert(key, config.clone());
+                }
+                config
+            }
+        };
+
+        config.dbname(self.creds.dbname).user(self.creds.user);
+
+        Ok(config)
+    }
+
+    async fn do_wake_compute(&self) -> Result<ComputeConnCfg, WakeComputeError> {
         let req = self
             .endpoint
             .get("proxy_wake_compute")
@@ -205,11 +317,7 @@ impl<'a> Api<'a> {
         };
 
         let mut config = ComputeConnCfg::new();
-        config
-            .host(host)
-            .port(port)
-            .dbname(self.creds.dbname)
-            .user(self.creds.user);
+        config.host(host).port(port);
 
         Ok(config)
     }
@@ -218,7 +326,7 @@ impl<'a> Api<'a> {
 /// Common logic for user handling in API V2.
 /// We reuse this for a mock API implementation in [`super::postgres`].
 pub(super) async fn handle_user<'a, Endpoint, GetAuthInfo, WakeCompute>(
-    client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin>,
+    client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin>>,
     endpoint: &'a Endpoint,
     get_auth_info: impl FnOnce(&'a Endpoint) -> GetAuthInfo,
     wake_compute: impl FnOnce(&'a Endpoint) -> WakeCompute,
@@ -252,6 +360,8 @@ where
     Ok(compute::NodeInfo {
         reported_auth_ok: false,
         config,
+        project: None,
+        address: None,
     })
 }
 
@@ -70,5 +70,7 @@ pub async fn handle_user(
     Ok(compute::NodeInfo {
         reported_auth_ok: true,
         config: db_info.into(),
+        project: None,
+        address: None,
     })
 }
@@ -9,7 +9,7 @@ use crate::{
     compute::{self, ComputeConnCfg},
     error::io_error,
     scram,
-    stream::PqStream,
+    stream::{PqStream, Stream},
     url::ApiUrl,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -36,7 +36,7 @@ impl<'a> Api<'a> {
     /// Authenticate the existing user or throw an error.
     pub(super) async fn handle_user(
         self,
-        client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
+        client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
     ) -> auth::Result<compute::NodeInfo> {
         // We reuse user handling logic from a production module.
         console::handle_user(client, &self, Self::get_auth_info, Self::wake_compute).await
@@ -2,6 +2,7 @@
 
 use crate::error::UserFacingError;
 use std::borrow::Cow;
+use std::collections::HashSet;
 use thiserror::Error;
 use utils::pq_proto::StartupMessageParams;
 
@@ -14,10 +15,10 @@ pub enum ClientCredsParseError {
     InconsistentProjectNames(String, String),
 
     #[error(
-        "SNI ('{1}') inconsistently formatted with respect to common name ('{0}'). \
-        SNI should be formatted as '<project-name>.{0}'."
+        "SNI ('{0}') doesn't match any of the domains served by the proxy. \
+        SNI should be formatted as '<project-name>.<domain>'."
     )]
-    InconsistentSni(String, String),
+    UnknownSni(String),
 
     #[error("Project name ('{0}') must contain only alphanumeric characters and hyphen.")]
     MalformedProjectName(String),
@@ -44,7 +45,7 @@ impl<'a> ClientCredentials<'a> {
     pub fn parse(
         params: &'a StartupMessageParams,
         sni: Option<&str>,
-        common_name: Option<&str>,
+        common_names: Option<&HashSet<String>>,
     ) -> Result<Self, ClientCredsParseError> {
         use ClientCredsParseError::*;
 
@@ -61,12 +62,17 @@ impl<'a> ClientCredentials<'a> {
         });
 
         // Alternative project name is in fact a subdomain from SNI.
-        // NOTE: we do not consider SNI if `common_name` is missing.
+        // NOTE: we do not consider SNI if `common_names` is missing.
         let project_b = sni
-            .zip(common_name)
-            .map(|(sni, cn)| {
-                subdomain_from_sni(sni, cn)
-                    .ok_or_else(|| InconsistentSni(sni.into(), cn.into()))
+            .zip(common_names)
+            .map(|(sni, common_names)| {
+                // The most specific domain wins, e.g. if we serve both
+                // `example.com` and `eu.example.com`.
+                common_names
+                    .iter()
+                    .filter_map(|cn| subdomain_from_sni(sni, cn))
+                    .min_by_key(|subdomain| subdomain.len())
+                    .ok_or_else(|| UnknownSni(sni.into()))
                     .map(Cow::<'static, str>::Owned)
             })
             .transpose()?;
@@ -134,9 +140,9 @@ mod tests {
         let options = StartupMessageParams::new([("user", "john_doe"), ("database", "world")]);
 
         let sni = Some("foo.localhost");
-        let common_name = Some("localhost");
+        let common_names = HashSet::from(["localhost".to_owned()]);
 
-        let creds = ClientCredentials::parse(&options, sni, common_name)?;
+        let creds = ClientCredentials::parse(&options, sni, Some(&common_names))?;
         assert_eq!(creds.user, "john_doe");
         assert_eq!(creds.dbname, "world");
         assert_eq!(creds.project.as_deref(), Some("foo"));
@@ -169,9 +175,9 @@ mod tests {
         ]);
 
         let sni = Some("baz.localhost");
-        let common_name = Some("localhost");
+        let common_names = HashSet::from(["localhost".to_owned()]);
 
-        let creds = ClientCredentials::parse(&options, sni, common_name)?;
+        let creds = ClientCredentials::parse(&options, sni, Some(&common_names))?;
         assert_eq!(creds.user, "john_doe");
         assert_eq!(creds.dbname, "world");
         assert_eq!(creds.project.as_deref(), Some("baz"));
@@ -188,11 +194,39 @@ mod tests {
         ]);
 
         let sni = Some("second.localhost");
-        let common_name = Some("localhost");
+        let common_names = HashSet::from(["localhost".to_owned()]);
 
         assert!(matches!(
-            ClientCredentials::parse(&options, sni, common_name).expect_err("should fail"),
+            ClientCredentials::parse(&options, sni, Some(&common_names)).expect_err("should fail"),
             ClientCredsParseError::InconsistentProjectNames(_, _)
         ));
     }
+
+    #[test]
+    fn parse_project_from_sni_many_domains() -> anyhow::Result<()> {
+        let options = StartupMessageParams::new([("user", "john_doe"), ("database", "world")]);
+
+        let common_names = HashSet::from([
+            "example.com".to_owned(),
+            "eu.example.com".to_owned(),
+            "localhost".to_owned(),
+        ]);
+
+        for (sni, project) in [
+            ("foo.example.com", "foo"),
+            ("bar.eu.example.com", "bar"),
+            ("baz.localhost", "baz"),
+        ] {
+            let creds = ClientCredentials::parse(&options, Some(sni), Some(&common_names))?;
+            assert_eq!(creds.project.as_deref(), Some(project));
+        }
+
+        assert!(matches!(
+            ClientCredentials::parse(&options, Some("foo.unknown.org"), Some(&common_names))
+                .expect_err("should fail"),
+            ClientCredsParseError::UnknownSni(_)
+        ));
+
+        Ok(())
+    }
 }
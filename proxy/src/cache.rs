@@ -0,0 +1,298 @@
+//! Caches of the console responses, bounded both in size and in time.
+
+use anyhow::{bail, ensure, Context};
+use metrics::{register_int_counter_vec, IntCounterVec};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+static CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_console_cache_requests_total",
+        "Number of lookups in the caches of the console responses.",
+        &["cache", "outcome"]
+    )
+    .unwrap()
+});
+
+/// Size and time bounds of a [`TimedCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// Max number of entries, zero disables the cache.
+    pub size: usize,
+    /// Time to live of an entry.
+    pub ttl: Duration,
+}
+
+/// Parses the options from a string like `size=4000,ttl=5m`.
+impl std::str::FromStr for CacheOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> anyhow::Result<Self> {
+        let mut size = None;
+        let mut ttl = None;
+
+        for option in options.split(',') {
+            let (key, value) = option
+                .split_once('=')
+                .with_context(|| format!("bad key-value pair: {option}"))?;
+
+            match key {
+                "size" => size = Some(value.parse().context("bad cache size")?),
+                "ttl" => ttl = Some(humantime::parse_duration(value).context("bad cache ttl")?),
+                unknown => bail!("unknown key: {unknown}"),
+            }
+        }
+
+        let options = Self {
+            size: size.context("missing `size`")?,
+            ttl: ttl.context("missing `ttl`")?,
+        };
+        ensure!(
+            options.size == 0 || !options.ttl.is_zero(),
+            "ttl of the enabled cache must be positive"
+        );
+
+        Ok(options)
+    }
+}
+
+impl std::fmt::Display for CacheOptions {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "size={},ttl={}",
+            self.size,
+            humantime::format_duration(self.ttl)
+        )
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    created_at: Instant,
+}
+
+struct Inner<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    /// Keys in the order of insertion, which is also the order of expiration.
+    /// A key may appear here several times if it was re-inserted; only the
+    /// occurrence matching the entry's `created_at` is current.
+    order: VecDeque<(K, Instant)>,
+}
+
+impl<K: Hash + Eq, V> Inner<K, V> {
+    /// Removes the entry of the oldest occurrence in `order`, unless the entry
+    /// has been re-inserted since. Returns false if `order` is empty.
+    fn pop_oldest(&mut self) -> bool {
+        match self.order.pop_front() {
+            Some((key, created_at)) => {
+                if matches!(self.entries.get(&key), Some(e) if e.created_at == created_at) {
+                    self.entries.remove(&key);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A map whose entries expire after the fixed time to live. Once the cache
+/// is full, inserting a new key evicts the oldest entry.
+pub struct TimedCache<K, V> {
+    /// Used in the metrics.
+    name: &'static str,
+    options: CacheOptions,
+    inner: Mutex<Inner<K, V>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TimedCache<K, V> {
+    pub fn new(name: &'static str, options: CacheOptions) -> Self {
+        Self {
+            name,
+            options,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Returns the value of the key, unless it's missing or expired.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let value = {
+            let mut inner = self.inner.lock();
+            match inner.entries.get(key) {
+                Some(e) if e.created_at.elapsed() < self.options.ttl => Some(e.value.clone()),
+                Some(_) => {
+                    inner.entries.remove(key);
+                    None
+                }
+                None => None,
+            }
+        };
+
+        let outcome = if value.is_some() { "hit" } else { "miss" };
+        CACHE_REQUESTS
+            .with_label_values(&[self.name, outcome])
+            .inc();
+        value
+    }
+
+    /// Inserts the value of the key, resetting its time to live.
+    pub fn insert(&self, key: K, value: V) {
+        if self.options.size == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+
+        // Drop the expired entries first, then the oldest ones to make room.
+        while let Some((_, created_at)) = inner.order.front() {
+            if now.duration_since(*created_at) < self.options.ttl {
+                break;
+            }
+            inner.pop_oldest();
+        }
+        while inner.entries.len() >= self.options.size
+            && !inner.entries.contains_key(&key)
+            && inner.pop_oldest()
+        {}
+
+        inner.order.push_back((key.clone(), now));
+        inner.entries.insert(
+            key,
+            Entry {
+                value,
+                created_at: now,
+            },
+        );
+
+        // Keep re-inserted keys from piling up in the queue.
+        if inner.order.len() > 2 * self.options.size {
+            let Inner { entries, order } = &mut *inner;
+            order.retain(|(key, created_at)| {
+                matches!(entries.get(key), Some(e) if e.created_at == *created_at)
+            });
+        }
+    }
+
+    /// Removes the key, returns true if it was present.
+    pub fn invalidate(&self, key: &K) -> bool {
+        self.inner.lock().entries.remove(key).is_some()
+    }
+
+    /// Removes all keys matching the predicate, returns their number.
+    pub fn invalidate_matching(&self, mut predicate: impl FnMut(&K) -> bool) -> usize {
+        let mut inner = self.inner.lock();
+        let len = inner.entries.len();
+        inner.entries.retain(|key, _| !predicate(key));
+        len - inner.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cache_options() -> anyhow::Result<()> {
+        let options: CacheOptions = "size=4000,ttl=5m".parse()?;
+        assert_eq!(options.size, 4000);
+        assert_eq!(options.ttl, Duration::from_secs(300));
+        assert_eq!(options.to_string().parse::<CacheOptions>()?, options);
+
+        let disabled: CacheOptions = "ttl=0s,size=0".parse()?;
+        assert_eq!(disabled.size, 0);
+
+        assert!("size=4000".parse::<CacheOptions>().is_err());
+        assert!("size=4000,ttl=0s".parse::<CacheOptions>().is_err());
+        assert!("size=4000,ttl=5m,foo=bar".parse::<CacheOptions>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn evict_oldest_when_full() {
+        let cache = TimedCache::new(
+            "test",
+            CacheOptions {
+                size: 2,
+                ttl: Duration::from_secs(60),
+            },
+        );
+
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        // Re-inserting the key doesn't evict anything.
+        cache.insert("a", 3);
+        assert_eq!(cache.get(&"a"), Some(3));
+        assert_eq!(cache.get(&"b"), Some(2));
+
+        // "b" is the oldest entry now.
+        cache.insert("c", 4);
+        assert_eq!(cache.get(&"a"), Some(3));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(4));
+
+        assert!(cache.invalidate(&"c"));
+        assert!(!cache.invalidate(&"c"));
+        assert_eq!(cache.get(&"c"), None);
+    }
+
+    #[test]
+    fn expire_entries() {
+        let cache = TimedCache::new(
+            "test",
+            CacheOptions {
+                size: 10,
+                ttl: Duration::from_millis(10),
+            },
+        );
+
+        cache.insert(("project", "role"), 1);
+        assert_eq!(cache.get(&("project", "role")), Some(1));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&("project", "role")), None);
+    }
+
+    #[test]
+    fn disabled_cache() {
+        let cache = TimedCache::new(
+            "test",
+            CacheOptions {
+                size: 0,
+                ttl: Duration::ZERO,
+            },
+        );
+
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn invalidate_matching() {
+        let cache = TimedCache::new(
+            "test",
+            CacheOptions {
+                size: 10,
+                ttl: Duration::from_secs(60),
+            },
+        );
+
+        cache.insert(("p1", "r1"), 1);
+        cache.insert(("p1", "r2"), 2);
+        cache.insert(("p2", "r1"), 3);
+        assert_eq!(
+            cache.invalidate_matching(|(project, _)| *project == "p1"),
+            2
+        );
+        assert_eq!(cache.get(&("p1", "r1")), None);
+        assert_eq!(cache.get(&("p2", "r1")), Some(3));
+    }
+}
@@ -1,24 +1,169 @@
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
 use hashbrown::HashMap;
 use parking_lot::Mutex;
-use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, time::Duration};
 use tokio::net::TcpStream;
 use tokio_postgres::{CancelToken, NoTls};
 use utils::pq_proto::CancelKeyData;
 
+/// Identifies the proxy instance which has issued a cancel key,
+/// so that the other instances could route the `CancelRequest`s to it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InstanceId(pub u16);
+
+/// Don't keep the client waiting for an unresponsive peer.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl InstanceId {
+    /// Generate a random key carrying the instance id in the upper half of `backend_pid`.
+    /// The `cancel_key` the clients have to present stays entirely random.
+    fn generate_key(self) -> CancelKeyData {
+        let backend_pid = ((self.0 as u32) << 16) | (rand::random::<u16>() as u32);
+        CancelKeyData {
+            backend_pid: backend_pid as i32,
+            cancel_key: rand::random(),
+        }
+    }
+
+    /// The instance which has issued the key.
+    fn of_key(key: &CancelKeyData) -> Self {
+        Self((key.backend_pid as u32 >> 16) as u16)
+    }
+}
+
+impl std::str::FromStr for InstanceId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let id = s.parse().with_context(|| format!("bad instance id: {s}"))?;
+        Ok(Self(id))
+    }
+}
+
+impl std::fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Delivers the `CancelRequest`s to the proxy instances which have issued the keys.
+#[async_trait]
+pub trait CancelForwarder: Send + Sync {
+    async fn forward(&self, instance: InstanceId, key: CancelKeyData) -> anyhow::Result<()>;
+}
+
+/// Body of a `CancelRequest` forwarded via the http listener.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForwardedCancelRequest {
+    pub backend_pid: i32,
+    pub cancel_key: i32,
+}
+
+impl From<ForwardedCancelRequest> for CancelKeyData {
+    fn from(req: ForwardedCancelRequest) -> Self {
+        Self {
+            backend_pid: req.backend_pid,
+            cancel_key: req.cancel_key,
+        }
+    }
+}
+
+/// Forwards the `CancelRequest`s to the `/v1/cancel` endpoint
+/// of the other proxy instances' internal http listeners.
+pub struct HttpCancelForwarder {
+    client: reqwest::Client,
+    /// Addresses of the internal http listeners of the other instances.
+    peers: HashMap<InstanceId, String>,
+}
+
+impl HttpCancelForwarder {
+    pub fn new(peers: HashMap<InstanceId, String>) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(FORWARD_TIMEOUT)
+            .build()
+            .context("failed to build the http client")?;
+
+        Ok(Self { client, peers })
+    }
+
+    /// Parse a peer from a string like `<instance-id>=<host>:<port>`.
+    pub fn parse_peer(peer: &str) -> anyhow::Result<(InstanceId, String)> {
+        let (instance, address) = peer
+            .split_once('=')
+            .with_context(|| format!("bad peer: {peer}"))?;
+
+        Ok((instance.parse()?, address.to_owned()))
+    }
+}
+
+#[async_trait]
+impl CancelForwarder for HttpCancelForwarder {
+    async fn forward(&self, instance: InstanceId, key: CancelKeyData) -> anyhow::Result<()> {
+        let address = self
+            .peers
+            .get(&instance)
+            .with_context(|| format!("unknown proxy instance: {instance}"))?;
+
+        let body = ForwardedCancelRequest {
+            backend_pid: key.backend_pid,
+            cancel_key: key.cancel_key,
+        };
+        self.client
+            .post(format!("http://{address}/v1/cancel"))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
 /// Enables serving `CancelRequest`s.
 #[derive(Default)]
-pub struct CancelMap(Mutex<HashMap<CancelKeyData, Option<CancelClosure>>>);
+pub struct CancelMap {
+    /// Encoded in the keys of the sessions of this proxy instance.
+    instance_id: InstanceId,
+    sessions: Mutex<HashMap<CancelKeyData, Option<CancelClosure>>>,
+    /// Routes the keys issued by the other instances, if any.
+    forwarder: Option<Box<dyn CancelForwarder>>,
+}
 
 impl CancelMap {
-    /// Cancel a running query for the corresponding connection.
+    pub fn new(instance_id: InstanceId, forwarder: Option<Box<dyn CancelForwarder>>) -> Self {
+        Self {
+            instance_id,
+            sessions: Default::default(),
+            forwarder,
+        }
+    }
+
+    /// Cancel a running query for the corresponding connection,
+    /// which might belong to another proxy instance.
     pub async fn cancel_session(&self, key: CancelKeyData) -> anyhow::Result<()> {
+        let instance = InstanceId::of_key(&key);
+        if instance == self.instance_id {
+            return self.cancel_local_session(key).await;
+        }
+
+        match &self.forwarder {
+            Some(forwarder) => forwarder.forward(instance, key).await,
+            None => bail!("unknown session: {:?}", key),
+        }
+    }
+
+    /// Cancel a running query for the connection of this proxy instance.
+    /// Requests forwarded by other instances end up here, so they never loop.
+    pub async fn cancel_local_session(&self, key: CancelKeyData) -> anyhow::Result<()> {
         let cancel_closure = self
-            .0
+            .sessions
             .lock()
             .get(&key)
-            .and_then(|x| x.clone())
-            .with_context(|| format!("unknown session: {:?}", key))?;
+            .with_context(|| format!("unknown session: {:?}", key))?
+            .clone()
+            .with_context(|| format!("session isn't connected yet: {:?}", key))?;
 
         cancel_closure.try_cancel_query().await
     }
@@ -34,11 +179,11 @@ impl CancelMap {
         // for it. The client will be able to notice that this is not the
         // actual backend_pid, but backend_pid is not used for anything
         // so it doesn't matter.
-        let key = rand::random();
+        let key = self.instance_id.generate_key();
 
         // Random key collisions are unlikely to happen here, but they're still possible,
         // which is why we have to take care not to rewrite an existing key.
-        self.0
+        self.sessions
             .lock()
             .try_insert(key, None)
             .map_err(|_| anyhow!("session already exists: {:?}", key))?;
@@ -46,7 +191,7 @@ impl CancelMap {
         // This will guarantee that the session gets dropped
         // as soon as the future is finished.
         scopeguard::defer! {
-            self.0.lock().remove(&key);
+            self.sessions.lock().remove(&key);
         }
 
         let session = Session::new(key, self);
@@ -55,12 +200,12 @@ impl CancelMap {
 
     #[cfg(test)]
     fn contains(&self, session: &Session) -> bool {
-        self.0.lock().contains_key(&session.key)
+        self.sessions.lock().contains_key(&session.key)
     }
 
     #[cfg(test)]
     fn is_empty(&self) -> bool {
-        self.0.lock().is_empty()
+        self.sessions.lock().is_empty()
     }
 }
 
@@ -106,11 +251,21 @@ impl<'a> Session<'a> {
     /// Store the cancel token for the given session.
     /// This enables query cancellation in [`crate::proxy::handshake`].
     pub fn enable_query_cancellation(self, cancel_closure: CancelClosure) -> CancelKeyData {
+        self.set_query_cancellation(Some(cancel_closure));
+        self.key
+    }
+
+    /// Replace the cancel token of the session, e.g. when a pooled session
+    /// moves on to another compute connection, or drop it with `None`.
+    pub fn set_query_cancellation(&self, cancel_closure: Option<CancelClosure>) {
         self.cancel_map
-            .0
+            .sessions
             .lock()
-            .insert(self.key, Some(cancel_closure));
+            .insert(self.key, cancel_closure);
+    }
 
+    /// The user-facing key identifying this session.
+    pub fn key(&self) -> CancelKeyData {
         self.key
     }
 }
@@ -119,6 +274,7 @@ impl<'a> Session<'a> {
 mod tests {
     use super::*;
     use once_cell::sync::Lazy;
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn check_session_drop() -> anyhow::Result<()> {
@@ -149,4 +305,85 @@ mod tests {
 
         Ok(())
     }
+
+    /// Delivers the `CancelRequest`s straight to the other instances' maps.
+    #[derive(Default)]
+    struct LocalForwarder(Mutex<HashMap<InstanceId, Arc<CancelMap>>>);
+
+    #[async_trait]
+    impl CancelForwarder for Arc<LocalForwarder> {
+        async fn forward(&self, instance: InstanceId, key: CancelKeyData) -> anyhow::Result<()> {
+            let cancel_map = self
+                .0
+                .lock()
+                .get(&instance)
+                .cloned()
+                .with_context(|| format!("unknown proxy instance: {instance}"))?;
+
+            cancel_map.cancel_local_session(key).await
+        }
+    }
+
+    #[test]
+    fn instance_id_in_key() {
+        for id in [0, 1, 42, u16::MAX] {
+            let key = InstanceId(id).generate_key();
+            assert_eq!(InstanceId::of_key(&key), InstanceId(id));
+        }
+
+        // All bits of `cancel_key` are random, none of them is spent on the instance id.
+        let bits = (0..64)
+            .map(|_| InstanceId(42).generate_key().cancel_key)
+            .fold(0, |bits, key| bits | key);
+        assert_eq!(bits, -1);
+    }
+
+    #[tokio::test]
+    async fn forward_cancel_request() -> anyhow::Result<()> {
+        let forwarder = Arc::new(LocalForwarder::default());
+        let first = Arc::new(CancelMap::new(
+            InstanceId(1),
+            Some(Box::new(forwarder.clone())),
+        ));
+        let second = Arc::new(CancelMap::new(InstanceId(2), None));
+        forwarder.0.lock().insert(InstanceId(2), second.clone());
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn({
+            let second = second.clone();
+            async move {
+                second
+                    .with_session(|session| async move {
+                        tx.send(session.key()).expect("failed to send");
+                        done_rx.await.ok();
+                        Ok(())
+                    })
+                    .await
+            }
+        });
+
+        let key = rx.await.context("failed to hear from the task")?;
+        assert_eq!(InstanceId::of_key(&key), InstanceId(2));
+
+        // The request has reached the second instance, whose session
+        // doesn't have a compute connection to cancel queries on.
+        let error = first.cancel_session(key).await.expect_err("should fail");
+        assert!(error.to_string().contains("isn't connected yet"), "{error}");
+
+        // Nobody knows about the third instance.
+        let key = InstanceId(3).generate_key();
+        let error = first.cancel_session(key).await.expect_err("should fail");
+        assert!(
+            error.to_string().contains("unknown proxy instance"),
+            "{error}"
+        );
+
+        // Without a forwarder, the foreign keys are unknown.
+        let error = second.cancel_session(key).await.expect_err("should fail");
+        assert!(error.to_string().contains("unknown session"), "{error}");
+
+        done_tx.send(()).ok();
+        task.await?
+    }
 }
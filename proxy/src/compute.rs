@@ -1,12 +1,50 @@
 use crate::{cancellation::CancelClosure, error::UserFacingError};
-use futures::TryFutureExt;
+use futures::{future::BoxFuture, FutureExt, TryFutureExt};
 use itertools::Itertools;
-use std::{io, net::SocketAddr};
+use metrics::{linear_buckets, register_histogram, Histogram};
+use once_cell::sync::Lazy;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 use tokio::net::TcpStream;
-use tokio_postgres::NoTls;
+use tokio_postgres::{error::SqlState, NoTls};
 use utils::pq_proto::StartupMessageParams;
 
+/// Max number of attempts to connect to a compute node which is starting up.
+const MAX_CONNECT_ATTEMPTS: u32 = 10;
+/// The delay between the attempts doubles up to this value.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+const BASE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+static CONNECT_ATTEMPTS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "proxy_compute_connect_attempts",
+        "Number of attempts it took to connect to a compute node.",
+        linear_buckets(1.0, 1.0, MAX_CONNECT_ATTEMPTS as usize).unwrap()
+    )
+    .unwrap()
+});
+
+static CONNECT_WAIT_TIME: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "proxy_compute_connect_wait_seconds",
+        "Time spent waiting for a woken compute node to accept the connection, including the retries."
+    )
+    .unwrap()
+});
+
+static REWAKE_TIME: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "proxy_compute_rewake_seconds",
+        "Time spent asking the console for the address of a compute node which refused the connection."
+    )
+    .unwrap()
+});
+
 #[derive(Debug, Error)]
 pub enum ConnectionError {
     /// This error doesn't seem to reveal any secrets; for instance,
@@ -21,6 +59,30 @@ pub enum ConnectionError {
     FailedToFetchPgVersion,
 }
 
+impl ConnectionError {
+    /// Errors which are expected while the compute node is starting up.
+    fn is_retryable(&self) -> bool {
+        use ConnectionError::*;
+        match self {
+            FailedToConnectToCompute => true,
+            Postgres(err) => match err.code() {
+                Some(code) => code == &SqlState::CANNOT_CONNECT_NOW,
+                // The compute might have closed the connection while starting up.
+                None => std::error::Error::source(err).map_or(false, |e| e.is::<io::Error>()),
+            },
+            FailedToFetchPgVersion => false,
+        }
+    }
+}
+
+/// Exponential backoff before the next attempt to connect.
+fn retry_delay(attempt: u32) -> Duration {
+    std::cmp::min(
+        BASE_RETRY_DELAY * 2u32.saturating_pow(attempt - 1),
+        MAX_RETRY_DELAY,
+    )
+}
+
 impl UserFacingError for ConnectionError {
     fn to_string_client(&self) -> String {
         use ConnectionError::*;
@@ -47,6 +109,12 @@ pub struct NodeInfo {
     pub reported_auth_ok: bool,
     /// Compute node connection params.
     pub config: tokio_postgres::Config,
+    /// Project of the compute node if its address came from the console,
+    /// so that the cached address is dropped if the compute is unreachable.
+    pub project: Option<String>,
+    /// Compute address reported by a repeated `wake_compute`, which takes
+    /// precedence over the one in `config` if the compute has moved.
+    pub address: Option<(String, u16)>,
 }
 
 impl NodeInfo {
@@ -65,6 +133,10 @@ impl NodeInfo {
         // We can't reuse connection establishing logic from `tokio_postgres` here,
         // because it has no means for extracting the underlying socket which we
         // require for our business.
+        if let Some((host, port)) = &self.address {
+            return connect_once(host.as_str(), *port).await;
+        }
+
         let mut connection_error = None;
         let ports = self.config.get_ports();
         let hosts = self.config.get_hosts();
@@ -111,11 +183,17 @@ pub struct PostgresConnection {
 }
 
 impl NodeInfo {
-    /// Connect to a corresponding compute node.
-    pub async fn connect(
+    /// Connect to a corresponding compute node with the client's startup parameters,
+    /// retrying as described in [`NodeInfo::connect_with_retries`].
+    pub async fn connect<W, F>(
         mut self,
         params: &StartupMessageParams,
-    ) -> Result<(PostgresConnection, CancelClosure), ConnectionError> {
+        wake_compute: W,
+    ) -> Result<(PostgresConnection, CancelClosure), ConnectionError>
+    where
+        W: FnMut() -> F,
+        F: Future<Output = Option<ComputeConnCfg>>,
+    {
         if let Some(options) = params.options_raw() {
             // We must drop all proxy-specific parameters.
             #[allow(unstable_name_collisions)]
@@ -148,6 +226,89 @@ impl NodeInfo {
         // Currently, tokio-postgres doesn't allow us to pass
         // arbitrary parameters, but the ones above are a good start.
 
+        self.connect_with_retries(|node| node.establish().boxed(), wake_compute)
+            .await
+    }
+
+    /// Connect to a corresponding compute node with `connect_once`. Since the compute
+    /// might be still starting up after `wake_compute`, we retry the connection a few times;
+    /// if the compute is unreachable, `wake_compute` is called to check if it has moved.
+    /// The new address is kept for the later connections to the compute.
+    pub async fn connect_with_retries<T, C, W, F>(
+        &mut self,
+        mut connect_once: C,
+        mut wake_compute: W,
+    ) -> Result<T, ConnectionError>
+    where
+        C: for<'a> FnMut(&'a NodeInfo) -> BoxFuture<'a, Result<T, ConnectionError>>,
+        W: FnMut() -> F,
+        F: Future<Output = Option<ComputeConnCfg>>,
+    {
+        let started_at = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let result = connect_once(self).await;
+            match result {
+                Err(e) if e.is_retryable() && attempt < MAX_CONNECT_ATTEMPTS => {
+                    println!("failed to connect to compute (attempt {attempt}): {e}");
+                    tokio::time::sleep(retry_delay(attempt)).await;
+                    attempt += 1;
+
+                    // A compute which is still starting up has accepted the connection,
+                    // so there's no point in asking the console for its address again.
+                    if matches!(e, ConnectionError::FailedToConnectToCompute) {
+                        let wake_started_at = Instant::now();
+                        let config = wake_compute().await;
+                        REWAKE_TIME.observe(wake_started_at.elapsed().as_secs_f64());
+
+                        if let Some(config) = config {
+                            self.update_address(&config);
+                        }
+                    }
+                }
+                result => {
+                    CONNECT_ATTEMPTS.observe(attempt as f64);
+                    if result.is_ok() {
+                        CONNECT_WAIT_TIME.observe(started_at.elapsed().as_secs_f64());
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Switch to the address reported by `wake_compute`, if it has changed.
+    fn update_address(&mut self, config: &ComputeConnCfg) {
+        use tokio_postgres::config::Host;
+
+        let host = match config.get_hosts().first() {
+            Some(Host::Tcp(host)) => host,
+            _ => return,
+        };
+        let port = config.get_ports().first().copied().unwrap_or(5432);
+
+        let current = match &self.address {
+            Some((host, port)) => Some((host.as_str(), *port)),
+            None => match (
+                self.config.get_hosts().first(),
+                self.config.get_ports().first(),
+            ) {
+                (Some(Host::Tcp(host)), port) => {
+                    Some((host.as_str(), port.copied().unwrap_or(5432)))
+                }
+                _ => None,
+            },
+        };
+
+        if current != Some((host.as_str(), port)) {
+            println!("compute has moved to {host}:{port}");
+            self.address = Some((host.clone(), port));
+        }
+    }
+
+    /// Connect to a corresponding compute node once, without forwarding any
+    /// client-specific startup parameters, e.g. for a pooled connection.
+    pub async fn establish(&self) -> Result<(PostgresConnection, CancelClosure), ConnectionError> {
         let (socket_addr, mut stream) = self
             .connect_raw()
             .await
@@ -166,4 +327,134 @@ impl NodeInfo {
 
         Ok((db, cancel_closure))
     }
+
+    /// Connect to a corresponding compute node and return a client which
+    /// runs the queries on the proxy's behalf, e.g. for SQL over HTTP.
+    pub async fn connect_client(&self) -> Result<tokio_postgres::Client, ConnectionError> {
+        let (_, stream) = self
+            .connect_raw()
+            .await
+            .map_err(|_| ConnectionError::FailedToConnectToCompute)?;
+
+        // TODO: establish a secure connection to the DB
+        let (client, conn) = self.config.connect_raw(stream, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                println!("compute connection error: {e}");
+            }
+        });
+
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{PqStream, Stream};
+    use bytes::BytesMut;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use utils::pq_proto::{BeMessage as Be, BeParameterStatusMessage};
+
+    /// `ErrorResponse` of a compute which is still starting up.
+    const CANNOT_CONNECT_NOW: &[u8] =
+        b"E\0\0\0\x37SFATAL\0C57P03\0Mthe database system is starting up\0\0";
+
+    /// A mock compute node which rejects the first `starting_up`
+    /// connections with `CANNOT_CONNECT_NOW` and accepts the next one.
+    async fn mock_compute(listener: TcpListener, starting_up: usize) -> anyhow::Result<()> {
+        for attempt in 0..=starting_up {
+            let (socket, _) = listener.accept().await?;
+            let mut stream = PqStream::new(Stream::from_raw(socket));
+            stream.read_startup_packet().await?;
+
+            let mut buf = BytesMut::new();
+            if attempt < starting_up {
+                buf.extend_from_slice(CANNOT_CONNECT_NOW);
+            } else {
+                Be::write(&mut buf, &Be::AuthenticationOk)?;
+                Be::write(
+                    &mut buf,
+                    &Be::ParameterStatus(BeParameterStatusMessage::ServerVersion("15.0")),
+                )?;
+                Be::write(&mut buf, &Be::ReadyForQuery)?;
+            }
+            stream.into_inner().write_all(&buf).await?;
+        }
+
+        Ok(())
+    }
+
+    fn node_info(port: u16) -> NodeInfo {
+        let mut config = ComputeConnCfg::new();
+        config
+            .host("127.0.0.1")
+            .port(port)
+            .user("john_doe")
+            .dbname("earth");
+
+        NodeInfo {
+            reported_auth_ok: false,
+            config,
+            project: None,
+            address: None,
+        }
+    }
+
+    #[test]
+    fn retry_delay_is_bounded() {
+        assert_eq!(retry_delay(1), BASE_RETRY_DELAY);
+        assert_eq!(retry_delay(2), BASE_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(MAX_CONNECT_ATTEMPTS), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn connect_retries_starting_compute() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let compute = tokio::spawn(mock_compute(listener, 2));
+
+        let params = StartupMessageParams::new([("user", "john_doe")]);
+        let mut wakes = 0;
+        let (db, _) = node_info(port)
+            .connect(&params, || {
+                wakes += 1;
+                async { None }
+            })
+            .await?;
+        assert_eq!(db.version, "15.0");
+        // The compute has accepted the connections, it hasn't moved.
+        assert_eq!(wakes, 0);
+
+        compute.await?
+    }
+
+    #[tokio::test]
+    async fn connect_rewakes_unreachable_compute() -> anyhow::Result<()> {
+        // Nobody listens on this port anymore, as if the compute has moved.
+        let refusing_port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let compute = tokio::spawn(mock_compute(listener, 0));
+
+        let params = StartupMessageParams::new([("user", "john_doe")]);
+        let mut wakes = 0;
+        let (db, _) = node_info(refusing_port)
+            .connect(&params, || {
+                wakes += 1;
+                async move {
+                    let mut config = ComputeConnCfg::new();
+                    config.host("127.0.0.1").port(port);
+                    Some(config)
+                }
+            })
+            .await?;
+        assert_eq!(db.version, "15.0");
+        assert_eq!(wakes, 1);
+
+        compute.await?
+    }
 }
@@ -1,45 +1,368 @@
-use crate::auth;
-use anyhow::{ensure, Context};
-use std::sync::Arc;
+use crate::{auth, pool};
+use anyhow::{anyhow, bail, ensure, Context};
+use parking_lot::{Mutex, RwLock};
+use rustls::{server::ClientHello, sign::CertifiedKey};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::signal::unix::{signal, SignalKind};
+use x509_parser::oid_registry;
 
 pub struct ProxyConfig {
     pub tls_config: Option<TlsConfig>,
     pub auth_backend: auth::BackendType<'static, ()>,
+    /// Compute connections shared by the clients in transaction pooling mode.
+    pub connection_pool: Option<pool::ConnectionPool>,
 }
 
 pub struct TlsConfig {
     pub config: Arc<rustls::ServerConfig>,
-    pub common_name: Option<String>,
+    /// Picks the server certificate by SNI.
+    pub cert_resolver: Arc<CertResolver>,
 }
 
 impl TlsConfig {
-    pub fn to_server_config(&self) -> Arc<rustls::ServerConfig> {
-        self.config.clone()
+    /// Server config for a single connection, along with the channel binding data
+    /// of the certificate it presents, which is known once the handshake is done.
+    /// The latter stays empty if the certificate isn't resolved, i.e. the session is resumed.
+    pub fn to_connection_config(
+        &self,
+    ) -> (
+        Arc<rustls::ServerConfig>,
+        Arc<Mutex<Option<ServerCertDigest>>>,
+    ) {
+        let digest = Arc::new(Mutex::new(None));
+        let mut config = rustls::ServerConfig::clone(&self.config);
+        config.cert_resolver = Arc::new(ConnectionCertResolver {
+            resolver: self.cert_resolver.clone(),
+            digest: digest.clone(),
+        });
+
+        (Arc::new(config), digest)
+    }
+
+    /// Channel binding data of the certificate we present for the given SNI.
+    pub fn tls_server_end_point(&self, sni: Option<&str>) -> ServerCertDigest {
+        // NB: this might be off if the certificates have just been reloaded,
+        // in which case the client will have to retry authentication.
+        self.cert_resolver
+            .certs()
+            .resolve(sni)
+            .map_or(ServerCertDigest::Undefined, |cert| {
+                cert.tls_server_end_point
+            })
+    }
+
+    /// Domains served by the proxy, used in asserting project name formatting invariant.
+    pub fn common_names(&self) -> Arc<HashSet<String>> {
+        self.cert_resolver.certs().common_names.clone()
+    }
+}
+
+/// The `tls-server-end-point` channel binding data.
+/// RFC: <https://datatracker.ietf.org/doc/html/rfc5929#section-4>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerCertDigest {
+    /// Hash of the certificate signed with a SHA-256 (or weaker) algorithm.
+    Sha256([u8; 32]),
+    /// We don't compute the hash for other signature algorithms,
+    /// so channel binding won't be offered to the clients.
+    Undefined,
+}
+
+impl ServerCertDigest {
+    pub fn new(cert: &rustls::Certificate) -> anyhow::Result<Self> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+            .context("Failed to parse the server certificate")?;
+
+        // The hash function is the one used in the certificate's signature,
+        // with MD5 and SHA-1 being replaced by SHA-256.
+        let sha256_oids = [
+            oid_registry::OID_PKCS1_MD5WITHRSAENC,
+            oid_registry::OID_PKCS1_SHA1WITHRSA,
+            oid_registry::OID_PKCS1_SHA256WITHRSA,
+            oid_registry::OID_SIG_ECDSA_WITH_SHA256,
+        ];
+
+        let algorithm = &parsed.signature_algorithm.algorithm;
+        if sha256_oids.iter().any(|oid| oid == algorithm) {
+            Ok(Self::Sha256(Sha256::digest(&cert.0).into()))
+        } else {
+            println!(
+                "channel binding is not supported for the certificate signature algorithm {}",
+                algorithm
+            );
+            Ok(Self::Undefined)
+        }
+    }
+
+    /// The data to be compared with the client's, if channel binding is supported.
+    pub fn cert_digest(&self) -> Option<&[u8]> {
+        match self {
+            Self::Sha256(digest) => Some(digest),
+            Self::Undefined => None,
+        }
+    }
+}
+
+/// Where the server certificates are loaded from.
+#[derive(Debug, Default)]
+pub struct CertSource {
+    /// Paths to the key and the certificate presented by default,
+    /// i.e. if SNI doesn't match any other certificate.
+    pub default: Option<(PathBuf, PathBuf)>,
+    /// Directory with a subdirectory per certificate, each containing
+    /// `tls.key` and `tls.crt` (e.g. Kubernetes TLS secrets).
+    pub certs_dir: Option<PathBuf>,
+}
+
+impl CertSource {
+    /// Key and certificate paths, the default certificate goes first.
+    fn paths(&self) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+        let mut paths: Vec<_> = self.default.iter().cloned().collect();
+
+        if let Some(certs_dir) = &self.certs_dir {
+            let mut dirs = Vec::new();
+            let entries = std::fs::read_dir(certs_dir)
+                .with_context(|| format!("Failed to read '{}'", certs_dir.display()))?;
+            for entry in entries {
+                let entry = entry?;
+                // Skip the hidden directories, e.g. `..data` of the Kubernetes volumes.
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+
+                let path = entry.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+            }
+
+            // Keep the order stable, the first one might be the default.
+            dirs.sort();
+            paths.extend(
+                dirs.into_iter()
+                    .map(|dir| (dir.join("tls.key"), dir.join("tls.crt"))),
+            );
+        }
+
+        Ok(paths)
+    }
+
+    /// Modification times of the files, to tell if they should be reloaded.
+    fn modified(&self) -> anyhow::Result<Vec<(PathBuf, Option<SystemTime>)>> {
+        let mut modified = Vec::new();
+        for (key_path, cert_path) in self.paths()? {
+            for path in [key_path, cert_path] {
+                let mtime = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                modified.push((path, mtime));
+            }
+        }
+
+        Ok(modified)
+    }
+
+    fn load(&self) -> anyhow::Result<CertSet> {
+        let modified = self.modified()?;
+
+        let mut certs = CertSet::default();
+        for (key_path, cert_path) in self.paths()? {
+            certs.add(ServerCert::load(&key_path, &cert_path)?);
+        }
+        ensure!(certs.default.is_some(), "No TLS certificates found");
+
+        certs.modified = modified;
+        Ok(certs)
+    }
+}
+
+/// A certificate ready to be presented to the clients.
+struct ServerCert {
+    key: Arc<CertifiedKey>,
+    /// Domain of the wildcard certificate, i.e. the common name without `*.`.
+    common_name: Option<String>,
+    tls_server_end_point: ServerCertDigest,
+}
+
+impl ServerCert {
+    fn load(key_path: &Path, cert_path: &Path) -> anyhow::Result<Self> {
+        let key = {
+            let key_bytes = std::fs::read(key_path).context("TLS key file")?;
+            let mut keys = rustls_pemfile::pkcs8_private_keys(&mut &key_bytes[..]).context(
+                format!("Failed to read TLS keys at '{}'", key_path.display()),
+            )?;
+
+            ensure!(keys.len() == 1, "keys.len() = {} (should be 1)", keys.len());
+            keys.pop().map(rustls::PrivateKey).unwrap()
+        };
+
+        let cert_chain_bytes = std::fs::read(cert_path).context(format!(
+            "Failed to read TLS cert file at '{}'.",
+            cert_path.display()
+        ))?;
+        let cert_path = cert_path.display();
+        let cert_chain: Vec<_> = {
+            rustls_pemfile::certs(&mut &cert_chain_bytes[..])
+                .context(format!(
+                    "Failed to read TLS certificate chain from bytes from file at '{cert_path}'."
+                ))?
+                .into_iter()
+                .map(rustls::Certificate)
+                .collect()
+        };
+
+        // The first certificate in the chain is the one we present to the clients.
+        let (tls_server_end_point, common_name) = match cert_chain.first() {
+            Some(cert) => {
+                let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+                    .context(format!("Failed to parse the certificate at '{cert_path}'."))?;
+                let common_name = parsed
+                    .subject()
+                    .iter_common_name()
+                    .find_map(|cn| cn.as_str().ok()?.strip_prefix("*."))
+                    .map(|s| s.to_string());
+
+                (ServerCertDigest::new(cert)?, common_name)
+            }
+            None => bail!("No certificates found in '{cert_path}'"),
+        };
+
+        let signing_key = rustls::sign::any_supported_type(&key)
+            .map_err(|_| anyhow!("Unsupported TLS key type at '{}'", key_path.display()))?;
+
+        Ok(Self {
+            key: Arc::new(CertifiedKey::new(cert_chain, signing_key)),
+            common_name,
+            tls_server_end_point,
+        })
+    }
+}
+
+/// A snapshot of the server certificates.
+#[derive(Default)]
+struct CertSet {
+    /// Certificates by the domain they serve.
+    by_common_name: HashMap<String, Arc<ServerCert>>,
+    /// Presented if SNI is missing or doesn't match anything else.
+    default: Option<Arc<ServerCert>>,
+    common_names: Arc<HashSet<String>>,
+    /// Modification times of the files these certificates were loaded from.
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl CertSet {
+    fn add(&mut self, cert: ServerCert) {
+        let cert = Arc::new(cert);
+        if let Some(common_name) = &cert.common_name {
+            self.by_common_name
+                .entry(common_name.clone())
+                .or_insert_with(|| cert.clone());
+            Arc::make_mut(&mut self.common_names).insert(common_name.clone());
+        }
+
+        self.default.get_or_insert(cert);
+    }
+
+    /// Pick the certificate for `<project-name>.<common-name>`.
+    fn resolve(&self, sni: Option<&str>) -> Option<&Arc<ServerCert>> {
+        sni.and_then(|sni| sni.split_once('.'))
+            .and_then(|(_, domain)| self.by_common_name.get(domain))
+            .or(self.default.as_ref())
+    }
+}
+
+/// How often the certificate files are checked for changes.
+const CERTS_CHECK_PERIOD: Duration = Duration::from_secs(60);
+
+/// Server certificates which are picked by SNI and may be reloaded from disk.
+pub struct CertResolver {
+    source: CertSource,
+    certs: RwLock<Arc<CertSet>>,
+}
+
+impl CertResolver {
+    pub fn new(source: CertSource) -> anyhow::Result<Self> {
+        let certs = source.load()?;
+        Ok(Self {
+            source,
+            certs: RwLock::new(Arc::new(certs)),
+        })
+    }
+
+    fn certs(&self) -> Arc<CertSet> {
+        self.certs.read().clone()
+    }
+
+    /// Load the certificates once again. On failure, keep serving the old ones.
+    fn reload(&self) -> anyhow::Result<()> {
+        let certs = self.source.load()?;
+        let common_names = certs.common_names.iter().cloned().collect::<Vec<_>>();
+        *self.certs.write() = Arc::new(certs);
+
+        println!("Reloaded TLS certificates for {common_names:?}");
+        Ok(())
+    }
+
+    /// Reload the certificates on SIGHUP or once their files have changed.
+    pub async fn reload_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut interval = tokio::time::interval(CERTS_CHECK_PERIOD);
+
+        loop {
+            tokio::select! {
+                _ = sighup.recv() => println!("Got SIGHUP, reloading TLS certificates"),
+                _ = interval.tick() => {
+                    match self.source.modified() {
+                        Ok(modified) if modified != self.certs().modified => {
+                            println!("TLS certificates have changed, reloading them");
+                        }
+                        Ok(_) => continue,
+                        Err(e) => {
+                            println!("Failed to check TLS certificates: {e:#}");
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            if let Err(e) = self.reload() {
+                println!("Failed to reload TLS certificates: {e:#}");
+            }
+        }
+    }
+}
+
+impl rustls::server::ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs();
+        let cert = certs.resolve(client_hello.server_name())?;
+        Some(cert.key.clone())
+    }
+}
+
+/// Picks the certificate for a single connection and remembers its digest, so that
+/// the channel binding data matches the certificate even if they've been reloaded since.
+struct ConnectionCertResolver {
+    resolver: Arc<CertResolver>,
+    digest: Arc<Mutex<Option<ServerCertDigest>>>,
+}
+
+impl rustls::server::ResolvesServerCert for ConnectionCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certs = self.resolver.certs();
+        let cert = certs.resolve(client_hello.server_name())?;
+        *self.digest.lock() = Some(cert.tls_server_end_point);
+        Some(cert.key.clone())
     }
 }
 
 /// Configure TLS for the main endpoint.
-pub fn configure_tls(key_path: &str, cert_path: &str) -> anyhow::Result<TlsConfig> {
-    let key = {
-        let key_bytes = std::fs::read(key_path).context("TLS key file")?;
-        let mut keys = rustls_pemfile::pkcs8_private_keys(&mut &key_bytes[..])
-            .context(format!("Failed to read TLS keys at '{key_path}'"))?;
-
-        ensure!(keys.len() == 1, "keys.len() = {} (should be 1)", keys.len());
-        keys.pop().map(rustls::PrivateKey).unwrap()
-    };
-
-    let cert_chain_bytes = std::fs::read(cert_path)
-        .context(format!("Failed to read TLS cert file at '{cert_path}.'"))?;
-    let cert_chain = {
-        rustls_pemfile::certs(&mut &cert_chain_bytes[..])
-            .context(format!(
-                "Failed to read TLS certificate chain from bytes from file at '{cert_path}'."
-            ))?
-            .into_iter()
-            .map(rustls::Certificate)
-            .collect()
-    };
+pub fn configure_tls(source: CertSource) -> anyhow::Result<TlsConfig> {
+    let cert_resolver = Arc::new(CertResolver::new(source)?);
 
     let config = rustls::ServerConfig::builder()
         .with_safe_default_cipher_suites()
@@ -47,23 +370,95 @@ pub fn configure_tls(key_path: &str, cert_path: &str) -> anyhow::Result<TlsConfi
         // allow TLS 1.2 to be compatible with older client libraries
         .with_protocol_versions(&[&rustls::version::TLS13, &rustls::version::TLS12])?
         .with_no_client_auth()
-        .with_single_cert(cert_chain, key)?
+        .with_cert_resolver(cert_resolver.clone())
         .into();
 
-    // determine common name from tls-cert (-c server.crt param).
-    // used in asserting project name formatting invariant.
-    let common_name = {
-        let pem = x509_parser::pem::parse_x509_pem(&cert_chain_bytes)
-            .context(format!(
-                "Failed to parse PEM object from bytes from file at '{cert_path}'."
-            ))?
-            .1;
-        let common_name = pem.parse_x509()?.subject().to_string();
-        common_name.strip_prefix("CN=*.").map(|s| s.to_string())
-    };
-
     Ok(TlsConfig {
         config,
-        common_name,
+        cert_resolver,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a self-signed certificate for `*.<domain>` to the directory.
+    fn write_cert(dir: &Path, domain: &str) -> anyhow::Result<()> {
+        let cert = rcgen::Certificate::from_params({
+            let mut params = rcgen::CertificateParams::new(vec![format!("*.{domain}")]);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, format!("*.{domain}"));
+            params
+        })?;
+
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("tls.key"), cert.serialize_private_key_pem())?;
+        std::fs::write(dir.join("tls.crt"), cert.serialize_pem()?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_cert_by_sni() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_cert(&dir.path().join("b"), "example.com")?;
+        write_cert(&dir.path().join("a"), "localtest.me")?;
+        // Hidden directories would otherwise come first and hold the default certificate.
+        write_cert(&dir.path().join("..data"), "hidden.org")?;
+
+        let resolver = CertResolver::new(CertSource {
+            default: None,
+            certs_dir: Some(dir.path().into()),
+        })?;
+
+        let certs = resolver.certs();
+        let expected = HashSet::from(["example.com".to_owned(), "localtest.me".to_owned()]);
+        assert_eq!(*certs.common_names, expected);
+
+        let domain = |sni: Option<&str>| {
+            let cert = certs.resolve(sni)?;
+            cert.common_name.clone()
+        };
+        assert_eq!(
+            domain(Some("project.example.com")).as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            domain(Some("project.localtest.me")).as_deref(),
+            Some("localtest.me")
+        );
+        // The first directory holds the default certificate.
+        assert_eq!(
+            domain(Some("project.unknown.org")).as_deref(),
+            Some("localtest.me")
+        );
+        assert_eq!(domain(None).as_deref(), Some("localtest.me"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reload_certs() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_cert(&dir.path().join("a"), "example.com")?;
+
+        let resolver = CertResolver::new(CertSource {
+            default: None,
+            certs_dir: Some(dir.path().into()),
+        })?;
+        assert!(!resolver.certs().common_names.contains("localtest.me"));
+
+        write_cert(&dir.path().join("b"), "localtest.me")?;
+        assert_ne!(resolver.source.modified()?, resolver.certs().modified);
+        resolver.reload()?;
+        assert!(resolver.certs().common_names.contains("localtest.me"));
+
+        // A broken certificate doesn't replace the working ones.
+        std::fs::write(dir.path().join("b").join("tls.crt"), "garbage")?;
+        assert!(resolver.reload().is_err());
+        assert!(resolver.certs().common_names.contains("localtest.me"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+//! Per-session audit and usage events, e.g. for billing. Each client session
+//! produces a single event once it's over, which is then sent to a sink
+//! (a JSON lines file or an http endpoint) in batches.
+
+use crate::auth;
+use anyhow::Context;
+use async_trait::async_trait;
+use metrics::{register_int_counter_vec, IntCounterVec};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use utils::pq_proto::StartupMessageParams;
+
+static SESSION_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_session_events_total",
+        "Number of session events by their fate.",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+/// Max number of events waiting to be sent; the new ones are dropped beyond that.
+const QUEUE_SIZE: usize = 10_000;
+
+/// Max number of events sent at once.
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// How often the pending events are sent.
+const FLUSH_PERIOD: Duration = Duration::from_secs(10);
+
+/// Max number of attempts to send a batch before the events are given up on.
+const MAX_WRITE_ATTEMPTS: u32 = 3;
+/// The delay before the next attempt grows linearly with this step.
+const WRITE_RETRY_STEP: Duration = Duration::from_millis(500);
+
+/// Don't let an unresponsive endpoint hold up the queue indefinitely.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the client has been authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    Scram,
+    /// The project name and the password were passed in the password field.
+    PasswordHack,
+    Link,
+    /// The cleartext password checked by the compute node, e.g. for SQL over HTTP.
+    Password,
+}
+
+impl From<&auth::BackendType<'_, auth::ClientCredentials<'_>>> for AuthMethod {
+    fn from(creds: &auth::BackendType<'_, auth::ClientCredentials<'_>>) -> Self {
+        use auth::BackendType::*;
+        match creds {
+            Console(_, creds) | Postgres(_, creds) if creds.project().is_none() => {
+                Self::PasswordHack
+            }
+            Console(..) | Postgres(..) => Self::Scram,
+            Link(_) => Self::Link,
+        }
+    }
+}
+
+/// Audit and usage record of a client session.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionEvent {
+    pub session_id: uuid::Uuid,
+    pub project: Option<String>,
+    pub role: Option<String>,
+    pub database: Option<String>,
+    pub peer_ip: Option<IpAddr>,
+    pub application_name: Option<String>,
+    /// Missing if the client has been rejected before we could tell.
+    pub auth_method: Option<AuthMethod>,
+    /// RFC 3339 timestamp.
+    pub started_at: String,
+    /// Time to authenticate the client and connect to the compute node,
+    /// missing if we haven't got that far.
+    pub connect_latency_ms: Option<u64>,
+    /// Bytes received from the client.
+    pub bytes_in: u64,
+    /// Bytes sent to the client.
+    pub bytes_out: u64,
+    pub duration_ms: u64,
+}
+
+/// Collects the details of a session and reports them once dropped.
+pub struct SessionAccounting {
+    event: SessionEvent,
+    started: Instant,
+    connected: Option<Instant>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl SessionAccounting {
+    pub fn new(extra: &auth::ConsoleReqExtra<'_>, params: &StartupMessageParams) -> Self {
+        let event = SessionEvent {
+            session_id: extra.session_id,
+            project: None,
+            role: params.get("user").map(str::to_owned),
+            database: params.get("database").map(str::to_owned),
+            peer_ip: extra.peer_ip,
+            application_name: extra.application_name.map(str::to_owned),
+            auth_method: None,
+            started_at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            connect_latency_ms: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            duration_ms: 0,
+        };
+
+        Self {
+            event,
+            started: Instant::now(),
+            connected: None,
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_project(&mut self, project: Option<&str>) {
+        self.event.project = project.map(str::to_owned);
+    }
+
+    pub fn set_auth_method(&mut self, auth_method: AuthMethod) {
+        self.event.auth_method = Some(auth_method);
+    }
+
+    /// The client is ready to send queries.
+    pub fn connected(&mut self) {
+        self.connected.get_or_insert_with(Instant::now);
+    }
+
+    pub fn count_bytes_in(&self, cnt: usize) {
+        self.bytes_in.fetch_add(cnt as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_bytes_out(&self, cnt: usize) {
+        self.bytes_out.fetch_add(cnt as u64, Ordering::Relaxed);
+    }
+
+    fn finish(&self) -> SessionEvent {
+        let millis = |duration: Duration| duration.as_millis() as u64;
+
+        SessionEvent {
+            connect_latency_ms: self
+                .connected
+                .map(|connected| millis(connected - self.started)),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            duration_ms: millis(self.started.elapsed()),
+            ..self.event.clone()
+        }
+    }
+}
+
+impl Drop for SessionAccounting {
+    fn drop(&mut self) {
+        if let Some(events) = EVENTS.get() {
+            let outcome = match events.try_send(self.finish()) {
+                Ok(()) => "queued",
+                Err(_) => "dropped",
+            };
+            SESSION_EVENTS.with_label_values(&[outcome]).inc();
+        }
+    }
+}
+
+/// Every sink of the session events is supposed to implement this trait.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn write(&self, events: &[SessionEvent]) -> anyhow::Result<()>;
+}
+
+/// Appends the events to a file, one JSON object per line.
+pub struct JsonLinesFile {
+    path: PathBuf,
+}
+
+impl JsonLinesFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl EventSink for JsonLinesFile {
+    async fn write(&self, events: &[SessionEvent]) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut buf, event)?;
+            buf.push(b'\n');
+        }
+
+        // Reopen the file every time, so that it could be rotated.
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        file.write_all(&buf).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Posts the batches of events to an http endpoint as `{"events": [...]}`.
+pub struct HttpBatch {
+    client: reqwest::Client,
+    url: reqwest::Url,
+}
+
+impl HttpBatch {
+    pub fn new(url: reqwest::Url) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .context("failed to build the http client")?;
+
+        Ok(Self { client, url })
+    }
+}
+
+#[async_trait]
+impl EventSink for HttpBatch {
+    async fn write(&self, events: &[SessionEvent]) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct Batch<'a> {
+            events: &'a [SessionEvent],
+        }
+
+        self.client
+            .post(self.url.clone())
+            .json(&Batch { events })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Parse the sink from an http(s) URL or a file path.
+pub fn parse_sink(sink: &str) -> anyhow::Result<Box<dyn EventSink>> {
+    if sink.starts_with("http://") || sink.starts_with("https://") {
+        let url = sink.parse().context("bad session events url")?;
+        return Ok(Box::new(HttpBatch::new(url)?));
+    }
+
+    Ok(Box::new(JsonLinesFile::new(sink.into())))
+}
+
+static EVENTS: OnceCell<mpsc::Sender<SessionEvent>> = OnceCell::new();
+
+/// Enable the session events. Without this, nothing is reported.
+/// The returned queue should be passed to [`send_loop`].
+pub fn configure() -> anyhow::Result<mpsc::Receiver<SessionEvent>> {
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    EVENTS
+        .set(tx)
+        .map_err(|_| anyhow::anyhow!("session events are already configured"))?;
+
+    Ok(rx)
+}
+
+/// Write the batch to the sink, retrying a few times on failure.
+async fn write_with_retries(sink: &dyn EventSink, batch: &[SessionEvent]) -> anyhow::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match sink.write(batch).await {
+            Err(e) if attempt < MAX_WRITE_ATTEMPTS => {
+                println!(
+                    "failed to send {} session events (attempt {attempt}): {e:#}",
+                    batch.len()
+                );
+                tokio::time::sleep(WRITE_RETRY_STEP * attempt).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Send the queued events to the sink in batches.
+pub async fn send_loop(
+    mut events: mpsc::Receiver<SessionEvent>,
+    sink: Box<dyn EventSink>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(FLUSH_PERIOD);
+    let mut batch = Vec::new();
+
+    loop {
+        let closed = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    batch.push(event);
+                    if batch.len() < MAX_BATCH_SIZE {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            },
+            _ = interval.tick() => false,
+        };
+
+        if !batch.is_empty() {
+            let outcome = match write_with_retries(sink.as_ref(), &batch).await {
+                Ok(()) => "sent",
+                Err(e) => {
+                    println!("failed to send {} session events: {e:#}", batch.len());
+                    "failed"
+                }
+            };
+            SESSION_EVENTS
+                .with_label_values(&[outcome])
+                .inc_by(batch.len() as u64);
+            batch.clear();
+        }
+
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Keeps the events in memory.
+    #[derive(Default)]
+    struct TestSink(Mutex<Vec<SessionEvent>>);
+
+    #[async_trait]
+    impl EventSink for Arc<TestSink> {
+        async fn write(&self, events: &[SessionEvent]) -> anyhow::Result<()> {
+            self.0.lock().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    /// Fails the given number of writes before passing the events on.
+    struct FlakySink {
+        failures: Mutex<u32>,
+        sink: Arc<TestSink>,
+    }
+
+    #[async_trait]
+    impl EventSink for FlakySink {
+        async fn write(&self, events: &[SessionEvent]) -> anyhow::Result<()> {
+            {
+                let mut failures = self.failures.lock();
+                if *failures > 0 {
+                    *failures -= 1;
+                    anyhow::bail!("sink is unavailable");
+                }
+            }
+
+            self.sink.write(events).await
+        }
+    }
+
+    fn accounting() -> SessionAccounting {
+        let extra = auth::ConsoleReqExtra {
+            session_id: uuid::Uuid::new_v4(),
+            application_name: Some("psql"),
+            peer_ip: Some([127, 0, 0, 1].into()),
+        };
+        let params = StartupMessageParams::new([("user", "john_doe"), ("database", "world")]);
+
+        let mut accounting = SessionAccounting::new(&extra, &params);
+        accounting.set_auth_method(AuthMethod::Scram);
+        accounting
+    }
+
+    #[test]
+    fn session_event() {
+        let mut accounting = accounting();
+        assert_eq!(accounting.finish().connect_latency_ms, None);
+
+        accounting.set_project(Some("my-project"));
+        accounting.connected();
+        accounting.count_bytes_in(10);
+        accounting.count_bytes_out(20);
+        accounting.count_bytes_out(30);
+
+        let event = accounting.finish();
+        assert_eq!(event.project.as_deref(), Some("my-project"));
+        assert_eq!(event.role.as_deref(), Some("john_doe"));
+        assert_eq!(event.database.as_deref(), Some("world"));
+        assert_eq!(event.application_name.as_deref(), Some("psql"));
+        assert!(event.connect_latency_ms.is_some());
+        assert_eq!((event.bytes_in, event.bytes_out), (10, 50));
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["auth_method"], "scram");
+        assert_eq!(json["peer_ip"], "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn send_events_in_batches() -> anyhow::Result<()> {
+        let sink = Arc::new(TestSink::default());
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let task = tokio::spawn(send_loop(rx, Box::new(sink.clone())));
+
+        for _ in 0..MAX_BATCH_SIZE + 1 {
+            tx.send(accounting().finish()).await?;
+        }
+
+        // The rest of the events are sent once the queue is closed.
+        drop(tx);
+        task.await??;
+        assert_eq!(sink.0.lock().len(), MAX_BATCH_SIZE + 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_failed_writes() -> anyhow::Result<()> {
+        let events = [accounting().finish(), accounting().finish()];
+
+        // The sink recovers before we run out of attempts.
+        let sink = Arc::new(TestSink::default());
+        let flaky = FlakySink {
+            failures: Mutex::new(MAX_WRITE_ATTEMPTS - 1),
+            sink: sink.clone(),
+        };
+        write_with_retries(&flaky, &events).await?;
+        assert_eq!(sink.0.lock().len(), events.len());
+
+        // The sink doesn't recover in time, the events are given up on.
+        let sink = Arc::new(TestSink::default());
+        let flaky = FlakySink {
+            failures: Mutex::new(MAX_WRITE_ATTEMPTS),
+            sink: sink.clone(),
+        };
+        assert!(write_with_retries(&flaky, &events).await.is_err());
+        assert!(sink.0.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn json_lines_file() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sink = JsonLinesFile::new(dir.path().join("events.jsonl"));
+
+        let events = [accounting().finish(), accounting().finish()];
+        sink.write(&events).await?;
+        sink.write(&events[..1]).await?;
+
+        let contents = std::fs::read_to_string(dir.path().join("events.jsonl"))?;
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2]["session_id"], events[0].session_id.to_string());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
 pub mod server;
+pub mod sql_over_http;
+pub mod websocket;
 
 use crate::url::ApiUrl;
 
@@ -1,17 +1,66 @@
-use anyhow::anyhow;
+use super::{sql_over_http, websocket};
+use crate::{
+    cancellation::{CancelMap, ForwardedCancelRequest},
+    config::ProxyConfig,
+};
+use anyhow::{anyhow, Context};
 use hyper::{Body, Request, Response, StatusCode};
-use std::net::TcpListener;
-use utils::http::{endpoint, error::ApiError, json::json_response, RouterBuilder, RouterService};
+use routerify::{ext::RequestExt, RequestServiceBuilder, Router};
+use std::{net::TcpListener, sync::Arc};
+use utils::http::{
+    endpoint,
+    error::{self, ApiError},
+    json::{json_request, json_response},
+    RouterBuilder, RouterService,
+};
 
 async fn status_handler(_: Request<Body>) -> Result<Response<Body>, ApiError> {
     json_response(StatusCode::OK, "")
 }
 
+/// Serve a `CancelRequest` forwarded by another proxy instance.
+async fn cancel_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let cancel_map = request
+        .data::<Arc<CancelMap>>()
+        .expect("unknown state type")
+        .clone();
+    let cancel_request: ForwardedCancelRequest = json_request(&mut request).await?;
+
+    cancel_map
+        .cancel_local_session(cancel_request.into())
+        .await
+        .map_err(ApiError::NotFound)?;
+
+    json_response(StatusCode::OK, "")
+}
+
 fn make_router() -> RouterBuilder<hyper::Body, ApiError> {
     let router = endpoint::make_router();
     router.get("/v1/status", status_handler)
 }
 
+/// Endpoints the clients send their credentials to, served over TLS only.
+fn make_tls_router(
+    config: &'static ProxyConfig,
+    cancel_map: Arc<CancelMap>,
+) -> RouterBuilder<hyper::Body, ApiError> {
+    Router::builder()
+        .data(config)
+        .data(cancel_map)
+        .post("/sql", sql_over_http::sql_handler)
+        .get("/ws", websocket::websocket_handler)
+        .err_handler(error::handler)
+}
+
+/// Endpoints for the other proxy instances, which must not be exposed to the clients.
+fn make_internal_router(cancel_map: Arc<CancelMap>) -> RouterBuilder<hyper::Body, ApiError> {
+    let router = endpoint::make_router();
+    router
+        .data(cancel_map)
+        .get("/v1/status", status_handler)
+        .post("/v1/cancel", cancel_handler)
+}
+
 pub async fn thread_main(http_listener: TcpListener) -> anyhow::Result<()> {
     scopeguard::defer! {
         println!("http has shut down");
@@ -25,3 +74,65 @@ pub async fn thread_main(http_listener: TcpListener) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Serve the client endpoints, terminating TLS with the certificates of the postgres protocol listener.
+pub async fn tls_thread_main(
+    config: &'static ProxyConfig,
+    cancel_map: Arc<CancelMap>,
+    tls_listener: tokio::net::TcpListener,
+) -> anyhow::Result<()> {
+    scopeguard::defer! {
+        println!("tls http has shut down");
+    }
+
+    let tls = config
+        .tls_config
+        .as_ref()
+        .context("tls http listener requires the TLS config")?;
+    let mut service_builder = RequestServiceBuilder::new(
+        make_tls_router(config, cancel_map)
+            .build()
+            .map_err(|e| anyhow!(e))?,
+    )
+    .map_err(|e| anyhow!(e))?;
+
+    loop {
+        let (socket, peer_addr) = tls_listener.accept().await?;
+        // Every connection resolves the certificate by SNI, just like the postgres protocol ones.
+        let (tls_config, _) = tls.to_connection_config();
+        let service = service_builder.build(peer_addr);
+        tokio::spawn(async move {
+            let result = async {
+                let stream = tokio_rustls::TlsAcceptor::from(tls_config)
+                    .accept(socket)
+                    .await?;
+                hyper::server::conn::Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await?;
+                anyhow::Ok(())
+            };
+
+            if let Err(e) = result.await {
+                println!("tls http connection from {peer_addr} failed: {e}");
+            }
+        });
+    }
+}
+
+pub async fn internal_thread_main(
+    cancel_map: Arc<CancelMap>,
+    internal_http_listener: TcpListener,
+) -> anyhow::Result<()> {
+    scopeguard::defer! {
+        println!("internal http has shut down");
+    }
+
+    let service = || RouterService::new(make_internal_router(cancel_map).build()?);
+
+    hyper::Server::from_tcp(internal_http_listener)?
+        .serve(service().map_err(|e| anyhow!(e))?)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Postgres protocol over WebSocket, for the browser and edge clients.
+//!
+//! The client upgrades a request to the `/ws` endpoint of the wss listener,
+//! which terminates TLS with the certificates of the postgres protocol listener,
+//! and then sends the postgres protocol byte stream in the binary frames.
+//! The project is selected by the `Host` header, just like by SNI.
+
+use crate::{cancellation::CancelMap, config::ProxyConfig, error::io_error, proxy};
+use anyhow::anyhow;
+use bytes::{Buf, Bytes};
+use futures::{ready, SinkExt, StreamExt};
+use hyper::{header, Body, Request, Response, StatusCode};
+use routerify::ext::RequestExt;
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use utils::http::error::ApiError;
+
+/// Byte stream over the binary frames of a WebSocket.
+pub struct WebSocketRw<S> {
+    stream: WebSocketStream<S>,
+    /// The rest of the last received frame.
+    chunk: Bytes,
+}
+
+impl<S> WebSocketRw<S> {
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        Self {
+            stream,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketRw<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.stream.poll_ready_unpin(cx)).map_err(io_error)?;
+        self.stream
+            .start_send_unpin(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.poll_flush_unpin(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.poll_close_unpin(cx).map_err(io_error)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketRw<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.chunk.is_empty() {
+            let message = match ready!(self.stream.poll_next_unpin(cx)) {
+                Some(message) => message.map_err(io_error)?,
+                // Report EOF.
+                None => return Poll::Ready(Ok(())),
+            };
+
+            match message {
+                Message::Binary(data) => self.chunk = data.into(),
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                Message::Text(_) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected text frame, the postgres protocol requires binary frames",
+                    )))
+                }
+                // Pings are answered by the library.
+                _ => continue,
+            }
+        }
+
+        let len = std::cmp::min(self.chunk.len(), buf.remaining());
+        buf.put_slice(&self.chunk[..len]);
+        self.chunk.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Hostname from the `Host` header, without the port.
+fn hostname(request: &Request<Body>) -> Option<String> {
+    let host = request.headers().get(header::HOST)?.to_str().ok()?;
+    let hostname = host
+        .rsplit_once(':')
+        .map_or(host, |(hostname, _port)| hostname);
+    Some(hostname.to_owned())
+}
+
+fn header_contains(request: &Request<Body>, name: header::HeaderName, token: &str) -> bool {
+    request
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+pub async fn websocket_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let config = *request
+        .data::<&'static ProxyConfig>()
+        .expect("unknown state type");
+    let cancel_map = request
+        .data::<Arc<CancelMap>>()
+        .expect("unknown state type")
+        .clone();
+
+    if !header_contains(&request, header::CONNECTION, "upgrade")
+        || !header_contains(&request, header::UPGRADE, "websocket")
+    {
+        return Err(ApiError::BadRequest(anyhow!(
+            "expected a WebSocket upgrade"
+        )));
+    }
+    if !header_contains(&request, header::SEC_WEBSOCKET_VERSION, "13") {
+        return Err(ApiError::BadRequest(anyhow!(
+            "unsupported WebSocket version"
+        )));
+    }
+    let accept_key = match request.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => return Err(ApiError::BadRequest(anyhow!("missing WebSocket key"))),
+    };
+
+    let hostname = hostname(&request);
+    let peer_ip = request.remote_addr().ip();
+    tokio::spawn(async move {
+        let result = async {
+            let upgraded = hyper::upgrade::on(request).await?;
+            let stream = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            let stream = WebSocketRw::new(stream);
+            proxy::handle_ws_client(config, &cancel_map, stream, peer_ip, hostname.as_deref()).await
+        };
+
+        if let Err(e) = result.await {
+            println!("error: {e}");
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Body::empty())
+        .map_err(|e| ApiError::InternalServerError(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn hostname_from_host_header() {
+        let request = |host: &str| {
+            Request::builder()
+                .header(header::HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(
+            hostname(&request("my-project.localtest.me:7001")).as_deref(),
+            Some("my-project.localtest.me")
+        );
+        assert_eq!(
+            hostname(&request("my-project.localtest.me")).as_deref(),
+            Some("my-project.localtest.me")
+        );
+        assert_eq!(hostname(&Request::new(Body::empty())), None);
+    }
+
+    #[tokio::test]
+    async fn byte_stream_over_websocket() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client, server) = tokio::join!(
+            WebSocketStream::from_raw_socket(client, Role::Client, None),
+            WebSocketStream::from_raw_socket(server, Role::Server, None),
+        );
+        let (mut client, mut server) = (WebSocketRw::new(client), WebSocketRw::new(server));
+
+        client.write_all(b"hello, ").await?;
+        client.write_all(b"world").await?;
+        client.shutdown().await?;
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await?;
+        assert_eq!(received, b"hello, world");
+
+        Ok(())
+    }
+}
@@ -5,14 +5,18 @@
 //! in somewhat transparent manner (again via communication with control plane API).
 
 mod auth;
+mod cache;
 mod cancellation;
 mod compute;
 mod config;
 mod error;
+mod events;
 mod http;
 mod mgmt;
 mod parse;
+mod pool;
 mod proxy;
+mod rate_limit;
 mod sasl;
 mod scram;
 mod stream;
@@ -23,7 +27,7 @@ use anyhow::{bail, Context};
 use clap::{self, Arg};
 use config::ProxyConfig;
 use futures::FutureExt;
-use std::{borrow::Cow, future::Future, net::SocketAddr};
+use std::{borrow::Cow, future::Future, net::SocketAddr, sync::Arc};
 use tokio::{net::TcpListener, task::JoinError};
 use utils::project_git_version;
 
@@ -71,6 +75,18 @@ async fn main() -> anyhow::Result<()> {
                 .help("listen for incoming http connections (metrics, etc) on ip:port")
                 .default_value("127.0.0.1:7001"),
         )
+        .arg(
+            Arg::new("wss")
+                .long("wss")
+                .takes_value(true)
+                .help("listen for incoming WebSocket and SQL over HTTP connections over TLS on ip:port, requires the TLS certificates"),
+        )
+        .arg(
+            Arg::new("internal-http")
+                .long("internal-http")
+                .takes_value(true)
+                .help("listen for the requests of the other proxy instances (forwarded cancel requests) on ip:port, not to be exposed to the clients; disabled by default"),
+        )
         .arg(
             Arg::new("uri")
                 .short('u')
@@ -103,23 +119,141 @@ async fn main() -> anyhow::Result<()> {
                 .takes_value(true)
                 .help("path to TLS cert for client postgres connections"),
         )
+        .arg(
+            Arg::new("certs-dir")
+                .long("certs-dir")
+                .takes_value(true)
+                .help("path to directory with a subdirectory of tls.key and tls.crt per served domain"),
+        )
+        .arg(
+            Arg::new("auth-cache")
+                .long("auth-cache")
+                .takes_value(true)
+                .help("cache of the role secrets received from the console, e.g. size=4000,ttl=1m; disabled by default")
+                .default_value("size=0,ttl=0s"),
+        )
+        .arg(
+            Arg::new("wake-compute-cache")
+                .long("wake-compute-cache")
+                .takes_value(true)
+                .help(
+                    "cache of the compute addresses received from the console, size=0 disables it",
+                )
+                .default_value("size=4000,ttl=4m"),
+        )
+        .arg(
+            Arg::new("connection-pool")
+                .long("connection-pool")
+                .takes_value(true)
+                .help(
+                    "enable transaction pooling of the compute connections with the given limits, \
+                    e.g. max_size=20,idle_timeout=5m; session state (SET, prepared statements, \
+                    advisory locks, LISTEN) doesn't survive the transaction in this mode",
+                ),
+        )
+        .arg(
+            Arg::new("connection-pool-endpoint")
+                .long("connection-pool-endpoint")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .requires("connection-pool")
+                .help("override the pool limits of an endpoint, e.g. my-project:max_size=50,idle_timeout=1m"),
+        )
+        .arg(
+            Arg::new("connection-rate-limit-per-ip")
+                .long("connection-rate-limit-per-ip")
+                .takes_value(true)
+                .help("token bucket limit of new connections per client IP, e.g. rate=10,burst=100"),
+        )
+        .arg(
+            Arg::new("connection-rate-limit-per-project")
+                .long("connection-rate-limit-per-project")
+                .takes_value(true)
+                .help("token bucket limit of new connections per project, e.g. rate=100,burst=1000"),
+        )
+        .arg(
+            Arg::new("console-rate-limit-per-ip")
+                .long("console-rate-limit-per-ip")
+                .takes_value(true)
+                .help("token bucket limit of console requests per client IP, e.g. rate=1,burst=10"),
+        )
+        .arg(
+            Arg::new("console-rate-limit-per-project")
+                .long("console-rate-limit-per-project")
+                .takes_value(true)
+                .help("token bucket limit of console requests per project, e.g. rate=5,burst=50"),
+        )
+        .arg(
+            Arg::new("max-project-connections")
+                .long("max-project-connections")
+                .takes_value(true)
+                .help("max number of active client connections per project, 0 disables the limit")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("instance-id")
+                .long("instance-id")
+                .takes_value(true)
+                .help("id of this proxy instance encoded in the cancel keys, unique among the peers")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("cancel-peer")
+                .long("cancel-peer")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("forward cancel requests to the internal http listener of another instance, e.g. 2=proxy-2:7002"),
+        )
+        .arg(
+            Arg::new("session-events")
+                .long("session-events")
+                .takes_value(true)
+                .help("report every client session to a JSON lines file or to an http(s) endpoint"),
+        )
         .get_matches();
 
-    let tls_config = match (
+    let default_cert = match (
         arg_matches.value_of("tls-key"),
         arg_matches.value_of("tls-cert"),
     ) {
-        (Some(key_path), Some(cert_path)) => Some(config::configure_tls(key_path, cert_path)?),
+        (Some(key_path), Some(cert_path)) => Some((key_path.into(), cert_path.into())),
         (None, None) => None,
         _ => bail!("either both or neither tls-key and tls-cert must be specified"),
     };
+    let certs_dir = arg_matches.value_of("certs-dir").map(Into::into);
+
+    let tls_config = match (default_cert, certs_dir) {
+        (None, None) => None,
+        (default, certs_dir) => Some(config::configure_tls(config::CertSource {
+            default,
+            certs_dir,
+        })?),
+    };
 
     let proxy_address: SocketAddr = arg_matches.value_of("proxy").unwrap().parse()?;
     let mgmt_address: SocketAddr = arg_matches.value_of("mgmt").unwrap().parse()?;
     let http_address: SocketAddr = arg_matches.value_of("http").unwrap().parse()?;
+    let internal_http_address: Option<SocketAddr> = arg_matches
+        .value_of("internal-http")
+        .map(str::parse)
+        .transpose()?;
+    let wss_address: Option<SocketAddr> =
+        arg_matches.value_of("wss").map(str::parse).transpose()?;
+    if wss_address.is_some() && tls_config.is_none() {
+        bail!("wss listener requires tls-key and tls-cert or certs-dir to be specified");
+    }
 
     let auth_backend = match arg_matches.value_of("auth-backend").unwrap() {
         "console" => {
+            let auth_cache: cache::CacheOptions =
+                arg_matches.value_of("auth-cache").unwrap().parse()?;
+            let wake_compute_cache: cache::CacheOptions = arg_matches
+                .value_of("wake-compute-cache")
+                .unwrap()
+                .parse()?;
+            println!("Using auth cache {auth_cache}, wake compute cache {wake_compute_cache}");
+            auth::backend::configure_caches(auth_cache, wake_compute_cache)?;
+
             let url = arg_matches.value_of("auth-endpoint").unwrap().parse()?;
             let endpoint = http::Endpoint::new(url, reqwest::Client::new());
             auth::BackendType::Console(Cow::Owned(endpoint), ())
@@ -135,9 +269,62 @@ async fn main() -> anyhow::Result<()> {
         other => bail!("unsupported auth backend: {other}"),
     };
 
+    let parse_rate_limit = |name: &str| -> anyhow::Result<rate_limit::RateLimitOptions> {
+        match arg_matches.value_of(name) {
+            Some(options) => options
+                .parse()
+                .with_context(|| format!("failed to parse {name}")),
+            None => Ok(rate_limit::RateLimitOptions::DISABLED),
+        }
+    };
+    rate_limit::configure(rate_limit::Limits {
+        connections_per_ip: rate_limit::RateLimiter::new(
+            "connections_per_ip",
+            parse_rate_limit("connection-rate-limit-per-ip")?,
+        ),
+        connections_per_project: rate_limit::RateLimiter::new(
+            "connections_per_project",
+            parse_rate_limit("connection-rate-limit-per-project")?,
+        ),
+        console_requests_per_ip: rate_limit::RateLimiter::new(
+            "console_requests_per_ip",
+            parse_rate_limit("console-rate-limit-per-ip")?,
+        ),
+        console_requests_per_project: rate_limit::RateLimiter::new(
+            "console_requests_per_project",
+            parse_rate_limit("console-rate-limit-per-project")?,
+        ),
+        active_connections_per_project: rate_limit::ConnectionLimiter::new(
+            arg_matches
+                .value_of("max-project-connections")
+                .unwrap()
+                .parse()?,
+        ),
+    })?;
+
+    let connection_pool = match arg_matches.value_of("connection-pool") {
+        Some(options) => {
+            let default: pool::PoolOptions = options.parse()?;
+            let endpoints = arg_matches
+                .values_of("connection-pool-endpoint")
+                .into_iter()
+                .flatten()
+                .map(pool::PoolConfig::parse_endpoint_options)
+                .collect::<anyhow::Result<_>>()?;
+
+            println!("Using transaction pooling of the compute connections: {default}");
+            Some(pool::ConnectionPool::new(pool::PoolConfig {
+                default,
+                endpoints,
+            }))
+        }
+        None => None,
+    };
+
     let config: &ProxyConfig = Box::leak(Box::new(ProxyConfig {
         tls_config,
         auth_backend,
+        connection_pool,
     }));
 
     println!("Version: {GIT_VERSION}");
@@ -147,18 +334,96 @@ async fn main() -> anyhow::Result<()> {
     println!("Starting http on {}", http_address);
     let http_listener = TcpListener::bind(http_address).await?.into_std()?;
 
+    let wss_listener = match wss_address {
+        Some(wss_address) => {
+            println!("Starting wss on {}", wss_address);
+            Some(TcpListener::bind(wss_address).await?)
+        }
+        None => None,
+    };
+
+    // The internal listener doesn't authenticate the requests, so it's only started on demand.
+    let internal_http_listener = match internal_http_address {
+        Some(internal_http_address) => {
+            println!("Starting internal http on {}", internal_http_address);
+            Some(TcpListener::bind(internal_http_address).await?.into_std()?)
+        }
+        None => None,
+    };
+
     println!("Starting mgmt on {}", mgmt_address);
     let mgmt_listener = TcpListener::bind(mgmt_address).await?.into_std()?;
 
     println!("Starting proxy on {}", proxy_address);
     let proxy_listener = TcpListener::bind(proxy_address).await?;
 
-    let tasks = [
+    // Clients may cancel the queries of the WebSocket sessions and vice versa.
+    // The sessions of the other instances are cancelled by their owners.
+    let instance_id: cancellation::InstanceId =
+        arg_matches.value_of("instance-id").unwrap().parse()?;
+    let cancel_peers = arg_matches
+        .values_of("cancel-peer")
+        .into_iter()
+        .flatten()
+        .map(cancellation::HttpCancelForwarder::parse_peer)
+        .collect::<anyhow::Result<hashbrown::HashMap<_, _>>>()?;
+    if !cancel_peers.is_empty() && internal_http_address.is_none() {
+        bail!("cancel-peer requires internal-http to receive the cancel requests of the peers");
+    }
+    println!(
+        "Instance id {instance_id}, cancel requests are forwarded to {} peers",
+        cancel_peers.len()
+    );
+    let cancel_forwarder: Option<Box<dyn cancellation::CancelForwarder>> =
+        match cancel_peers.is_empty() {
+            true => None,
+            false => Some(Box::new(cancellation::HttpCancelForwarder::new(
+                cancel_peers,
+            )?)),
+        };
+    let cancel_map = Arc::new(cancellation::CancelMap::new(instance_id, cancel_forwarder));
+
+    let mut tasks = vec![
         tokio::spawn(http::server::thread_main(http_listener)),
-        tokio::spawn(proxy::thread_main(config, proxy_listener)),
+        tokio::spawn(proxy::thread_main(
+            config,
+            cancel_map.clone(),
+            proxy_listener,
+        )),
         tokio::task::spawn_blocking(move || mgmt::thread_main(mgmt_listener)),
-    ]
-    .map(flatten_err);
+        tokio::spawn(rate_limit::gc_loop()),
+    ];
+
+    if let Some(internal_http_listener) = internal_http_listener {
+        tasks.push(tokio::spawn(http::server::internal_thread_main(
+            cancel_map.clone(),
+            internal_http_listener,
+        )));
+    }
+
+    if let Some(wss_listener) = wss_listener {
+        tasks.push(tokio::spawn(http::server::tls_thread_main(
+            config,
+            cancel_map,
+            wss_listener,
+        )));
+    }
+
+    if let Some(pool) = &config.connection_pool {
+        tasks.push(tokio::spawn(pool.gc_loop()));
+    }
+
+    if let Some(tls) = &config.tls_config {
+        tasks.push(tokio::spawn(tls.cert_resolver.clone().reload_loop()));
+    }
+
+    if let Some(sink) = arg_matches.value_of("session-events") {
+        println!("Reporting session events to {sink}");
+        let sink = events::parse_sink(sink)?;
+        tasks.push(tokio::spawn(events::send_loop(events::configure()?, sink)));
+    }
+
+    let tasks = tasks.into_iter().map(flatten_err);
 
     // This will block until all tasks have completed.
     // Furthermore, the first one to fail will cancel the rest.
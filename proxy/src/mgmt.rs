@@ -69,6 +69,24 @@ struct MgmtHandler;
 //
 // // to test manually by sending a query to mgmt interface:
 // psql -h 127.0.0.1 -p 9999 -c '{"session_id":"4f10dde522e14739","result":{"Success":{"host":"127.0.0.1","port":5432,"dbname":"stas","user":"stas","password":"stas"}}}'
+// Cached console responses may be dropped as well, e.g. after a password change:
+// psql -h 127.0.0.1 -p 9999 -c '{"invalidate_cache":{"project":"foo","role":"stas"}}'
+// Without "role", the compute address and the secrets of all roles of the project are dropped.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MgmtRequest {
+    PsqlSession(PsqlSessionResponse),
+    InvalidateCache {
+        invalidate_cache: CacheInvalidationRequest,
+    },
+}
+
+#[derive(Deserialize)]
+struct CacheInvalidationRequest {
+    project: String,
+    role: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct PsqlSessionResponse {
     session_id: String,
@@ -111,7 +129,12 @@ impl postgres_backend::Handler for MgmtHandler {
 fn try_process_query(pgb: &mut PostgresBackend, query_string: &str) -> anyhow::Result<()> {
     println!("Got mgmt query [redacted]"); // Content contains password, don't print it
 
-    let resp: PsqlSessionResponse = serde_json::from_str(query_string)?;
+    let resp = match serde_json::from_str(query_string)? {
+        MgmtRequest::PsqlSession(resp) => resp,
+        MgmtRequest::InvalidateCache { invalidate_cache } => {
+            return invalidate_cache_query(pgb, invalidate_cache)
+        }
+    };
 
     match auth::backend::notify(&resp.session_id, resp.result.into_compute_ready()) {
         Ok(()) => {
@@ -126,3 +149,24 @@ fn try_process_query(pgb: &mut PostgresBackend, query_string: &str) -> anyhow::R
 
     Ok(())
 }
+
+fn invalidate_cache_query(
+    pgb: &mut PostgresBackend,
+    req: CacheInvalidationRequest,
+) -> anyhow::Result<()> {
+    let role = req.role.as_deref();
+    let mut invalidated = auth::backend::invalidate_auth_info(&req.project, role);
+    if role.is_none() && auth::backend::invalidate_node_info(&req.project) {
+        invalidated += 1;
+    }
+    println!(
+        "invalidated {invalidated} cached console responses of project {}",
+        req.project
+    );
+
+    pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
+        .write_message_noflush(&BeMessage::DataRow(&[Some(b"ok")]))?
+        .write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
+
+    Ok(())
+}
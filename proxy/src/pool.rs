@@ -0,0 +1,717 @@
+//! Transaction pooling of the compute connections.
+//!
+//! In this mode, the proxy keeps warm compute connections per endpoint, role and database,
+//! and lends one to a client only for the duration of a transaction: once the compute reports
+//! that it's idle again (`ReadyForQuery` with the idle transaction status), the connection goes
+//! back to the pool and may serve the next transaction of any client. This lets lots of
+//! short-lived clients share a few compute connections, at the price of the session state
+//! (`SET`, named prepared statements, advisory locks, `LISTEN`) not surviving the transaction.
+
+use crate::{
+    auth,
+    cancellation::{self, CancelClosure},
+    compute::{self, NodeInfo},
+    error::UserFacingError,
+    proxy::inc_proxied,
+    stream::MetricsStream,
+};
+use anyhow::{bail, ensure, Context};
+use bytes::BytesMut;
+use futures::FutureExt;
+use metrics::{register_int_gauge, IntGauge};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpStream,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+};
+use utils::pq_proto::{BeMessage, RawMessage, StartupMessageParams};
+
+/// How long a client may wait for a compute connection if the pool is exhausted.
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often the idle connections are checked for expiration.
+const GC_PERIOD: Duration = Duration::from_secs(10);
+
+const TAG_QUERY: u8 = b'Q';
+const TAG_SYNC: u8 = b'S';
+const TAG_FUNCTION_CALL: u8 = b'F';
+const TAG_TERMINATE: u8 = b'X';
+const TAG_READY_FOR_QUERY: u8 = b'Z';
+/// Transaction status of the `ReadyForQuery` message: not in a transaction block.
+const TRANSACTION_STATUS_IDLE: u8 = b'I';
+
+static POOL_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "proxy_pool_compute_connections",
+        "Number of compute connections opened by the transaction pools."
+    )
+    .unwrap()
+});
+
+static POOL_IDLE_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "proxy_pool_idle_compute_connections",
+        "Number of compute connections waiting in the transaction pools."
+    )
+    .unwrap()
+});
+
+/// Limits of the pool of a single endpoint, role and database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// Max number of compute connections, both idle and lent to clients.
+    pub max_size: usize,
+    /// Idle connections are closed after this time, so that the compute may suspend.
+    pub idle_timeout: Duration,
+}
+
+/// Parses the options from a string like `max_size=20,idle_timeout=5m`.
+impl std::str::FromStr for PoolOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> anyhow::Result<Self> {
+        let mut max_size = None;
+        let mut idle_timeout = None;
+
+        for option in options.split(',') {
+            let (key, value) = option
+                .split_once('=')
+                .with_context(|| format!("bad key-value pair: {option}"))?;
+
+            match key {
+                "max_size" => max_size = Some(value.parse().context("bad pool max_size")?),
+                "idle_timeout" => {
+                    idle_timeout =
+                        Some(humantime::parse_duration(value).context("bad pool idle_timeout")?)
+                }
+                unknown => bail!("unknown key: {unknown}"),
+            }
+        }
+
+        let options = Self {
+            max_size: max_size.context("missing `max_size`")?,
+            idle_timeout: idle_timeout.context("missing `idle_timeout`")?,
+        };
+        ensure!(options.max_size > 0, "pool max_size must be positive");
+
+        Ok(options)
+    }
+}
+
+impl std::fmt::Display for PoolOptions {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "max_size={},idle_timeout={}",
+            self.max_size,
+            humantime::format_duration(self.idle_timeout)
+        )
+    }
+}
+
+/// Pool limits, with the overrides for particular endpoints.
+#[derive(Debug)]
+pub struct PoolConfig {
+    pub default: PoolOptions,
+    pub endpoints: HashMap<String, PoolOptions>,
+}
+
+impl PoolConfig {
+    /// Parses the endpoint's override like `<endpoint>:max_size=50,idle_timeout=1m`.
+    pub fn parse_endpoint_options(input: &str) -> anyhow::Result<(String, PoolOptions)> {
+        let (endpoint, options) = input
+            .split_once(':')
+            .with_context(|| format!("missing endpoint name: {input}"))?;
+
+        Ok((endpoint.to_owned(), options.parse()?))
+    }
+
+    fn options(&self, endpoint: &str) -> PoolOptions {
+        self.endpoints
+            .get(endpoint)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error(transparent)]
+    Connect(#[from] compute::ConnectionError),
+
+    #[error("Timed out waiting for a free connection to the compute node")]
+    AcquireTimeout,
+}
+
+impl UserFacingError for PoolError {
+    fn to_string_client(&self) -> String {
+        use PoolError::*;
+        match self {
+            Connect(e) => e.to_string_client(),
+            AcquireTimeout => self.to_string(),
+        }
+    }
+}
+
+/// The compute connections are shared only by the clients of the same endpoint, role and database.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    /// Project name, or the compute address if the auth backend doesn't know the project.
+    endpoint: String,
+    user: String,
+    dbname: String,
+}
+
+impl PoolKey {
+    fn new(node: &NodeInfo) -> Option<Self> {
+        use tokio_postgres::config::Host;
+
+        let endpoint = match &node.project {
+            Some(project) => project.clone(),
+            None => {
+                let port = node.config.get_ports().first().copied().unwrap_or(5432);
+                match node.config.get_hosts().first()? {
+                    Host::Tcp(host) => format!("{host}:{port}"),
+                    Host::Unix(_) => return None,
+                }
+            }
+        };
+
+        Some(Self {
+            endpoint,
+            user: node.config.get_user()?.to_owned(),
+            dbname: node.config.get_dbname()?.to_owned(),
+        })
+    }
+}
+
+/// A compute connection opened by the pool.
+pub struct PooledConnection {
+    stream: TcpStream,
+    version: String,
+    cancel_closure: CancelClosure,
+    /// Counts the connection against the pool's `max_size`.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+    fn new(
+        db: compute::PostgresConnection,
+        cancel_closure: CancelClosure,
+        permit: OwnedSemaphorePermit,
+    ) -> Self {
+        POOL_CONNECTIONS.inc();
+        Self {
+            stream: db.stream,
+            version: db.version,
+            cancel_closure,
+            _permit: permit,
+        }
+    }
+
+    /// PostgreSQL version of the compute node.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn cancel_closure(&self) -> CancelClosure {
+        self.cancel_closure.clone()
+    }
+
+    /// Check that the idle connection hasn't been closed by the compute meanwhile.
+    /// An idle compute doesn't send anything, so any data also means the connection is unusable.
+    fn is_alive(&self) -> bool {
+        let mut buf = [0u8; 1];
+        match self.stream.try_read(&mut buf) {
+            Err(e) => e.kind() == std::io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        }
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        POOL_CONNECTIONS.dec();
+    }
+}
+
+struct IdleConnection {
+    conn: PooledConnection,
+    idle_since: Instant,
+}
+
+/// The pool of a single endpoint, role and database.
+pub struct EndpointPool {
+    options: PoolOptions,
+    idle: Mutex<Vec<IdleConnection>>,
+    /// One permit per compute connection, either idle or lent.
+    permits: Arc<Semaphore>,
+    /// Notified when a connection is returned to the pool.
+    returned: Notify,
+}
+
+impl EndpointPool {
+    fn new(options: PoolOptions) -> Self {
+        Self {
+            options,
+            idle: Mutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(options.max_size)),
+            returned: Notify::new(),
+        }
+    }
+
+    /// Take an idle connection, or open a new one if the pool isn't full,
+    /// or wait for a connection to be returned otherwise.
+    /// New connections are retried and re-woken like the dedicated ones.
+    pub async fn acquire<W, F>(
+        &self,
+        node: &mut NodeInfo,
+        mut wake_compute: W,
+    ) -> Result<PooledConnection, PoolError>
+    where
+        W: FnMut() -> F,
+        F: Future<Output = Option<compute::ComputeConnCfg>>,
+    {
+        let deadline = tokio::time::sleep(ACQUIRE_TIMEOUT);
+        tokio::pin!(deadline);
+
+        loop {
+            if let Some(conn) = self.pop_idle() {
+                return Ok(conn);
+            }
+
+            tokio::select! {
+                permit = self.permits.clone().acquire_owned() => {
+                    let permit = permit.expect("the semaphore is never closed");
+                    let (db, cancel_closure) = node
+                        .connect_with_retries(|node| node.establish().boxed(), &mut wake_compute)
+                        .await?;
+                    return Ok(PooledConnection::new(db, cancel_closure, permit));
+                }
+                _ = self.returned.notified() => continue,
+                _ = &mut deadline => return Err(PoolError::AcquireTimeout),
+            }
+        }
+    }
+
+    /// Return the connection once the compute is idle, i.e. not in a transaction.
+    pub fn release(&self, conn: PooledConnection) {
+        POOL_IDLE_CONNECTIONS.inc();
+        self.idle.lock().push(IdleConnection {
+            conn,
+            idle_since: Instant::now(),
+        });
+        self.returned.notify_one();
+    }
+
+    fn pop_idle(&self) -> Option<PooledConnection> {
+        let mut idle = self.idle.lock();
+        // Reuse the most recently used connections, so that the others may expire.
+        while let Some(IdleConnection { conn, idle_since }) = idle.pop() {
+            POOL_IDLE_CONNECTIONS.dec();
+            if idle_since.elapsed() < self.options.idle_timeout && conn.is_alive() {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn drop_expired(&self) {
+        let mut idle = self.idle.lock();
+        let len = idle.len();
+        idle.retain(|c| c.idle_since.elapsed() < self.options.idle_timeout);
+        POOL_IDLE_CONNECTIONS.sub((len - idle.len()) as i64);
+    }
+}
+
+/// Compute connections of all endpoints, roles and databases.
+pub struct ConnectionPool {
+    config: PoolConfig,
+    endpoints: Mutex<HashMap<PoolKey, Arc<EndpointPool>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            endpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the pool for the client's compute connections, unless the client needs a dedicated one.
+    pub fn get(&self, node: &NodeInfo, params: &StartupMessageParams) -> Option<Arc<EndpointPool>> {
+        // Replication and the custom startup options only make sense for the whole session.
+        let custom_options = params.options_raw().map_or(false, |mut options| {
+            options.any(|opt| !opt.starts_with("project="))
+        });
+        if custom_options || params.get("replication").is_some() {
+            return None;
+        }
+
+        // The proxy hasn't checked the password supplied by the client (see the password hack),
+        // only the compute checks it on connect, so such clients can't use the shared connections.
+        if !node.reported_auth_ok && node.config.get_password().is_some() {
+            return None;
+        }
+
+        let key = PoolKey::new(node)?;
+        let options = self.config.options(&key.endpoint);
+        let pool = self
+            .endpoints
+            .lock()
+            .entry(key)
+            .or_insert_with(|| Arc::new(EndpointPool::new(options)))
+            .clone();
+
+        Some(pool)
+    }
+
+    /// Close the expired idle connections, so that the computes may suspend,
+    /// and forget the pools without connections.
+    pub async fn gc_loop(&self) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(GC_PERIOD);
+        loop {
+            interval.tick().await;
+
+            self.endpoints.lock().retain(|_, pool| {
+                pool.drop_expired();
+                // Clients get the pools under this lock, so nobody else can get this one.
+                let unused = Arc::strong_count(pool) == 1
+                    && pool.permits.available_permits() == pool.options.max_size;
+                !unused
+            });
+        }
+    }
+}
+
+/// Buffers the incoming protocol messages.
+#[derive(Default)]
+struct MessageReader {
+    buf: BytesMut,
+}
+
+impl MessageReader {
+    /// Read the next message, `None` means EOF. This function is cancellation-safe:
+    /// a partially received message stays in the buffer until the next call.
+    async fn read(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> anyhow::Result<Option<RawMessage>> {
+        loop {
+            if let Some(msg) = RawMessage::parse(&mut self.buf)? {
+                return Ok(Some(msg));
+            }
+
+            self.buf.reserve(8192);
+            if stream.read_buf(&mut self.buf).await? == 0 {
+                ensure!(self.buf.is_empty(), "connection closed mid-message");
+                return Ok(None);
+            }
+        }
+    }
+
+    fn has_message(&self) -> bool {
+        RawMessage::is_complete(&self.buf)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+/// Proxy the client's traffic until it disconnects, lending it a compute
+/// connection from the pool for each transaction.
+pub async fn proxy_pass<W, F>(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    pool: &EndpointPool,
+    node: &mut NodeInfo,
+    mut wake_compute: W,
+    session: &cancellation::Session<'_>,
+) -> anyhow::Result<()>
+where
+    W: FnMut() -> F,
+    F: Future<Output = Option<compute::ComputeConnCfg>>,
+{
+    let mut client = BufWriter::new(client);
+    let mut client_reader = MessageReader::default();
+
+    loop {
+        // Wait for the client to start a transaction.
+        let msg = match client_reader.read(&mut client).await? {
+            Some(msg) if msg.tag() != TAG_TERMINATE => msg,
+            _ => return Ok(()),
+        };
+
+        let mut conn = match pool.acquire(node, &mut wake_compute).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                if let Some(project) = &node.project {
+                    auth::backend::invalidate_node_info(project);
+                }
+                return throw_error(&mut client, e).await;
+            }
+        };
+
+        session.set_query_cancellation(Some(conn.cancel_closure()));
+        // The client's traffic is counted in the metrics on the way to the compute, as for the regular connections.
+        let mut compute = MetricsStream::new(&mut conn.stream, inc_proxied);
+        let idle = run_transaction(&mut client, &mut client_reader, &mut compute, msg).await?;
+        session.set_query_cancellation(None);
+
+        if !idle {
+            // The client has left mid-transaction; closing the connection rolls it back.
+            return Ok(());
+        }
+        pool.release(conn);
+    }
+}
+
+/// Forward the messages between the client and the compute until the compute is
+/// idle again. Returns false if the client has disconnected instead.
+async fn run_transaction(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    client_reader: &mut MessageReader,
+    compute: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    first_msg: RawMessage,
+) -> anyhow::Result<bool> {
+    let mut compute_reader = MessageReader::default();
+    // Number of queries and syncs yet to be answered with `ReadyForQuery`.
+    let mut pending = 0usize;
+
+    let mut msg = Some(first_msg);
+    loop {
+        if let Some(msg) = msg.take() {
+            if matches!(msg.tag(), TAG_QUERY | TAG_SYNC | TAG_FUNCTION_CALL) {
+                pending += 1;
+            }
+            compute.write_all(msg.as_bytes()).await?;
+            compute.flush().await?;
+        }
+
+        tokio::select! {
+            client_msg = client_reader.read(client) => {
+                match client_msg? {
+                    Some(client_msg) if client_msg.tag() != TAG_TERMINATE => msg = Some(client_msg),
+                    _ => return Ok(false),
+                }
+            }
+            compute_msg = compute_reader.read(compute) => {
+                let compute_msg = compute_msg?.context("compute closed the connection")?;
+                client.write_all(compute_msg.as_bytes()).await?;
+                if !compute_reader.has_message() {
+                    client.flush().await?;
+                }
+
+                if compute_msg.tag() == TAG_READY_FOR_QUERY {
+                    pending = pending.saturating_sub(1);
+                    // Anything the compute has sent after that belongs to this client as well.
+                    if pending == 0
+                        && compute_msg.body() == [TRANSACTION_STATUS_IDLE]
+                        && compute_reader.is_empty()
+                    {
+                        client.flush().await?;
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn throw_error<T>(
+    client: &mut (impl AsyncWrite + Unpin),
+    error: impl UserFacingError + Into<anyhow::Error>,
+) -> anyhow::Result<T> {
+    let mut buf = BytesMut::new();
+    BeMessage::write(
+        &mut buf,
+        &BeMessage::ErrorResponse(&error.to_string_client()),
+    )?;
+    client.write_all(&buf).await?;
+    client.flush().await?;
+    Err(error.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pool_options() -> anyhow::Result<()> {
+        let options: PoolOptions = "max_size=20,idle_timeout=5m".parse()?;
+        assert_eq!(options.max_size, 20);
+        assert_eq!(options.idle_timeout, Duration::from_secs(300));
+        assert_eq!(options.to_string().parse::<PoolOptions>()?, options);
+
+        assert!("max_size=0,idle_timeout=5m".parse::<PoolOptions>().is_err());
+        assert!("max_size=20".parse::<PoolOptions>().is_err());
+
+        let (endpoint, endpoint_options) =
+            PoolConfig::parse_endpoint_options("my-project:max_size=50,idle_timeout=1m")?;
+        assert_eq!(endpoint, "my-project");
+        assert_eq!(endpoint_options.max_size, 50);
+
+        let config = PoolConfig {
+            default: options,
+            endpoints: HashMap::from([(endpoint, endpoint_options)]),
+        };
+        assert_eq!(config.options("my-project"), endpoint_options);
+        assert_eq!(config.options("other-project"), options);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_messages_in_chunks() -> anyhow::Result<()> {
+        let (mut tx, mut rx) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            // ReadyForQuery, split in the middle.
+            tx.write_all(&[b'Z', 0, 0]).await?;
+            tx.flush().await?;
+            tokio::task::yield_now().await;
+            tx.write_all(&[0, 5, b'I']).await?;
+            anyhow::Ok(())
+        });
+
+        let mut reader = MessageReader::default();
+        let msg = reader.read(&mut rx).await?.context("missing message")?;
+        assert_eq!(msg.tag(), TAG_READY_FOR_QUERY);
+        assert_eq!(msg.body(), [TRANSACTION_STATUS_IDLE]);
+        assert!(reader.read(&mut rx).await?.is_none());
+
+        Ok(())
+    }
+
+    fn query(sql: &str) -> Vec<u8> {
+        let mut msg = vec![TAG_QUERY];
+        msg.extend_from_slice(&(4 + sql.len() as u32 + 1).to_be_bytes());
+        msg.extend_from_slice(sql.as_bytes());
+        msg.push(0);
+        msg
+    }
+
+    fn ready_for_query(status: u8) -> Vec<u8> {
+        vec![TAG_READY_FOR_QUERY, 0, 0, 0, 5, status]
+    }
+
+    /// Mock compute answering each query with `ReadyForQuery` of the given transaction status.
+    fn spawn_compute(
+        mut compute: tokio::io::DuplexStream,
+        statuses: Vec<u8>,
+    ) -> tokio::task::JoinHandle<anyhow::Result<()>> {
+        tokio::spawn(async move {
+            let mut reader = MessageReader::default();
+            for status in statuses {
+                let msg = reader.read(&mut compute).await?.context("missing query")?;
+                assert_eq!(msg.tag(), TAG_QUERY);
+                compute.write_all(&ready_for_query(status)).await?;
+            }
+            // Hold the connection until the proxy closes it.
+            assert!(reader.read(&mut compute).await?.is_none());
+            Ok(())
+        })
+    }
+
+    async fn read_message(
+        reader: &mut MessageReader,
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> anyhow::Result<Vec<u8>> {
+        let msg = reader.read(stream).await?.context("missing message")?;
+        Ok(msg.as_bytes().to_vec())
+    }
+
+    /// Client connected to a mock compute through `run_transaction`.
+    struct TestTransaction {
+        client: tokio::io::DuplexStream,
+        client_reader: MessageReader,
+        /// Returns whether the compute is idle again, like `run_transaction`.
+        transaction: tokio::task::JoinHandle<anyhow::Result<bool>>,
+        compute: tokio::task::JoinHandle<anyhow::Result<()>>,
+    }
+
+    /// Start a transaction block on a compute answering with the given statuses,
+    /// and check that the connection stays with the client meanwhile.
+    async fn begin_transaction(statuses: Vec<u8>) -> anyhow::Result<TestTransaction> {
+        let (mut client, mut proxy_client) = tokio::io::duplex(1024);
+        let (compute, mut proxy_compute) = tokio::io::duplex(1024);
+        let compute = spawn_compute(compute, statuses);
+
+        client.write_all(&query("BEGIN")).await?;
+        let mut transaction = tokio::spawn(async move {
+            let mut proxy_client_reader = MessageReader::default();
+            let first_msg = proxy_client_reader
+                .read(&mut proxy_client)
+                .await?
+                .context("missing query")?;
+            run_transaction(
+                &mut proxy_client,
+                &mut proxy_client_reader,
+                &mut proxy_compute,
+                first_msg,
+            )
+            .await
+        });
+
+        // The compute is in a transaction block, the connection stays with the client.
+        let still_lent = tokio::time::timeout(Duration::from_millis(100), &mut transaction).await;
+        assert!(still_lent.is_err());
+        let mut client_reader = MessageReader::default();
+        assert_eq!(
+            read_message(&mut client_reader, &mut client).await?,
+            ready_for_query(b'T')
+        );
+
+        Ok(TestTransaction {
+            client,
+            client_reader,
+            transaction,
+            compute,
+        })
+    }
+
+    #[tokio::test]
+    async fn release_connection_when_idle() -> anyhow::Result<()> {
+        let TestTransaction {
+            mut client,
+            mut client_reader,
+            transaction,
+            compute,
+        } = begin_transaction(vec![b'T', TRANSACTION_STATUS_IDLE]).await?;
+
+        client.write_all(&query("COMMIT")).await?;
+        assert!(transaction.await??);
+        assert_eq!(
+            read_message(&mut client_reader, &mut client).await?,
+            ready_for_query(TRANSACTION_STATUS_IDLE)
+        );
+        compute.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_connection_when_client_leaves_mid_transaction() -> anyhow::Result<()> {
+        let TestTransaction {
+            client,
+            transaction,
+            compute,
+            ..
+        } = begin_transaction(vec![b'T']).await?;
+
+        // The connection in a transaction block is not returned to the pool.
+        drop(client);
+        assert!(!transaction.await??);
+        compute.await??;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,16 @@
 use crate::auth;
 use crate::cancellation::{self, CancelMap};
+use crate::compute;
 use crate::config::{ProxyConfig, TlsConfig};
+use crate::events::{self, SessionAccounting};
+use crate::pool;
+use crate::rate_limit;
 use crate::stream::{MetricsStream, PqStream, Stream};
 use anyhow::{bail, Context};
 use futures::TryFutureExt;
 use metrics::{register_int_counter, IntCounter};
 use once_cell::sync::Lazy;
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 use tokio::io::{AsyncRead, AsyncWrite};
 use utils::pq_proto::{BeMessage as Be, *};
 
@@ -37,6 +41,13 @@ static NUM_BYTES_PROXIED_COUNTER: Lazy<IntCounter> = Lazy::new(|| {
     .unwrap()
 });
 
+/// This function will be called for writes to either direction.
+pub(crate) fn inc_proxied(cnt: usize) {
+    // Consider inventing something more sophisticated
+    // if this ever becomes a bottleneck (cacheline bouncing).
+    NUM_BYTES_PROXIED_COUNTER.inc_by(cnt as u64);
+}
+
 /// A small combinator for pluggable error logging.
 async fn log_error<R, F>(future: F) -> F::Output
 where
@@ -50,6 +61,7 @@ where
 
 pub async fn thread_main(
     config: &'static ProxyConfig,
+    cancel_map: Arc<CancelMap>,
     listener: tokio::net::TcpListener,
 ) -> anyhow::Result<()> {
     scopeguard::defer! {
@@ -60,7 +72,6 @@ pub async fn thread_main(
     // will be inherited by all accepted client sockets.
     socket2::SockRef::from(&listener).set_keepalive(true)?;
 
-    let cancel_map = Arc::new(CancelMap::default());
     loop {
         let (socket, peer_addr) = listener.accept().await?;
         println!("accepted connection from {}", peer_addr);
@@ -71,15 +82,62 @@ pub async fn thread_main(
                 .set_nodelay(true)
                 .context("failed to set socket option")?;
 
-            handle_client(config, &cancel_map, socket).await
+            handle_client(config, &cancel_map, socket, peer_addr.ip(), ClientMode::Tcp).await
         }));
     }
 }
 
+/// Serve a client which tunnels the postgres protocol through a WebSocket.
+pub async fn handle_ws_client(
+    config: &ProxyConfig,
+    cancel_map: &CancelMap,
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send,
+    peer_ip: IpAddr,
+    hostname: Option<&str>,
+) -> anyhow::Result<()> {
+    handle_client(
+        config,
+        cancel_map,
+        stream,
+        peer_ip,
+        ClientMode::Websockets { hostname },
+    )
+    .await
+}
+
+/// How the client has reached us.
+enum ClientMode<'a> {
+    /// Plain postgres protocol over TCP.
+    Tcp,
+    /// Postgres protocol in the binary frames of a WebSocket. TLS is terminated
+    /// by the wss listener, and the hostname comes from the `Host` header.
+    Websockets { hostname: Option<&'a str> },
+}
+
+impl<'a> ClientMode<'a> {
+    /// TLS config for the postgres protocol handshake, if it's our job.
+    fn handshake_tls<'b>(&self, tls: Option<&'b TlsConfig>) -> Option<&'b TlsConfig> {
+        match self {
+            ClientMode::Tcp => tls,
+            ClientMode::Websockets { .. } => None,
+        }
+    }
+
+    /// Hostname the client has used to reach us, which may contain the project name.
+    fn hostname<'s, S>(&'s self, stream: &'s Stream<S>) -> Option<&'s str> {
+        match self {
+            ClientMode::Tcp => stream.sni_hostname(),
+            ClientMode::Websockets { hostname } => *hostname,
+        }
+    }
+}
+
 async fn handle_client(
     config: &ProxyConfig,
     cancel_map: &CancelMap,
     stream: impl AsyncRead + AsyncWrite + Unpin + Send,
+    peer_ip: IpAddr,
+    mode: ClientMode<'_>,
 ) -> anyhow::Result<()> {
     // The `closed` counter will increase when this future is destroyed.
     NUM_CONNECTIONS_ACCEPTED_COUNTER.inc();
@@ -88,25 +146,61 @@ async fn handle_client(
     }
 
     let tls = config.tls_config.as_ref();
-    let (mut stream, params) = match handshake(stream, tls, cancel_map).await? {
+    let handshake_tls = mode.handshake_tls(tls);
+    let (mut stream, params) = match handshake(stream, handshake_tls, cancel_map).await? {
         Some(x) => x,
         None => return Ok(()), // it's a cancellation request
     };
 
+    let extra = auth::ConsoleReqExtra {
+        // Currently it's OK to generate a new UUID **here**, but
+        // it might be better to move this to `cancellation::Session`.
+        session_id: uuid::Uuid::new_v4(),
+        application_name: params.get("application_name"),
+        peer_ip: Some(peer_ip),
+    };
+
+    // Reports the session once it's over, however it ends,
+    // including the clients we reject before the authentication.
+    let mut accounting = SessionAccounting::new(&extra, &params);
+
+    let result = rate_limit::check_new_connection(peer_ip);
+    async { result }.or_else(|e| stream.throw_error(e)).await?;
+
     // Extract credentials which we're going to use for auth.
     let creds = {
-        let sni = stream.get_ref().sni_hostname();
-        let common_name = tls.and_then(|tls| tls.common_name.as_deref());
+        let sni = mode.hostname(stream.get_ref());
+        let common_names = tls.map(|tls| tls.common_names());
         let result = config
             .auth_backend
             .as_ref()
-            .map(|_| auth::ClientCredentials::parse(&params, sni, common_name))
+            .map(|_| auth::ClientCredentials::parse(&params, sni, common_names.as_deref()))
             .transpose();
 
         async { result }.or_else(|e| stream.throw_error(e)).await?
     };
+    accounting.set_auth_method(events::AuthMethod::from(&creds));
+    accounting.set_project(creds.project());
+
+    // Throttle the project before the authentication costs us any console requests.
+    let connection_guard = match creds.project() {
+        Some(project) => {
+            let result = rate_limit::acquire_project_connection(project);
+            Some(async { result }.or_else(|e| stream.throw_error(e)).await?)
+        }
+        None => None,
+    };
 
-    let client = Client::new(stream, creds, &params);
+    let pool = config.connection_pool.as_ref();
+    let client = Client::new(
+        stream,
+        creds,
+        &params,
+        extra,
+        accounting,
+        pool,
+        connection_guard,
+    );
     cancel_map
         .with_session(|session| client.connect_to_db(session))
         .await
@@ -141,9 +235,7 @@ async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
                     if let Some(tls) = tls.take() {
                         // Upgrade raw stream into a secure TLS-backed stream.
                         // NOTE: We've consumed `tls`; this fact will be used later.
-                        stream = PqStream::new(
-                            stream.into_inner().upgrade(tls.to_server_config()).await?,
-                        );
+                        stream = PqStream::new(stream.into_inner().upgrade(tls).await?);
                     }
                 }
                 _ => bail!(ERR_PROTO_VIOLATION),
@@ -183,6 +275,14 @@ struct Client<'a, S> {
     creds: auth::BackendType<'a, auth::ClientCredentials<'a>>,
     /// KV-dictionary with PostgreSQL connection params.
     params: &'a StartupMessageParams,
+    /// Extra query params we'd like to pass to the console.
+    extra: auth::ConsoleReqExtra<'a>,
+    /// Reports the session once it's over.
+    accounting: SessionAccounting,
+    /// Compute connections shared by the clients, if transaction pooling is enabled.
+    pool: Option<&'a pool::ConnectionPool>,
+    /// The project's connection slot, unless the project is only known after the authentication.
+    connection_guard: Option<Option<rate_limit::ConnectionGuard>>,
 }
 
 impl<'a, S> Client<'a, S> {
@@ -191,39 +291,87 @@ impl<'a, S> Client<'a, S> {
         stream: PqStream<S>,
         creds: auth::BackendType<'a, auth::ClientCredentials<'a>>,
         params: &'a StartupMessageParams,
+        extra: auth::ConsoleReqExtra<'a>,
+        accounting: SessionAccounting,
+        pool: Option<&'a pool::ConnectionPool>,
+        connection_guard: Option<Option<rate_limit::ConnectionGuard>>,
     ) -> Self {
         Self {
             stream,
             creds,
             params,
+            extra,
+            accounting,
+            pool,
+            connection_guard,
         }
     }
 }
 
-impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<'_, S> {
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<'_, Stream<S>> {
     /// Let the client authenticate and connect to the designated compute node.
     async fn connect_to_db(self, session: cancellation::Session<'_>) -> anyhow::Result<()> {
         let Self {
             mut stream,
-            creds,
+            mut creds,
             params,
+            extra,
+            mut accounting,
+            pool,
+            connection_guard,
         } = self;
 
-        let extra = auth::ConsoleReqExtra {
-            // Currently it's OK to generate a new UUID **here**, but
-            // it might be better to move this to `cancellation::Session`.
-            session_id: uuid::Uuid::new_v4(),
-            application_name: params.get("application_name"),
-        };
-
         // Authenticate and connect to a compute node.
         let auth = creds.authenticate(&extra, &mut stream).await;
         let node = async { auth }.or_else(|e| stream.throw_error(e)).await?;
         let reported_auth_ok = node.reported_auth_ok;
+        let project = node.project.clone();
+        accounting.set_project(project.as_deref());
+
+        // Holds the project's connection slot until the client disconnects.
+        let _connection_guard = match (connection_guard, &project) {
+            (Some(guard), _) => guard,
+            (None, Some(project)) => {
+                let result = rate_limit::acquire_project_connection(project);
+                async { result }.or_else(|e| stream.throw_error(e)).await?
+            }
+            (None, None) => None,
+        };
+
+        // If the compute isn't ready yet, it might have been woken up elsewhere.
+        let (creds, extra) = (&creds, &extra);
+        let wake_compute = move || async move {
+            match creds.wake_compute(extra).await {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("failed to wake compute: {e}");
+                    None
+                }
+            }
+        };
+
+        if let Some(pool) = pool.and_then(|pool| pool.get(&node, params)) {
+            return Self::proxy_pass_pooled(
+                stream,
+                &pool,
+                node,
+                wake_compute,
+                session,
+                &mut accounting,
+            )
+            .await;
+        }
 
         let (db, cancel_closure) = node
-            .connect(params)
-            .or_else(|e| stream.throw_error(e))
+            .connect(params, wake_compute)
+            .or_else(|e| {
+                // The cached compute address might be stale, e.g. if the compute was
+                // suspended and woken up elsewhere; let the next attempt ask the console.
+                if let Some(project) = &project {
+                    auth::backend::invalidate_node_info(project);
+                }
+                stream.throw_error(e)
+            })
             .await?;
 
         let cancel_key_data = session.enable_query_cancellation(cancel_closure);
@@ -242,48 +390,112 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<'_, S> {
             .write_message_noflush(&Be::BackendKeyData(cancel_key_data))?
             .write_message(&BeMessage::ReadyForQuery)
             .await?;
-
-        /// This function will be called for writes to either direction.
-        fn inc_proxied(cnt: usize) {
-            // Consider inventing something more sophisticated
-            // if this ever becomes a bottleneck (cacheline bouncing).
-            NUM_BYTES_PROXIED_COUNTER.inc_by(cnt as u64);
-        }
+        accounting.connected();
 
         // Starting from here we only proxy the client's traffic.
         let mut db = MetricsStream::new(db.stream, inc_proxied);
-        let mut client = MetricsStream::new(stream.into_inner(), inc_proxied);
+        let mut client = client_metrics_stream(stream, &accounting);
         let _ = tokio::io::copy_bidirectional(&mut client, &mut db).await?;
 
         Ok(())
     }
+
+    /// Finish the client's startup with a pooled compute connection,
+    /// then lend it the pooled connections transaction by transaction.
+    async fn proxy_pass_pooled<W, F>(
+        mut stream: PqStream<Stream<S>>,
+        pool: &pool::EndpointPool,
+        mut node: compute::NodeInfo,
+        mut wake_compute: W,
+        session: cancellation::Session<'_>,
+        accounting: &mut SessionAccounting,
+    ) -> anyhow::Result<()>
+    where
+        W: FnMut() -> F,
+        F: std::future::Future<Output = Option<compute::ComputeConnCfg>>,
+    {
+        // Make sure the compute is reachable before reporting success to the client.
+        let project = node.project.clone();
+        let conn = pool
+            .acquire(&mut node, &mut wake_compute)
+            .or_else(|e| {
+                if let Some(project) = &project {
+                    auth::backend::invalidate_node_info(project);
+                }
+                stream.throw_error(e)
+            })
+            .await?;
+
+        if !node.reported_auth_ok {
+            stream
+                .write_message_noflush(&Be::AuthenticationOk)?
+                .write_message_noflush(&BeParameterStatusMessage::encoding())?;
+        }
+
+        // The client's cancellation key stays the same, while the
+        // compute connection it's routed to changes with each transaction.
+        stream
+            .write_message_noflush(&BeMessage::ParameterStatus(
+                BeParameterStatusMessage::ServerVersion(conn.version()),
+            ))?
+            .write_message_noflush(&Be::BackendKeyData(session.key()))?
+            .write_message(&BeMessage::ReadyForQuery)
+            .await?;
+        pool.release(conn);
+        accounting.connected();
+
+        let mut client = client_metrics_stream(stream, accounting);
+        pool::proxy_pass(&mut client, pool, &mut node, wake_compute, &session).await
+    }
+}
+
+/// Count the client's traffic both in the metrics and in the session's accounting.
+fn client_metrics_stream<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    stream: PqStream<Stream<S>>,
+    accounting: &'a SessionAccounting,
+) -> MetricsStream<Stream<S>, impl FnMut(usize) + 'a, impl FnMut(usize) + 'a> {
+    MetricsStream::new(stream.into_inner(), move |cnt| {
+        inc_proxied(cnt);
+        accounting.count_bytes_out(cnt);
+    })
+    // The bytes read here are written to the compute, which counts them in the metrics.
+    .with_read_count(move |cnt| accounting.count_bytes_in(cnt))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{self, CertSource};
     use crate::{auth, scram};
     use async_trait::async_trait;
     use rstest::rstest;
-    use tokio_postgres::config::SslMode;
+    use tokio_postgres::config::{ChannelBinding, SslMode};
     use tokio_postgres::tls::{MakeTlsConnect, NoTls};
     use tokio_postgres_rustls::MakeRustlsConnect;
 
-    /// Generate a set of TLS certificates: CA + server.
+    /// Generate a set of TLS certificates: CA + server (PEM, along with its key).
     fn generate_certs(
         hostname: &str,
-    ) -> anyhow::Result<(rustls::Certificate, rustls::Certificate, rustls::PrivateKey)> {
+        common_name: &str,
+    ) -> anyhow::Result<(rustls::Certificate, String, String)> {
         let ca = rcgen::Certificate::from_params({
             let mut params = rcgen::CertificateParams::default();
             params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
             params
         })?;
 
-        let cert = rcgen::generate_simple_self_signed(vec![hostname.into()])?;
+        let cert = rcgen::Certificate::from_params({
+            let mut params = rcgen::CertificateParams::new(vec![hostname.into()]);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, format!("*.{common_name}"));
+            params
+        })?;
+
         Ok((
             rustls::Certificate(ca.serialize_der()?),
-            rustls::Certificate(cert.serialize_der_with_signer(&ca)?),
-            rustls::PrivateKey(cert.serialize_private_key_der()),
+            cert.serialize_pem_with_signer(&ca)?,
+            cert.serialize_private_key_pem(),
         ))
     }
 
@@ -307,19 +519,18 @@ mod tests {
         hostname: &'a str,
         common_name: &'a str,
     ) -> anyhow::Result<(ClientConfig<'a>, TlsConfig)> {
-        let (ca, cert, key) = generate_certs(hostname)?;
+        let (ca, cert, key) = generate_certs(hostname, common_name)?;
 
         let tls_config = {
-            let config = rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(vec![cert], key)?
-                .into();
-
-            TlsConfig {
-                config,
-                common_name: Some(common_name.to_string()),
-            }
+            let dir = tempfile::tempdir()?;
+            let (key_path, cert_path) = (dir.path().join("tls.key"), dir.path().join("tls.crt"));
+            std::fs::write(&key_path, key)?;
+            std::fs::write(&cert_path, cert)?;
+
+            config::configure_tls(CertSource {
+                default: Some((key_path, cert_path)),
+                certs_dir: None,
+            })?
         };
 
         let client_config = {
@@ -516,6 +727,64 @@ mod tests {
         proxy.await?
     }
 
+    #[rstest]
+    #[case(ChannelBinding::Disable)]
+    #[case(ChannelBinding::Prefer)]
+    #[case(ChannelBinding::Require)]
+    #[tokio::test]
+    async fn scram_auth_channel_binding(
+        #[case] channel_binding: ChannelBinding,
+    ) -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+
+        let (client_config, server_config) =
+            generate_tls_config("generic-project-name.localhost", "localhost")?;
+        let tls_server_end_point = server_config.tls_server_end_point(None);
+        assert!(tls_server_end_point.cert_digest().is_some());
+        let proxy = tokio::spawn(dummy_proxy(
+            client,
+            Some(server_config),
+            Scram::new("password")?,
+        ));
+
+        let (_client, _conn) = tokio_postgres::Config::new()
+            .user("user")
+            .dbname("db")
+            .password("password")
+            .ssl_mode(SslMode::Require)
+            .channel_binding(channel_binding)
+            .connect_raw(server, client_config.make_tls_connect()?)
+            .await?;
+
+        proxy.await?
+    }
+
+    #[tokio::test]
+    async fn scram_auth_channel_binding_requires_tls() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+
+        let proxy = tokio::spawn(dummy_proxy(client, None, Scram::new("password")?));
+
+        let _client_err = tokio_postgres::Config::new()
+            .user("user")
+            .dbname("db")
+            .password("password")
+            .options("project=generic-project-name")
+            .ssl_mode(SslMode::Disable)
+            .channel_binding(ChannelBinding::Require)
+            .connect_raw(server, NoTls)
+            .await
+            .err() // -> Option<E>
+            .context("client shouldn't be able to connect")?;
+
+        let _server_err = proxy
+            .await?
+            .err() // -> Option<E>
+            .context("server shouldn't accept client")?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn scram_auth_mock() -> anyhow::Result<()> {
         let (client, server) = tokio::io::duplex(1024);
@@ -0,0 +1,385 @@
+//! Throttling of the clients: token bucket rate limits on new connections
+//! and on the console requests, keyed by project and by client's IP address,
+//! as well as a cap on the number of active connections per project.
+
+use crate::error::UserFacingError;
+use anyhow::{bail, ensure, Context};
+use metrics::{register_int_counter_vec, IntCounterVec};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+static REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_rate_limit_rejections_total",
+        "Number of connections and console requests rejected by the limits.",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+/// How often the idle buckets are dropped.
+const GC_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum RateLimitError {
+    #[error("Too many connection attempts, please retry later")]
+    Connections,
+
+    #[error("Too many requests to wake up the compute node, please retry later")]
+    ConsoleRequests,
+
+    #[error("Too many active connections to the project (max {0})")]
+    ActiveConnections(usize),
+}
+
+impl UserFacingError for RateLimitError {}
+
+/// Parameters of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitOptions {
+    /// Tokens added per second, zero disables the limit.
+    pub rate: f64,
+    /// Max number of tokens, i.e. requests allowed in a burst.
+    pub burst: f64,
+}
+
+impl RateLimitOptions {
+    pub const DISABLED: Self = Self {
+        rate: 0.0,
+        burst: 0.0,
+    };
+
+    fn is_disabled(&self) -> bool {
+        self.rate == 0.0
+    }
+}
+
+/// Parses the options from a string like `rate=10,burst=100`.
+impl std::str::FromStr for RateLimitOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> anyhow::Result<Self> {
+        let mut rate = None;
+        let mut burst = None;
+
+        for option in options.split(',') {
+            let (key, value) = option
+                .split_once('=')
+                .with_context(|| format!("bad key-value pair: {option}"))?;
+
+            match key {
+                "rate" => rate = Some(value.parse().context("bad rate limit rate")?),
+                "burst" => burst = Some(value.parse().context("bad rate limit burst")?),
+                unknown => bail!("unknown key: {unknown}"),
+            }
+        }
+
+        let options = Self {
+            rate: rate.context("missing `rate`")?,
+            burst: burst.context("missing `burst`")?,
+        };
+        ensure!(
+            options.rate >= 0.0 && options.burst >= 1.0,
+            "rate must be non-negative and burst must be at least 1"
+        );
+
+        Ok(options)
+    }
+}
+
+impl std::fmt::Display for RateLimitOptions {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "rate={},burst={}", self.rate, self.burst)
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, options: &RateLimitOptions, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * options.rate, options.burst);
+        self.updated_at = now;
+    }
+}
+
+/// Token buckets keyed by project or by IP address.
+pub struct RateLimiter<K> {
+    /// Used in the metrics.
+    name: &'static str,
+    options: RateLimitOptions,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(name: &'static str, options: RateLimitOptions) -> Self {
+        Self {
+            name,
+            options,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the key's bucket, returns false if it's empty.
+    pub fn check(&self, key: K) -> bool {
+        self.check_and(key, || true)
+    }
+
+    /// Like [`Self::check`], but the token is only taken if `other`
+    /// (e.g. another limiter) allows the request as well.
+    pub fn check_and(&self, key: K, other: impl FnOnce() -> bool) -> bool {
+        if self.options.is_disabled() {
+            return other();
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.entry(key).or_insert(TokenBucket {
+            tokens: self.options.burst,
+            updated_at: now,
+        });
+
+        bucket.refill(&self.options, now);
+        if bucket.tokens < 1.0 {
+            REJECTIONS.with_label_values(&[self.name]).inc();
+            return false;
+        }
+        if !other() {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Drop the full buckets, they're no different from the missing ones.
+    fn gc(&self) {
+        let now = Instant::now();
+        self.buckets.lock().retain(|_, bucket| {
+            bucket.refill(&self.options, now);
+            bucket.tokens < self.options.burst
+        });
+    }
+}
+
+/// Counts the active connections per project.
+pub struct ConnectionLimiter {
+    /// Zero disables the limit.
+    max_connections: usize,
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// Holds the project's connection slot until dropped.
+pub struct ConnectionGuard {
+    project: String,
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            active: Default::default(),
+        }
+    }
+
+    pub fn acquire(&self, project: &str) -> Result<Option<ConnectionGuard>, RateLimitError> {
+        if self.max_connections == 0 {
+            return Ok(None);
+        }
+
+        let mut active = self.active.lock();
+        let count = active.entry(project.to_owned()).or_default();
+        if *count >= self.max_connections {
+            REJECTIONS.with_label_values(&["active_connections"]).inc();
+            return Err(RateLimitError::ActiveConnections(self.max_connections));
+        }
+
+        *count += 1;
+        Ok(Some(ConnectionGuard {
+            project: project.to_owned(),
+            active: Arc::clone(&self.active),
+        }))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock();
+        if let Some(count) = active.get_mut(&self.project) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.project);
+            }
+        }
+    }
+}
+
+/// All limits of the proxy.
+pub struct Limits {
+    pub connections_per_ip: RateLimiter<IpAddr>,
+    pub connections_per_project: RateLimiter<String>,
+    pub console_requests_per_ip: RateLimiter<IpAddr>,
+    pub console_requests_per_project: RateLimiter<String>,
+    pub active_connections_per_project: ConnectionLimiter,
+}
+
+static LIMITS: OnceCell<Limits> = OnceCell::new();
+
+/// Enable the limits. Without this, nothing is throttled.
+pub fn configure(limits: Limits) -> anyhow::Result<()> {
+    LIMITS
+        .set(limits)
+        .map_err(|_| anyhow::anyhow!("rate limits are already configured"))
+}
+
+/// Check the rate of the new connections from the address.
+pub fn check_new_connection(peer_ip: IpAddr) -> Result<(), RateLimitError> {
+    match LIMITS.get() {
+        Some(limits) if !limits.connections_per_ip.check(peer_ip) => {
+            Err(RateLimitError::Connections)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check the rate of the new connections to the project and take
+/// one of its connection slots for the lifetime of the connection.
+pub fn acquire_project_connection(
+    project: &str,
+) -> Result<Option<ConnectionGuard>, RateLimitError> {
+    let limits = match LIMITS.get() {
+        Some(limits) => limits,
+        None => return Ok(None),
+    };
+
+    if !limits.connections_per_project.check(project.to_owned()) {
+        return Err(RateLimitError::Connections);
+    }
+
+    limits.active_connections_per_project.acquire(project)
+}
+
+/// Check the rate of the console requests on behalf of the project and the address.
+pub fn check_console_request(project: &str, peer_ip: Option<IpAddr>) -> Result<(), RateLimitError> {
+    let limits = match LIMITS.get() {
+        Some(limits) => limits,
+        None => return Ok(()),
+    };
+
+    // A request rejected by either of the limits shouldn't count against the other one.
+    let ok = limits
+        .console_requests_per_project
+        .check_and(project.to_owned(), || {
+            peer_ip.map_or(true, |ip| limits.console_requests_per_ip.check(ip))
+        });
+
+    match ok {
+        true => Ok(()),
+        false => Err(RateLimitError::ConsoleRequests),
+    }
+}
+
+/// Periodically drop the buckets of the clients which have calmed down.
+pub async fn gc_loop() -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(GC_PERIOD);
+    loop {
+        interval.tick().await;
+
+        if let Some(limits) = LIMITS.get() {
+            limits.connections_per_ip.gc();
+            limits.connections_per_project.gc();
+            limits.console_requests_per_ip.gc();
+            limits.console_requests_per_project.gc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_limit_options() -> anyhow::Result<()> {
+        let options: RateLimitOptions = "rate=10,burst=100".parse()?;
+        assert_eq!(options.rate, 10.0);
+        assert_eq!(options.burst, 100.0);
+        assert_eq!(options.to_string().parse::<RateLimitOptions>()?, options);
+
+        assert!("rate=10".parse::<RateLimitOptions>().is_err());
+        assert!("rate=-1,burst=10".parse::<RateLimitOptions>().is_err());
+        assert!("rate=10,burst=0".parse::<RateLimitOptions>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(
+            "test",
+            RateLimitOptions {
+                rate: 1000.0,
+                burst: 2.0,
+            },
+        );
+
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        // Other keys have their own buckets.
+        assert!(limiter.check("b"));
+
+        // The bucket is refilled over time.
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limiter.check("a"));
+
+        let disabled = RateLimiter::new("test", RateLimitOptions::DISABLED);
+        assert!((0..100).all(|_| disabled.check("a")));
+    }
+
+    #[test]
+    fn token_bucket_and_other() {
+        let options = RateLimitOptions {
+            rate: 0.001,
+            burst: 1.0,
+        };
+        let first = RateLimiter::new("first", options);
+        let second = RateLimiter::new("second", options);
+
+        // The token isn't taken if the other limiter rejects the request...
+        assert!(second.check("a"));
+        assert!(!first.check_and("a", || second.check("a")));
+        // ... nor is the other limiter consulted if this one does.
+        assert!(first.check("a"));
+        assert!(!first.check_and("a", || panic!("must not be called")));
+
+        let disabled = RateLimiter::new("disabled", RateLimitOptions::DISABLED);
+        assert!(!disabled.check_and("a", || false));
+    }
+
+    #[test]
+    fn active_connections() -> anyhow::Result<()> {
+        let limiter = ConnectionLimiter::new(2);
+
+        let first = limiter.acquire("project")?;
+        let _second = limiter.acquire("project")?;
+        assert!(limiter.acquire("project").is_err());
+        assert!(limiter.acquire("other-project").is_ok());
+
+        drop(first);
+        assert!(limiter.acquire("project").is_ok());
+
+        Ok(())
+    }
+}
@@ -27,6 +27,40 @@ impl std::str::FromStr for TlsServerEndPoint {
     }
 }
 
+/// Channel binding as seen by the server, i.e. whether we've offered
+/// `SCRAM-SHA-256-PLUS` and whether the client has chosen it.
+#[derive(Debug, Clone, Copy)]
+pub enum ServerChannelBinding<'a> {
+    /// Channel binding isn't available, e.g. the connection isn't secure.
+    NotSupported,
+    /// We've offered channel binding, but the client has chosen `SCRAM-SHA-256`.
+    NotUsed,
+    /// The client has chosen `SCRAM-SHA-256-PLUS`; holds our certificate's digest.
+    Required(&'a [u8]),
+}
+
+impl ServerChannelBinding<'_> {
+    /// Check the client's channel binding flag against the chosen mechanism.
+    fn check_flag<T>(&self, cbind_flag: &ChannelBinding<T>) -> sasl::Result<()> {
+        use ChannelBinding::*;
+        match (self, cbind_flag) {
+            (Self::Required(_), Required(_)) => Ok(()),
+            (Self::Required(_), _) => Err(SaslError::ChannelBindingFailed(
+                "SCRAM-SHA-256-PLUS requires channel binding",
+            )),
+            (_, Required(_)) => Err(SaslError::ChannelBindingFailed(
+                "channel binding requires SCRAM-SHA-256-PLUS",
+            )),
+            // The client supports channel binding, yet it thinks we don't.
+            // This might've been caused by a MITM attack.
+            (Self::NotUsed, NotSupportedServer) => Err(SaslError::ChannelBindingFailed(
+                "client didn't see SCRAM-SHA-256-PLUS offered by server",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
 enum ExchangeState {
     /// Waiting for [`ClientFirstMessage`].
     Initial,
@@ -43,20 +77,20 @@ pub struct Exchange<'a> {
     state: ExchangeState,
     secret: &'a ServerSecret,
     nonce: fn() -> [u8; SCRAM_RAW_NONCE_LEN],
-    cert_digest: Option<&'a [u8]>,
+    channel_binding: ServerChannelBinding<'a>,
 }
 
 impl<'a> Exchange<'a> {
     pub fn new(
         secret: &'a ServerSecret,
         nonce: fn() -> [u8; SCRAM_RAW_NONCE_LEN],
-        cert_digest: Option<&'a [u8]>,
+        channel_binding: ServerChannelBinding<'a>,
     ) -> Self {
         Self {
             state: ExchangeState::Initial,
             secret,
             nonce,
-            cert_digest,
+            channel_binding,
         }
     }
 }
@@ -70,6 +104,8 @@ impl sasl::Mechanism for Exchange<'_> {
             Initial => {
                 let client_first_message =
                     ClientFirstMessage::parse(input).ok_or(SaslError::BadClientMessage)?;
+                self.channel_binding
+                    .check_flag(&client_first_message.cbind_flag)?;
 
                 let server_first_message = client_first_message.build_server_first_message(
                     &(self.nonce)(),
@@ -94,10 +130,9 @@ impl sasl::Mechanism for Exchange<'_> {
                 let client_final_message =
                     ClientFinalMessage::parse(input).ok_or(SaslError::BadClientMessage)?;
 
-                let channel_binding = cbind_flag.encode(|_| {
-                    self.cert_digest
-                        .map(base64::encode)
-                        .ok_or(SaslError::ChannelBindingFailed("no cert digest provided"))
+                let channel_binding = cbind_flag.encode(|_| match self.channel_binding {
+                    ServerChannelBinding::Required(cert_digest) => Ok(cert_digest),
+                    _ => Err(SaslError::ChannelBindingFailed("no cert digest provided")),
                 })?;
 
                 // This might've been caused by a MITM attack
@@ -133,3 +168,76 @@ impl sasl::Mechanism for Exchange<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::{password::SaltedPassword, ScramKey};
+    use super::*;
+    use sasl::{Mechanism, Step};
+
+    const PASSWORD: &str = "pencil";
+    const SALT: &[u8] = b"salt and pepper";
+    const ITERATIONS: u32 = 4096;
+    const CERT_DIGEST: [u8; 32] = [42; 32];
+    const GS2_HEADER: &str = "p=tls-server-end-point,,";
+
+    fn nonce() -> [u8; SCRAM_RAW_NONCE_LEN] {
+        [1; SCRAM_RAW_NONCE_LEN]
+    }
+
+    fn client_key() -> ScramKey {
+        SaltedPassword::new(PASSWORD.as_bytes(), SALT, ITERATIONS).client_key()
+    }
+
+    /// Authenticate as a `SCRAM-SHA-256-PLUS` client which sees the given certificate digest.
+    fn exchange_with_channel_binding(client_cert_digest: &[u8]) -> sasl::Result<ScramKey> {
+        let secret = ServerSecret::build(PASSWORD, SALT, ITERATIONS).expect("ascii password");
+        let exchange = Exchange::new(&secret, nonce, ServerChannelBinding::Required(&CERT_DIGEST));
+
+        let client_first_message_bare = "n=,r=rOprNGfwEbeRWgbNEkqO";
+        let client_first_message = format!("{GS2_HEADER}{client_first_message_bare}");
+        let (exchange, server_first_message) = match exchange.exchange(&client_first_message)? {
+            (Step::Continue(exchange), msg) => (exchange, msg),
+            (Step::Authenticated(_), _) => panic!("authenticated before the final message"),
+        };
+
+        let combined_nonce = server_first_message
+            .split(',')
+            .find_map(|part| part.strip_prefix("r="))
+            .expect("server nonce");
+        let mut cbind_data = GS2_HEADER.as_bytes().to_vec();
+        cbind_data.extend_from_slice(client_cert_digest);
+        let without_proof = format!("c={},r={combined_nonce}", base64::encode(cbind_data));
+
+        let client_key = client_key();
+        let signature = SignatureBuilder {
+            client_first_message_bare,
+            server_first_message: &server_first_message,
+            client_final_message_without_proof: &without_proof,
+        }
+        .build(&client_key.sha256());
+        // ClientProof is ClientKey xored with ClientSignature, just as the other way round.
+        let proof = signature.derive_client_key(&client_key.as_bytes());
+        let client_final_message = format!("{without_proof},p={}", base64::encode(proof));
+
+        match exchange.exchange(&client_final_message)? {
+            (Step::Authenticated(key), _) => Ok(key),
+            (Step::Continue(_), _) => panic!("not authenticated after the final message"),
+        }
+    }
+
+    #[test]
+    fn exchange_tls_server_end_point() -> sasl::Result<()> {
+        let key = exchange_with_channel_binding(&CERT_DIGEST)?;
+        assert!(key == client_key());
+
+        Ok(())
+    }
+
+    #[test]
+    fn exchange_tls_server_end_point_mismatch() {
+        // The client sees another certificate, e.g. the one of a MITM.
+        let result = exchange_with_channel_binding(&[7; 32]);
+        assert!(matches!(result, Err(SaslError::ChannelBindingFailed(_))));
+    }
+}
@@ -1,10 +1,9 @@
+use crate::config::{ServerCertDigest, TlsConfig};
 use crate::error::UserFacingError;
 use anyhow::bail;
 use bytes::BytesMut;
 use pin_project_lite::pin_project;
-use rustls::ServerConfig;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::{io, task};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
@@ -136,7 +135,12 @@ pin_project! {
         /// which may then be upgraded into a secure stream.
         Raw { #[pin] raw: S },
         /// We box [`TlsStream`] since it can be quite large.
-        Tls { #[pin] tls: Box<TlsStream<S>> },
+        Tls {
+            #[pin]
+            tls: Box<TlsStream<S>>,
+            /// Channel binding data of the certificate we've presented.
+            tls_server_end_point: ServerCertDigest,
+        },
     }
 }
 
@@ -150,7 +154,18 @@ impl<S> Stream<S> {
     pub fn sni_hostname(&self) -> Option<&str> {
         match self {
             Stream::Raw { .. } => None,
-            Stream::Tls { tls } => tls.get_ref().1.sni_hostname(),
+            Stream::Tls { tls, .. } => tls.get_ref().1.sni_hostname(),
+        }
+    }
+
+    /// Return channel binding data if the connection is secure.
+    pub fn tls_server_end_point(&self) -> ServerCertDigest {
+        match self {
+            Stream::Raw { .. } => ServerCertDigest::Undefined,
+            Stream::Tls {
+                tls_server_end_point,
+                ..
+            } => *tls_server_end_point,
         }
     }
 }
@@ -167,11 +182,21 @@ pub enum StreamUpgradeError {
 
 impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
     /// If possible, upgrade raw stream into a secure TLS-based stream.
-    pub async fn upgrade(self, cfg: Arc<ServerConfig>) -> Result<Self, StreamUpgradeError> {
+    pub async fn upgrade(self, cfg: &TlsConfig) -> Result<Self, StreamUpgradeError> {
         match self {
             Stream::Raw { raw } => {
-                let tls = Box::new(tokio_rustls::TlsAcceptor::from(cfg).accept(raw).await?);
-                Ok(Stream::Tls { tls })
+                let (config, digest) = cfg.to_connection_config();
+                let acceptor = tokio_rustls::TlsAcceptor::from(config);
+                let tls = Box::new(acceptor.accept(raw).await?);
+                // A resumed session doesn't resolve the certificate, look it up by SNI then.
+                let sni = tls.get_ref().1.sni_hostname();
+                let presented = *digest.lock();
+                let tls_server_end_point =
+                    presented.unwrap_or_else(|| cfg.tls_server_end_point(sni));
+                Ok(Stream::Tls {
+                    tls,
+                    tls_server_end_point,
+                })
             }
             Stream::Tls { .. } => Err(StreamUpgradeError::AlreadyTls),
         }
@@ -187,7 +212,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_read(context, buf),
-            Tls { tls } => tls.poll_read(context, buf),
+            Tls { tls, .. } => tls.poll_read(context, buf),
         }
     }
 }
@@ -201,7 +226,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_write(context, buf),
-            Tls { tls } => tls.poll_write(context, buf),
+            Tls { tls, .. } => tls.poll_write(context, buf),
         }
     }
 
@@ -212,7 +237,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_flush(context),
-            Tls { tls } => tls.poll_flush(context),
+            Tls { tls, .. } => tls.poll_flush(context),
         }
     }
 
@@ -223,7 +248,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_shutdown(context),
-            Tls { tls } => tls.poll_shutdown(context),
+            Tls { tls, .. } => tls.poll_shutdown(context),
         }
     }
 }
@@ -231,35 +256,54 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
 pin_project! {
     /// This stream tracks all writes and calls user provided
     /// callback when the underlying stream is flushed.
-    pub struct MetricsStream<S, W> {
+    /// Optionally, it also reports the reads as they happen.
+    pub struct MetricsStream<S, W, R> {
         #[pin]
         stream: S,
         write_count: usize,
         inc_write_count: W,
+        inc_read_count: R,
     }
 }
 
-impl<S, W> MetricsStream<S, W> {
+impl<S, W> MetricsStream<S, W, fn(usize)> {
     pub fn new(stream: S, inc_write_count: W) -> Self {
         Self {
             stream,
             write_count: 0,
             inc_write_count,
+            inc_read_count: |_| {},
+        }
+    }
+}
+
+impl<S, W, R> MetricsStream<S, W, R> {
+    /// Call the user provided callback on every read as well.
+    pub fn with_read_count<R2: FnMut(usize)>(self, inc_read_count: R2) -> MetricsStream<S, W, R2> {
+        MetricsStream {
+            stream: self.stream,
+            write_count: self.write_count,
+            inc_write_count: self.inc_write_count,
+            inc_read_count,
         }
     }
 }
 
-impl<S: AsyncRead + Unpin, W> AsyncRead for MetricsStream<S, W> {
+impl<S: AsyncRead + Unpin, W, R: FnMut(usize)> AsyncRead for MetricsStream<S, W, R> {
     fn poll_read(
         self: Pin<&mut Self>,
         context: &mut task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> task::Poll<io::Result<()>> {
-        self.project().stream.poll_read(context, buf)
+        let this = self.project();
+        let filled = buf.filled().len();
+        this.stream.poll_read(context, buf).map_ok(|()| {
+            (this.inc_read_count)(buf.filled().len() - filled);
+        })
     }
 }
 
-impl<S: AsyncWrite + Unpin, W: FnMut(usize)> AsyncWrite for MetricsStream<S, W> {
+impl<S: AsyncWrite + Unpin, W: FnMut(usize), R> AsyncWrite for MetricsStream<S, W, R> {
     fn poll_write(
         self: Pin<&mut Self>,
         context: &mut task::Context<'_>,
@@ -0,0 +1,50 @@
+//! Permission checks of the JWT claims presented to the safekeeper,
+//! see docs/authentication.md for the scopes.
+
+use anyhow::{bail, Result};
+use utils::auth::{Claims, Scope};
+use utils::id::TenantId;
+
+/// Checks whether the claims grant access to the data of the given tenant,
+/// or to the safekeeper-wide APIs if `tenant_id` is `None`.
+pub fn check_permission(claims: &Claims, tenant_id: Option<TenantId>) -> Result<()> {
+    match (&claims.scope, tenant_id) {
+        (Scope::Tenant, None) => {
+            bail!("Attempt to access management api with tenant scope. Permission denied")
+        }
+        (Scope::Tenant, Some(tenant_id)) => {
+            if claims.tenant_id != Some(tenant_id) {
+                bail!("Tenant id mismatch. Permission denied")
+            }
+            Ok(())
+        }
+        (Scope::PageServerApi, _) => {
+            bail!("PageServerApi scope makes no sense for Safekeeper. Permission denied")
+        }
+        (Scope::SafekeeperData, _) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_permission() {
+        let tenant_id = TenantId::generate();
+        let other_tenant_id = TenantId::generate();
+
+        let tenant = Claims::new(Some(tenant_id), Scope::Tenant);
+        assert!(check_permission(&tenant, Some(tenant_id)).is_ok());
+        assert!(check_permission(&tenant, Some(other_tenant_id)).is_err());
+        assert!(check_permission(&tenant, None).is_err());
+
+        let safekeeper_data = Claims::new(None, Scope::SafekeeperData);
+        assert!(check_permission(&safekeeper_data, Some(tenant_id)).is_ok());
+        assert!(check_permission(&safekeeper_data, None).is_ok());
+
+        let pageserver_api = Claims::new(None, Scope::PageServerApi);
+        assert!(check_permission(&pageserver_api, Some(tenant_id)).is_err());
+        assert!(check_permission(&pageserver_api, None).is_err());
+    }
+}
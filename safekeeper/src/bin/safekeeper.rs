@@ -1,7 +1,7 @@
 //
 // Main entry point for the safekeeper executable
 //
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{App, Arg};
 use const_format::formatcp;
 use daemonize::Daemonize;
@@ -24,7 +24,9 @@ use safekeeper::defaults::{
 };
 use safekeeper::http;
 use safekeeper::remove_wal;
+use safekeeper::timeline_offload;
 use safekeeper::wal_backup;
+use safekeeper::wal_check;
 use safekeeper::wal_service;
 use safekeeper::GlobalTimelines;
 use safekeeper::SafeKeeperConf;
@@ -134,12 +136,67 @@ fn main() -> anyhow::Result<()> {
                 .default_missing_value("true")
                 .help("Enable/disable WAL backup to s3. When disabled, safekeeper removes WAL ignoring WAL backup horizon."),
         )
+        .arg(
+            Arg::new("wal-backup-compression")
+                .long("wal-backup-compression")
+                .takes_value(true)
+                .default_value("none")
+                .help("Compression of the WAL segments backed up to s3: none or zstd. Segments are readable regardless of the codec they were uploaded with."),
+        )
+        .arg(
+            Arg::new("enable-peer-recovery")
+                .long("enable-peer-recovery")
+                .takes_value(true)
+                .default_value("true")
+                .default_missing_value("true")
+                .help("Enable/disable fetching the missing committed WAL from other safekeepers of the same term, while no compute is connected."),
+        )
+        .arg(
+            Arg::new("enable-timeline-offload")
+                .long("enable-timeline-offload")
+                .takes_value(true)
+                .default_value("true")
+                .default_missing_value("true")
+                .help("Enable/disable offloading of the idle timelines from memory. Offloaded timelines keep only the control file state and are loaded back on the next request."),
+        )
+        .arg(
+            Arg::new("timeline-offload-after")
+                .long("timeline-offload-after")
+                .takes_value(true)
+                .help("How long the timeline should stay idle (no computes, WAL backed up, pageserver caught up) to be offloaded from memory (default: 10m)"),
+        )
+        .arg(
+            Arg::new("max-slot-wal-keep-size")
+                .long("max-slot-wal-keep-size")
+                .takes_value(true)
+                .help("How far behind the end of WAL, in megabytes, logical replication slots of the compute can hold the WAL, like the postgres setting of the same name. Unlimited if not set."),
+        )
+        .arg(
+            Arg::new("wal-check-interval")
+                .long("wal-check-interval")
+                .takes_value(true)
+                .help("Period of checking the committed WAL against the backup archive and the other safekeepers of the timeline, e.g. '1h'. Disabled if not set."),
+        )
         .arg(
             Arg::new("auth-validation-public-key-path")
                 .long("auth-validation-public-key-path")
                 .takes_value(true)
                 .help("Path to an RSA .pem public key which is used to check JWT tokens")
         )
+        .arg(
+            Arg::new("enable-pg-auth")
+                .long("enable-pg-auth")
+                .takes_value(true)
+                .default_value("false")
+                .default_missing_value("true")
+                .help("Enable/disable checking of JWT tokens, passed as the password, on the postgres protocol connections. Requires --auth-validation-public-key-path. Peer connections of the safekeeper use the token from the SAFEKEEPER_AUTH_TOKEN environment variable."),
+        )
+        .arg(
+            Arg::new("peer-http-addrs")
+                .long("peer-http-addrs")
+                .takes_value(true)
+                .help("Comma separated HTTP API addresses of the other safekeepers, e.g. 'http://sk-1:7676,http://sk-2:7676'. Timelines can be pulled from these safekeepers only, authenticating with the token from the SAFEKEEPER_AUTH_TOKEN environment variable."),
+        )
         .get_matches();
 
     if let Some(addr) = arg_matches.value_of("dump-control-file") {
@@ -211,10 +268,57 @@ fn main() -> anyhow::Result<()> {
         .unwrap()
         .parse()
         .context("failed to parse bool enable-s3-offload bool")?;
+    conf.wal_backup_compression = arg_matches
+        .value_of("wal-backup-compression")
+        .unwrap()
+        .parse()
+        .map_err(|e: String| anyhow!(e))
+        .context("failed to parse wal-backup-compression")?;
+
+    conf.peer_recovery_enabled = arg_matches
+        .value_of("enable-peer-recovery")
+        .unwrap()
+        .parse()
+        .context("failed to parse bool enable-peer-recovery")?;
+
+    conf.timeline_offload_enabled = arg_matches
+        .value_of("enable-timeline-offload")
+        .unwrap()
+        .parse()
+        .context("failed to parse bool enable-timeline-offload")?;
+    if let Some(offload_after) = arg_matches.value_of("timeline-offload-after") {
+        conf.timeline_offload_after = humantime::parse_duration(offload_after)
+            .context("failed to parse timeline-offload-after")?;
+    }
+
+    if let Some(keep_size) = arg_matches.value_of("max-slot-wal-keep-size") {
+        let keep_size_mb: u64 = keep_size
+            .parse()
+            .context("failed to parse max-slot-wal-keep-size")?;
+        conf.max_slot_wal_keep_size = Some(keep_size_mb * 1024 * 1024);
+    }
+
+    if let Some(interval) = arg_matches.value_of("wal-check-interval") {
+        conf.wal_check_interval = Some(
+            humantime::parse_duration(interval).context("failed to parse wal-check-interval")?,
+        );
+    }
 
     conf.auth_validation_public_key_path = arg_matches
         .value_of("auth-validation-public-key-path")
         .map(PathBuf::from);
+    conf.pg_auth_enabled = arg_matches
+        .value_of("enable-pg-auth")
+        .unwrap()
+        .parse()
+        .context("failed to parse bool enable-pg-auth")?;
+    if conf.pg_auth_enabled && conf.auth_validation_public_key_path.is_none() {
+        bail!("--enable-pg-auth requires --auth-validation-public-key-path");
+    }
+
+    if let Some(addrs) = arg_matches.value_of("peer-http-addrs") {
+        conf.peer_http_addrs = addrs.split(',').map(str::to_owned).collect();
+    }
 
     start_safekeeper(conf, given_id, arg_matches.is_present("init"))
 }
@@ -303,11 +407,12 @@ fn start_safekeeper(mut conf: SafeKeeperConf, given_id: Option<NodeId>, init: bo
     GlobalTimelines::init(conf.clone(), wal_backup_launcher_tx)?;
 
     let conf_ = conf.clone();
+    let http_auth = auth.clone();
     threads.push(
         thread::Builder::new()
             .name("http_endpoint_thread".into())
             .spawn(|| {
-                let router = http::make_router(conf_, auth);
+                let router = http::make_router(conf_, http_auth);
                 endpoint::serve_thread_main(
                     router,
                     http_listener,
@@ -318,11 +423,15 @@ fn start_safekeeper(mut conf: SafeKeeperConf, given_id: Option<NodeId>, init: bo
     );
 
     let conf_cloned = conf.clone();
+    let pg_auth = if conf.pg_auth_enabled {
+        auth.clone()
+    } else {
+        None
+    };
     let safekeeper_thread = thread::Builder::new()
         .name("Safekeeper thread".into())
         .spawn(|| {
-            // TODO: add auth
-            if let Err(e) = wal_service::thread_main(conf_cloned, pg_listener) {
+            if let Err(e) = wal_service::thread_main(conf_cloned, pg_listener, pg_auth) {
                 info!("safekeeper thread terminated: {e}");
             }
         })
@@ -364,6 +473,28 @@ fn start_safekeeper(mut conf: SafeKeeperConf, given_id: Option<NodeId>, init: bo
             })?,
     );
 
+    if conf.timeline_offload_enabled {
+        let conf_ = conf.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("timeline offload thread".into())
+                .spawn(|| {
+                    timeline_offload::thread_main(conf_);
+                })?,
+        );
+    }
+
+    if let Some(interval) = conf.wal_check_interval {
+        let conf_ = conf.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("WAL check thread".into())
+                .spawn(move || {
+                    wal_check::thread_main(conf_, interval);
+                })?,
+        );
+    }
+
     // TODO: put more thoughts into handling of failed threads
     // We probably should restart them.
 
@@ -18,6 +18,7 @@ use tokio::{runtime, time::sleep};
 use tracing::*;
 use url::Url;
 
+use crate::recovery;
 use crate::GlobalTimelines;
 use crate::SafeKeeperConf;
 use etcd_broker::{
@@ -201,7 +202,7 @@ struct Lease {
     ka_stream: LeaseKeepAliveStream,
 }
 
-/// Push once in a while data about all active timelines to the broker.
+/// Push once in a while data about all active and offloaded timelines to the broker.
 async fn push_loop(conf: SafeKeeperConf) -> anyhow::Result<()> {
     let mut client = Client::connect(&conf.broker_endpoints, None).await?;
     let mut leases: HashMap<TenantTimelineId, Lease> = HashMap::new();
@@ -215,12 +216,24 @@ async fn push_loop(conf: SafeKeeperConf) -> anyhow::Result<()> {
         let mut active_tlis = GlobalTimelines::get_all();
         active_tlis.retain(|tli| tli.is_active());
 
-        let active_tlis_set: HashSet<TenantTimelineId> =
-            active_tlis.iter().map(|tli| tli.ttid).collect();
+        let mut tlis_info: Vec<(TenantTimelineId, SkTimelineInfo)> = active_tlis
+            .iter()
+            .map(|tli| (tli.ttid, tli.get_public_info(&conf)))
+            .collect();
+        let mut tlis_set: HashSet<TenantTimelineId> =
+            tlis_info.iter().map(|(ttid, _)| *ttid).collect();
+        // Offloaded timelines keep publishing the info they had when offloaded, so that
+        // peers and pageservers still know about them. The timeline might have been
+        // offloaded after we got the active ones, don't push it twice.
+        for (ttid, sk_info) in GlobalTimelines::get_offloaded_info() {
+            if tlis_set.insert(ttid) {
+                tlis_info.push((ttid, sk_info));
+            }
+        }
 
         // // Get and maintain (if not yet) per timeline lease to automatically delete obsolete data.
-        for tli in &active_tlis {
-            if let Entry::Vacant(v) = leases.entry(tli.ttid) {
+        for (ttid, _) in &tlis_info {
+            if let Entry::Vacant(v) = leases.entry(*ttid) {
                 let lease = client.lease_grant(LEASE_TTL_SEC, None).await?;
                 let (keeper, ka_stream) = client.lease_keep_alive(lease.id()).await?;
                 v.insert(Lease {
@@ -230,17 +243,16 @@ async fn push_loop(conf: SafeKeeperConf) -> anyhow::Result<()> {
                 });
             }
         }
-        leases.retain(|ttid, _| active_tlis_set.contains(ttid));
+        leases.retain(|ttid, _| tlis_set.contains(ttid));
 
         // Push data concurrently to not suffer from latency, with many timelines it can be slow.
-        let handles = active_tlis
-            .iter()
-            .map(|tli| {
-                let sk_info = tli.get_public_info(&conf);
+        let handles = tlis_info
+            .into_iter()
+            .map(|(ttid, sk_info)| {
                 let key =
-                    timeline_safekeeper_path(conf.broker_etcd_prefix.clone(), tli.ttid, conf.my_id);
-                let lease = leases.remove(&tli.ttid).unwrap();
-                tokio::spawn(push_sk_info(tli.ttid, client.clone(), key, sk_info, lease))
+                    timeline_safekeeper_path(conf.broker_etcd_prefix.clone(), ttid, conf.my_id);
+                let lease = leases.remove(&ttid).unwrap();
+                tokio::spawn(push_sk_info(ttid, client.clone(), key, sk_info, lease))
             })
             .collect::<Vec<_>>();
         for h in handles {
@@ -281,9 +293,19 @@ async fn pull_loop(conf: SafeKeeperConf) -> Result<()> {
         match subscription.value_updates.recv().await {
             Some(new_info) => {
                 // note: there are blocking operations below, but it's considered fine for now
-                if let Ok(tli) = GlobalTimelines::get(new_info.key.id) {
+                if let Ok(tli) =
+                    GlobalTimelines::get_for_peer_info(new_info.key.id, &new_info.value)
+                {
                     tli.record_safekeeper_info(&new_info.value, new_info.key.node_id)
-                        .await?
+                        .await?;
+
+                    if conf.peer_recovery_enabled && new_info.key.node_id != conf.my_id {
+                        if let Some(donor) =
+                            tli.try_start_peer_recovery(&new_info.value, new_info.key.node_id)
+                        {
+                            spawn(recovery::recovery_main(tli, donor));
+                        }
+                    }
                 }
             }
             None => {
@@ -18,7 +18,7 @@ use crate::SafeKeeperConf;
 use std::convert::TryInto;
 
 // contains persistent metadata for safekeeper
-const CONTROL_FILE_NAME: &str = "safekeeper.control";
+pub const CONTROL_FILE_NAME: &str = "safekeeper.control";
 // needed to atomically update the state using `rename`
 const CONTROL_FILE_NAME_PARTIAL: &str = "safekeeper.control.partial";
 pub const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
@@ -71,6 +71,20 @@ impl FileStorage {
         Ok(store)
     }
 
+    /// Create file storage for a timeline in the given directory rather than in the
+    /// timeline one, e.g. to prepare the timeline before moving it in place. Doesn't persist it yet.
+    pub fn create_new_in(
+        timeline_dir: PathBuf,
+        conf: &SafeKeeperConf,
+        state: SafeKeeperState,
+    ) -> FileStorage {
+        FileStorage {
+            timeline_dir,
+            conf: conf.clone(),
+            state,
+        }
+    }
+
     /// Check the magic/version in the on-disk data and deserialize it, if possible.
     fn deser_sk_state(buf: &mut &[u8]) -> Result<SafeKeeperState> {
         // Read the version independent part
@@ -1,19 +1,24 @@
 //! Part of Safekeeper pretending to be Postgres, i.e. handling Postgres
 //! protocol commands.
 
+use crate::auth::check_permission;
 use crate::json_ctrl::{handle_json_ctrl, AppendLogicalMessage};
 use crate::receive_wal::ReceiveWalConn;
 
 use crate::send_wal::ReplicationConn;
 
+use crate::wal_check;
 use crate::{GlobalTimelines, SafeKeeperConf};
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 
-use postgres_ffi::PG_TLI;
+use postgres_ffi::{XLogFileName, PG_TLI};
 use regex::Regex;
+use std::str;
+use std::sync::Arc;
 
-use tracing::info;
+use tracing::{info, warn};
 use utils::{
+    auth::{Claims, JwtAuth, Scope},
     id::{TenantId, TenantTimelineId, TimelineId},
     lsn::Lsn,
     postgres_backend::{self, PostgresBackend},
@@ -28,30 +33,74 @@ pub struct SafekeeperPostgresHandler {
     pub tenant_id: Option<TenantId>,
     pub timeline_id: Option<TimelineId>,
     pub ttid: TenantTimelineId,
+    auth: Option<Arc<JwtAuth>>,
+    claims: Option<Claims>,
 }
 
 /// Parsed Postgres command.
 enum SafekeeperPostgresCommand {
     StartWalPush,
-    StartReplication { start_lsn: Lsn },
+    StartReplication {
+        start_lsn: Lsn,
+        stop_lsn: Option<Lsn>,
+    },
     IdentifySystem,
-    JSONCtrl { cmd: AppendLogicalMessage },
+    TermHistory,
+    WalChecksums {
+        start_lsn: Lsn,
+        end_lsn: Lsn,
+    },
+    JSONCtrl {
+        cmd: AppendLogicalMessage,
+    },
 }
 
 fn parse_cmd(cmd: &str) -> Result<SafekeeperPostgresCommand> {
     if cmd.starts_with("START_WAL_PUSH") {
         Ok(SafekeeperPostgresCommand::StartWalPush)
     } else if cmd.starts_with("START_REPLICATION") {
-        let re =
-            Regex::new(r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)").unwrap();
-        let mut caps = re.captures_iter(cmd);
-        let start_lsn = caps
-            .next()
-            .map(|cap| cap[1].parse::<Lsn>())
-            .context("failed to parse start LSN from START_REPLICATION command")??;
-        Ok(SafekeeperPostgresCommand::StartReplication { start_lsn })
+        let re = Regex::new(
+            r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)(?: STOP ([[:xdigit:]]+/[[:xdigit:]]+))?",
+        )
+        .unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse start LSN from START_REPLICATION command")?;
+        let start_lsn = caps[1]
+            .parse::<Lsn>()
+            .with_context(|| format!("failed to parse start LSN {}", &caps[1]))?;
+        let stop_lsn = caps
+            .get(2)
+            .map(|stop_lsn| {
+                stop_lsn
+                    .as_str()
+                    .parse::<Lsn>()
+                    .with_context(|| format!("failed to parse stop LSN {}", stop_lsn.as_str()))
+            })
+            .transpose()?;
+        Ok(SafekeeperPostgresCommand::StartReplication {
+            start_lsn,
+            stop_lsn,
+        })
     } else if cmd.starts_with("IDENTIFY_SYSTEM") {
         Ok(SafekeeperPostgresCommand::IdentifySystem)
+    } else if cmd.starts_with("TERM_HISTORY") {
+        Ok(SafekeeperPostgresCommand::TermHistory)
+    } else if cmd.starts_with("WAL_CHECKSUMS") {
+        let re = Regex::new(
+            r"WAL_CHECKSUMS ([[:xdigit:]]+/[[:xdigit:]]+) ([[:xdigit:]]+/[[:xdigit:]]+)",
+        )
+        .unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse LSN range from WAL_CHECKSUMS command")?;
+        let start_lsn = caps[1]
+            .parse::<Lsn>()
+            .with_context(|| format!("failed to parse start LSN {}", &caps[1]))?;
+        let end_lsn = caps[2]
+            .parse::<Lsn>()
+            .with_context(|| format!("failed to parse end LSN {}", &caps[2]))?;
+        Ok(SafekeeperPostgresCommand::WalChecksums { start_lsn, end_lsn })
     } else if cmd.starts_with("JSON_CTRL") {
         let cmd = cmd.strip_prefix("JSON_CTRL").context("invalid prefix")?;
         Ok(SafekeeperPostgresCommand::JSONCtrl {
@@ -63,6 +112,31 @@ fn parse_cmd(cmd: &str) -> Result<SafekeeperPostgresCommand> {
 }
 
 impl postgres_backend::Handler for SafekeeperPostgresHandler {
+    fn check_auth_jwt(&mut self, _pgb: &mut PostgresBackend, jwt_response: &[u8]) -> Result<()> {
+        // this unwrap is never triggered, because check_auth_jwt only called when auth_type is NeonJWT
+        // which requires auth to be present
+        let data = self
+            .auth
+            .as_ref()
+            .unwrap()
+            .decode(str::from_utf8(jwt_response)?)?;
+
+        if matches!(data.claims.scope, Scope::Tenant) {
+            ensure!(
+                data.claims.tenant_id.is_some(),
+                "jwt token scope is Tenant, but tenant id is missing"
+            )
+        }
+
+        info!(
+            "jwt auth succeeded for scope: {:#?} by tenant id: {:?}",
+            data.claims.scope, data.claims.tenant_id,
+        );
+
+        self.claims = Some(data.claims);
+        Ok(())
+    }
+
     // tenant_id and timeline_id are passed in connection string params
     fn startup(&mut self, _pgb: &mut PostgresBackend, sm: &FeStartupPacket) -> Result<()> {
         if let FeStartupPacket::StartupMessage { params, .. } = sm {
@@ -103,14 +177,20 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
 
         let tenant_id = self.tenant_id.context("tenantid is required")?;
         let timeline_id = self.timeline_id.context("timelineid is required")?;
+        self.check_permission(pgb, Some(tenant_id), query_string)?;
         self.ttid = TenantTimelineId::new(tenant_id, timeline_id);
 
         match cmd {
             SafekeeperPostgresCommand::StartWalPush => ReceiveWalConn::new(pgb).run(self),
-            SafekeeperPostgresCommand::StartReplication { start_lsn } => {
-                ReplicationConn::new(pgb).run(self, pgb, start_lsn)
-            }
+            SafekeeperPostgresCommand::StartReplication {
+                start_lsn,
+                stop_lsn,
+            } => ReplicationConn::new(pgb).run(self, pgb, start_lsn, stop_lsn),
             SafekeeperPostgresCommand::IdentifySystem => self.handle_identify_system(pgb),
+            SafekeeperPostgresCommand::TermHistory => self.handle_term_history(pgb),
+            SafekeeperPostgresCommand::WalChecksums { start_lsn, end_lsn } => {
+                self.handle_wal_checksums(pgb, start_lsn, end_lsn)
+            }
             SafekeeperPostgresCommand::JSONCtrl { ref cmd } => handle_json_ctrl(self, pgb, cmd),
         }
         .context(format!(
@@ -122,16 +202,49 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
 }
 
 impl SafekeeperPostgresHandler {
-    pub fn new(conf: SafeKeeperConf) -> Self {
+    pub fn new(conf: SafeKeeperConf, auth: Option<Arc<JwtAuth>>) -> Self {
         SafekeeperPostgresHandler {
             conf,
             appname: None,
             tenant_id: None,
             timeline_id: None,
             ttid: TenantTimelineId::empty(),
+            auth,
+            claims: None,
         }
     }
 
+    /// Checks the claims of the connection, if auth is enabled, against the tenant
+    /// of the command. Denied commands are logged.
+    fn check_permission(
+        &self,
+        pgb: &PostgresBackend,
+        tenant_id: Option<TenantId>,
+        query_string: &str,
+    ) -> Result<()> {
+        if self.auth.is_none() {
+            // auth is set to Trust, nothing to check so just return ok
+            return Ok(());
+        }
+        // auth is some, just checked above, when auth is some
+        // then claims are always present because of checks during connection init
+        // so this expect won't trigger
+        let claims = self
+            .claims
+            .as_ref()
+            .expect("claims presence already checked");
+        check_permission(claims, tenant_id).map_err(|e| {
+            warn!(
+                "denied query {:?} from {} with scope {:?} of tenant {:?}: {e}",
+                query_string,
+                pgb.get_peer_addr(),
+                claims.scope,
+                claims.tenant_id,
+            );
+            e
+        })
+    }
+
     ///
     /// Handle IDENTIFY_SYSTEM replication command
     ///
@@ -189,6 +302,80 @@ impl SafekeeperPostgresHandler {
         Ok(())
     }
 
+    ///
+    /// Handle TERM_HISTORY command, returning the term history of the committed WAL,
+    /// one (term, lsn) row per term switch.
+    ///
+    fn handle_term_history(&mut self, pgb: &mut PostgresBackend) -> Result<()> {
+        let tli = GlobalTimelines::get(self.ttid)?;
+        let term_history = tli.get_committed_term_history();
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor {
+                name: b"term",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"lsn",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+        ]))?;
+        for entry in term_history.0.iter() {
+            let term = entry.term.to_string();
+            let lsn = entry.lsn.to_string();
+            pgb.write_message_noflush(&BeMessage::DataRow(&[
+                Some(term.as_bytes()),
+                Some(lsn.as_bytes()),
+            ]))?;
+        }
+        pgb.write_message(&BeMessage::CommandComplete(b"TERM_HISTORY"))?;
+        Ok(())
+    }
+
+    ///
+    /// Handle WAL_CHECKSUMS command, returning the crc32c checksums of the full committed
+    /// segments within the range, one (segment name, crc32c) row per segment.
+    ///
+    fn handle_wal_checksums(
+        &mut self,
+        pgb: &mut PostgresBackend,
+        start_lsn: Lsn,
+        end_lsn: Lsn,
+    ) -> Result<()> {
+        let tli = GlobalTimelines::get(self.ttid)?;
+        let wal_seg_size = tli.get_wal_seg_size();
+        let checksums = wal_check::local_segment_checksums(&tli, start_lsn, end_lsn)?;
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor {
+                name: b"segment",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"crc32c",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+        ]))?;
+        for checksum in checksums {
+            let segment = XLogFileName(PG_TLI, checksum.segno, wal_seg_size);
+            let crc32c = format!("{:08x}", checksum.crc32c);
+            pgb.write_message_noflush(&BeMessage::DataRow(&[
+                Some(segment.as_bytes()),
+                Some(crc32c.as_bytes()),
+            ]))?;
+        }
+        pgb.write_message(&BeMessage::CommandComplete(b"WAL_CHECKSUMS"))?;
+        Ok(())
+    }
+
     /// Returns true if current connection is a replication connection, originating
     /// from a walproposer recovery function. This connection gets a special handling:
     /// safekeeper must stream all local WAL till the flush_lsn, whether committed or not.
@@ -1,26 +1,38 @@
 use anyhow::anyhow;
+use hyper::header::CONTENT_TYPE;
 use hyper::{Body, Request, Response, StatusCode, Uri};
 
 use anyhow::Context;
 use once_cell::sync::Lazy;
-use serde::Serialize;
 use serde::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::task::JoinError;
+use tokio_util::io::ReaderStream;
+use tracing::*;
 
+use crate::auth;
+use crate::membership::Configuration;
+use crate::pull_timeline::{self, PullTimelineRequest};
+use crate::recovery;
 use crate::safekeeper::Term;
 use crate::safekeeper::TermHistory;
+use crate::send_wal::HotStandbyFeedback;
+use crate::timeline::{Timeline, TimelineError};
+use crate::wal_check;
 
 use crate::timelines_global_map::TimelineDeleteForceResult;
 use crate::GlobalTimelines;
 use crate::SafeKeeperConf;
 use etcd_broker::subscription_value::SkTimelineInfo;
 use utils::{
-    auth::JwtAuth,
+    auth::{Claims, JwtAuth},
     http::{
-        endpoint::{self, auth_middleware, check_permission},
+        endpoint::{self, auth_middleware},
         error::ApiError,
         json::{json_request, json_response},
         request::{ensure_no_body, parse_request_param},
@@ -32,6 +44,25 @@ use utils::{
 
 use super::models::TimelineCreateRequest;
 
+/// Checks the JWT claims of the request, if auth is enabled, against the tenant or,
+/// with `None`, against the safekeeper-wide APIs. Denied requests are logged.
+fn check_permission(request: &Request<Body>, tenant_id: Option<TenantId>) -> Result<(), ApiError> {
+    match request.context::<Claims>() {
+        Some(claims) => auth::check_permission(&claims, tenant_id).map_err(|e| {
+            warn!(
+                "denied {} {} from {} with scope {:?} of tenant {:?}: {e}",
+                request.method(),
+                request.uri().path(),
+                request.remote_addr(),
+                claims.scope,
+                claims.tenant_id,
+            );
+            ApiError::Forbidden(e.to_string())
+        }),
+        None => Ok(()), // claims is None because auth is disabled
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct SafekeeperStatus {
     id: NodeId,
@@ -91,6 +122,19 @@ struct TimelineStatus {
     peer_horizon_lsn: Lsn,
     #[serde(serialize_with = "display_serialize")]
     remote_consistent_lsn: Lsn,
+    mconf: Configuration,
+    logical_slots: Vec<LogicalSlotStatus>,
+}
+
+/// Logical replication slot of the compute.
+#[derive(Debug, Serialize)]
+struct LogicalSlotStatus {
+    name: String,
+    plugin: String,
+    #[serde(serialize_with = "display_serialize")]
+    restart_lsn: Lsn,
+    #[serde(serialize_with = "display_serialize")]
+    confirmed_flush_lsn: Lsn,
 }
 
 /// Report info about timeline.
@@ -128,10 +172,87 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
         backup_lsn: inmem.backup_lsn,
         peer_horizon_lsn: inmem.peer_horizon_lsn,
         remote_consistent_lsn: inmem.remote_consistent_lsn,
+        mconf: state.mconf,
+        logical_slots: state
+            .logical_slots
+            .0
+            .into_iter()
+            .map(|slot| LogicalSlotStatus {
+                name: slot.name,
+                plugin: slot.plugin,
+                restart_lsn: slot.restart_lsn,
+                confirmed_flush_lsn: slot.confirmed_flush_lsn,
+            })
+            .collect(),
     };
     json_response(StatusCode::OK, status)
 }
 
+/// WAL sender of the timeline, like a row of `pg_stat_replication`.
+#[derive(Debug, Serialize)]
+struct WalSenderStatus {
+    replica_id: usize,
+    application_name: Option<String>,
+    #[serde(serialize_with = "display_serialize")]
+    client_addr: SocketAddr,
+    /// Time the WAL sender started, RFC 3339.
+    backend_start: String,
+    #[serde(serialize_with = "display_serialize")]
+    sent_lsn: Lsn,
+    /// Feedback of the pageserver, absent for other replicas.
+    pageserver_feedback: Option<PageserverFeedbackStatus>,
+    /// Hot standby feedback of the replica, if it sent any.
+    hs_feedback: Option<HotStandbyFeedback>,
+}
+
+#[derive(Debug, Serialize)]
+struct PageserverFeedbackStatus {
+    #[serde(serialize_with = "display_serialize")]
+    last_received_lsn: Lsn,
+    #[serde(serialize_with = "display_serialize")]
+    disk_consistent_lsn: Lsn,
+    #[serde(serialize_with = "display_serialize")]
+    remote_consistent_lsn: Lsn,
+    /// Time of the last feedback, RFC 3339.
+    reply_time: String,
+}
+
+/// Lists the WAL senders of the timeline with their positions.
+async fn timeline_wal_senders_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(ttid)
+        .with_context(|| format!("Couldn't get timeline {ttid}"))
+        .map_err(ApiError::NotFound)?;
+    let wal_senders: Vec<WalSenderStatus> = tli
+        .get_replicas()
+        .into_iter()
+        .map(|replica| WalSenderStatus {
+            replica_id: replica.id,
+            application_name: replica.appname,
+            client_addr: replica.client_addr,
+            backend_start: humantime::format_rfc3339(replica.start_time).to_string(),
+            sent_lsn: replica.sent_lsn.load(),
+            pageserver_feedback: replica.state.pageserver_feedback.map(|feedback| {
+                PageserverFeedbackStatus {
+                    last_received_lsn: Lsn(feedback.ps_writelsn),
+                    disk_consistent_lsn: Lsn(feedback.ps_flushlsn),
+                    remote_consistent_lsn: Lsn(feedback.ps_applylsn),
+                    reply_time: humantime::format_rfc3339(feedback.ps_replytime).to_string(),
+                }
+            }),
+            // Feedback xmin stays u64::MAX until the replica sends the hot standby feedback.
+            hs_feedback: Some(replica.state.hs_feedback)
+                .filter(|hs_feedback| hs_feedback.xmin != u64::MAX),
+        })
+        .collect();
+    json_response(StatusCode::OK, wal_senders)
+}
+
 async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let request_data: TimelineCreateRequest = json_request(&mut request).await?;
 
@@ -144,6 +265,203 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
     Err(ApiError::BadRequest(anyhow!("not implemented")))
 }
 
+#[derive(Debug, Deserialize)]
+struct MembershipSwitchRequest {
+    mconf: Configuration,
+}
+
+#[derive(Debug, Serialize)]
+struct MembershipSwitchResponse {
+    previous_conf: Configuration,
+    current_conf: Configuration,
+}
+
+/// Switches the timeline to the next membership configuration.
+/// A safekeeper joining the timeline copies the committed WAL from the current members first,
+/// since it starts voting right after the switch.
+async fn timeline_membership_switch_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, None)?;
+    let request_data: MembershipSwitchRequest = json_request(&mut request).await?;
+    let mconf = request_data.mconf;
+    let my_id = get_conf(&request).my_id;
+
+    let tli = GlobalTimelines::get(ttid)
+        // The joining safekeeper should get the timeline created before the switch.
+        .with_context(|| format!("Couldn't get timeline {ttid}"))
+        .map_err(ApiError::NotFound)?;
+    let current_conf = tli.get_state().1.mconf;
+    // Retried switches are no-ops.
+    if mconf != current_conf {
+        mconf
+            .validate_switch_from(&current_conf)
+            .map_err(ApiError::BadRequest)?;
+        if mconf.is_joining(my_id) {
+            copy_wal_from_members(&tli, &mconf).await?;
+        }
+    }
+
+    let previous_conf = tli
+        .switch_membership(&mconf)
+        .map_err(ApiError::InternalServerError)?;
+    json_response(
+        StatusCode::OK,
+        MembershipSwitchResponse {
+            previous_conf,
+            current_conf: mconf,
+        },
+    )
+}
+
+/// Copies the committed WAL of the timeline from the first available current member.
+async fn copy_wal_from_members(tli: &Timeline, mconf: &Configuration) -> Result<(), ApiError> {
+    for member in &mconf.members {
+        match recovery::recover_from_member(tli, member).await {
+            Ok(flush_lsn) => {
+                info!(
+                    "copied WAL of timeline {} up to {flush_lsn} from member {}",
+                    tli.ttid, member.id
+                );
+                return Ok(());
+            }
+            Err(e) => warn!(
+                "failed to copy WAL of timeline {} from member {}: {e:#}",
+                tli.ttid, member.id
+            ),
+        }
+    }
+    Err(ApiError::InternalServerError(anyhow!(
+        "failed to copy WAL of timeline {} from any of the current members",
+        tli.ttid
+    )))
+}
+
+/// Pulls the timeline from another safekeeper, copying its control file and WAL.
+async fn timeline_pull_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, None)?;
+    let request_data: PullTimelineRequest = json_request(&mut request).await?;
+    let conf = get_conf(&request);
+    // The safekeeper presents its own token to the source, so it must be a known peer.
+    if !pull_timeline::is_known_peer(conf, &request_data.source_http_addr) {
+        return Err(ApiError::BadRequest(anyhow!(
+            "source {} is not a known peer safekeeper",
+            request_data.source_http_addr
+        )));
+    }
+
+    let resp = pull_timeline::pull_timeline(conf, ttid, request_data)
+        .await
+        .map_err(|e| match e.downcast_ref::<TimelineError>() {
+            // The timeline exists locally or is being pulled concurrently.
+            Some(TimelineError::AlreadyExists(_)) => ApiError::Conflict(format!("{e:#}")),
+            _ => ApiError::InternalServerError(e),
+        })?;
+    json_response(StatusCode::OK, resp)
+}
+
+/// Lists the timeline WAL segments, for the safekeepers pulling the timeline.
+async fn timeline_wal_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+    GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+
+    let timeline_dir = get_conf(&request).timeline_dir(&ttid);
+    let segments = tokio::task::spawn_blocking(move || {
+        pull_timeline::list_wal_segments(&timeline_dir).map_err(ApiError::InternalServerError)
+    })
+    .await
+    .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))??;
+    json_response(StatusCode::OK, segments)
+}
+
+/// Streams the timeline control file or WAL segment, for the safekeepers pulling the timeline.
+async fn timeline_file_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+    let file_name: String = parse_request_param(&request, "file_name")?;
+    GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+
+    let timeline_dir = get_conf(&request).timeline_dir(&ttid);
+    let path = pull_timeline::servable_file_path(&timeline_dir, &file_name).ok_or_else(|| {
+        ApiError::BadRequest(anyhow!("{file_name} is not a timeline file to serve"))
+    })?;
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ApiError::NotFound(anyhow!(
+                "file {file_name} of timeline {ttid} not found"
+            )))
+        }
+        Err(e) => return Err(ApiError::InternalServerError(e.into())),
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .body(Body::wrap_stream(ReaderStream::new(file)))
+        .map_err(|e| ApiError::InternalServerError(e.into()))
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+struct WalCheckRequest {
+    /// Defaults to the start of the local WAL.
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    start_lsn: Option<Lsn>,
+    /// Defaults to the commit_lsn.
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    end_lsn: Option<Lsn>,
+}
+
+/// Checks the committed WAL of the timeline against the backup archive and the other members
+/// of the timeline.
+async fn timeline_wal_check_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+    let request_data: WalCheckRequest = json_request(&mut request).await?;
+    let my_id = get_conf(&request).my_id;
+
+    let tli = GlobalTimelines::get(ttid)
+        .with_context(|| format!("Couldn't get timeline {ttid}"))
+        .map_err(ApiError::NotFound)?;
+    let (local_start_lsn, commit_lsn) = tli.get_local_committed_wal();
+    let start_lsn = request_data.start_lsn.unwrap_or(local_start_lsn);
+    let end_lsn = request_data.end_lsn.unwrap_or(commit_lsn);
+    if start_lsn > end_lsn {
+        return Err(ApiError::BadRequest(anyhow!(
+            "start_lsn {start_lsn} is after end_lsn {end_lsn}"
+        )));
+    }
+    let peers = wal_check::other_members(&tli, my_id);
+
+    let report = wal_check::check_wal_consistency(tli, start_lsn, end_lsn, peers)
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, report)
+}
+
 /// Deactivates the timeline and removes its data directory.
 async fn timeline_delete_force_handler(
     mut request: Request<Body>,
@@ -152,7 +470,7 @@ async fn timeline_delete_force_handler(
         parse_request_param(&request, "tenant_id")?,
         parse_request_param(&request, "timeline_id")?,
     );
-    check_permission(&request, Some(ttid.tenant_id))?;
+    check_permission(&request, None)?;
     ensure_no_body(&mut request).await?;
     let resp = tokio::task::spawn_blocking(move || {
         // FIXME: `delete_force` can fail from both internal errors and bad requests. Add better
@@ -170,7 +488,7 @@ async fn tenant_delete_force_handler(
     mut request: Request<Body>,
 ) -> Result<Response<Body>, ApiError> {
     let tenant_id = parse_request_param(&request, "tenant_id")?;
-    check_permission(&request, Some(tenant_id))?;
+    check_permission(&request, None)?;
     ensure_no_body(&mut request).await?;
     let delete_info = tokio::task::spawn_blocking(move || {
         // FIXME: `delete_force_all_for_tenant` can return an error for multiple different reasons;
@@ -195,7 +513,7 @@ async fn record_safekeeper_info(mut request: Request<Body>) -> Result<Response<B
         parse_request_param(&request, "tenant_id")?,
         parse_request_param(&request, "timeline_id")?,
     );
-    check_permission(&request, Some(ttid.tenant_id))?;
+    check_permission(&request, None)?;
     let safekeeper_info: SkTimelineInfo = json_request(&mut request).await?;
 
     let tli = GlobalTimelines::get(ttid)
@@ -250,6 +568,30 @@ pub fn make_router(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_delete_force_handler,
         )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/membership",
+            timeline_membership_switch_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/pull",
+            timeline_pull_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_senders",
+            timeline_wal_senders_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wal",
+            timeline_wal_list_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/file/:file_name",
+            timeline_file_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_check",
+            timeline_wal_check_handler,
+        )
         .delete("/v1/tenant/:tenant_id", tenant_delete_force_handler)
         // for tests
         .post(
@@ -1,25 +1,33 @@
 use defaults::DEFAULT_WAL_BACKUP_RUNTIME_THREADS;
 //
 use remote_storage::RemoteStorageConfig;
+use safekeeper_api::WalCompression;
 use std::path::PathBuf;
 use std::time::Duration;
 use url::Url;
 
 use utils::id::{NodeId, TenantId, TenantTimelineId};
 
+pub mod auth;
 pub mod broker;
 pub mod control_file;
 pub mod control_file_upgrade;
 pub mod handler;
 pub mod http;
 pub mod json_ctrl;
+pub mod logical_slots;
+pub mod membership;
 pub mod metrics;
+pub mod pull_timeline;
 pub mod receive_wal;
+pub mod recovery;
 pub mod remove_wal;
 pub mod safekeeper;
 pub mod send_wal;
 pub mod timeline;
+pub mod timeline_offload;
 pub mod wal_backup;
+pub mod wal_check;
 pub mod wal_service;
 pub mod wal_storage;
 
@@ -36,6 +44,7 @@ pub mod defaults {
 
     pub const DEFAULT_RECALL_PERIOD: Duration = Duration::from_secs(10);
     pub const DEFAULT_WAL_BACKUP_RUNTIME_THREADS: usize = 8;
+    pub const DEFAULT_TIMELINE_OFFLOAD_AFTER: Duration = Duration::from_secs(10 * 60);
 }
 
 #[derive(Debug, Clone)]
@@ -56,10 +65,20 @@ pub struct SafeKeeperConf {
     pub remote_storage: Option<RemoteStorageConfig>,
     pub backup_runtime_threads: usize,
     pub wal_backup_enabled: bool,
+    pub wal_backup_compression: WalCompression,
+    pub peer_recovery_enabled: bool,
+    pub wal_check_interval: Option<Duration>,
+    pub timeline_offload_enabled: bool,
+    pub timeline_offload_after: Duration,
+    /// How far behind the end of WAL logical slots of the compute can hold the WAL, in bytes.
+    pub max_slot_wal_keep_size: Option<u64>,
     pub my_id: NodeId,
     pub broker_endpoints: Vec<Url>,
     pub broker_etcd_prefix: String,
     pub auth_validation_public_key_path: Option<PathBuf>,
+    pub pg_auth_enabled: bool,
+    /// HTTP API addresses of the other safekeepers, the only ones timelines can be pulled from.
+    pub peer_http_addrs: Vec<String>,
 }
 
 impl SafeKeeperConf {
@@ -91,7 +110,15 @@ impl Default for SafeKeeperConf {
             broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
             backup_runtime_threads: DEFAULT_WAL_BACKUP_RUNTIME_THREADS,
             wal_backup_enabled: true,
+            wal_backup_compression: WalCompression::None,
+            peer_recovery_enabled: true,
+            wal_check_interval: None,
+            timeline_offload_enabled: true,
+            timeline_offload_after: defaults::DEFAULT_TIMELINE_OFFLOAD_AFTER,
+            max_slot_wal_keep_size: None,
             auth_validation_public_key_path: None,
+            pg_auth_enabled: false,
+            peer_http_addrs: Vec::new(),
         }
     }
 }
@@ -0,0 +1,169 @@
+//! Logical replication slots of the timeline.
+//!
+//! Logical decoding on the compute needs the WAL since the slots' `restart_lsn`, and the subscribers
+//! resume from their `confirmed_flush_lsn`. Computes are ephemeral, so the elected proposer sends
+//! the state of its logical slots to the safekeepers, which keep it in the control file and don't
+//! remove the WAL the slots still need, up to `max_slot_wal_keep_size` behind the end of WAL.
+//! The safekeepers return the slots in their vote responses, and the walproposer of a restarted
+//! compute restores the slots of the donor safekeeper.
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use utils::lsn::Lsn;
+
+/// Size of the serialized slot with empty name and plugin.
+const MIN_SLOT_SIZE: usize = 4 + 4 + 4 + 4 + 8 + 8;
+
+/// State of the logical replication slot on the compute.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogicalSlot {
+    pub name: String,
+    /// Output plugin decoding the changes.
+    pub plugin: String,
+    /// Oid of the database the slot belongs to.
+    pub database: u32,
+    /// Oldest transaction that might have written the catalog rows the slot needs.
+    pub catalog_xmin: u32,
+    /// Oldest LSN the slot might need to decode the changes from.
+    pub restart_lsn: Lsn,
+    /// LSN up to which the subscriber confirmed receiving the changes.
+    pub confirmed_flush_lsn: Lsn,
+}
+
+/// Logical replication slots of the timeline, ordered by name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogicalSlots(pub Vec<LogicalSlot>);
+
+impl LogicalSlots {
+    pub fn empty() -> LogicalSlots {
+        LogicalSlots(Vec::new())
+    }
+
+    /// Oldest WAL position needed by the slots, slots that don't reserve WAL yet are skipped.
+    pub fn min_restart_lsn(&self) -> Option<Lsn> {
+        self.0
+            .iter()
+            .map(|slot| slot.restart_lsn)
+            .filter(|lsn| *lsn != Lsn::INVALID)
+            .min()
+    }
+
+    /// Parse the slots, sent by the proposer as n_slots followed by (name length, name,
+    /// plugin length, plugin, database, catalog_xmin, restart_lsn, confirmed_flush_lsn) entries.
+    pub fn from_bytes(bytes: &mut Bytes) -> Result<LogicalSlots> {
+        if bytes.remaining() < 4 {
+            bail!("LogicalSlots misses len");
+        }
+        let n_slots = bytes.get_u32_le();
+        // Don't trust n_slots to allocate more than the message might hold.
+        let mut slots =
+            Vec::with_capacity(min(n_slots as usize, bytes.remaining() / MIN_SLOT_SIZE));
+        for _ in 0..n_slots {
+            if bytes.remaining() < 4 {
+                bail!("LogicalSlots is incomplete");
+            }
+            let name = get_string(bytes)?;
+            let plugin = get_string(bytes)?;
+            if bytes.remaining() < 4 + 4 + 8 + 8 {
+                bail!("LogicalSlots is incomplete");
+            }
+            let database = bytes.get_u32_le();
+            let catalog_xmin = bytes.get_u32_le();
+            let restart_lsn = bytes.get_u64_le().into();
+            let confirmed_flush_lsn = bytes.get_u64_le().into();
+            slots.push(LogicalSlot {
+                name,
+                plugin,
+                database,
+                catalog_xmin,
+                restart_lsn,
+                confirmed_flush_lsn,
+            });
+        }
+        slots.sort_by(|a, b| a.name.cmp(&b.name));
+        for pair in slots.windows(2) {
+            if pair[0].name == pair[1].name {
+                bail!("logical slot {} is listed twice", pair[0].name);
+            }
+        }
+        Ok(LogicalSlots(slots))
+    }
+
+    /// Serialize the slots the same way the proposer sends them.
+    pub fn serialize(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.0.len() as u32);
+        for slot in &self.0 {
+            buf.put_u32_le(slot.name.len() as u32);
+            buf.put_slice(slot.name.as_bytes());
+            buf.put_u32_le(slot.plugin.len() as u32);
+            buf.put_slice(slot.plugin.as_bytes());
+            buf.put_u32_le(slot.database);
+            buf.put_u32_le(slot.catalog_xmin);
+            buf.put_u64_le(slot.restart_lsn.into());
+            buf.put_u64_le(slot.confirmed_flush_lsn.into());
+        }
+    }
+}
+
+/// Parse a string sent as its length followed by the bytes.
+fn get_string(bytes: &mut Bytes) -> Result<String> {
+    if bytes.remaining() < 4 {
+        bail!("LogicalSlots is incomplete");
+    }
+    let len = bytes.get_u32_le() as usize;
+    if bytes.remaining() < len {
+        bail!("LogicalSlots is incomplete");
+    }
+    Ok(String::from_utf8(bytes.split_to(len).to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(slots: &[(&str, u64, u64)]) -> Bytes {
+        let slots = LogicalSlots(
+            slots
+                .iter()
+                .map(|(name, restart_lsn, confirmed_flush_lsn)| LogicalSlot {
+                    name: name.to_string(),
+                    plugin: "pgoutput".to_owned(),
+                    database: 5,
+                    catalog_xmin: 730,
+                    restart_lsn: Lsn(*restart_lsn),
+                    confirmed_flush_lsn: Lsn(*confirmed_flush_lsn),
+                })
+                .collect(),
+        );
+        let mut buf = BytesMut::new();
+        slots.serialize(&mut buf);
+        buf.freeze()
+    }
+
+    #[test]
+    fn test_logical_slots_parsing() {
+        let mut bytes = serialize(&[
+            ("sub_b", 0x300, 0x400),
+            ("sub_a", 0, 0),
+            ("sub_c", 0x200, 0x250),
+        ]);
+        let slots = LogicalSlots::from_bytes(&mut bytes).unwrap();
+        assert!(!bytes.has_remaining());
+        let names: Vec<&str> = slots.0.iter().map(|slot| slot.name.as_str()).collect();
+        assert_eq!(names, ["sub_a", "sub_b", "sub_c"]);
+        assert_eq!(slots.0[1].plugin, "pgoutput");
+        assert_eq!(slots.0[1].catalog_xmin, 730);
+        // sub_a doesn't reserve WAL yet
+        assert_eq!(slots.min_restart_lsn(), Some(Lsn(0x200)));
+        assert_eq!(LogicalSlots::empty().min_restart_lsn(), None);
+
+        let mut bytes = serialize(&[("sub", 0x300, 0x400), ("sub", 0x200, 0x250)]);
+        assert!(LogicalSlots::from_bytes(&mut bytes).is_err());
+        let mut bytes = serialize(&[("sub", 0x300, 0x400)]).slice(..10);
+        assert!(LogicalSlots::from_bytes(&mut bytes).is_err());
+        let mut bytes = Bytes::from_static(&u32::MAX.to_le_bytes());
+        assert!(LogicalSlots::from_bytes(&mut bytes).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Membership configuration of the timeline safekeepers.
+//!
+//! The set of safekeepers of a timeline changes with joint consensus: the configuration switches
+//! from the old member set to the joint one, where proposers need a quorum of both the old and
+//! the new member sets to be elected and to commit, and then to the new member set alone.
+//! Every switch bumps the configuration generation; acceptors vote only for proposers of
+//! the same generation, so a proposer elected in an older configuration has to restart
+//! the election once it learns about the newer one.
+//!
+//! Generation 0 stands for the timelines that have never been configured: their member set is
+//! defined by the computes' `neon.safekeepers` setting only.
+
+use anyhow::{bail, ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::{cmp::min, fmt};
+use utils::id::NodeId;
+
+/// Size of a member with an empty address in the serialized member set.
+const MIN_MEMBER_SIZE: usize = 8 + 4;
+
+/// Number of the membership configuration, increasing on every switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Generation(pub u32);
+
+impl Generation {
+    /// Generation of the timelines without a membership configuration.
+    pub const INVALID: Generation = Generation(0);
+
+    pub fn is_valid(&self) -> bool {
+        *self != Self::INVALID
+    }
+}
+
+impl fmt::Display for Generation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A safekeeper in the membership configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafekeeperId {
+    pub id: NodeId,
+    /// Address of the safekeeper's postgres protocol listener, `host:port`.
+    pub pg_addr: String,
+}
+
+/// Membership configuration of the timeline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configuration {
+    pub generation: Generation,
+    pub members: Vec<SafekeeperId>,
+    /// Member set the configuration switches to. When set, the configuration is joint.
+    pub new_members: Option<Vec<SafekeeperId>>,
+}
+
+impl Configuration {
+    /// Configuration of the timelines, that have never been configured.
+    pub fn empty() -> Configuration {
+        Configuration {
+            generation: Generation::INVALID,
+            members: Vec::new(),
+            new_members: None,
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.new_members.is_some()
+    }
+
+    /// Whether the safekeeper votes in this configuration: it belongs to the old or the new member set.
+    /// Every safekeeper votes in the configuration of generation 0.
+    pub fn is_voter(&self, sk_id: NodeId) -> bool {
+        !self.generation.is_valid()
+            || member_set_contains(&self.members, sk_id)
+            || self
+                .new_members
+                .as_ref()
+                .map_or(false, |new_members| member_set_contains(new_members, sk_id))
+    }
+
+    /// Whether the safekeeper is added to the timeline by this configuration.
+    pub fn is_joining(&self, sk_id: NodeId) -> bool {
+        !member_set_contains(&self.members, sk_id)
+            && self
+                .new_members
+                .as_ref()
+                .map_or(false, |new_members| member_set_contains(new_members, sk_id))
+    }
+
+    /// Checks that the configuration can replace `current` one: it should be the next step of the joint
+    /// consensus, either entering the joint configuration from the current member set or leaving it to the new member set.
+    pub fn validate_switch_from(&self, current: &Configuration) -> Result<()> {
+        ensure!(
+            self.generation > current.generation,
+            "configuration generation {} is not newer than the current one {}",
+            self.generation,
+            current.generation
+        );
+        ensure!(
+            !self.members.is_empty(),
+            "configuration should have at least one member"
+        );
+        validate_member_set(&self.members)?;
+        if let Some(new_members) = &self.new_members {
+            ensure!(
+                !new_members.is_empty(),
+                "joint configuration should have at least one new member"
+            );
+            validate_member_set(new_members)?;
+        }
+
+        if !current.generation.is_valid() {
+            // The first configuration records the member set as it is.
+            return Ok(());
+        }
+        match (&current.new_members, &self.new_members) {
+            (None, Some(_)) => ensure!(
+                same_member_set(&current.members, &self.members),
+                "joint configuration should keep the current members {:?}",
+                current.members
+            ),
+            (Some(current_new_members), None) => ensure!(
+                same_member_set(current_new_members, &self.members),
+                "configuration leaving the joint one should consist of its new members {current_new_members:?}"
+            ),
+            (None, None) => bail!("member set can be changed only through a joint configuration"),
+            (Some(_), Some(_)) => bail!("current configuration is joint already"),
+        }
+        Ok(())
+    }
+
+    /// Parse the configuration, sent by the proposer as a generation followed by the member sets.
+    pub fn from_bytes(bytes: &mut Bytes) -> Result<Configuration> {
+        if bytes.remaining() < 4 {
+            bail!("Configuration misses generation");
+        }
+        let generation = Generation(bytes.get_u32_le());
+        let members = member_set_from_bytes(bytes)?;
+        if bytes.remaining() < 1 {
+            bail!("Configuration misses joint flag");
+        }
+        let new_members = if bytes.get_u8() != 0 {
+            Some(member_set_from_bytes(bytes)?)
+        } else {
+            None
+        };
+        Ok(Configuration {
+            generation,
+            members,
+            new_members,
+        })
+    }
+
+    /// Serialize the configuration the same way `from_bytes` parses it.
+    pub fn serialize(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.generation.0);
+        serialize_member_set(&self.members, buf);
+        match &self.new_members {
+            Some(new_members) => {
+                buf.put_u8(1);
+                serialize_member_set(new_members, buf);
+            }
+            None => buf.put_u8(0),
+        }
+    }
+}
+
+fn member_set_contains(members: &[SafekeeperId], sk_id: NodeId) -> bool {
+    members.iter().any(|member| member.id == sk_id)
+}
+
+fn same_member_set(members: &[SafekeeperId], other: &[SafekeeperId]) -> bool {
+    members.len() == other.len()
+        && members
+            .iter()
+            .all(|member| member_set_contains(other, member.id))
+}
+
+fn validate_member_set(members: &[SafekeeperId]) -> Result<()> {
+    for (i, member) in members.iter().enumerate() {
+        ensure!(
+            !member_set_contains(&members[..i], member.id),
+            "safekeeper {} is listed twice in the member set",
+            member.id
+        );
+    }
+    Ok(())
+}
+
+// Member set is n_members followed by (node id, address length, address) triples
+fn member_set_from_bytes(bytes: &mut Bytes) -> Result<Vec<SafekeeperId>> {
+    if bytes.remaining() < 4 {
+        bail!("member set misses len");
+    }
+    let n_members = bytes.get_u32_le();
+    // Don't trust n_members to allocate more than the message might hold.
+    let mut members =
+        Vec::with_capacity(min(n_members as usize, bytes.remaining() / MIN_MEMBER_SIZE));
+    for _ in 0..n_members {
+        if bytes.remaining() < MIN_MEMBER_SIZE {
+            bail!("member set is incomplete");
+        }
+        let id = NodeId(bytes.get_u64_le());
+        let addr_len = bytes.get_u32_le() as usize;
+        if bytes.remaining() < addr_len {
+            bail!("member {id} address is incomplete");
+        }
+        let pg_addr = String::from_utf8(bytes.split_to(addr_len).to_vec())?;
+        members.push(SafekeeperId { id, pg_addr });
+    }
+    Ok(members)
+}
+
+fn serialize_member_set(members: &[SafekeeperId], buf: &mut BytesMut) {
+    buf.put_u32_le(members.len() as u32);
+    for member in members {
+        buf.put_u64_le(member.id.0);
+        buf.put_u32_le(member.pg_addr.len() as u32);
+        buf.put_slice(member.pg_addr.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member_set(ids: &[u64]) -> Vec<SafekeeperId> {
+        ids.iter()
+            .map(|&id| SafekeeperId {
+                id: NodeId(id),
+                pg_addr: format!("sk-{id}:5454"),
+            })
+            .collect()
+    }
+
+    fn configuration(
+        generation: u32,
+        members: &[u64],
+        new_members: Option<&[u64]>,
+    ) -> Configuration {
+        Configuration {
+            generation: Generation(generation),
+            members: member_set(members),
+            new_members: new_members.map(member_set),
+        }
+    }
+
+    #[test]
+    fn test_configuration_switch() {
+        let current = configuration(1, &[1, 2, 3], None);
+
+        let joint = configuration(2, &[1, 2, 3], Some(&[1, 2, 4]));
+        joint.validate_switch_from(&current).unwrap();
+        assert!(joint.is_voter(NodeId(4)));
+        assert!(joint.is_joining(NodeId(4)));
+        assert!(!joint.is_joining(NodeId(1)));
+
+        let new = configuration(3, &[1, 2, 4], None);
+        new.validate_switch_from(&joint).unwrap();
+        assert!(!new.is_voter(NodeId(3)));
+
+        // the member set can't change without a joint configuration
+        assert!(new.validate_switch_from(&current).is_err());
+        // stale generation
+        assert!(configuration(1, &[1, 2, 3], Some(&[1, 2, 4]))
+            .validate_switch_from(&current)
+            .is_err());
+        // joint configuration with other old members
+        assert!(configuration(2, &[1, 2], Some(&[1, 2, 4]))
+            .validate_switch_from(&current)
+            .is_err());
+        // leaving the joint configuration to other new members
+        assert!(configuration(3, &[1, 2, 3], None)
+            .validate_switch_from(&joint)
+            .is_err());
+        // duplicate members
+        assert!(configuration(2, &[1, 1, 2], None)
+            .validate_switch_from(&Configuration::empty())
+            .is_err());
+
+        // any member set can be configured first, and everyone votes before that
+        current
+            .validate_switch_from(&Configuration::empty())
+            .unwrap();
+        assert!(Configuration::empty().is_voter(NodeId(5)));
+    }
+
+    #[test]
+    fn test_configuration_serialization() {
+        for conf in [
+            configuration(2, &[1, 2, 3], Some(&[1, 2, 4])),
+            configuration(3, &[1, 2, 4], None),
+        ] {
+            let mut buf = BytesMut::new();
+            conf.serialize(&mut buf);
+            let mut bytes = buf.freeze();
+            assert_eq!(Configuration::from_bytes(&mut bytes).unwrap(), conf);
+            assert!(!bytes.has_remaining());
+        }
+
+        let mut buf = BytesMut::new();
+        configuration(2, &[1, 2, 3], None).serialize(&mut buf);
+        let mut bytes = buf.freeze().slice(..20);
+        assert!(Configuration::from_bytes(&mut bytes).is_err());
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(2);
+        buf.put_u32_le(u32::MAX);
+        let mut bytes = buf.freeze();
+        assert!(Configuration::from_bytes(&mut bytes).is_err());
+    }
+}
@@ -2,7 +2,10 @@
 
 use std::time::{Instant, SystemTime};
 
-use ::metrics::{register_histogram, GaugeVec, Histogram, IntGauge, DISK_WRITE_SECONDS_BUCKETS};
+use ::metrics::{
+    register_histogram, register_int_counter_vec, register_int_gauge, GaugeVec, Histogram,
+    IntCounterVec, IntGauge, DISK_WRITE_SECONDS_BUCKETS,
+};
 use anyhow::Result;
 use metrics::{
     core::{AtomicU64, Collector, Desc, GenericGaugeVec, Opts},
@@ -15,7 +18,7 @@ use utils::{id::TenantTimelineId, lsn::Lsn};
 
 use crate::{
     safekeeper::{SafeKeeperState, SafekeeperMemState},
-    timeline::ReplicaState,
+    timeline::Replica,
     GlobalTimelines,
 };
 
@@ -61,6 +64,29 @@ pub static PERSIST_CONTROL_FILE_SECONDS: Lazy<Histogram> = Lazy::new(|| {
     )
     .expect("Failed to register safekeeper_persist_control_file_seconds histogram vec")
 });
+pub static OFFLOADED_TIMELINES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "safekeeper_offloaded_timelines",
+        "Number of idle timelines offloaded from memory"
+    )
+    .expect("Failed to register safekeeper_offloaded_timelines gauge")
+});
+pub static WAL_CHECK_SEGMENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "safekeeper_wal_check_segments_total",
+        "Number of WAL segments compared by the WAL consistency check, by the source compared with",
+        &["source"]
+    )
+    .expect("Failed to register safekeeper_wal_check_segments_total counter vec")
+});
+pub static WAL_CHECK_DIVERGENCES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "safekeeper_wal_check_divergences_total",
+        "Number of divergences found by the WAL consistency check, by kind",
+        &["kind"]
+    )
+    .expect("Failed to register safekeeper_wal_check_divergences_total counter vec")
+});
 
 /// Metrics for WalStorage in a single timeline.
 #[derive(Clone, Default)]
@@ -100,7 +126,7 @@ pub fn time_io_closure(closure: impl FnOnce() -> Result<()>) -> Result<f64> {
 /// Metrics for a single timeline.
 pub struct FullTimelineInfo {
     pub ttid: TenantTimelineId,
-    pub replicas: Vec<ReplicaState>,
+    pub replicas: Vec<Replica>,
     pub wal_backup_active: bool,
     pub timeline_is_active: bool,
     pub num_computes: u32,
@@ -126,6 +152,12 @@ pub struct TimelineCollector {
     remote_consistent_lsn: GenericGaugeVec<AtomicU64>,
     feedback_ps_write_lsn: GenericGaugeVec<AtomicU64>,
     feedback_last_time_seconds: GenericGaugeVec<AtomicU64>,
+    replica_sent_lsn: GenericGaugeVec<AtomicU64>,
+    replica_ps_last_received_lsn: GenericGaugeVec<AtomicU64>,
+    replica_ps_disk_consistent_lsn: GenericGaugeVec<AtomicU64>,
+    replica_ps_remote_consistent_lsn: GenericGaugeVec<AtomicU64>,
+    replica_lag_bytes: GenericGaugeVec<AtomicU64>,
+    replica_hs_xmin: GenericGaugeVec<AtomicU64>,
     timeline_active: GenericGaugeVec<AtomicU64>,
     wal_backup_active: GenericGaugeVec<AtomicU64>,
     connected_computes: IntGaugeVec,
@@ -228,6 +260,68 @@ impl TimelineCollector {
         .unwrap();
         descs.extend(feedback_last_time_seconds.desc().into_iter().cloned());
 
+        let replica_labels = &["tenant_id", "timeline_id", "replica_id", "application_name"];
+
+        let replica_sent_lsn = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_replica_sent_lsn",
+                "End of the WAL sent to the replica, grouped by WAL sender",
+            ),
+            replica_labels,
+        )
+        .unwrap();
+        descs.extend(replica_sent_lsn.desc().into_iter().cloned());
+
+        let replica_ps_last_received_lsn = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_replica_ps_last_received_lsn",
+                "Last LSN received by the pageserver, grouped by WAL sender",
+            ),
+            replica_labels,
+        )
+        .unwrap();
+        descs.extend(replica_ps_last_received_lsn.desc().into_iter().cloned());
+
+        let replica_ps_disk_consistent_lsn = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_replica_ps_disk_consistent_lsn",
+                "LSN persisted to disk by the pageserver, grouped by WAL sender",
+            ),
+            replica_labels,
+        )
+        .unwrap();
+        descs.extend(replica_ps_disk_consistent_lsn.desc().into_iter().cloned());
+
+        let replica_ps_remote_consistent_lsn = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_replica_ps_remote_consistent_lsn",
+                "LSN persisted to the remote storage by the pageserver, grouped by WAL sender",
+            ),
+            replica_labels,
+        )
+        .unwrap();
+        descs.extend(replica_ps_remote_consistent_lsn.desc().into_iter().cloned());
+
+        let replica_lag_bytes = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_replica_lag_bytes",
+                "Committed WAL not yet received by the pageserver, grouped by WAL sender",
+            ),
+            replica_labels,
+        )
+        .unwrap();
+        descs.extend(replica_lag_bytes.desc().into_iter().cloned());
+
+        let replica_hs_xmin = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_replica_hs_xmin",
+                "Oldest transaction the hot standby replica needs, grouped by WAL sender",
+            ),
+            replica_labels,
+        )
+        .unwrap();
+        descs.extend(replica_hs_xmin.desc().into_iter().cloned());
+
         let timeline_active = GenericGaugeVec::new(
             Opts::new(
                 "safekeeper_timeline_active",
@@ -329,6 +423,12 @@ impl TimelineCollector {
             remote_consistent_lsn,
             feedback_ps_write_lsn,
             feedback_last_time_seconds,
+            replica_sent_lsn,
+            replica_ps_last_received_lsn,
+            replica_ps_disk_consistent_lsn,
+            replica_ps_remote_consistent_lsn,
+            replica_lag_bytes,
+            replica_hs_xmin,
             timeline_active,
             wal_backup_active,
             connected_computes,
@@ -360,6 +460,12 @@ impl Collector for TimelineCollector {
         self.remote_consistent_lsn.reset();
         self.feedback_ps_write_lsn.reset();
         self.feedback_last_time_seconds.reset();
+        self.replica_sent_lsn.reset();
+        self.replica_ps_last_received_lsn.reset();
+        self.replica_ps_disk_consistent_lsn.reset();
+        self.replica_ps_remote_consistent_lsn.reset();
+        self.replica_lag_bytes.reset();
+        self.replica_hs_xmin.reset();
         self.timeline_active.reset();
         self.wal_backup_active.reset();
         self.connected_computes.reset();
@@ -385,7 +491,7 @@ impl Collector for TimelineCollector {
 
             let mut most_advanced: Option<utils::pq_proto::ReplicationFeedback> = None;
             for replica in tli.replicas.iter() {
-                if let Some(replica_feedback) = replica.pageserver_feedback {
+                if let Some(replica_feedback) = replica.state.pageserver_feedback {
                     if let Some(current) = most_advanced {
                         if current.ps_writelsn < replica_feedback.ps_writelsn {
                             most_advanced = Some(replica_feedback);
@@ -448,6 +554,43 @@ impl Collector for TimelineCollector {
                 }
             }
 
+            for replica in tli.replicas.iter() {
+                let replica_id = replica.id.to_string();
+                let replica_labels = &[
+                    tenant_id.as_str(),
+                    timeline_id.as_str(),
+                    replica_id.as_str(),
+                    replica.appname.as_deref().unwrap_or(""),
+                ];
+                self.replica_sent_lsn
+                    .with_label_values(replica_labels)
+                    .set(replica.sent_lsn.load().into());
+                if let Some(feedback) = replica.state.pageserver_feedback {
+                    self.replica_ps_last_received_lsn
+                        .with_label_values(replica_labels)
+                        .set(feedback.ps_writelsn);
+                    self.replica_ps_disk_consistent_lsn
+                        .with_label_values(replica_labels)
+                        .set(feedback.ps_flushlsn);
+                    self.replica_ps_remote_consistent_lsn
+                        .with_label_values(replica_labels)
+                        .set(feedback.ps_applylsn);
+                    self.replica_lag_bytes
+                        .with_label_values(replica_labels)
+                        .set(
+                            u64::from(tli.mem_state.commit_lsn)
+                                .saturating_sub(feedback.ps_writelsn),
+                        );
+                }
+                // u64::MAX means no feedback was received, 0 that the replica holds no xmin.
+                let xmin = replica.state.hs_feedback.xmin;
+                if xmin != u64::MAX && xmin != 0 {
+                    self.replica_hs_xmin
+                        .with_label_values(replica_labels)
+                        .set(xmin);
+                }
+            }
+
             if tli.last_removed_segno != 0 {
                 let segno_count = tli
                     .flush_lsn
@@ -470,6 +613,12 @@ impl Collector for TimelineCollector {
         mfs.extend(self.remote_consistent_lsn.collect());
         mfs.extend(self.feedback_ps_write_lsn.collect());
         mfs.extend(self.feedback_last_time_seconds.collect());
+        mfs.extend(self.replica_sent_lsn.collect());
+        mfs.extend(self.replica_ps_last_received_lsn.collect());
+        mfs.extend(self.replica_ps_disk_consistent_lsn.collect());
+        mfs.extend(self.replica_ps_remote_consistent_lsn.collect());
+        mfs.extend(self.replica_lag_bytes.collect());
+        mfs.extend(self.replica_hs_xmin.collect());
         mfs.extend(self.timeline_active.collect());
         mfs.extend(self.wal_backup_active.collect());
         mfs.extend(self.connected_computes.collect());
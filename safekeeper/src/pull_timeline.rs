@@ -0,0 +1,329 @@
+//! Pulling a timeline from another safekeeper over HTTP, to seed a new safekeeper with it.
+//!
+//! The source serves the timeline's control file and WAL segments as they are on its disk.
+//! The pulling safekeeper downloads them into a temporary directory, validates the copy and
+//! atomically moves it in place, registering the timeline in [`GlobalTimelines`].
+//! Since the files are copied one by one while the source keeps working, only the WAL committed
+//! by the copied control file is kept: the uncommitted tail might have been rewritten meanwhile.
+
+use std::cmp::max;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use futures::StreamExt;
+use postgres_ffi::v14::xlog_utils::{IsPartialXLogFileName, IsXLogFileName, XLogFromFileName};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+use utils::{id::TenantTimelineId, lsn::Lsn};
+
+use crate::control_file::{self, FileStorage, CONTROL_FILE_NAME};
+use crate::recovery::PEER_AUTH_TOKEN;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+#[derive(Debug, Deserialize)]
+pub struct PullTimelineRequest {
+    /// HTTP API address of the source safekeeper, e.g. `http://sk-1:7676`.
+    pub source_http_addr: String,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct PullTimelineResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub local_start_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub commit_lsn: Lsn,
+    pub wal_segments: usize,
+}
+
+/// Lists the names of the timeline's WAL segments, in the order of their LSNs.
+pub fn list_wal_segments(timeline_dir: &Path) -> Result<Vec<String>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(timeline_dir)
+        .with_context(|| format!("failed to list timeline dir {}", timeline_dir.display()))?
+    {
+        let file_name = entry?.file_name();
+        if let Some(file_name) = file_name.to_str() {
+            if IsXLogFileName(file_name) || IsPartialXLogFileName(file_name) {
+                segments.push(file_name.to_owned());
+            }
+        }
+    }
+    // Segment names are fixed width hex, so they sort in the LSN order.
+    segments.sort();
+    Ok(segments)
+}
+
+/// Path of the timeline file, that can be served to the pulling safekeepers:
+/// the control file or a WAL segment.
+pub fn servable_file_path(timeline_dir: &Path, file_name: &str) -> Option<PathBuf> {
+    if file_name == CONTROL_FILE_NAME
+        || IsXLogFileName(file_name)
+        || IsPartialXLogFileName(file_name)
+    {
+        Some(timeline_dir.join(file_name))
+    } else {
+        None
+    }
+}
+
+/// Whether the address is one of the configured peer safekeepers, which the timelines can be pulled from.
+pub fn is_known_peer(conf: &SafeKeeperConf, source_http_addr: &str) -> bool {
+    let source_http_addr = source_http_addr.trim_end_matches('/');
+    conf.peer_http_addrs
+        .iter()
+        .any(|peer| peer.trim_end_matches('/') == source_http_addr)
+}
+
+/// Pulls the timeline from the source safekeeper and registers it locally.
+/// The safekeeper authenticates to the source with its own token, if any.
+pub async fn pull_timeline(
+    conf: &SafeKeeperConf,
+    ttid: TenantTimelineId,
+    request: PullTimelineRequest,
+) -> Result<PullTimelineResponse> {
+    // Offloaded timelines exist as well, no need to load them back to check.
+    let pull = GlobalTimelines::start_pull(ttid)?;
+    info!("pulling timeline {ttid} from {}", request.source_http_addr);
+
+    let tmp_dir = pull.tmp_dir().to_path_buf();
+    // Leftovers of a previous pull interrupted by a restart.
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    fs::create_dir_all(&tmp_dir)?;
+
+    let source = TimelineSource {
+        client: reqwest::Client::new(),
+        timeline_url: format!(
+            "{}/v1/tenant/{}/timeline/{}",
+            request.source_http_addr.trim_end_matches('/'),
+            ttid.tenant_id,
+            ttid.timeline_id
+        ),
+        auth_token: PEER_AUTH_TOKEN.as_deref(),
+    };
+    // On failure, the guard removes the temporary directory.
+    let response = download_timeline(conf, ttid, &source, &tmp_dir).await?;
+    tokio::task::spawn_blocking(move || GlobalTimelines::load_pulled_timeline(pull)).await??;
+    Ok(response)
+}
+
+/// Downloads the control file and WAL segments of the timeline into `tmp_dir`
+/// and validates them.
+async fn download_timeline(
+    conf: &SafeKeeperConf,
+    ttid: TenantTimelineId,
+    source: &TimelineSource,
+    tmp_dir: &Path,
+) -> Result<PullTimelineResponse> {
+    // The control file goes first: the WAL downloaded after it contains its commit_lsn.
+    let control_file_path = tmp_dir.join(CONTROL_FILE_NAME);
+    source
+        .download_file(CONTROL_FILE_NAME, &control_file_path)
+        .await?;
+    let mut state = FileStorage::load_control_file(&control_file_path)?;
+    ensure!(
+        state.tenant_id == ttid.tenant_id && state.timeline_id == ttid.timeline_id,
+        "source returned control file of timeline {}/{}",
+        state.tenant_id,
+        state.timeline_id
+    );
+    ensure!(
+        state.server.wal_seg_size != 0 && state.timeline_start_lsn != Lsn(0),
+        "timeline {ttid} is not initialized on the source"
+    );
+    let wal_seg_size = state.server.wal_seg_size as usize;
+
+    let segments: Vec<String> = source.get_json("wal").await?;
+    let (first_segno, end_lsn) = validate_segment_names(&segments, wal_seg_size)?;
+    let segments_start_lsn = Lsn(first_segno * wal_seg_size as u64);
+    ensure!(
+        segments_start_lsn <= state.commit_lsn && state.commit_lsn <= end_lsn,
+        "source WAL segments between {segments_start_lsn} and {end_lsn} don't contain commit_lsn {}",
+        state.commit_lsn
+    );
+
+    for segment in &segments {
+        let segment_path = tmp_dir.join(segment);
+        source.download_file(segment, &segment_path).await?;
+        let segment_size = fs::metadata(&segment_path)?.len();
+        ensure!(
+            segment_size == wal_seg_size as u64,
+            "WAL segment {segment} has size {segment_size}, expected {wal_seg_size}"
+        );
+    }
+
+    // The source might have removed earlier WAL, the local copy starts at the first segment.
+    state.local_start_lsn = max(state.local_start_lsn, segments_start_lsn);
+    let local_start_lsn = state.local_start_lsn;
+    let commit_lsn = state.commit_lsn;
+    let tmp_dir = tmp_dir.to_path_buf();
+    let conf = conf.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut storage = FileStorage::create_new_in(tmp_dir, &conf, state.clone());
+        control_file::Storage::persist(&mut storage, &state)
+    })
+    .await??;
+
+    info!(
+        "downloaded {} WAL segments of timeline {ttid}, local_start_lsn {local_start_lsn}, commit_lsn {commit_lsn}",
+        segments.len()
+    );
+    Ok(PullTimelineResponse {
+        local_start_lsn,
+        commit_lsn,
+        wal_segments: segments.len(),
+    })
+}
+
+/// Checks that the segments are WAL segment file names, consecutive, and only the last one might be partial.
+/// The names are joined to the local timeline path later, so anything else is rejected.
+/// Returns the number of the first segment and the LSN the segments end at.
+fn validate_segment_names(segments: &[String], wal_seg_size: usize) -> Result<(u64, Lsn)> {
+    ensure!(!segments.is_empty(), "source has no WAL segments");
+
+    let mut first_segno = None;
+    let mut prev_segno = None;
+    for (i, segment) in segments.iter().enumerate() {
+        let is_partial = IsPartialXLogFileName(segment);
+        ensure!(
+            is_partial || IsXLogFileName(segment),
+            "source returned invalid WAL segment name {segment:?}"
+        );
+        ensure!(
+            !is_partial || i == segments.len() - 1,
+            "partial WAL segment {segment} is not the last one"
+        );
+        let (segno, _) = XLogFromFileName(&segment[..24], wal_seg_size);
+        if let Some(prev_segno) = prev_segno {
+            ensure!(
+                segno == prev_segno + 1,
+                "WAL segment {segment} doesn't follow the previous one"
+            );
+        }
+        first_segno.get_or_insert(segno);
+        prev_segno = Some(segno);
+    }
+
+    let last_segno = prev_segno.expect("segments are not empty");
+    Ok((
+        first_segno.expect("segments are not empty"),
+        Lsn((last_segno + 1) * wal_seg_size as u64),
+    ))
+}
+
+/// HTTP API of the source safekeeper, for one timeline.
+struct TimelineSource {
+    client: reqwest::Client,
+    timeline_url: String,
+    auth_token: Option<&'static str>,
+}
+
+impl TimelineSource {
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.get(format!("{}/{path}", self.timeline_url));
+        match self.auth_token {
+            Some(auth_token) => request.bearer_auth(auth_token),
+            None => request,
+        }
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = self.get(path).send().await?.error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    /// Streams the timeline file from the source to the local path, syncing it.
+    async fn download_file(&self, file_name: &str, path: &Path) -> Result<()> {
+        let response = self
+            .get(&format!("file/{file_name}"))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("failed to download {file_name}"))?;
+
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.with_context(|| format!("failed to download {file_name}"))?;
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres_ffi::{XLogFileName, PG_TLI, WAL_SEGMENT_SIZE};
+
+    fn segment_name(segno: u64, partial: bool) -> String {
+        let name = XLogFileName(PG_TLI, segno, WAL_SEGMENT_SIZE);
+        if partial {
+            format!("{name}.partial")
+        } else {
+            name
+        }
+    }
+
+    #[test]
+    fn test_validate_segment_names() {
+        let segments = vec![
+            segment_name(3, false),
+            segment_name(4, false),
+            segment_name(5, true),
+        ];
+        assert_eq!(
+            validate_segment_names(&segments, WAL_SEGMENT_SIZE).unwrap(),
+            (3, Lsn(6 * WAL_SEGMENT_SIZE as u64))
+        );
+
+        // gap
+        let segments = vec![segment_name(3, false), segment_name(5, true)];
+        assert!(validate_segment_names(&segments, WAL_SEGMENT_SIZE).is_err());
+        // partial in the middle
+        let segments = vec![segment_name(3, true), segment_name(4, false)];
+        assert!(validate_segment_names(&segments, WAL_SEGMENT_SIZE).is_err());
+        assert!(validate_segment_names(&[], WAL_SEGMENT_SIZE).is_err());
+        // not WAL segment names
+        for name in ["00000001", "00000001000000000000000G", "../x"] {
+            let segments = vec![segment_name(3, false), name.to_owned()];
+            assert!(validate_segment_names(&segments, WAL_SEGMENT_SIZE).is_err());
+        }
+        let segments = vec![format!("../{}", segment_name(3, false))];
+        assert!(validate_segment_names(&segments, WAL_SEGMENT_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_known_peers() {
+        let conf = SafeKeeperConf {
+            peer_http_addrs: vec![
+                "http://sk-1:7676".to_owned(),
+                "http://sk-2:7676/".to_owned(),
+            ],
+            ..Default::default()
+        };
+        assert!(is_known_peer(&conf, "http://sk-1:7676"));
+        assert!(is_known_peer(&conf, "http://sk-1:7676/"));
+        assert!(is_known_peer(&conf, "http://sk-2:7676"));
+        assert!(!is_known_peer(&conf, "http://attacker:7676"));
+        assert!(!is_known_peer(
+            &SafeKeeperConf::default(),
+            "http://sk-1:7676"
+        ));
+    }
+
+    #[test]
+    fn test_servable_files() {
+        let dir = Path::new("/timeline");
+        assert!(servable_file_path(dir, CONTROL_FILE_NAME).is_some());
+        assert!(servable_file_path(dir, &segment_name(1, true)).is_some());
+        assert!(servable_file_path(dir, "../../etc/passwd").is_none());
+        assert!(servable_file_path(dir, "safekeeper.control.partial").is_none());
+    }
+}
@@ -20,6 +20,7 @@ use std::thread;
 
 use crate::safekeeper::AcceptorProposerMessage;
 use crate::safekeeper::ProposerAcceptorMessage;
+use crate::safekeeper::SK_PROTOCOL_VERSION_NO_MCONF;
 
 use crate::handler::SafekeeperPostgresHandler;
 use utils::{
@@ -146,6 +147,7 @@ impl ProposerPollStream {
         let read_thread = thread::Builder::new()
             .name("Read WAL thread".into())
             .spawn(move || -> Result<()> {
+                let mut proto_version = SK_PROTOCOL_VERSION_NO_MCONF;
                 loop {
                     let copy_data = match FeMessage::read(&mut r)? {
                         Some(FeMessage::CopyData(bytes)) => bytes,
@@ -153,7 +155,10 @@ impl ProposerPollStream {
                         None => bail!("connection closed unexpectedly"),
                     };
 
-                    let msg = ProposerAcceptorMessage::parse(copy_data)?;
+                    let msg = ProposerAcceptorMessage::parse(copy_data, proto_version)?;
+                    if let ProposerAcceptorMessage::Greeting(greeting) = &msg {
+                        proto_version = greeting.protocol_version;
+                    }
                     msg_tx.send(msg)?;
                 }
                 // msg_tx will be dropped here, this will also close msg_rx
@@ -0,0 +1,213 @@
+//! Recovery of the committed WAL from peer safekeepers.
+//!
+//! A safekeeper that was down or partitioned away misses the WAL committed by the others meanwhile.
+//! Walproposer recovers it on the next compute start, but until then the safekeeper lags behind
+//! and weakens the durability of the timeline. So instead, the safekeeper notices a peer with a higher
+//! commit_lsn in the same term through the broker, verifies the peer's term history against its own
+//! and streams the missing WAL from the peer with a regular replication connection.
+//!
+//! The same streaming copies the WAL to a safekeeper joining the timeline's membership configuration,
+//! before the safekeeper starts voting, see [`recover_from_member`].
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, ensure, Context, Result};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use postgres::{SimpleQueryMessage, SimpleQueryRow};
+use postgres_protocol::message::backend::ReplicationMessage;
+use tokio::{pin, time};
+use tokio_postgres::{config::ReplicationMode, replication::ReplicationStream, Client};
+use tracing::*;
+use utils::{id::NodeId, lsn::Lsn};
+
+use crate::membership::SafekeeperId;
+use crate::safekeeper::{TermHistory, TermSwitchEntry};
+use crate::timeline::Timeline;
+
+/// Maximum time to wait for the connection to the peer to open.
+const RECOVERY_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Application name of the recovery connections to the peer.
+const RECOVERY_APPLICATION_NAME: &str = "safekeeper_recovery";
+
+/// JWT token presented to the peers which check the tokens of the postgres protocol
+/// connections or of the HTTP API, e.g. when pulling a timeline from them.
+pub(crate) static PEER_AUTH_TOKEN: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("SAFEKEEPER_AUTH_TOKEN").ok());
+
+/// Maximum time to wait for the next message of the WAL stream from the peer.
+const RECOVERY_MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A peer safekeeper to recover the missing WAL from.
+#[derive(Debug, Clone)]
+pub struct RecoveryDonor {
+    pub sk_id: NodeId,
+    /// Address of the peer's postgres protocol listener.
+    pub pg_addr: String,
+    pub commit_lsn: Lsn,
+}
+
+/// Recovers the WAL of the timeline from the donor, up to the donor's commit_lsn.
+/// Another recovery of the timeline can be started once this one finishes.
+pub async fn recovery_main(tli: Arc<Timeline>, donor: RecoveryDonor) {
+    let span = info_span!("peer recovery", ttid = %tli.ttid, donor = %donor.sk_id);
+    async {
+        info!(
+            "started, recovering WAL up to {} from {}",
+            donor.commit_lsn, donor.pg_addr
+        );
+        match recover(&tli, &donor).await {
+            Ok(flush_lsn) => info!("finished, flush_lsn {flush_lsn}"),
+            Err(e) => warn!("failed: {e:#}"),
+        }
+    }
+    .instrument(span)
+    .await;
+
+    tli.finish_peer_recovery();
+}
+
+async fn recover(tli: &Timeline, donor: &RecoveryDonor) -> Result<Lsn> {
+    let client = connect_to_peer(tli, &donor.pg_addr, RECOVERY_APPLICATION_NAME).await?;
+    recover_committed_wal(tli, &client, donor.commit_lsn).await
+}
+
+/// Recovers the WAL of the timeline from the member of its membership configuration,
+/// up to the member's commit_lsn, e.g. to catch up the safekeeper joining the configuration.
+pub async fn recover_from_member(tli: &Timeline, member: &SafekeeperId) -> Result<Lsn> {
+    tli.start_requested_peer_recovery()?;
+    let res = async {
+        let client = connect_to_peer(tli, &member.pg_addr, RECOVERY_APPLICATION_NAME).await?;
+        // Replication clients get the commit_lsn of the safekeeper as the current WAL position.
+        let commit_lsn = identify_commit_lsn(&client).await?;
+        recover_committed_wal(tli, &client, commit_lsn).await
+    }
+    .instrument(info_span!("member recovery", ttid = %tli.ttid, member = %member.id))
+    .await;
+    tli.finish_peer_recovery();
+    res
+}
+
+/// Opens a replication connection to the peer safekeeper for the timeline.
+pub(crate) async fn connect_to_peer(
+    tli: &Timeline,
+    pg_addr: &str,
+    application_name: &str,
+) -> Result<Client> {
+    let (host, port) = pg_addr
+        .rsplit_once(':')
+        .with_context(|| format!("invalid peer address {pg_addr}"))?;
+    let port: u16 = port
+        .parse()
+        .with_context(|| format!("invalid port in peer address {pg_addr}"))?;
+    // Set the parameters one by one, the address must not inject other connection options.
+    let mut connect_cfg = tokio_postgres::Config::new();
+    connect_cfg
+        .host(host)
+        .port(port)
+        .options(&format!(
+            "-c timeline_id={} tenant_id={}",
+            tli.ttid.timeline_id, tli.ttid.tenant_id
+        ))
+        .application_name(application_name)
+        .replication_mode(ReplicationMode::Physical);
+    if let Some(token) = PEER_AUTH_TOKEN.as_ref() {
+        connect_cfg.password(token);
+    }
+    let (client, connection) = time::timeout(
+        RECOVERY_CONNECT_TIMEOUT,
+        connect_cfg.connect(postgres::NoTls),
+    )
+    .await
+    .context("Timed out while waiting for the peer connection to open")?
+    .context("Failed to open the peer connection")?;
+
+    // The connection finishes after the client is dropped.
+    tokio::spawn(async move {
+        if let Err(connection_error) = connection.await {
+            if !connection_error.is_closed() {
+                debug!("peer connection aborted: {connection_error}")
+            }
+        }
+    });
+
+    Ok(client)
+}
+
+/// Streams the WAL from the connected peer up to its `commit_lsn`, appending it to the local WAL.
+async fn recover_committed_wal(tli: &Timeline, client: &Client, commit_lsn: Lsn) -> Result<Lsn> {
+    // Check the histories upfront, not to fetch the WAL we won't accept.
+    let peer_term_history = parse_term_history(&client.simple_query("TERM_HISTORY").await?)?;
+    tli.check_peer_term_history(&peer_term_history)?;
+
+    let mut flush_lsn = tli.get_flush_lsn();
+    if flush_lsn == Lsn(0) {
+        // Nothing is written locally yet, take the whole WAL of the peer.
+        flush_lsn = peer_term_history.0[0].lsn;
+    }
+    if flush_lsn >= commit_lsn {
+        return Ok(flush_lsn);
+    }
+
+    let query = format!("START_REPLICATION PHYSICAL {flush_lsn} STOP {commit_lsn}");
+    let copy_stream = client.copy_both_simple(&query).await?;
+    let physical_stream = ReplicationStream::new(copy_stream);
+    pin!(physical_stream);
+
+    while flush_lsn < commit_lsn {
+        let replication_message = time::timeout(RECOVERY_MESSAGE_TIMEOUT, physical_stream.next())
+            .await
+            .context("Timed out while waiting for the WAL from the peer")?
+            .context("Replication stream ended before the peer commit_lsn")??;
+
+        if let ReplicationMessage::XLogData(xlog_data) = replication_message {
+            let wal_start = Lsn::from(xlog_data.wal_start());
+            ensure!(
+                wal_start == flush_lsn,
+                "Expected WAL data starting at {flush_lsn}, got {wal_start}"
+            );
+            // Peers that do not support the stop position keep streaming after it
+            let xlog_data = xlog_data.data();
+            let bytes_left = (commit_lsn.0 - flush_lsn.0) as usize;
+            let wal_data = &xlog_data[..xlog_data.len().min(bytes_left)];
+
+            tli.append_recovered_wal(&peer_term_history, commit_lsn, flush_lsn, wal_data)?;
+            flush_lsn += wal_data.len() as u64;
+        }
+    }
+
+    Ok(flush_lsn)
+}
+
+/// Runs IDENTIFY_SYSTEM on the peer, returning its WAL position.
+async fn identify_commit_lsn(client: &Client) -> Result<Lsn> {
+    let response = client.simple_query("IDENTIFY_SYSTEM").await?;
+    for message in &response {
+        if let SimpleQueryMessage::Row(row) = message {
+            let xlogpos = row.get(2).context("xlogpos is missing")?;
+            return Ok(xlogpos.parse()?);
+        }
+    }
+    bail!("peer returned no IDENTIFY_SYSTEM row")
+}
+
+/// Parses the response of the TERM_HISTORY command, one (term, lsn) row per term switch.
+pub(crate) fn parse_term_history(response: &[SimpleQueryMessage]) -> Result<TermHistory> {
+    fn parse_entry(row: &SimpleQueryRow) -> Result<TermSwitchEntry> {
+        let term = row.get(0).context("term is missing")?.parse()?;
+        let lsn = row.get(1).context("lsn is missing")?.parse()?;
+        Ok(TermSwitchEntry { term, lsn })
+    }
+
+    let mut entries = Vec::new();
+    for message in response {
+        if let SimpleQueryMessage::Row(row) = message {
+            entries.push(parse_entry(row).context("invalid TERM_HISTORY row")?);
+        }
+    }
+    if entries.is_empty() {
+        bail!("peer returned empty term history");
+    }
+    Ok(TermHistory(entries))
+}
@@ -17,7 +17,8 @@ pub fn thread_main(conf: SafeKeeperConf) {
             let ttid = tli.ttid;
             let _enter =
                 info_span!("", tenant = %ttid.tenant_id, timeline = %ttid.timeline_id).entered();
-            if let Err(e) = tli.remove_old_wal(conf.wal_backup_enabled) {
+            if let Err(e) = tli.remove_old_wal(conf.wal_backup_enabled, conf.max_slot_wal_keep_size)
+            {
                 warn!("failed to remove WAL: {}", e);
             }
         }
@@ -1,6 +1,6 @@
 //! Acceptor part of proposer-acceptor consensus algorithm.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
@@ -14,6 +14,8 @@ use std::io::Read;
 use tracing::*;
 
 use crate::control_file;
+use crate::logical_slots::LogicalSlots;
+use crate::membership::{Configuration, Generation};
 use crate::send_wal::HotStandbyFeedback;
 
 use crate::wal_storage;
@@ -25,8 +27,10 @@ use utils::{
 };
 
 pub const SK_MAGIC: u32 = 0xcafeceefu32;
-pub const SK_FORMAT_VERSION: u32 = 7;
-const SK_PROTOCOL_VERSION: u32 = 2;
+pub const SK_FORMAT_VERSION: u32 = 10;
+pub const SK_PROTOCOL_VERSION: u32 = 3;
+/// Protocol version of the proposers, unaware of the membership configuration.
+pub const SK_PROTOCOL_VERSION_NO_MCONF: u32 = 2;
 pub const UNKNOWN_SERVER_VERSION: u32 = 0;
 
 /// Consensus logical timestamp.
@@ -65,6 +69,17 @@ impl TermHistory {
         Ok(TermHistory(res))
     }
 
+    /// Whether the histories agree on all the term switches both of them have,
+    /// i.e. one of them is a continuation of the other.
+    pub fn agrees_with(&self, other: &TermHistory) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .all(|(entry, other_entry)| {
+                entry.term == other_entry.term && entry.lsn == other_entry.lsn
+            })
+    }
+
     /// Return copy of self with switches happening strictly after up_to
     /// truncated.
     pub fn up_to(&self, up_to: Lsn) -> TermHistory {
@@ -204,6 +219,14 @@ pub struct SafeKeeperState {
     // obviously can be stale. (Currently not saved at all, but let's provision
     // place to have less file version upgrades).
     pub peers: Peers,
+    /// Membership configuration of the timeline safekeepers.
+    pub mconf: Configuration,
+    /// Logical replication slots of the compute, as last reported by the proposer.
+    pub logical_slots: LogicalSlots,
+    /// LSN up to which the WAL of the segment it belongs to is backed up as a partial
+    /// segment, by us or by peers. Persisted to know which partial segment to remove
+    /// from the remote storage once the whole segment is backed up.
+    pub partial_backup_lsn: Lsn,
 }
 
 #[derive(Debug, Clone)]
@@ -215,6 +238,7 @@ pub struct SafekeeperMemState {
     pub peer_horizon_lsn: Lsn,
     pub remote_consistent_lsn: Lsn,
     pub proposer_uuid: PgUuid,
+    pub partial_backup_lsn: Lsn,
 }
 
 impl SafeKeeperState {
@@ -239,6 +263,9 @@ impl SafeKeeperState {
             peer_horizon_lsn: Lsn(0),
             remote_consistent_lsn: Lsn(0),
             peers: Peers(peers.iter().map(|p| (*p, PeerInfo::new())).collect()),
+            mconf: Configuration::empty(),
+            logical_slots: LogicalSlots::empty(),
+            partial_backup_lsn: Lsn(0),
         }
     }
 
@@ -271,6 +298,9 @@ pub struct ProposerGreeting {
     pub tenant_id: TenantId,
     pub tli: TimeLineID,
     pub wal_seg_size: u32,
+    /// Membership configuration known to the proposer, sent since protocol version 3.
+    #[serde(skip)]
+    pub mconf: Option<Configuration>,
 }
 
 /// Acceptor -> Proposer initial response: the highest term known to me
@@ -279,12 +309,17 @@ pub struct ProposerGreeting {
 pub struct AcceptorGreeting {
     term: u64,
     node_id: NodeId,
+    /// Membership configuration of the acceptor, sent to the proposers that know about them.
+    mconf: Option<Configuration>,
 }
 
 /// Vote request sent from proposer to safekeepers
 #[derive(Debug, Deserialize)]
 pub struct VoteRequest {
     term: Term,
+    /// Generation of the proposer's membership configuration, sent since protocol version 3.
+    #[serde(skip)]
+    generation: Option<Generation>,
 }
 
 /// Vote itself, sent from safekeeper to proposer
@@ -298,6 +333,10 @@ pub struct VoteResponse {
     truncate_lsn: Lsn,
     term_history: TermHistory,
     timeline_start_lsn: Lsn,
+    // Acceptor's membership configuration, for the proposers that sent their generation.
+    mconf: Option<Configuration>,
+    // Logical replication slots for the compute to restore, sent along with mconf.
+    logical_slots: Option<LogicalSlots>,
 }
 
 /*
@@ -366,6 +405,14 @@ impl AppendResponse {
     }
 }
 
+/// Logical replication slots of the compute, sent by the elected proposer
+/// whenever they change.
+#[derive(Debug)]
+pub struct LogicalSlotsUpdate {
+    pub term: Term,
+    pub slots: LogicalSlots,
+}
+
 /// Proposer -> Acceptor messages
 #[derive(Debug)]
 pub enum ProposerAcceptorMessage {
@@ -375,22 +422,38 @@ pub enum ProposerAcceptorMessage {
     AppendRequest(AppendRequest),
     NoFlushAppendRequest(AppendRequest),
     FlushWAL,
+    LogicalSlots(LogicalSlotsUpdate),
 }
 
 impl ProposerAcceptorMessage {
-    /// Parse proposer message.
-    pub fn parse(msg_bytes: Bytes) -> Result<ProposerAcceptorMessage> {
+    /// Parse proposer message. `proto_version` is the protocol version from the greeting
+    /// of the proposer, the messages after the greeting depend on it.
+    pub fn parse(msg_bytes: Bytes, proto_version: u32) -> Result<ProposerAcceptorMessage> {
         // xxx using Reader is inefficient but easy to work with bincode
         let mut stream = msg_bytes.reader();
         // u64 is here to avoid padding; it will be removed once we stop packing C structs into the wire as is
         let tag = stream.read_u64::<LittleEndian>()? as u8 as char;
         match tag {
             'g' => {
-                let msg = ProposerGreeting::des_from(&mut stream)?;
+                let mut msg = ProposerGreeting::des_from(&mut stream)?;
+                if msg.protocol_version >= SK_PROTOCOL_VERSION {
+                    let mut msg_bytes = stream.into_inner();
+                    msg.mconf = Some(Configuration::from_bytes(&mut msg_bytes)?);
+                }
                 Ok(ProposerAcceptorMessage::Greeting(msg))
             }
             'v' => {
-                let msg = VoteRequest::des_from(&mut stream)?;
+                let mut msg = VoteRequest::des_from(&mut stream)?;
+                // Proposers unaware of the membership configuration don't send the generation.
+                if proto_version >= SK_PROTOCOL_VERSION {
+                    let mut msg_bytes = stream.into_inner();
+                    // The generation follows the proposer id.
+                    if msg_bytes.remaining() < 16 + 4 {
+                        bail!("VoteRequest message is not complete");
+                    }
+                    msg_bytes.advance(16);
+                    msg.generation = Some(Generation(msg_bytes.get_u32_le()));
+                }
                 Ok(ProposerAcceptorMessage::VoteRequest(msg))
             }
             'e' => {
@@ -435,6 +498,18 @@ impl ProposerAcceptorMessage {
 
                 Ok(ProposerAcceptorMessage::AppendRequest(msg))
             }
+            'l' => {
+                let mut msg_bytes = stream.into_inner();
+                if msg_bytes.remaining() < 8 {
+                    bail!("LogicalSlotsUpdate message is not complete");
+                }
+                let term = msg_bytes.get_u64_le();
+                let slots = LogicalSlots::from_bytes(&mut msg_bytes)?;
+                Ok(ProposerAcceptorMessage::LogicalSlots(LogicalSlotsUpdate {
+                    term,
+                    slots,
+                }))
+            }
             _ => bail!("unknown proposer-acceptor message tag: {}", tag,),
         }
     }
@@ -456,6 +531,9 @@ impl AcceptorProposerMessage {
                 buf.put_u64_le('g' as u64);
                 buf.put_u64_le(msg.term);
                 buf.put_u64_le(msg.node_id.0);
+                if let Some(mconf) = &msg.mconf {
+                    mconf.serialize(buf);
+                }
             }
             AcceptorProposerMessage::VoteResponse(msg) => {
                 buf.put_u64_le('v' as u64);
@@ -469,6 +547,12 @@ impl AcceptorProposerMessage {
                     buf.put_u64_le(e.lsn.into());
                 }
                 buf.put_u64_le(msg.timeline_start_lsn.into());
+                if let Some(mconf) = &msg.mconf {
+                    mconf.serialize(buf);
+                }
+                if let Some(logical_slots) = &msg.logical_slots {
+                    logical_slots.serialize(buf);
+                }
             }
             AcceptorProposerMessage::AppendResponse(msg) => {
                 buf.put_u64_le('a' as u64);
@@ -538,6 +622,7 @@ where
                 peer_horizon_lsn: state.peer_horizon_lsn,
                 remote_consistent_lsn: state.remote_consistent_lsn,
                 proposer_uuid: state.proposer_uuid,
+                partial_backup_lsn: state.partial_backup_lsn,
             },
             state,
             wal_store,
@@ -546,7 +631,7 @@ where
     }
 
     /// Get history of term switches for the available WAL
-    fn get_term_history(&self) -> TermHistory {
+    pub fn get_term_history(&self) -> TermHistory {
         self.state
             .acceptor_state
             .term_history
@@ -577,6 +662,7 @@ where
                 self.handle_append_request(msg, false)
             }
             ProposerAcceptorMessage::FlushWAL => self.handle_flush(),
+            ProposerAcceptorMessage::LogicalSlots(msg) => self.handle_logical_slots(msg),
         }
     }
 
@@ -587,7 +673,7 @@ where
         msg: &ProposerGreeting,
     ) -> Result<Option<AcceptorProposerMessage>> {
         // Check protocol compatibility
-        if msg.protocol_version != SK_PROTOCOL_VERSION {
+        if !(SK_PROTOCOL_VERSION_NO_MCONF..=SK_PROTOCOL_VERSION).contains(&msg.protocol_version) {
             bail!(
                 "incompatible protocol version {}, expected {}",
                 msg.protocol_version,
@@ -645,6 +731,32 @@ where
             self.state.persist(&state)?;
         }
 
+        match &msg.mconf {
+            // Proposers that don't know about the membership configuration can't
+            // gather a joint quorum, so they are allowed for unconfigured timelines only.
+            None if self.state.mconf.generation.is_valid() => bail!(
+                "timeline has membership configuration generation {}, unsupported by protocol version {}",
+                self.state.mconf.generation,
+                msg.protocol_version
+            ),
+            Some(mconf) if mconf.generation > self.state.mconf.generation => {
+                mconf
+                    .validate_switch_from(&self.state.mconf)
+                    .with_context(|| {
+                        format!(
+                            "proposer {:?} sent invalid membership configuration {:?}",
+                            msg.proposer_id, mconf
+                        )
+                    })?;
+                info!(
+                    "adopting membership configuration {:?} from proposer {:?}",
+                    mconf, msg.proposer_id
+                );
+                self.persist_mconf(mconf.clone())?;
+            }
+            _ => {}
+        }
+
         info!(
             "processed greeting from proposer {:?}, sending term {:?}",
             msg.proposer_id, self.state.acceptor_state.term
@@ -652,9 +764,38 @@ where
         Ok(Some(AcceptorProposerMessage::Greeting(AcceptorGreeting {
             term: self.state.acceptor_state.term,
             node_id: self.node_id,
+            mconf: msg.mconf.as_ref().map(|_| self.state.mconf.clone()),
         })))
     }
 
+    /// Switch to the given membership configuration, requested externally.
+    /// Returns the previous configuration.
+    pub fn switch_membership(&mut self, mconf: &Configuration) -> Result<Configuration> {
+        let previous = self.state.mconf.clone();
+        if *mconf == previous {
+            // The switch is retried.
+            return Ok(previous);
+        }
+        mconf.validate_switch_from(&previous)?;
+
+        info!(
+            "switching membership configuration from {:?} to {:?}",
+            previous, mconf
+        );
+        self.persist_mconf(mconf.clone())?;
+        Ok(previous)
+    }
+
+    /// Persist the new membership configuration along with the term bump:
+    /// the proposer elected in the previous configuration might not have the
+    /// quorum in the new one, it should be fenced off and restart the election.
+    fn persist_mconf(&mut self, mconf: Configuration) -> Result<()> {
+        let mut state = self.state.clone();
+        state.mconf = mconf;
+        state.acceptor_state.term += 1;
+        self.persist_control_file(state)
+    }
+
     /// Give vote for the given term, if we haven't done that previously.
     fn handle_vote_request(
         &mut self,
@@ -679,8 +820,22 @@ where
             truncate_lsn: self.state.peer_horizon_lsn,
             term_history: self.get_term_history(),
             timeline_start_lsn: self.state.timeline_start_lsn,
+            mconf: msg.generation.map(|_| self.state.mconf.clone()),
+            logical_slots: msg.generation.map(|_| self.state.logical_slots.clone()),
         };
-        if self.state.acceptor_state.term < msg.term {
+        let proposer_generation = msg.generation.unwrap_or(Generation::INVALID);
+        if proposer_generation != self.state.mconf.generation {
+            // The proposer will learn our configuration from the response and restart the election.
+            info!(
+                "refusing vote for term {} of proposer with membership configuration generation {}, ours is {}",
+                msg.term, proposer_generation, self.state.mconf.generation
+            );
+        } else if !self.state.mconf.is_voter(self.node_id) {
+            info!(
+                "refusing vote for term {}, not a member of configuration {:?}",
+                msg.term, self.state.mconf
+            );
+        } else if self.state.acceptor_state.term < msg.term {
             let mut state = self.state.clone();
             state.acceptor_state.term = msg.term;
             // persist vote before sending it out
@@ -796,6 +951,25 @@ where
         self.persist_control_file(self.state.clone())
     }
 
+    /// Whether the control file is up to date with the in-memory state.
+    pub fn is_inmem_persisted(&self) -> bool {
+        self.state.commit_lsn == self.inmem.commit_lsn
+            && self.state.backup_lsn == self.inmem.backup_lsn
+            && self.state.peer_horizon_lsn == self.inmem.peer_horizon_lsn
+            && self.state.remote_consistent_lsn == self.inmem.remote_consistent_lsn
+            && self.state.proposer_uuid == self.inmem.proposer_uuid
+            && self.state.partial_backup_lsn == self.inmem.partial_backup_lsn
+    }
+
+    /// Persist the in-memory state, if it is ahead of the control file, e.g. before
+    /// the timeline is offloaded from memory.
+    pub fn persist_inmem(&mut self) -> Result<()> {
+        if !self.is_inmem_persisted() {
+            self.persist_control_file(self.state.clone())?;
+        }
+        Ok(())
+    }
+
     /// Persist in-memory state to the disk, taking other data from state.
     fn persist_control_file(&mut self, mut state: SafeKeeperState) -> Result<()> {
         state.commit_lsn = self.inmem.commit_lsn;
@@ -803,6 +977,7 @@ where
         state.peer_horizon_lsn = self.inmem.peer_horizon_lsn;
         state.remote_consistent_lsn = self.inmem.remote_consistent_lsn;
         state.proposer_uuid = self.inmem.proposer_uuid;
+        state.partial_backup_lsn = self.inmem.partial_backup_lsn;
         self.state.persist(&state)
     }
 
@@ -906,6 +1081,9 @@ where
                 self.state.backup_lsn + (self.state.server.wal_seg_size as u64) < new_backup_lsn;
             self.inmem.backup_lsn = new_backup_lsn;
         }
+        if let Some(partial_backup_lsn) = sk_info.partial_backup_lsn {
+            self.inmem.partial_backup_lsn = max(partial_backup_lsn, self.inmem.partial_backup_lsn);
+        }
         if let Some(remote_consistent_lsn) = sk_info.remote_consistent_lsn {
             let new_remote_consistent_lsn =
                 max(remote_consistent_lsn, self.inmem.remote_consistent_lsn);
@@ -927,12 +1105,164 @@ where
         Ok(())
     }
 
+    /// Check that the WAL of a peer safekeeper, described by its term history,
+    /// can be appended to our WAL: the histories are the same up to our
+    /// flush_lsn, so our WAL is a part of the peer's one, and our entire term
+    /// history (which might go beyond our WAL) is a part of the peer's history.
+    /// A safekeeper without any WAL yet, e.g. the one joining the timeline, can
+    /// take the WAL of any peer.
+    pub fn check_peer_term_history(&self, peer_term_history: &TermHistory) -> Result<()> {
+        let local_history = self.get_term_history();
+        let peer_history = peer_term_history.up_to(self.flush_lsn());
+        ensure!(
+            term_history_is_prefix(&local_history, &peer_history)
+                && local_history.0.len() == peer_history.0.len(),
+            "local WAL with term history {:?} diverges from the peer WAL with term history {:?}",
+            local_history,
+            peer_term_history
+        );
+        ensure!(
+            term_history_is_prefix(&self.state.acceptor_state.term_history, peer_term_history),
+            "local term history {:?} is not a part of the peer term history {:?}",
+            self.state.acceptor_state.term_history,
+            peer_term_history
+        );
+        Ok(())
+    }
+
+    /// Append the committed WAL received from a peer safekeeper, after
+    /// verifying it against the peer's term history. Recovered WAL is flushed
+    /// immediately and the peer's term history is adopted.
+    pub fn append_recovered_wal(
+        &mut self,
+        peer_term_history: &TermHistory,
+        peer_commit_lsn: Lsn,
+        begin_lsn: Lsn,
+        wal_data: &[u8],
+    ) -> Result<()> {
+        self.check_peer_term_history(peer_term_history)?;
+
+        if self.state.timeline_start_lsn == Lsn(0) {
+            self.init_from_peer(peer_term_history, begin_lsn)?;
+        }
+
+        let flush_lsn = self.flush_lsn();
+        ensure!(
+            begin_lsn == flush_lsn,
+            "recovered WAL starts at {}, expected it at flush_lsn {}",
+            begin_lsn,
+            flush_lsn
+        );
+        let end_lsn = begin_lsn + wal_data.len() as u64;
+        ensure!(
+            end_lsn <= peer_commit_lsn,
+            "recovered WAL ends at {}, after the peer commit_lsn {}",
+            end_lsn,
+            peer_commit_lsn
+        );
+
+        self.wal_store.write_wal(begin_lsn, wal_data)?;
+        self.wal_store.flush_wal()?;
+
+        if self.state.acceptor_state.term_history.0.len() < peer_term_history.0.len() {
+            let mut state = self.state.clone();
+            state.acceptor_state.term_history = peer_term_history.clone();
+            // We hold the WAL of the peer's epoch now, don't let older proposers in.
+            if let Some(last_entry) = peer_term_history.0.last() {
+                state.acceptor_state.term = max(state.acceptor_state.term, last_entry.term);
+            }
+            self.persist_control_file(state)?;
+        }
+
+        self.global_commit_lsn = max(self.global_commit_lsn, peer_commit_lsn);
+        self.update_commit_lsn()?;
+
+        trace!(
+            "appended recovered WAL of len {}, begin_lsn={}, end_lsn={}",
+            wal_data.len(),
+            begin_lsn,
+            end_lsn
+        );
+        Ok(())
+    }
+
+    /// Initialize the timeline without any WAL to receive the WAL of the peer
+    /// since its beginning, like `handle_elected` does for the first proposer.
+    fn init_from_peer(&mut self, peer_term_history: &TermHistory, begin_lsn: Lsn) -> Result<()> {
+        let timeline_start_lsn = match peer_term_history.0.first() {
+            Some(first_entry) => first_entry.lsn,
+            None => bail!("peer timeline is not initialized by a proposer yet"),
+        };
+        ensure!(
+            begin_lsn == timeline_start_lsn,
+            "recovered WAL starts at {}, expected it at the peer timeline start {}",
+            begin_lsn,
+            timeline_start_lsn
+        );
+
+        self.wal_store.truncate_wal(timeline_start_lsn)?;
+
+        let mut state = self.state.clone();
+        state.timeline_start_lsn = timeline_start_lsn;
+        state.local_start_lsn = timeline_start_lsn;
+        info!(
+            "setting timeline_start_lsn and local_start_lsn to {} of the peer",
+            timeline_start_lsn
+        );
+        self.global_commit_lsn = max(self.global_commit_lsn, timeline_start_lsn);
+        self.inmem.commit_lsn = max(self.inmem.commit_lsn, timeline_start_lsn);
+        self.inmem.backup_lsn = max(self.inmem.backup_lsn, timeline_start_lsn);
+        self.inmem.remote_consistent_lsn =
+            max(self.inmem.remote_consistent_lsn, timeline_start_lsn);
+        self.persist_control_file(state)
+    }
+
+    /// Persist the logical replication slots of the compute. Only the proposer
+    /// elected in the current term runs the compute the slots belong to: other
+    /// proposers might have got our vote in this term but not the quorum. Since
+    /// the membership switch bumps the term, the proposers of the previous
+    /// configuration are ignored as well.
+    fn handle_logical_slots(
+        &mut self,
+        msg: &LogicalSlotsUpdate,
+    ) -> Result<Option<AcceptorProposerMessage>> {
+        if msg.term != self.state.acceptor_state.term {
+            info!(
+                "ignoring logical slots of term {}, current term is {}",
+                msg.term, self.state.acceptor_state.term
+            );
+            return Ok(None);
+        }
+        // ProposerElected adopts the term history of the proposer, ending with its term.
+        let elected_term = self.state.acceptor_state.term_history.0.last();
+        if elected_term.map(|entry| entry.term) != Some(msg.term) {
+            info!(
+                "ignoring logical slots of term {}, the proposer is not elected",
+                msg.term
+            );
+            return Ok(None);
+        }
+        if msg.slots == self.state.logical_slots {
+            return Ok(None);
+        }
+
+        let mut state = self.state.clone();
+        state.logical_slots = msg.slots.clone();
+        self.persist_control_file(state)?;
+        Ok(None)
+    }
+
     /// Get oldest segno we still need to keep. We hold WAL till it is consumed
     /// by all of 1) pageserver (remote_consistent_lsn) 2) peers 3) s3
-    /// offloading.
+    /// offloading 4) logical replication slots of the compute, unless they lag
+    /// more than `max_slot_wal_keep_size` behind the end of WAL.
     /// While it is safe to use inmem values for determining horizon,
     /// we use persistent to make possible normal states less surprising.
-    pub fn get_horizon_segno(&self, wal_backup_enabled: bool) -> XLogSegNo {
+    pub fn get_horizon_segno(
+        &self,
+        wal_backup_enabled: bool,
+        max_slot_wal_keep_size: Option<u64>,
+    ) -> XLogSegNo {
         let mut horizon_lsn = min(
             self.state.remote_consistent_lsn,
             self.state.peer_horizon_lsn,
@@ -940,15 +1270,38 @@ where
         if wal_backup_enabled {
             horizon_lsn = min(horizon_lsn, self.state.backup_lsn);
         }
+        if let Some(mut restart_lsn) = self.state.logical_slots.min_restart_lsn() {
+            if let Some(max_keep_size) = max_slot_wal_keep_size {
+                // Like in postgres, the WAL of the lagging slots is removed. The subscribers
+                // of such slots can't be resumed.
+                let keep_lsn = Lsn(self.flush_lsn().0.saturating_sub(max_keep_size));
+                restart_lsn = max(restart_lsn, keep_lsn);
+            }
+            horizon_lsn = min(horizon_lsn, restart_lsn);
+        }
         horizon_lsn.segment_number(self.state.server.wal_seg_size as usize)
     }
 }
 
+/// Check that all entries of the term history are present in the other one, in the same order.
+fn term_history_is_prefix(history: &TermHistory, other: &TermHistory) -> bool {
+    history.0.len() <= other.0.len()
+        && history
+            .0
+            .iter()
+            .zip(other.0.iter())
+            .all(|(entry, other_entry)| {
+                entry.term == other_entry.term && entry.lsn == other_entry.lsn
+            })
+}
+
 #[cfg(test)]
 mod tests {
     use postgres_ffi::WAL_SEGMENT_SIZE;
 
     use super::*;
+    use crate::logical_slots::LogicalSlot;
+    use crate::membership::SafekeeperId;
     use crate::wal_storage::Storage;
     use std::ops::Deref;
 
@@ -1021,7 +1374,10 @@ mod tests {
         let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
 
         // check voting for 1 is ok
-        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 1 });
+        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest {
+            term: 1,
+            generation: None,
+        });
         let mut vote_resp = sk.process_msg(&vote_request);
         match vote_resp.unwrap() {
             Some(AcceptorProposerMessage::VoteResponse(resp)) => assert!(resp.vote_given != 0),
@@ -1096,4 +1452,254 @@ mod tests {
         sk.wal_store.truncate_wal(Lsn(3)).unwrap(); // imitate the complete record at 3 %)
         assert_eq!(sk.get_epoch(), 1);
     }
+
+    #[test]
+    fn test_peer_recovery() {
+        let mut state = test_sk_state();
+        state.timeline_start_lsn = Lsn(1);
+        state.acceptor_state = AcceptorState {
+            term: 2,
+            term_history: TermHistory(vec![
+                TermSwitchEntry {
+                    term: 1,
+                    lsn: Lsn(1),
+                },
+                TermSwitchEntry {
+                    term: 2,
+                    lsn: Lsn(10),
+                },
+            ]),
+        };
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(20) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+
+        // the peer has switched the term earlier, so its WAL after Lsn(5) differs from ours
+        let diverged_history = TermHistory(vec![
+            TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            },
+            TermSwitchEntry {
+                term: 2,
+                lsn: Lsn(5),
+            },
+        ]);
+        assert!(sk
+            .append_recovered_wal(&diverged_history, Lsn(40), Lsn(20), b"wal")
+            .is_err());
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(20));
+
+        let peer_history = TermHistory(vec![
+            TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            },
+            TermSwitchEntry {
+                term: 2,
+                lsn: Lsn(10),
+            },
+            TermSwitchEntry {
+                term: 3,
+                lsn: Lsn(30),
+            },
+        ]);
+        // recovered WAL should continue the local one
+        assert!(sk
+            .append_recovered_wal(&peer_history, Lsn(40), Lsn(25), b"wal")
+            .is_err());
+        // and should not go beyond the peer commit_lsn
+        assert!(sk
+            .append_recovered_wal(&peer_history, Lsn(21), Lsn(20), b"wal")
+            .is_err());
+
+        sk.append_recovered_wal(&peer_history, Lsn(40), Lsn(20), &[0; 20])
+            .unwrap();
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(40));
+        assert_eq!(sk.inmem.commit_lsn, Lsn(40));
+        assert_eq!(sk.get_epoch(), 3);
+        assert_eq!(sk.state.acceptor_state.term, 3);
+        assert_eq!(sk.state.acceptor_state.term_history.0.len(), 3);
+
+        // a safekeeper without WAL takes the peer WAL since its beginning
+        let storage = InMemoryState {
+            persisted_state: test_sk_state(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+        sk.check_peer_term_history(&peer_history).unwrap();
+        assert!(sk
+            .append_recovered_wal(&peer_history, Lsn(40), Lsn(5), b"wal")
+            .is_err());
+        sk.append_recovered_wal(&peer_history, Lsn(40), Lsn(1), &[0; 39])
+            .unwrap();
+        assert_eq!(sk.state.timeline_start_lsn, Lsn(1));
+        assert_eq!(sk.state.local_start_lsn, Lsn(1));
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(40));
+        assert_eq!(sk.inmem.commit_lsn, Lsn(40));
+    }
+
+    #[test]
+    fn test_parse_vote_request() {
+        let mut buf = BytesMut::new();
+        buf.put_u64_le('v' as u64);
+        buf.put_u64_le(5); // term
+        buf.put_slice(&[0xff; 16]); // proposer id
+        let v2 = buf.clone().freeze();
+        buf.put_u32_le(7); // generation
+        let v3 = buf.freeze();
+
+        let parse = |msg: &Bytes, proto_version| {
+            let msg = ProposerAcceptorMessage::parse(msg.clone(), proto_version).unwrap();
+            match msg {
+                ProposerAcceptorMessage::VoteRequest(vote_request) => vote_request,
+                msg => panic!("unexpected message: {:?}", msg),
+            }
+        };
+
+        // the proposer id is not mistaken for the generation
+        let vote_request = parse(&v2, SK_PROTOCOL_VERSION_NO_MCONF);
+        assert_eq!((vote_request.term, vote_request.generation), (5, None));
+
+        let vote_request = parse(&v3, SK_PROTOCOL_VERSION);
+        assert_eq!(
+            (vote_request.term, vote_request.generation),
+            (5, Some(Generation(7)))
+        );
+        assert!(ProposerAcceptorMessage::parse(v2, SK_PROTOCOL_VERSION).is_err());
+    }
+
+    #[test]
+    fn test_membership_voting() {
+        let member_set = |ids: &[u64]| -> Vec<SafekeeperId> {
+            ids.iter()
+                .map(|&id| SafekeeperId {
+                    id: NodeId(id),
+                    pg_addr: format!("sk-{id}:5454"),
+                })
+                .collect()
+        };
+        let vote = |sk: &mut SafeKeeper<InMemoryState, DummyWalStore>,
+                    term: Term,
+                    generation: Option<u32>| {
+            let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest {
+                term,
+                generation: generation.map(Generation),
+            });
+            match sk.process_msg(&vote_request).unwrap() {
+                Some(AcceptorProposerMessage::VoteResponse(resp)) => resp,
+                r => panic!("unexpected response: {:?}", r),
+            }
+        };
+
+        let mut state = test_sk_state();
+        state.mconf = Configuration {
+            generation: Generation(1),
+            members: member_set(&[1, 2, 3]),
+            new_members: None,
+        };
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(4)).unwrap();
+
+        // not a member yet
+        assert_eq!(vote(&mut sk, 1, Some(1)).vote_given, 0);
+
+        let joint = Configuration {
+            generation: Generation(2),
+            members: member_set(&[1, 2, 3]),
+            new_members: Some(member_set(&[1, 2, 4])),
+        };
+        sk.switch_membership(&joint).unwrap();
+        assert_eq!(sk.state.mconf, joint);
+        assert_eq!(
+            sk.state.acceptor_state.term, 1,
+            "switch should fence off the proposers of the previous configuration"
+        );
+        // retries are fine, stale configurations are not
+        sk.switch_membership(&joint).unwrap();
+        assert_eq!(sk.state.acceptor_state.term, 1);
+        assert!(sk
+            .switch_membership(&Configuration {
+                generation: Generation(1),
+                members: member_set(&[1, 2, 4]),
+                new_members: None,
+            })
+            .is_err());
+
+        // proposers of other generations learn the configuration instead of getting the vote
+        let resp = vote(&mut sk, 2, Some(1));
+        assert_eq!(resp.vote_given, 0);
+        assert_eq!(resp.mconf, Some(joint.clone()));
+        assert_eq!(vote(&mut sk, 2, None).vote_given, 0);
+        assert_ne!(vote(&mut sk, 2, Some(2)).vote_given, 0);
+    }
+
+    #[test]
+    fn test_logical_slots() {
+        let seg_size = WAL_SEGMENT_SIZE as u64;
+        let mut state = test_sk_state();
+        state.acceptor_state.term = 2;
+        state.remote_consistent_lsn = Lsn(10 * seg_size);
+        state.peer_horizon_lsn = Lsn(10 * seg_size);
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+        assert_eq!(sk.get_horizon_segno(false, None), 10);
+
+        let slots = LogicalSlots(vec![LogicalSlot {
+            name: "sub".to_owned(),
+            plugin: "pgoutput".to_owned(),
+            database: 5,
+            catalog_xmin: 730,
+            restart_lsn: Lsn(3 * seg_size + 100),
+            confirmed_flush_lsn: Lsn(4 * seg_size),
+        }]);
+        let update = |term| {
+            ProposerAcceptorMessage::LogicalSlots(LogicalSlotsUpdate {
+                term,
+                slots: slots.clone(),
+            })
+        };
+
+        // slots of the stale proposer are ignored
+        assert!(sk.process_msg(&update(1)).unwrap().is_none());
+        assert_eq!(sk.state.logical_slots, LogicalSlots::empty());
+        // as well as of the proposer not elected yet
+        assert!(sk.process_msg(&update(2)).unwrap().is_none());
+        assert_eq!(sk.state.logical_slots, LogicalSlots::empty());
+
+        let mut state = sk.state.clone();
+        state.acceptor_state.term_history = TermHistory(vec![TermSwitchEntry {
+            term: 2,
+            lsn: Lsn(seg_size),
+        }]);
+        sk.state.persist(&state).unwrap();
+
+        assert!(sk.process_msg(&update(2)).unwrap().is_none());
+        assert_eq!(sk.state.logical_slots, slots);
+        // WAL since the slot restart_lsn is kept
+        assert_eq!(sk.get_horizon_segno(false, None), 3);
+        // up to max_slot_wal_keep_size behind the end of WAL
+        sk.wal_store.lsn = Lsn(8 * seg_size);
+        assert_eq!(sk.get_horizon_segno(false, Some(2 * seg_size)), 6);
+        assert_eq!(sk.get_horizon_segno(false, Some(6 * seg_size)), 3);
+
+        // slots are sent back to the proposer to be restored on the compute
+        match sk.process_msg(&ProposerAcceptorMessage::VoteRequest(VoteRequest {
+            term: 3,
+            generation: Some(Generation::INVALID),
+        })) {
+            Ok(Some(AcceptorProposerMessage::VoteResponse(resp))) => {
+                assert_eq!(resp.logical_slots, Some(slots))
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
 }
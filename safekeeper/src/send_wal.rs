@@ -22,7 +22,7 @@ use tokio::time::timeout;
 use tracing::*;
 use utils::{
     bin_ser::BeSer,
-    lsn::Lsn,
+    lsn::{AtomicLsn, Lsn},
     postgres_backend::PostgresBackend,
     pq_proto::{BeMessage, FeMessage, ReplicationFeedback, WalSndKeepAlive, XLogDataBody},
     sock_split::ReadStream,
@@ -160,11 +160,15 @@ impl ReplicationConn {
     ///
     /// Handle START_REPLICATION replication command
     ///
+    /// If `requested_stop_pos` is given, streaming ends after the committed WAL up to it is sent,
+    /// which allows the pageserver to fetch fixed WAL ranges from several safekeepers at once.
+    ///
     pub fn run(
         &mut self,
         spg: &mut SafekeeperPostgresHandler,
         pgb: &mut PostgresBackend,
         mut start_pos: Lsn,
+        requested_stop_pos: Option<Lsn>,
     ) -> Result<()> {
         let _enter = info_span!("WAL sender", timeline = %spg.timeline_id.unwrap()).entered();
 
@@ -175,9 +179,15 @@ impl ReplicationConn {
         let bg_stream_in = self.stream_in.take().unwrap();
         let bg_timeline_id = spg.timeline_id.unwrap();
 
-        let state = ReplicaState::new();
+        // Sent WAL position is published to the timeline after every message, without
+        // taking the timeline lock.
+        let sent_lsn = Arc::new(AtomicLsn::from(start_pos));
         // This replica_id is used below to check if it's time to stop replication.
-        let replica_id = bg_timeline.add_replica(state);
+        let replica_id = bg_timeline.add_replica(
+            spg.appname.clone(),
+            *pgb.get_peer_addr(),
+            Arc::clone(&sent_lsn),
+        );
 
         // Use a guard object to remove our entry from the timeline, when the background
         // thread and us have both finished using it.
@@ -223,7 +233,11 @@ impl ReplicationConn {
                 None
             };
 
-            info!("Start replication from {:?} till {:?}", start_pos, stop_pos);
+            info!(
+                "Start replication from {:?} till {:?}",
+                start_pos,
+                stop_pos.or(requested_stop_pos)
+            );
 
             // switch to copy
             pgb.write_message(&BeMessage::CopyBothResponse)?;
@@ -250,11 +264,20 @@ impl ReplicationConn {
                     }
                     end_pos = stop_pos;
                 } else {
+                    if let Some(requested_stop_pos) = requested_stop_pos {
+                        if start_pos >= requested_stop_pos {
+                            break; /* requested WAL range is sent */
+                        }
+                    }
+
                     /* Wait until we have some data to stream */
                     let lsn = wait_for_lsn(&mut commit_lsn_watch_rx, start_pos).await?;
 
                     if let Some(lsn) = lsn {
-                        end_pos = lsn;
+                        end_pos = match requested_stop_pos {
+                            Some(requested_stop_pos) => lsn.min(requested_stop_pos),
+                            None => lsn,
+                        };
                     } else {
                         // TODO: also check once in a while whether we are walsender
                         // to right pageserver.
@@ -294,6 +317,7 @@ impl ReplicationConn {
                 .context("Failed to send XLogData")?;
 
                 start_pos += send_size as u64;
+                sent_lsn.store(start_pos);
                 trace!("sent WAL up to {}", start_pos);
             }
 
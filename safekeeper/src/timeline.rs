@@ -1,7 +1,7 @@
 //! This module implements Timeline lifecycle management and has all neccessary code
 //! to glue together SafeKeeper and all other background services.
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
 
 use etcd_broker::subscription_value::SkTimelineInfo;
 
@@ -13,20 +13,25 @@ use std::cmp::{max, min};
 
 use parking_lot::{Mutex, MutexGuard};
 
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use tokio::sync::mpsc::Sender;
 use tracing::*;
 
 use utils::{
     id::{NodeId, TenantTimelineId},
-    lsn::Lsn,
+    lsn::{AtomicLsn, Lsn},
     pq_proto::ReplicationFeedback,
 };
 
+use crate::membership::Configuration;
+use crate::recovery::RecoveryDonor;
 use crate::safekeeper::{
     AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
-    SafekeeperMemState, ServerInfo,
+    SafekeeperMemState, ServerInfo, TermHistory,
 };
 use crate::send_wal::HotStandbyFeedback;
 use crate::{control_file, safekeeper::UNKNOWN_SERVER_VERSION};
@@ -70,12 +75,26 @@ impl ReplicaState {
     }
 }
 
+/// WAL sender connection of the timeline, streaming WAL to a pageserver or a replica.
+#[derive(Debug, Clone)]
+pub struct Replica {
+    /// Replica internal ID, assigned by the timeline.
+    pub id: usize,
+    pub appname: Option<String>,
+    pub client_addr: SocketAddr,
+    pub start_time: SystemTime,
+    /// End of the WAL sent to the replica, updated by the WAL sender without taking
+    /// the timeline lock.
+    pub sent_lsn: Arc<AtomicLsn>,
+    pub state: ReplicaState,
+}
+
 /// Shared state associated with database instance
 pub struct SharedState {
     /// Safekeeper object
     sk: SafeKeeper<control_file::FileStorage, wal_storage::PhysicalStorage>,
     /// State of replicas
-    replicas: Vec<Option<ReplicaState>>,
+    replicas: Vec<Option<Replica>>,
     /// True when WAL backup launcher oversees the timeline, making sure WAL is
     /// offloaded, allows to bother launcher less.
     wal_backup_active: bool,
@@ -90,6 +109,15 @@ pub struct SharedState {
     active: bool,
     num_computes: u32,
     last_removed_segno: XLogSegNo,
+    /// True while the missing WAL is recovered from a peer safekeeper.
+    peer_recovery_active: bool,
+}
+
+/// State of the timeline offloaded from memory: it keeps only the control file
+/// and the info it keeps publishing to the broker.
+pub struct OffloadedTimeline {
+    pub state: SafeKeeperState,
+    pub sk_info: SkTimelineInfo,
 }
 
 impl SharedState {
@@ -120,6 +148,7 @@ impl SharedState {
             active: false,
             num_computes: 0,
             last_removed_segno: 0,
+            peer_recovery_active: false,
         })
     }
 
@@ -132,13 +161,23 @@ impl SharedState {
 
         let wal_store = wal_storage::PhysicalStorage::new(ttid, conf, &control_store)?;
 
+        // Old WAL is removed without updating the control file, so find out
+        // what is left on disk: the pageserver relies on the local start LSN
+        // we report to fall back to the WAL archive for the removed segments.
+        let last_removed_segno = wal_storage::first_segment_on_disk(
+            &conf.timeline_dir(ttid),
+            control_store.server.wal_seg_size as usize,
+        )?
+        .unwrap_or(0);
+
         Ok(Self {
             sk: SafeKeeper::new(control_store, wal_store, conf.my_id)?,
             replicas: Vec::new(),
             wal_backup_active: false,
             active: false,
             num_computes: 0,
-            last_removed_segno: 0,
+            last_removed_segno,
+            peer_recovery_active: false,
         })
     }
 
@@ -149,6 +188,15 @@ impl SharedState {
             || self.sk.inmem.remote_consistent_lsn < self.sk.inmem.commit_lsn
     }
 
+    /// Whether the timeline is idle and can be offloaded from memory: nobody
+    /// is connected, WAL is backed up and the pageserver has caught up.
+    fn is_offloadable(&self) -> bool {
+        !self.is_active()
+            && !self.wal_backup_active
+            && !self.peer_recovery_active
+            && self.replicas.iter().all(Option::is_none)
+    }
+
     /// Mark timeline active/inactive and return whether s3 offloading requires
     /// start/stop action.
     fn update_status(&mut self, ttid: TenantTimelineId) -> bool {
@@ -164,9 +212,11 @@ impl SharedState {
     fn is_wal_backup_required(&self) -> bool {
         let seg_size = self.get_wal_seg_size();
         self.num_computes > 0 ||
-        // Currently only the whole segment is offloaded, so compare segment numbers.
+        // Whole segments are offloaded as soon as they are complete, so compare segment numbers.
                (self.sk.inmem.commit_lsn.segment_number(seg_size) >
-                self.sk.inmem.backup_lsn.segment_number(seg_size))
+                self.sk.inmem.backup_lsn.segment_number(seg_size)) ||
+        // The tail of the current segment is offloaded as a partial segment.
+               self.sk.inmem.commit_lsn > max(self.sk.inmem.backup_lsn, self.sk.inmem.partial_backup_lsn)
     }
 
     /// Is current state of s3 offloading is not what it ought to be?
@@ -203,10 +253,37 @@ impl SharedState {
         self.sk.state.server.wal_seg_size as usize
     }
 
+    /// Get the LSN of the earliest WAL present locally: older segments are
+    /// either removed already or were never received by this safekeeper.
+    fn get_local_wal_start_lsn(&self) -> Lsn {
+        let removed_up_to = Lsn(self.last_removed_segno * self.get_wal_seg_size() as u64);
+        max(self.sk.state.local_start_lsn, removed_up_to)
+    }
+
+    /// Get info about the timeline to publish to the broker.
+    fn get_public_info(&self, conf: &SafeKeeperConf) -> SkTimelineInfo {
+        SkTimelineInfo {
+            last_log_term: Some(self.sk.get_epoch()),
+            flush_lsn: Some(self.sk.wal_store.flush_lsn()),
+            // note: this value is not flushed to control file yet and can be lost
+            commit_lsn: Some(self.sk.inmem.commit_lsn),
+            // TODO: rework feedbacks to avoid max here
+            remote_consistent_lsn: Some(max(
+                self.get_replicas_state().remote_consistent_lsn,
+                self.sk.inmem.remote_consistent_lsn,
+            )),
+            peer_horizon_lsn: Some(self.sk.inmem.peer_horizon_lsn),
+            local_start_lsn: Some(self.get_local_wal_start_lsn()),
+            safekeeper_connstr: Some(conf.listen_pg_addr.clone()),
+            backup_lsn: Some(self.sk.inmem.backup_lsn),
+            partial_backup_lsn: Some(self.sk.inmem.partial_backup_lsn),
+        }
+    }
+
     /// Get combined state of all alive replicas
     pub fn get_replicas_state(&self) -> ReplicaState {
         let mut acc = ReplicaState::new();
-        for state in self.replicas.iter().flatten() {
+        for state in self.replicas.iter().flatten().map(|replica| &replica.state) {
             acc.hs_feedback.ts = max(acc.hs_feedback.ts, state.hs_feedback.ts);
             acc.hs_feedback.xmin = min(acc.hs_feedback.xmin, state.hs_feedback.xmin);
             acc.hs_feedback.catalog_xmin =
@@ -251,15 +328,22 @@ impl SharedState {
 
     /// Assign new replica ID. We choose first empty cell in the replicas vector
     /// or extend the vector if there are no free slots.
-    pub fn add_replica(&mut self, state: ReplicaState) -> usize {
+    pub fn add_replica(&mut self, mut replica: Replica) -> usize {
         if let Some(pos) = self.replicas.iter().position(|r| r.is_none()) {
-            self.replicas[pos] = Some(state);
+            replica.id = pos;
+            self.replicas[pos] = Some(replica);
             return pos;
         }
         let pos = self.replicas.len();
-        self.replicas.push(Some(state));
+        replica.id = pos;
+        self.replicas.push(Some(replica));
         pos
     }
+
+    /// Get all alive replicas.
+    fn get_replicas(&self) -> Vec<Replica> {
+        self.replicas.iter().flatten().cloned().collect()
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -423,6 +507,48 @@ impl Timeline {
         }
     }
 
+    /// Whether the timeline is idle and can be offloaded from memory.
+    pub fn is_offloadable(&self) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+
+        self.write_shared_state().is_offloadable()
+    }
+
+    /// Persists the in-memory state of the idle timeline, so that it can be offloaded
+    /// without writing anything. Returns false if the timeline is not idle anymore.
+    pub fn persist_for_offload(&self) -> Result<bool> {
+        if self.is_cancelled() {
+            return Ok(false);
+        }
+
+        let mut shared_state = self.write_shared_state();
+        if !shared_state.is_offloadable() {
+            return Ok(false);
+        }
+        shared_state.sk.persist_inmem()?;
+        Ok(true)
+    }
+
+    /// Returns the state to keep once the idle timeline is offloaded. Returns `None` if
+    /// the timeline is busy, not idle anymore or its state has changed since it was persisted.
+    pub fn try_offload(&self, conf: &SafeKeeperConf) -> Option<OffloadedTimeline> {
+        // The caller holds the global timelines lock, don't wait for the timeline one.
+        let shared_state = self.mutex.try_lock()?;
+        if self.is_cancelled()
+            || !shared_state.is_offloadable()
+            || !shared_state.sk.is_inmem_persisted()
+        {
+            return None;
+        }
+
+        Some(OffloadedTimeline {
+            state: shared_state.sk.state.clone(),
+            sk_info: shared_state.get_public_info(conf),
+        })
+    }
+
     /// Returns if timeline is cancelled.
     pub fn is_cancelled(&self) -> bool {
         *self.cancellation_rx.borrow()
@@ -484,7 +610,7 @@ impl Timeline {
 
         let mut shared_state = self.write_shared_state();
         if shared_state.num_computes == 0 {
-            let replica_state = shared_state.replicas[replica_id].unwrap();
+            let replica_state = shared_state.replicas[replica_id].as_ref().unwrap().state;
             let stop = shared_state.sk.inmem.commit_lsn == Lsn(0) || // no data at all yet
             (replica_state.remote_consistent_lsn != Lsn::MAX && // Lsn::MAX means that we don't know the latest LSN yet.
              replica_state.remote_consistent_lsn >= shared_state.sk.inmem.commit_lsn);
@@ -528,12 +654,7 @@ impl Timeline {
         if state.active {
             Some(FullTimelineInfo {
                 ttid: self.ttid,
-                replicas: state
-                    .replicas
-                    .iter()
-                    .filter_map(|r| r.as_ref())
-                    .copied()
-                    .collect(),
+                replicas: state.get_replicas(),
                 wal_backup_active: state.wal_backup_active,
                 timeline_is_active: state.active,
                 num_computes: state.num_computes,
@@ -584,11 +705,155 @@ impl Timeline {
         Ok(rmsg)
     }
 
+    /// Returns the term history of the committed WAL, for peers to verify
+    /// their WAL against it before recovering the WAL from this safekeeper.
+    pub fn get_committed_term_history(&self) -> TermHistory {
+        let shared_state = self.write_shared_state();
+        shared_state
+            .sk
+            .get_term_history()
+            .up_to(shared_state.sk.inmem.commit_lsn)
+    }
+
+    /// Checks whether the peer safekeeper has the committed WAL this one lacks
+    /// and marks the peer recovery as started, returning the peer to recover from.
+    ///
+    /// Recovery happens only when no compute is connected: walproposer recovers
+    /// safekeepers itself and would race with the peer recovery otherwise.
+    pub fn try_start_peer_recovery(
+        &self,
+        sk_info: &SkTimelineInfo,
+        sk_id: NodeId,
+    ) -> Option<RecoveryDonor> {
+        if self.is_cancelled() {
+            return None;
+        }
+
+        let mut shared_state = self.write_shared_state();
+        if shared_state.peer_recovery_active || shared_state.num_computes > 0 {
+            return None;
+        }
+        if shared_state.sk.state.timeline_start_lsn == Lsn(0) {
+            return None;
+        }
+        // Peers with WAL of another term need walproposer to decide which WAL wins.
+        if sk_info.last_log_term != Some(shared_state.sk.get_epoch()) {
+            return None;
+        }
+        let commit_lsn = sk_info.commit_lsn?;
+        if commit_lsn <= shared_state.sk.wal_store.flush_lsn() {
+            return None;
+        }
+        let pg_addr = sk_info.safekeeper_connstr.clone()?;
+
+        shared_state.peer_recovery_active = true;
+        Some(RecoveryDonor {
+            sk_id,
+            pg_addr,
+            commit_lsn,
+        })
+    }
+
+    /// Marks the peer recovery, requested externally rather than triggered by the broker data, as started.
+    /// Fails if another recovery of the timeline is running.
+    pub fn start_requested_peer_recovery(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        let mut shared_state = self.write_shared_state();
+        if shared_state.peer_recovery_active {
+            bail!("peer recovery of timeline {} is in progress", self.ttid);
+        }
+        shared_state.peer_recovery_active = true;
+        Ok(())
+    }
+
+    /// Marks the peer recovery as finished, allowing the next one to start.
+    pub fn finish_peer_recovery(&self) {
+        self.write_shared_state().peer_recovery_active = false;
+    }
+
+    /// Verifies that the WAL of a peer with the given term history can be appended to the local WAL.
+    pub fn check_peer_term_history(&self, peer_term_history: &TermHistory) -> Result<()> {
+        self.write_shared_state()
+            .sk
+            .check_peer_term_history(peer_term_history)
+    }
+
+    /// Appends the WAL recovered from a peer safekeeper and makes it durable.
+    /// Fails if a compute connected in the meantime, to let it take over the recovery.
+    pub fn append_recovered_wal(
+        &self,
+        peer_term_history: &TermHistory,
+        peer_commit_lsn: Lsn,
+        begin_lsn: Lsn,
+        wal_data: &[u8],
+    ) -> Result<()> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        let commit_lsn: Lsn;
+        {
+            let mut shared_state = self.write_shared_state();
+            if shared_state.num_computes > 0 {
+                bail!("compute connected, stopping peer recovery");
+            }
+            shared_state.sk.append_recovered_wal(
+                peer_term_history,
+                peer_commit_lsn,
+                begin_lsn,
+                wal_data,
+            )?;
+            commit_lsn = shared_state.sk.inmem.commit_lsn;
+        }
+        self.commit_lsn_watch_tx.send(commit_lsn)?;
+        Ok(())
+    }
+
+    /// Switches the timeline to the given membership configuration, returning the previous one.
+    pub fn switch_membership(&self, mconf: &Configuration) -> Result<Configuration> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        self.write_shared_state().sk.switch_membership(mconf)
+    }
+
+    /// Drops the WAL after commit_lsn of the timeline, pulled from another safekeeper:
+    /// the source could rewrite its uncommitted WAL while the files were being copied.
+    pub fn truncate_uncommitted_wal(&self) -> Result<()> {
+        let mut shared_state = self.write_shared_state();
+        let commit_lsn = shared_state.sk.inmem.commit_lsn;
+        let flush_lsn = shared_state.sk.wal_store.flush_lsn();
+        ensure!(
+            flush_lsn >= commit_lsn,
+            "timeline {} WAL ends at {flush_lsn}, before commit_lsn {commit_lsn}",
+            self.ttid
+        );
+        shared_state.sk.wal_store.truncate_wal(commit_lsn)
+    }
+
     /// Returns wal_seg_size.
     pub fn get_wal_seg_size(&self) -> usize {
         self.write_shared_state().get_wal_seg_size()
     }
 
+    /// Returns the directory with the timeline files.
+    pub fn get_timeline_dir(&self) -> &Path {
+        &self.timeline_dir
+    }
+
+    /// Returns the range of the committed WAL, present locally.
+    pub fn get_local_committed_wal(&self) -> (Lsn, Lsn) {
+        let shared_state = self.write_shared_state();
+        (
+            shared_state.get_local_wal_start_lsn(),
+            shared_state.sk.inmem.commit_lsn,
+        )
+    }
+
     /// Returns true only if the timeline is loaded and active.
     pub fn is_active(&self) -> bool {
         if self.is_cancelled() {
@@ -621,23 +886,27 @@ impl Timeline {
         Ok(())
     }
 
+    /// Returns the LSN up to which the current segment is backed up as a partial one.
+    pub fn get_wal_partial_backup_lsn(&self) -> Lsn {
+        self.write_shared_state().sk.inmem.partial_backup_lsn
+    }
+
+    /// Sets partial_backup_lsn after the partial segment upload and persists it,
+    /// not to lose track of the uploaded partial segment.
+    pub fn set_wal_partial_backup_lsn(&self, partial_backup_lsn: Lsn) -> Result<()> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        let mut shared_state = self.write_shared_state();
+        let inmem = &mut shared_state.sk.inmem;
+        inmem.partial_backup_lsn = max(inmem.partial_backup_lsn, partial_backup_lsn);
+        shared_state.sk.persist_inmem()
+    }
+
     /// Return public safekeeper info for broadcasting to broker and other peers.
     pub fn get_public_info(&self, conf: &SafeKeeperConf) -> SkTimelineInfo {
-        let shared_state = self.write_shared_state();
-        SkTimelineInfo {
-            last_log_term: Some(shared_state.sk.get_epoch()),
-            flush_lsn: Some(shared_state.sk.wal_store.flush_lsn()),
-            // note: this value is not flushed to control file yet and can be lost
-            commit_lsn: Some(shared_state.sk.inmem.commit_lsn),
-            // TODO: rework feedbacks to avoid max here
-            remote_consistent_lsn: Some(max(
-                shared_state.get_replicas_state().remote_consistent_lsn,
-                shared_state.sk.inmem.remote_consistent_lsn,
-            )),
-            peer_horizon_lsn: Some(shared_state.sk.inmem.peer_horizon_lsn),
-            safekeeper_connstr: Some(conf.listen_pg_addr.clone()),
-            backup_lsn: Some(shared_state.sk.inmem.backup_lsn),
-        }
+        self.write_shared_state().get_public_info(conf)
     }
 
     /// Update timeline state with peer safekeeper data.
@@ -662,15 +931,34 @@ impl Timeline {
         Ok(())
     }
 
-    /// Add send_wal replica to the in-memory vector of replicas.
-    pub fn add_replica(&self, state: ReplicaState) -> usize {
-        self.write_shared_state().add_replica(state)
+    /// Add send_wal replica to the in-memory vector of replicas, returning its ID.
+    pub fn add_replica(
+        &self,
+        appname: Option<String>,
+        client_addr: SocketAddr,
+        sent_lsn: Arc<AtomicLsn>,
+    ) -> usize {
+        self.write_shared_state().add_replica(Replica {
+            id: 0,
+            appname,
+            client_addr,
+            start_time: SystemTime::now(),
+            sent_lsn,
+            state: ReplicaState::new(),
+        })
     }
 
     /// Update replication replica state.
     pub fn update_replica_state(&self, id: usize, state: ReplicaState) {
         let mut shared_state = self.write_shared_state();
-        shared_state.replicas[id] = Some(state);
+        if let Some(replica) = shared_state.replicas[id].as_mut() {
+            replica.state = state;
+        }
+    }
+
+    /// Returns all WAL senders of the timeline.
+    pub fn get_replicas(&self) -> Vec<Replica> {
+        self.write_shared_state().get_replicas()
     }
 
     /// Remove send_wal replica from the in-memory vector of replicas.
@@ -686,8 +974,13 @@ impl Timeline {
     }
 
     /// Delete WAL segments from disk that are no longer needed. This is determined
-    /// based on pageserver's remote_consistent_lsn and local backup_lsn/peer_lsn.
-    pub fn remove_old_wal(&self, wal_backup_enabled: bool) -> Result<()> {
+    /// based on pageserver's remote_consistent_lsn, local backup_lsn/peer_lsn and
+    /// logical replication slots of the compute.
+    pub fn remove_old_wal(
+        &self,
+        wal_backup_enabled: bool,
+        max_slot_wal_keep_size: Option<u64>,
+    ) -> Result<()> {
         if self.is_cancelled() {
             bail!(TimelineError::Cancelled(self.ttid));
         }
@@ -696,7 +989,9 @@ impl Timeline {
         let remover: Box<dyn Fn(u64) -> Result<(), anyhow::Error>>;
         {
             let shared_state = self.write_shared_state();
-            horizon_segno = shared_state.sk.get_horizon_segno(wal_backup_enabled);
+            horizon_segno = shared_state
+                .sk
+                .get_horizon_segno(wal_backup_enabled, max_slot_wal_keep_size);
             remover = shared_state.sk.wal_store.remove_up_to();
             if horizon_segno <= 1 || horizon_segno <= shared_state.last_removed_segno {
                 return Ok(());
@@ -722,3 +1017,45 @@ fn delete_dir(path: &PathBuf) -> Result<bool> {
         Err(e) => Err(e.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replica_sent_lsn() -> Result<()> {
+        let (wal_backup_launcher_tx, _wal_backup_launcher_rx) = tokio::sync::mpsc::channel(1);
+        let server_info = ServerInfo {
+            pg_version: 140000,
+            system_id: 0,
+            wal_seg_size: 16 * 1024 * 1024,
+        };
+        let tli = Timeline::create_empty(
+            SafeKeeperConf::default(),
+            TenantTimelineId::generate(),
+            wal_backup_launcher_tx,
+            server_info,
+        )?;
+        let client_addr: SocketAddr = "127.0.0.1:5432".parse().unwrap();
+
+        let sent_lsn = Arc::new(AtomicLsn::from(Lsn(0x100)));
+        let id = tli.add_replica(Some("pageserver".to_owned()), client_addr, sent_lsn.clone());
+        let other_id = tli.add_replica(None, client_addr, Arc::new(AtomicLsn::from(Lsn(0x200))));
+        assert_ne!(id, other_id);
+
+        // The sender publishes its position without the timeline lock, readers see the latest one.
+        sent_lsn.store(Lsn(0x180));
+        let replicas = tli.get_replicas();
+        assert_eq!(replicas.len(), 2);
+        let replica = replicas.iter().find(|replica| replica.id == id).unwrap();
+        assert_eq!(replica.appname.as_deref(), Some("pageserver"));
+        assert_eq!(replica.sent_lsn.load(), Lsn(0x180));
+
+        tli.remove_replica(id);
+        let replicas = tli.get_replicas();
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].id, other_id);
+        assert_eq!(replicas[0].sent_lsn.load(), Lsn(0x200));
+        Ok(())
+    }
+}
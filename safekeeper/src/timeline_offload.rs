@@ -0,0 +1,49 @@
+//! Thread offloading idle timelines from memory.
+//!
+//! A loaded timeline holds its `SharedState` and an open WAL segment, while most of the
+//! timelines on a safekeeper are idle for a long time. Timelines idle for
+//! `timeline_offload_after` are offloaded from [`GlobalTimelines`], keeping only the control
+//! file state, and are loaded back once a compute connects or the WAL is requested.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::*;
+use utils::id::TenantTimelineId;
+
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+pub fn thread_main(conf: SafeKeeperConf) {
+    let check_interval = Duration::from_secs(10);
+    let mut idle_since: HashMap<TenantTimelineId, Instant> = HashMap::new();
+    loop {
+        let mut to_offload = Vec::new();
+        let mut still_idle = HashMap::new();
+        for tli in GlobalTimelines::get_all() {
+            if !tli.is_offloadable() {
+                continue;
+            }
+            let since = idle_since
+                .get(&tli.ttid)
+                .copied()
+                .unwrap_or_else(Instant::now);
+            if since.elapsed() >= conf.timeline_offload_after {
+                to_offload.push(tli.ttid);
+            } else {
+                still_idle.insert(tli.ttid, since);
+            }
+        }
+        idle_since = still_idle;
+
+        // References to the timelines are dropped by now, otherwise they are considered in use.
+        for ttid in to_offload {
+            let _enter =
+                info_span!("", tenant = %ttid.tenant_id, timeline = %ttid.timeline_id).entered();
+            if let Err(e) = GlobalTimelines::offload(ttid) {
+                warn!("failed to offload timeline: {:#}", e);
+            }
+        }
+        thread::sleep(check_interval)
+    }
+}
@@ -1,23 +1,43 @@
 //! This module contains global (tenant_id, timeline_id) -> Arc<Timeline> mapping.
 //! All timelines should always be present in this map, this is done by loading them
 //! all from the disk on startup and keeping them in memory.
+//!
+//! Idle timelines can be offloaded from memory, keeping only their control file state.
+//! Such timelines are loaded back transparently once requested, see [`GlobalTimelines::get`].
+//! The disk is never touched under the global lock while offloading or loading back, a timeline
+//! being loaded back is guarded by its own lock instead.
 
+use crate::metrics::OFFLOADED_TIMELINES;
 use crate::safekeeper::ServerInfo;
-use crate::timeline::{Timeline, TimelineError};
+use crate::timeline::{OffloadedTimeline, Timeline, TimelineError};
 use crate::SafeKeeperConf;
 use anyhow::{anyhow, bail, Context, Result};
+use etcd_broker::subscription_value::SkTimelineInfo;
 use once_cell::sync::Lazy;
 use serde::Serialize;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex, MutexGuard};
 use tokio::sync::mpsc::Sender;
 use tracing::*;
 use utils::id::{TenantId, TenantTimelineId, TimelineId};
 
+/// Timeline offloaded from memory.
+struct OffloadedEntry {
+    timeline: OffloadedTimeline,
+    /// Held while the timeline is loaded back, so that concurrent requests wait for
+    /// the single load instead of the global lock.
+    load_lock: Mutex<()>,
+}
+
 struct GlobalTimelinesState {
     timelines: HashMap<TenantTimelineId, Arc<Timeline>>,
+    /// Timelines offloaded from memory, loaded back on the first request.
+    offloaded: HashMap<TenantTimelineId, Arc<OffloadedEntry>>,
+    /// Timelines being pulled from another safekeeper, see [`GlobalTimelines::start_pull`].
+    pulling: HashSet<TenantTimelineId>,
     wal_backup_launcher_tx: Option<Sender<TenantTimelineId>>,
     conf: SafeKeeperConf,
 }
@@ -31,18 +51,33 @@ impl GlobalTimelinesState {
         )
     }
 
+    /// Whether the timeline is in the map, loaded or offloaded, or is being pulled.
+    fn contains(&self, ttid: &TenantTimelineId) -> bool {
+        self.timelines.contains_key(ttid)
+            || self.offloaded.contains_key(ttid)
+            || self.pulling.contains(ttid)
+    }
+
     /// Insert timeline into the map. Returns error if timeline with the same id already exists.
     fn try_insert(&mut self, timeline: Arc<Timeline>) -> Result<()> {
         let ttid = timeline.ttid;
-        if self.timelines.contains_key(&ttid) {
+        if self.contains(&ttid) {
             bail!(TimelineError::AlreadyExists(ttid));
         }
         self.timelines.insert(ttid, timeline);
         Ok(())
     }
 
-    /// Get timeline from the map. Returns error if timeline doesn't exist.
-    fn get(&self, ttid: &TenantTimelineId) -> Result<Arc<Timeline>> {
+    /// Whether the given entry is still the offloaded one of the timeline, i.e. it
+    /// wasn't loaded back or deleted meanwhile.
+    fn is_offloaded(&self, ttid: &TenantTimelineId, entry: &Arc<OffloadedEntry>) -> bool {
+        self.offloaded
+            .get(ttid)
+            .map_or(false, |offloaded| Arc::ptr_eq(offloaded, entry))
+    }
+
+    /// Get timeline from the map, if it is loaded in memory.
+    fn get_loaded(&self, ttid: &TenantTimelineId) -> Result<Arc<Timeline>> {
         self.timelines
             .get(ttid)
             .cloned()
@@ -53,11 +88,46 @@ impl GlobalTimelinesState {
 static TIMELINES_STATE: Lazy<Mutex<GlobalTimelinesState>> = Lazy::new(|| {
     Mutex::new(GlobalTimelinesState {
         timelines: HashMap::new(),
+        offloaded: HashMap::new(),
+        pulling: HashSet::new(),
         wal_backup_launcher_tx: None,
         conf: SafeKeeperConf::default(),
     })
 });
 
+/// Reservation of a timeline being pulled from another safekeeper. Unless the pulled timeline
+/// was loaded, the temporary directory is removed and the reservation released on drop.
+pub struct PullGuard {
+    ttid: TenantTimelineId,
+    tmp_dir: PathBuf,
+    loaded: bool,
+}
+
+impl PullGuard {
+    /// Directory to download the timeline to, exclusive to this pull.
+    pub fn tmp_dir(&self) -> &Path {
+        &self.tmp_dir
+    }
+}
+
+impl Drop for PullGuard {
+    fn drop(&mut self) {
+        if self.loaded {
+            return;
+        }
+        if self.tmp_dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&self.tmp_dir) {
+                warn!(
+                    "failed to remove {} after failed pull: {}",
+                    self.tmp_dir.display(),
+                    e
+                );
+            }
+        }
+        TIMELINES_STATE.lock().unwrap().pulling.remove(&self.ttid);
+    }
+}
+
 /// A zero-sized struct used to manage access to the global timelines map.
 pub struct GlobalTimelines;
 
@@ -154,14 +224,11 @@ impl GlobalTimelines {
     /// Create a new timeline with the given id. If the timeline already exists, returns
     /// an existing timeline.
     pub fn create(ttid: TenantTimelineId, server_info: ServerInfo) -> Result<Arc<Timeline>> {
-        let (conf, wal_backup_launcher_tx) = {
-            let state = TIMELINES_STATE.lock().unwrap();
-            if let Ok(timeline) = state.get(&ttid) {
-                // Timeline already exists, return it.
-                return Ok(timeline);
-            }
-            state.get_dependencies()
-        };
+        if let Ok(timeline) = Self::get_or_rehydrate(ttid) {
+            // Timeline already exists, return it.
+            return Ok(timeline);
+        }
+        let (conf, wal_backup_launcher_tx) = TIMELINES_STATE.lock().unwrap().get_dependencies();
 
         info!("creating new timeline {}", ttid);
 
@@ -209,11 +276,88 @@ impl GlobalTimelines {
         }
     }
 
+    /// Reserves the timeline id for pulling the timeline from another safekeeper. Until the
+    /// returned guard is dropped, the timeline can be neither created nor pulled concurrently.
+    /// Fails if the timeline exists already.
+    pub fn start_pull(ttid: TenantTimelineId) -> Result<PullGuard> {
+        let mut state = TIMELINES_STATE.lock().unwrap();
+        if state.contains(&ttid) {
+            bail!(TimelineError::AlreadyExists(ttid));
+        }
+        state.pulling.insert(ttid);
+        let tmp_dir = state
+            .conf
+            .workdir
+            .join(format!("tmp_pull_{}_{}", ttid.tenant_id, ttid.timeline_id));
+        Ok(PullGuard {
+            ttid,
+            tmp_dir,
+            loaded: false,
+        })
+    }
+
+    /// Moves the timeline pulled from another safekeeper from the temporary directory in place,
+    /// loads it and registers in the map. Fails if the timeline directory exists already.
+    /// The disk is accessed without the global lock, the timeline id is reserved by `pull` instead.
+    pub fn load_pulled_timeline(mut pull: PullGuard) -> Result<Arc<Timeline>> {
+        let ttid = pull.ttid;
+        let tmp_dir = pull.tmp_dir.as_path();
+        let (conf, wal_backup_launcher_tx) = TIMELINES_STATE.lock().unwrap().get_dependencies();
+        let timeline_dir = conf.timeline_dir(&ttid);
+        if timeline_dir.exists() {
+            // Most likely, a timeline that failed to load on startup.
+            bail!(TimelineError::Invalid(ttid));
+        }
+
+        let tenant_dir = conf.tenant_dir(&ttid.tenant_id);
+        std::fs::create_dir_all(&tenant_dir)
+            .with_context(|| format!("failed to create tenant dir {}", tenant_dir.display()))?;
+        std::fs::rename(tmp_dir, &timeline_dir).with_context(|| {
+            format!(
+                "failed to move pulled timeline from {} to {}",
+                tmp_dir.display(),
+                timeline_dir.display()
+            )
+        })?;
+        if !conf.no_sync {
+            File::open(&tenant_dir)
+                .and_then(|f| f.sync_all())
+                .context("failed to sync tenant dir")?;
+        }
+
+        let timeline =
+            Timeline::load_timeline(conf, ttid, wal_backup_launcher_tx).and_then(|timeline| {
+                timeline.truncate_uncommitted_wal()?;
+                Ok(timeline)
+            });
+        match timeline {
+            Ok(timeline) => {
+                let timeline = Arc::new(timeline);
+                let mut state = TIMELINES_STATE.lock().unwrap();
+                state.pulling.remove(&ttid);
+                state.try_insert(Arc::clone(&timeline))?;
+                pull.loaded = true;
+                info!("loaded timeline {} pulled from another safekeeper", ttid);
+                Ok(timeline)
+            }
+            Err(e) => {
+                // Don't leave a timeline that can't be loaded on the next startup.
+                if let Err(fs_err) = std::fs::remove_dir_all(&timeline_dir) {
+                    warn!(
+                        "failed to remove pulled timeline {} directory after load failure: {}",
+                        ttid, fs_err
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
     /// Get a timeline from the global map. If it's not present, it doesn't exist on disk,
     /// or was corrupted and couldn't be loaded on startup. Returned timeline is always valid,
     /// i.e. loaded in memory and not cancelled.
     pub fn get(ttid: TenantTimelineId) -> Result<Arc<Timeline>> {
-        let res = TIMELINES_STATE.lock().unwrap().get(&ttid);
+        let res = Self::get_or_rehydrate(ttid);
 
         match res {
             Ok(tli) => {
@@ -226,7 +370,125 @@ impl GlobalTimelines {
         }
     }
 
-    /// Returns all timelines. This is used for background timeline proccesses.
+    /// Get timeline from the map, loading it back if it was offloaded. Returns error
+    /// if timeline doesn't exist.
+    fn get_or_rehydrate(ttid: TenantTimelineId) -> Result<Arc<Timeline>> {
+        let offloaded = {
+            let state = TIMELINES_STATE.lock().unwrap();
+            if let Some(timeline) = state.timelines.get(&ttid) {
+                return Ok(timeline.clone());
+            }
+            match state.offloaded.get(&ttid) {
+                Some(offloaded) => offloaded.clone(),
+                None => bail!(TimelineError::NotFound(ttid)),
+            }
+        };
+        Self::rehydrate(ttid, offloaded)
+    }
+
+    /// Loads the offloaded timeline back to memory. The timeline is read from disk
+    /// holding only its own load lock.
+    fn rehydrate(ttid: TenantTimelineId, offloaded: Arc<OffloadedEntry>) -> Result<Arc<Timeline>> {
+        let _load_guard = offloaded.load_lock.lock().unwrap();
+        let (conf, wal_backup_launcher_tx) = {
+            let state = TIMELINES_STATE.lock().unwrap();
+            // Loaded back by a concurrent request while we were waiting for the load lock.
+            if let Some(timeline) = state.timelines.get(&ttid) {
+                return Ok(timeline.clone());
+            }
+            if !state.is_offloaded(&ttid, &offloaded) {
+                bail!(TimelineError::NotFound(ttid));
+            }
+            state.get_dependencies()
+        };
+
+        let timeline = Timeline::load_timeline(conf, ttid, wal_backup_launcher_tx)
+            .with_context(|| format!("failed to load offloaded timeline {}", ttid))?;
+        let timeline = Arc::new(timeline);
+
+        let mut state = TIMELINES_STATE.lock().unwrap();
+        // The timeline might have been deleted while we were loading it.
+        if !state.is_offloaded(&ttid, &offloaded) {
+            bail!(TimelineError::NotFound(ttid));
+        }
+        state.offloaded.remove(&ttid);
+        state.timelines.insert(ttid, timeline.clone());
+        OFFLOADED_TIMELINES.set(state.offloaded.len() as i64);
+        info!("loaded offloaded timeline {} back to memory", ttid);
+        Ok(timeline)
+    }
+
+    /// Get a timeline from the global map, if it is loaded in memory. Used by background
+    /// activities, which have nothing to do for the offloaded timelines.
+    pub fn get_loaded(ttid: TenantTimelineId) -> Result<Arc<Timeline>> {
+        let tli = TIMELINES_STATE.lock().unwrap().get_loaded(&ttid)?;
+        if tli.is_cancelled() {
+            bail!(TimelineError::Cancelled(ttid));
+        }
+        Ok(tli)
+    }
+
+    /// Get a timeline to record the peer safekeeper info in. The offloaded timeline is loaded
+    /// back only if the peer has committed the WAL it lacks, not to be loaded back by every update.
+    pub fn get_for_peer_info(
+        ttid: TenantTimelineId,
+        sk_info: &SkTimelineInfo,
+    ) -> Result<Arc<Timeline>> {
+        {
+            let state = TIMELINES_STATE.lock().unwrap();
+            if let Some(offloaded) = state.offloaded.get(&ttid) {
+                if sk_info.commit_lsn.map_or(true, |commit_lsn| {
+                    commit_lsn <= offloaded.timeline.state.commit_lsn
+                }) {
+                    bail!("timeline {} is offloaded", ttid);
+                }
+            }
+        }
+        Self::get(ttid)
+    }
+
+    /// Offloads the idle timeline from memory, keeping only its control file state.
+    /// Returns false if the timeline is used by someone or is not idle anymore.
+    pub fn offload(ttid: TenantTimelineId) -> Result<bool> {
+        let timeline = match TIMELINES_STATE.lock().unwrap().timelines.get(&ttid) {
+            Some(timeline) => timeline.clone(),
+            None => return Ok(false),
+        };
+        // Persist the state without the global lock, the timeline is offloaded
+        // below only if the state hasn't changed since.
+        if !timeline.persist_for_offload()? {
+            return Ok(false);
+        }
+
+        let mut state = TIMELINES_STATE.lock().unwrap();
+        match state.timelines.get(&ttid) {
+            Some(current) if Arc::ptr_eq(current, &timeline) => {}
+            _ => return Ok(false),
+        }
+        // Nobody can get a new reference while we hold the lock, so the timeline
+        // is not used if only the map and we hold it.
+        if Arc::strong_count(&timeline) > 2 {
+            return Ok(false);
+        }
+        let offloaded = match timeline.try_offload(&state.conf) {
+            Some(offloaded) => offloaded,
+            None => return Ok(false),
+        };
+
+        state.timelines.remove(&ttid);
+        state.offloaded.insert(
+            ttid,
+            Arc::new(OffloadedEntry {
+                timeline: offloaded,
+                load_lock: Mutex::new(()),
+            }),
+        );
+        OFFLOADED_TIMELINES.set(state.offloaded.len() as i64);
+        info!("offloaded idle timeline {} from memory", ttid);
+        Ok(true)
+    }
+
+    /// Returns all timelines loaded in memory. This is used for background timeline proccesses.
     pub fn get_all() -> Vec<Arc<Timeline>> {
         let global_lock = TIMELINES_STATE.lock().unwrap();
         global_lock
@@ -237,21 +499,33 @@ impl GlobalTimelines {
             .collect()
     }
 
-    /// Returns all timelines belonging to a given tenant. Used for deleting all timelines of a tenant,
-    /// and that's why it can return cancelled timelines, to retry deleting them.
-    fn get_all_for_tenant(tenant_id: TenantId) -> Vec<Arc<Timeline>> {
+    /// Returns the info to publish to the broker for all offloaded timelines, as of offloading.
+    pub fn get_offloaded_info() -> Vec<(TenantTimelineId, SkTimelineInfo)> {
+        let global_lock = TIMELINES_STATE.lock().unwrap();
+        global_lock
+            .offloaded
+            .iter()
+            .map(|(ttid, offloaded)| (*ttid, offloaded.timeline.sk_info.clone()))
+            .collect()
+    }
+
+    /// Returns ids of all timelines belonging to a given tenant, including the offloaded ones.
+    /// Used for deleting all timelines of a tenant, and that's why it can return cancelled
+    /// timelines, to retry deleting them.
+    fn get_all_for_tenant(tenant_id: TenantId) -> Vec<TenantTimelineId> {
         let global_lock = TIMELINES_STATE.lock().unwrap();
         global_lock
             .timelines
-            .values()
-            .filter(|t| t.ttid.tenant_id == tenant_id)
-            .cloned()
+            .keys()
+            .chain(global_lock.offloaded.keys())
+            .filter(|ttid| ttid.tenant_id == tenant_id)
+            .copied()
             .collect()
     }
 
     /// Cancels timeline, then deletes the corresponding data directory.
     pub fn delete_force(ttid: &TenantTimelineId) -> Result<TimelineDeleteForceResult> {
-        let tli_res = TIMELINES_STATE.lock().unwrap().get(ttid);
+        let tli_res = Self::get_or_rehydrate(*ttid);
         match tli_res {
             Ok(timeline) => {
                 // Take a lock and finish the deletion holding this mutex.
@@ -269,8 +543,15 @@ impl GlobalTimelines {
                 })
             }
             Err(_) => {
-                // Timeline is not memory, but it may still exist on disk in broken state.
-                let dir_path = TIMELINES_STATE.lock().unwrap().conf.timeline_dir(ttid);
+                // Timeline is not memory, but it may still exist on disk in broken state,
+                // e.g. an offloaded timeline that failed to load back.
+                let dir_path = {
+                    let mut state = TIMELINES_STATE.lock().unwrap();
+                    if state.offloaded.remove(ttid).is_some() {
+                        OFFLOADED_TIMELINES.set(state.offloaded.len() as i64);
+                    }
+                    state.conf.timeline_dir(ttid)
+                };
                 let dir_existed = delete_dir(dir_path)?;
 
                 Ok(TimelineDeleteForceResult {
@@ -294,13 +575,13 @@ impl GlobalTimelines {
         let mut err = None;
 
         let mut deleted = HashMap::new();
-        for tli in &to_delete {
-            match Self::delete_force(&tli.ttid) {
+        for ttid in &to_delete {
+            match Self::delete_force(ttid) {
                 Ok(result) => {
-                    deleted.insert(tli.ttid, result);
+                    deleted.insert(*ttid, result);
                 }
                 Err(e) => {
-                    error!("failed to delete timeline {}: {}", tli.ttid, e);
+                    error!("failed to delete timeline {}: {}", ttid, e);
                     // Save error to return later.
                     err = Some(e);
                 }
@@ -346,3 +627,61 @@ fn delete_dir(path: PathBuf) -> Result<bool> {
         Err(e) => Err(e.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // The map is global, so a single test goes through offloading and loading back.
+    #[test]
+    fn test_offload_rehydrate() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            no_sync: true,
+            ..Default::default()
+        };
+        let (wal_backup_launcher_tx, _wal_backup_launcher_rx) = tokio::sync::mpsc::channel(100);
+        GlobalTimelines::init(conf, wal_backup_launcher_tx)?;
+
+        let ttid = TenantTimelineId::generate();
+        let server_info = ServerInfo {
+            pg_version: 140000,
+            system_id: 0,
+            wal_seg_size: 16 * 1024 * 1024,
+        };
+        let timeline = GlobalTimelines::create(ttid, server_info)?;
+
+        // The timeline is in use while someone holds a reference.
+        assert!(!GlobalTimelines::offload(ttid)?);
+        drop(timeline);
+        assert!(GlobalTimelines::offload(ttid)?);
+        assert!(GlobalTimelines::get_loaded(ttid).is_err());
+        assert!(GlobalTimelines::get_all().is_empty());
+        let offloaded_info = GlobalTimelines::get_offloaded_info();
+        assert_eq!(offloaded_info.len(), 1);
+        assert_eq!(offloaded_info[0].0, ttid);
+
+        // Concurrent requests wait for a single load.
+        let handles = (0..4)
+            .map(|_| thread::spawn(move || GlobalTimelines::get(ttid)))
+            .collect::<Vec<_>>();
+        let timelines = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert!(timelines.iter().all(|tli| Arc::ptr_eq(tli, &timelines[0])));
+        assert!(GlobalTimelines::get_loaded(ttid).is_ok());
+        assert!(GlobalTimelines::get_offloaded_info().is_empty());
+        drop(timelines);
+
+        // An offloaded timeline is deleted from disk as well.
+        assert!(GlobalTimelines::offload(ttid)?);
+        let res = GlobalTimelines::delete_force(&ttid)?;
+        assert!(res.dir_existed);
+        assert!(GlobalTimelines::get(ttid).is_err());
+        assert!(GlobalTimelines::get_offloaded_info().is_empty());
+        Ok(())
+    }
+}
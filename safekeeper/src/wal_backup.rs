@@ -1,10 +1,11 @@
 use anyhow::{Context, Result};
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
 use etcd_broker::subscription_key::{
     NodeKind, OperationKind, SkOperationKind, SubscriptionKey, SubscriptionKind,
 };
 use tokio::task::JoinHandle;
 
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
@@ -14,14 +15,16 @@ use std::time::Duration;
 use postgres_ffi::v14::xlog_utils::XLogSegNoOffsetToRecPtr;
 use postgres_ffi::XLogFileName;
 use postgres_ffi::{XLogSegNo, PG_TLI};
-use remote_storage::GenericRemoteStorage;
+use remote_storage::{DownloadError, GenericRemoteStorage, StorageMetadata};
+use safekeeper_api::{WalCompression, WAL_COMPRESSION_METADATA_KEY};
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 use tokio::runtime::Builder;
 
 use tokio::select;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::watch;
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 use tracing::*;
 
 use utils::{id::TenantTimelineId, lsn::Lsn};
@@ -37,6 +40,13 @@ const BROKER_CONNECTION_RETRY_DELAY_MS: u64 = 1000;
 const UPLOAD_FAILURE_RETRY_MIN_MS: u64 = 10;
 const UPLOAD_FAILURE_RETRY_MAX_MS: u64 = 5000;
 
+/// How often the current, incomplete segment is backed up as a partial segment,
+/// while WAL keeps arriving. The tail of an idle timeline is backed up within this period.
+const PARTIAL_BACKUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Metadata key of the LSN the partial segment upload ends at.
+const PARTIAL_SEGMENT_END_LSN_METADATA_KEY: &str = "end_lsn";
+
 pub fn wal_backup_launcher_thread_main(
     conf: SafeKeeperConf,
     wal_backup_launcher_rx: Receiver<TenantTimelineId>,
@@ -55,7 +65,7 @@ pub fn wal_backup_launcher_thread_main(
 /// Check whether wal backup is required for timeline. If yes, mark that launcher is
 /// aware of current status and return the timeline.
 fn is_wal_backup_required(ttid: TenantTimelineId) -> Option<Arc<Timeline>> {
-    GlobalTimelines::get(ttid)
+    GlobalTimelines::get_loaded(ttid)
         .ok()
         .filter(|tli| tli.wal_backup_attend())
 }
@@ -128,12 +138,21 @@ async fn wal_backup_launcher_main_loop(
     );
 
     let conf_ = conf.clone();
+    WAL_BACKUP_COMPRESSION.get_or_init(|| conf_.wal_backup_compression);
     REMOTE_STORAGE.get_or_init(|| {
         conf_.remote_storage.as_ref().map(|c| {
             GenericRemoteStorage::from_config(conf_.workdir, c)
                 .expect("failed to create remote storage")
         })
     });
+    if let Some(storage) = REMOTE_STORAGE.get().and_then(Option::as_ref) {
+        if let Err(e) = storage.cleanup_abandoned_uploads().await {
+            warn!(
+                "failed to clean up abandoned remote storage uploads: {:?}",
+                e
+            );
+        }
+    }
 
     // Presense in this map means launcher is aware s3 offloading is needed for
     // the timeline, but task is started only if it makes sense for to offload
@@ -192,6 +211,10 @@ struct WalBackupTask {
     commit_lsn_watch_rx: watch::Receiver<Lsn>,
     leader: Option<ElectionLeader>,
     election: Election,
+    /// Time of the last partial segment upload by this task.
+    last_partial_backup: Option<Instant>,
+    /// The partial segments of the segments before this one are known to be removed.
+    partial_removed_segno: Option<XLogSegNo>,
 }
 
 /// Offload single timeline. Called only after we checked that backup
@@ -203,7 +226,7 @@ async fn backup_task_main(
     election: Election,
 ) {
     info!("started");
-    let res = GlobalTimelines::get(ttid);
+    let res = GlobalTimelines::get_loaded(ttid);
     if let Err(e) = res {
         error!("backup error for timeline {}: {}", ttid, e);
         return;
@@ -217,6 +240,8 @@ async fn backup_task_main(
         timeline_dir,
         leader: None,
         election,
+        last_partial_backup: None,
+        partial_removed_segno: None,
     };
 
     // task is spinned up only when wal_seg_size already initialized
@@ -250,15 +275,23 @@ impl WalBackupTask {
                 continue;
             }
             info!("acquired leadership");
+            // The previous leader might have not removed the partial segment.
+            self.remove_completed_partial_segments().await;
 
             // offload loop
             loop {
                 if retry_attempt == 0 {
-                    // wait for new WAL to arrive
-                    if let Err(e) = self.commit_lsn_watch_rx.changed().await {
-                        // should never happen, as we hold Arc to timeline.
-                        error!("commit_lsn watch shut down: {:?}", e);
-                        return;
+                    // wait for new WAL to arrive, or for the time to back up the partial segment
+                    let partial_backup_deadline = self.partial_backup_deadline(backup_lsn);
+                    select! {
+                        res = self.commit_lsn_watch_rx.changed() => {
+                            if let Err(e) = res {
+                                // should never happen, as we hold Arc to timeline.
+                                error!("commit_lsn watch shut down: {:?}", e);
+                                return;
+                            }
+                        }
+                        _ = sleep_until(partial_backup_deadline) => {}
                     }
                 } else {
                     // or just sleep if we errored previously
@@ -276,17 +309,16 @@ impl WalBackupTask {
                 // Note that backup_lsn can be higher than commit_lsn if we
                 // don't have much local WAL and others already uploaded
                 // segments we don't even have.
-                if backup_lsn.segment_number(self.wal_seg_size)
-                    >= commit_lsn.segment_number(self.wal_seg_size)
-                {
-                    continue; /* nothing to do, common case as we wake up on every commit_lsn bump */
+                let mut full_backup_needed = backup_lsn.segment_number(self.wal_seg_size)
+                    < commit_lsn.segment_number(self.wal_seg_size);
+                if full_backup_needed {
+                    // Perhaps peers advanced the position, check shmem value.
+                    backup_lsn = self.timeline.get_wal_backup_lsn();
+                    full_backup_needed = backup_lsn.segment_number(self.wal_seg_size)
+                        < commit_lsn.segment_number(self.wal_seg_size);
                 }
-                // Perhaps peers advanced the position, check shmem value.
-                backup_lsn = self.timeline.get_wal_backup_lsn();
-                if backup_lsn.segment_number(self.wal_seg_size)
-                    >= commit_lsn.segment_number(self.wal_seg_size)
-                {
-                    continue;
+                if !full_backup_needed && !self.is_partial_backup_due(backup_lsn, commit_lsn) {
+                    continue; /* nothing to do, common case as we wake up on every commit_lsn bump */
                 }
 
                 if let Some(l) = self.leader.as_mut() {
@@ -315,6 +347,20 @@ impl WalBackupTask {
                     }
                 }
 
+                if !full_backup_needed {
+                    match self.backup_partial(commit_lsn).await {
+                        Ok(()) => retry_attempt = 0,
+                        Err(e) => {
+                            error!(
+                                "failed while offloading partial segment up to {}: {:?}",
+                                commit_lsn, e
+                            );
+                            retry_attempt = min(retry_attempt + 1, u32::MAX);
+                        }
+                    }
+                    continue;
+                }
+
                 match backup_lsn_range(
                     backup_lsn,
                     commit_lsn,
@@ -331,6 +377,7 @@ impl WalBackupTask {
                             return;
                         }
                         retry_attempt = 0;
+                        self.remove_completed_partial_segments().await;
                     }
                     Err(e) => {
                         error!(
@@ -344,6 +391,82 @@ impl WalBackupTask {
             }
         }
     }
+
+    /// Whether the WAL of the current segment after the last partial upload should be backed up now.
+    fn is_partial_backup_due(&self, backup_lsn: Lsn, commit_lsn: Lsn) -> bool {
+        commit_lsn > max(backup_lsn, self.timeline.get_wal_partial_backup_lsn())
+            && self
+                .last_partial_backup
+                .map_or(true, |last_partial_backup| {
+                    last_partial_backup.elapsed() >= PARTIAL_BACKUP_INTERVAL
+                })
+    }
+
+    /// When to wake up to back up the partial segment, if there is WAL not backed up yet.
+    fn partial_backup_deadline(&self, backup_lsn: Lsn) -> Option<Instant> {
+        let commit_lsn = *self.commit_lsn_watch_rx.borrow();
+        if commit_lsn <= max(backup_lsn, self.timeline.get_wal_partial_backup_lsn()) {
+            return None;
+        }
+        Some(match self.last_partial_backup {
+            Some(last_partial_backup) => last_partial_backup + PARTIAL_BACKUP_INTERVAL,
+            None => Instant::now(),
+        })
+    }
+
+    /// Uploads the partial segment, unless it's backed up up to `commit_lsn` already.
+    /// partial_backup_lsn includes the uploads of the peers learned from the broker, so a lagging
+    /// safekeeper, which was the backup leader recently, doesn't replace the longer partial upload
+    /// of the new leader. Both might upload at the same time still, but the leaders switch rarely.
+    async fn backup_partial(&mut self, commit_lsn: Lsn) -> Result<()> {
+        let partial_backup_lsn = self.timeline.get_wal_partial_backup_lsn();
+        if partial_backup_lsn >= commit_lsn {
+            info!(
+                "skipping partial backup up to {}, it is backed up up to {} already",
+                commit_lsn, partial_backup_lsn
+            );
+            return Ok(());
+        }
+
+        backup_partial_segment(commit_lsn, self.wal_seg_size, &self.timeline_dir).await?;
+        self.last_partial_backup = Some(Instant::now());
+        self.timeline.set_wal_partial_backup_lsn(commit_lsn)
+    }
+
+    /// Removes the partial segments, uploaded by us or by the peers, of the segments backed up entirely.
+    /// Partial segments are uploaded up to the segment of partial_backup_lsn, and the earlier ones
+    /// are removed as soon as they are complete, so usually there is a single segment to check.
+    async fn remove_completed_partial_segments(&mut self) {
+        let partial_backup_lsn = self.timeline.get_wal_partial_backup_lsn();
+        if partial_backup_lsn == Lsn(0) {
+            return;
+        }
+        let partial_segno = partial_backup_lsn.segment_number(self.wal_seg_size);
+        let backup_segno = self
+            .timeline
+            .get_wal_backup_lsn()
+            .segment_number(self.wal_seg_size);
+
+        let first_segno = self.partial_removed_segno.unwrap_or(partial_segno);
+        let end_segno = min(backup_segno, partial_segno + 1);
+        for segno in first_segno..end_segno {
+            if let Err(e) =
+                remove_partial_segment(segno, self.wal_seg_size, &self.timeline_dir).await
+            {
+                warn!("failed to remove backed up partial segment {segno}: {e:?}");
+                return;
+            }
+            self.partial_removed_segno = Some(segno + 1);
+        }
+    }
+}
+
+/// Sleeps until the deadline, or forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
 }
 
 pub async fn backup_lsn_range(
@@ -424,23 +547,179 @@ fn get_segments(start: Lsn, end: Lsn, seg_size: usize) -> Vec<Segment> {
 
 static REMOTE_STORAGE: OnceCell<Option<GenericRemoteStorage>> = OnceCell::new();
 
+static WAL_BACKUP_COMPRESSION: OnceCell<WalCompression> = OnceCell::new();
+
 async fn backup_object(source_file: &Path, size: usize) -> Result<()> {
+    let file = BufReader::new(File::open(&source_file).await.with_context(|| {
+        format!(
+            "Failed to open file {} for wal backup",
+            source_file.display()
+        )
+    })?);
+
+    upload_object(file, size, source_file, HashMap::new()).await
+}
+
+/// Path of the partial segment, which the remote object of the segment's partial backup is named after.
+fn partial_segment_path(timeline_dir: &Path, segno: XLogSegNo, wal_seg_size: usize) -> PathBuf {
+    timeline_dir.join(XLogFileName(PG_TLI, segno, wal_seg_size) + ".partial")
+}
+
+/// Uploads the WAL of the current segment up to `commit_lsn` as a partial segment,
+/// replacing its previous partial upload.
+async fn backup_partial_segment(
+    commit_lsn: Lsn,
+    wal_seg_size: usize,
+    timeline_dir: &Path,
+) -> Result<()> {
+    let segno = commit_lsn.segment_number(wal_seg_size);
+    let size = commit_lsn.segment_offset(wal_seg_size);
+    let partial_path = partial_segment_path(timeline_dir, segno, wal_seg_size);
+
+    // The segment might have been completed and renamed meanwhile.
+    let file = match File::open(&partial_path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let segment_path = timeline_dir.join(XLogFileName(PG_TLI, segno, wal_seg_size));
+            File::open(&segment_path).await.with_context(|| {
+                format!(
+                    "Failed to open file {} for partial wal backup",
+                    segment_path.display()
+                )
+            })?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let metadata = HashMap::from([(
+        PARTIAL_SEGMENT_END_LSN_METADATA_KEY.to_string(),
+        commit_lsn.to_string(),
+    )]);
+    upload_object(
+        BufReader::new(file.take(size as u64)),
+        size,
+        &partial_path,
+        metadata,
+    )
+    .await?;
+    debug!(
+        "Backup of {} up to {} done",
+        partial_path.display(),
+        commit_lsn
+    );
+    Ok(())
+}
+
+/// Removes the remote partial segment of the segment.
+async fn remove_partial_segment(
+    segno: XLogSegNo,
+    wal_seg_size: usize,
+    timeline_dir: &Path,
+) -> Result<()> {
+    let partial_path = partial_segment_path(timeline_dir, segno, wal_seg_size);
+    delete_object(&partial_path).await?;
+    debug!(
+        "Removed backed up partial segment {}",
+        partial_path.display()
+    );
+    Ok(())
+}
+
+/// Uploads the WAL from `source` to the remote object of the `local_path`, compressing it if configured.
+/// The codec is added to the `metadata` stored along with the object.
+async fn upload_object(
+    source: impl tokio::io::AsyncBufRead + Unpin + Send + Sync + 'static,
+    size: usize,
+    local_path: &Path,
+    mut metadata: HashMap<String, String>,
+) -> Result<()> {
     let storage = REMOTE_STORAGE
         .get()
         .expect("failed to get remote storage")
         .as_ref()
         .unwrap();
 
-    let file = tokio::io::BufReader::new(File::open(&source_file).await.with_context(|| {
-        format!(
-            "Failed to open file {} for wal backup",
-            source_file.display()
-        )
-    })?);
+    let compression = WAL_BACKUP_COMPRESSION
+        .get()
+        .copied()
+        .unwrap_or(WalCompression::None);
+    match compression {
+        WalCompression::None => {
+            let metadata = (!metadata.is_empty()).then(|| StorageMetadata::new(metadata));
+            storage
+                .upload_storage_object(Box::new(source), size, local_path, metadata)
+                .await
+        }
+        compression => {
+            // The storage needs the object size upfront, so the segment is compressed in memory.
+            let compressed = compress_segment(source, compression)
+                .await
+                .with_context(|| {
+                    format!("Failed to compress {} for wal backup", local_path.display())
+                })?;
+            let compressed_size = compressed.len();
+            metadata.insert(
+                WAL_COMPRESSION_METADATA_KEY.to_string(),
+                compression.as_str().to_string(),
+            );
+            let metadata = StorageMetadata::new(metadata);
+            storage
+                .upload_storage_object(
+                    Box::new(std::io::Cursor::new(compressed)),
+                    compressed_size,
+                    local_path,
+                    Some(metadata),
+                )
+                .await
+        }
+    }
+}
 
-    storage
-        .upload_storage_object(Box::new(file), size, source_file)
-        .await
+async fn delete_object(local_path: &Path) -> Result<()> {
+    let storage = REMOTE_STORAGE
+        .get()
+        .expect("failed to get remote storage")
+        .as_ref()
+        .unwrap();
+
+    let remote_object_id = storage.remote_object_id(local_path)?;
+    storage.delete(&remote_object_id).await
+}
+
+async fn compress_segment(
+    mut segment: impl tokio::io::AsyncBufRead + Unpin,
+    compression: WalCompression,
+) -> Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    match compression {
+        WalCompression::None => {
+            segment.read_to_end(&mut compressed).await?;
+        }
+        WalCompression::Zstd => {
+            ZstdEncoder::new(segment)
+                .read_to_end(&mut compressed)
+                .await?;
+        }
+    }
+    Ok(compressed)
+}
+
+/// Codec of the backed up segment, as recorded in its metadata.
+fn segment_compression(metadata: Option<&StorageMetadata>) -> Result<WalCompression> {
+    match metadata.and_then(|metadata| metadata.get(WAL_COMPRESSION_METADATA_KEY)) {
+        Some(codec) => codec.parse().map_err(|e: String| anyhow::anyhow!(e)),
+        None => Ok(WalCompression::None),
+    }
+}
+
+fn decompressing_reader(
+    compressed: impl AsyncRead + Send + Unpin + 'static,
+    compression: WalCompression,
+) -> Pin<Box<dyn AsyncRead>> {
+    match compression {
+        WalCompression::None => Box::pin(compressed),
+        WalCompression::Zstd => Box::pin(ZstdDecoder::new(BufReader::new(compressed))),
+    }
 }
 
 pub async fn read_object(
@@ -452,21 +731,281 @@ pub async fn read_object(
         .context("Failed to get remote storage")?
         .as_ref()
         .context("No remote storage configured")?;
+    let compression = WAL_BACKUP_COMPRESSION
+        .get()
+        .copied()
+        .unwrap_or(WalCompression::None);
 
     info!(
         "segment download about to start for local path {} at offset {}",
         file_path.display(),
         offset
     );
-    let download = storage
-        .download_storage_object(Some((offset, None)), &file_path)
-        .await
-        .with_context(|| {
-            format!(
-                "Failed to open WAL segment download stream for local path {}",
+    match read_segment(storage, &file_path, offset, compression).await {
+        Ok(reader) => Ok(reader),
+        // The segment is not complete yet, its beginning might be backed up as a partial segment.
+        Err(DownloadError::NotFound) => {
+            let mut partial_path = file_path;
+            partial_path.set_extension("partial");
+            read_segment(storage, &partial_path, offset, compression)
+                .await
+                .map_err(|e| anyhow::Error::new(e).context(download_failure_context(&partial_path)))
+        }
+        Err(e) => Err(anyhow::Error::new(e).context(download_failure_context(&file_path))),
+    }
+}
+
+/// Downloads the backed up segment of the local path, starting at `offset` of the decompressed segment.
+///
+/// Compressed segments can't be read from the middle, so they are decompressed from the beginning,
+/// and a ranged request might even go past the end of the compressed object. Hence with the compression
+/// configured, the segments are downloaded entirely right away. Otherwise the ranged download is tried
+/// first, as the segment might have been uploaded before the compression was enabled.
+async fn read_segment(
+    storage: &GenericRemoteStorage,
+    file_path: &Path,
+    offset: u64,
+    compression: WalCompression,
+) -> Result<Pin<Box<dyn tokio::io::AsyncRead>>, DownloadError> {
+    if compression == WalCompression::None {
+        match storage
+            .download_storage_object(Some((offset, None)), file_path)
+            .await
+        {
+            Ok(download) => {
+                let compression = segment_compression(download.metadata.as_ref())
+                    .map_err(DownloadError::Other)?;
+                if compression == WalCompression::None {
+                    return Ok(download.download_stream);
+                }
+            }
+            Err(DownloadError::NotFound) => return Err(DownloadError::NotFound),
+            Err(e) => debug!(
+                "ranged download of segment {} failed, retrying with the whole segment: {e}",
                 file_path.display()
+            ),
+        }
+    }
+
+    debug!(
+        "downloading segment {} entirely to read it from offset {offset}",
+        file_path.display()
+    );
+    let download = storage.download_storage_object(None, file_path).await?;
+    let compression =
+        segment_compression(download.metadata.as_ref()).map_err(DownloadError::Other)?;
+    let mut reader = decompressing_reader(download.download_stream, compression);
+    let skipped = tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink())
+        .await
+        .map_err(|e| DownloadError::Other(e.into()))?;
+    if skipped != offset {
+        return Err(DownloadError::Other(anyhow::anyhow!(
+            "WAL segment for local path {} ends at {skipped}, before offset {offset}",
+            file_path.display()
+        )));
+    }
+    Ok(reader)
+}
+
+/// Whether the remote storage for the WAL backup is configured.
+pub fn is_remote_storage_configured() -> bool {
+    REMOTE_STORAGE.get().map_or(false, Option::is_some)
+}
+
+/// Downloads and decompresses the whole backed up segment, returns `None` if it is not in the remote storage.
+/// Unlike [`read_object`], doesn't fall back to the partial segment.
+pub async fn read_backed_up_segment(file_path: &Path) -> Result<Option<Vec<u8>>> {
+    let storage = REMOTE_STORAGE
+        .get()
+        .context("Failed to get remote storage")?
+        .as_ref()
+        .context("No remote storage configured")?;
+
+    let download = match storage.download_storage_object(None, file_path).await {
+        Ok(download) => download,
+        Err(DownloadError::NotFound) => return Ok(None),
+        Err(e) => return Err(anyhow::Error::new(e).context(download_failure_context(file_path))),
+    };
+    let compression = segment_compression(download.metadata.as_ref())?;
+    let mut segment = Vec::new();
+    decompressing_reader(download.download_stream, compression)
+        .read_to_end(&mut segment)
+        .await
+        .with_context(|| format!("Failed to read backed up segment {}", file_path.display()))?;
+    Ok(Some(segment))
+}
+
+fn download_failure_context(file_path: &Path) -> String {
+    format!(
+        "Failed to open WAL segment download stream for local path {}",
+        file_path.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres_ffi::WAL_SEGMENT_SIZE;
+    use remote_storage::LocalFs;
+
+    #[test]
+    fn test_segment_compression_roundtrip() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            // partial segment: some WAL followed by the zeroed tail
+            let mut segment = vec![0u8; WAL_SEGMENT_SIZE];
+            for (i, byte) in segment[..8192].iter_mut().enumerate() {
+                *byte = (i % 251) as u8;
+            }
+
+            let compressed = compress_segment(&segment[..], WalCompression::Zstd)
+                .await
+                .unwrap();
+            assert!(compressed.len() < WAL_SEGMENT_SIZE / 100);
+
+            let metadata = StorageMetadata::new(HashMap::from([(
+                WAL_COMPRESSION_METADATA_KEY.to_string(),
+                "zstd".to_string(),
+            )]));
+            let compression = segment_compression(Some(&metadata)).unwrap();
+            assert_eq!(compression, WalCompression::Zstd);
+            let mut decompressed = Vec::new();
+            decompressing_reader(std::io::Cursor::new(compressed), compression)
+                .read_to_end(&mut decompressed)
+                .await
+                .unwrap();
+            assert!(decompressed == segment);
+
+            // segments uploaded before the compression was enabled
+            assert_eq!(segment_compression(None).unwrap(), WalCompression::None);
+        });
+    }
+
+    #[test]
+    fn test_read_compressed_segment() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let workdir = tempfile::tempdir().unwrap();
+            let storage_root = tempfile::tempdir().unwrap();
+            let storage = GenericRemoteStorage::new(
+                LocalFs::new(storage_root.path().to_owned(), workdir.path().to_owned()).unwrap(),
+            );
+
+            let segment: Vec<u8> = (0..WAL_SEGMENT_SIZE).map(|i| (i % 251) as u8).collect();
+            let segment_path = workdir.path().join("000000010000000000000001");
+            let upload = |path: PathBuf, segment: Vec<u8>, compression: WalCompression| {
+                let storage = storage.clone();
+                async move {
+                    let compressed = compress_segment(&segment[..], compression).await.unwrap();
+                    // uncompressed segments are stored without metadata
+                    let metadata = (compression != WalCompression::None).then(|| {
+                        StorageMetadata::new(HashMap::from([(
+                            WAL_COMPRESSION_METADATA_KEY.to_string(),
+                            compression.as_str().to_string(),
+                        )]))
+                    });
+                    storage
+                        .upload_storage_object(
+                            Box::new(std::io::Cursor::new(compressed.clone())),
+                            compressed.len(),
+                            &path,
+                            metadata,
+                        )
+                        .await
+                        .unwrap();
+                    compressed.len()
+                }
+            };
+            let read = |path: PathBuf, offset: u64, compression: WalCompression| {
+                let storage = storage.clone();
+                async move {
+                    let mut data = Vec::new();
+                    read_segment(&storage, &path, offset, compression)
+                        .await
+                        .unwrap()
+                        .read_to_end(&mut data)
+                        .await
+                        .unwrap();
+                    data
+                }
+            };
+
+            let compressed_size =
+                upload(segment_path.clone(), segment.clone(), WalCompression::Zstd).await;
+            // the offset is beyond the compressed object
+            let offset = WAL_SEGMENT_SIZE as u64 / 2;
+            assert!(offset > compressed_size as u64);
+            for compression in [WalCompression::Zstd, WalCompression::None] {
+                let data = read(segment_path.clone(), offset, compression).await;
+                assert!(data == segment[offset as usize..]);
+            }
+
+            // partial segments uploaded before the compression was enabled
+            let partial_path = segment_path.with_extension("partial");
+            upload(
+                partial_path.clone(),
+                segment[..8192].to_vec(),
+                WalCompression::None,
             )
-        })?;
+            .await;
+            let data = read(partial_path, 100, WalCompression::Zstd).await;
+            assert!(data == segment[100..8192]);
+
+            assert!(matches!(
+                read_segment(
+                    &storage,
+                    &workdir.path().join("000000010000000000000002"),
+                    100,
+                    WalCompression::Zstd
+                )
+                .await,
+                Err(DownloadError::NotFound)
+            ));
+        });
+    }
 
-    Ok(download.download_stream)
+    #[test]
+    fn test_partial_segment_backup() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let workdir = tempfile::tempdir().unwrap();
+            let storage_root = tempfile::tempdir().unwrap();
+            let storage = GenericRemoteStorage::new(
+                LocalFs::new(storage_root.path().to_owned(), workdir.path().to_owned()).unwrap(),
+            );
+            assert!(REMOTE_STORAGE.set(Some(storage)).is_ok());
+
+            let timeline_dir = workdir.path().join("timeline");
+            std::fs::create_dir(&timeline_dir).unwrap();
+            let segno = 1;
+            let wal: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+            std::fs::write(
+                partial_segment_path(&timeline_dir, segno, WAL_SEGMENT_SIZE),
+                &wal,
+            )
+            .unwrap();
+
+            let segment_start = Lsn(XLogSegNoOffsetToRecPtr(segno, 0, WAL_SEGMENT_SIZE));
+            let end_lsn = segment_start + 4096u64;
+            backup_partial_segment(end_lsn, WAL_SEGMENT_SIZE, &timeline_dir)
+                .await
+                .unwrap();
+
+            // the segment is not backed up yet, its partial upload is read instead
+            let segment_path = timeline_dir.join(XLogFileName(PG_TLI, segno, WAL_SEGMENT_SIZE));
+            let mut data = Vec::new();
+            read_object(segment_path.clone(), 100)
+                .await
+                .unwrap()
+                .read_to_end(&mut data)
+                .await
+                .unwrap();
+            assert!(data == wal[100..4096]);
+
+            remove_partial_segment(segno, WAL_SEGMENT_SIZE, &timeline_dir)
+                .await
+                .unwrap();
+            assert!(read_object(segment_path, 100).await.is_err());
+        });
+    }
 }
@@ -0,0 +1,538 @@
+//! Checking the WAL of the timeline for consistency with the backup archive and the peer safekeepers.
+//!
+//! Committed WAL is the same on all safekeepers and in the archive, so a mismatch means a silent
+//! corruption or a bug in the consensus. The check compares crc32c checksums of the full local
+//! segments within the range with the backed up segments and with the same segments of the peers,
+//! computed by the peers themselves with the WAL_CHECKSUMS command. Besides, it compares the term
+//! histories of the committed WAL: diverged histories mean the WAL diverged, even where it is no
+//! longer present to compare.
+//!
+//! The check is run on demand through the HTTP API and, optionally, periodically by
+//! [`thread_main`] over the WAL committed since the previous run. The position of the periodic
+//! check is kept in the timeline directory, so the WAL is not checked again after a restart.
+
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs, thread, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use postgres::SimpleQueryMessage;
+use postgres_ffi::v14::xlog_utils::{IsXLogFileName, XLogFromFileName};
+use postgres_ffi::{XLogFileName, XLogSegNo, PG_TLI};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use tracing::*;
+use utils::{
+    id::{NodeId, TenantTimelineId},
+    lsn::Lsn,
+};
+
+use crate::metrics::{WAL_CHECK_DIVERGENCES, WAL_CHECK_SEGMENTS};
+use crate::recovery::{connect_to_peer, parse_term_history};
+use crate::timeline::Timeline;
+use crate::{wal_backup, GlobalTimelines, SafeKeeperConf};
+
+/// Application name of the connections to the peers, checking their WAL.
+const WAL_CHECK_APPLICATION_NAME: &str = "safekeeper_wal_check";
+
+/// File in the timeline directory with the LSN the WAL is periodically checked up to.
+const CHECKED_LSN_FILE_NAME: &str = "wal_check.lsn";
+
+/// Checksum of a full WAL segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentChecksum {
+    pub segno: XLogSegNo,
+    pub crc32c: u32,
+}
+
+/// Result of the WAL consistency check of the timeline.
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct WalCheckReport {
+    #[serde_as(as = "DisplayFromStr")]
+    pub start_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub end_lsn: Lsn,
+    /// Number of the full local segments within the range.
+    pub local_segments: usize,
+    /// Comparison with the backup archive, `None` if the remote storage is not configured.
+    pub remote: Option<RemoteCheck>,
+    pub peers: Vec<PeerCheck>,
+}
+
+impl WalCheckReport {
+    /// Whether no divergence was found. Unreachable peers are not a divergence.
+    pub fn is_consistent(&self) -> bool {
+        self.remote.as_ref().map_or(true, |remote| {
+            remote.missing_segments.is_empty() && remote.mismatched_segments.is_empty()
+        }) && self
+            .peers
+            .iter()
+            .all(|peer| !peer.term_history_diverged && peer.mismatched_segments.is_empty())
+    }
+
+    /// Whether all the peers have been checked, so the range doesn't have to be checked again.
+    pub fn is_complete(&self) -> bool {
+        self.peers.iter().all(|peer| peer.error.is_none())
+    }
+}
+
+/// Comparison of the local segments with the backed up ones.
+#[derive(Debug, Default, Serialize)]
+pub struct RemoteCheck {
+    pub checked_segments: usize,
+    /// Segments below backup_lsn, absent in the remote storage.
+    pub missing_segments: Vec<String>,
+    pub mismatched_segments: Vec<String>,
+}
+
+/// Comparison of the local segments with the peer's ones.
+#[derive(Debug, Default, Serialize)]
+pub struct PeerCheck {
+    pub pg_addr: String,
+    pub checked_segments: usize,
+    pub mismatched_segments: Vec<String>,
+    pub term_history_diverged: bool,
+    /// Error that prevented checking the peer.
+    pub error: Option<String>,
+}
+
+/// Numbers of the full segments within `[start_lsn, end_lsn)`.
+fn full_segments(start_lsn: Lsn, end_lsn: Lsn, wal_seg_size: usize) -> Range<XLogSegNo> {
+    let mut first = start_lsn.segment_number(wal_seg_size);
+    if start_lsn.segment_offset(wal_seg_size) != 0 {
+        first += 1;
+    }
+    let last = end_lsn.segment_number(wal_seg_size);
+    first..last.max(first)
+}
+
+fn segment_name(segno: XLogSegNo, wal_seg_size: usize) -> String {
+    XLogFileName(PG_TLI, segno, wal_seg_size)
+}
+
+/// Computes the checksums of the full local segments within `[start_lsn, end_lsn)`, that are committed.
+/// Segments removed meanwhile are skipped.
+pub fn local_segment_checksums(
+    tli: &Timeline,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+) -> Result<Vec<SegmentChecksum>> {
+    let wal_seg_size = tli.get_wal_seg_size();
+    let (local_start_lsn, commit_lsn) = tli.get_local_committed_wal();
+    let segments = full_segments(
+        start_lsn.max(local_start_lsn),
+        end_lsn.min(commit_lsn),
+        wal_seg_size,
+    );
+
+    let mut checksums = Vec::new();
+    for segno in segments {
+        if let Some(segment) = read_local_segment(tli.get_timeline_dir(), segno, wal_seg_size)? {
+            checksums.push(SegmentChecksum {
+                segno,
+                crc32c: crc32c::crc32c(&segment),
+            });
+        }
+    }
+    Ok(checksums)
+}
+
+/// Reads the full segment, which is still `.partial` if the WAL ends right at its end.
+fn read_local_segment(
+    timeline_dir: &Path,
+    segno: XLogSegNo,
+    wal_seg_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    let name = segment_name(segno, wal_seg_size);
+    for path in [
+        timeline_dir.join(&name),
+        timeline_dir.join(name.clone() + ".partial"),
+    ] {
+        match fs::read(&path) {
+            Ok(mut segment) => {
+                if segment.len() < wal_seg_size {
+                    bail!(
+                        "segment {} is {} bytes long, expected {wal_seg_size}",
+                        path.display(),
+                        segment.len()
+                    );
+                }
+                segment.truncate(wal_seg_size);
+                return Ok(Some(segment));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+    Ok(None)
+}
+
+/// Checks the committed WAL of the timeline within `[start_lsn, end_lsn)` against the backup archive
+/// and the given peer safekeepers, identified by their postgres protocol addresses.
+/// The peers get the safekeeper's auth token, so they must come from the membership configuration.
+pub async fn check_wal_consistency(
+    tli: Arc<Timeline>,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+    peers: Vec<String>,
+) -> Result<WalCheckReport> {
+    let tli_ = tli.clone();
+    let local =
+        tokio::task::spawn_blocking(move || local_segment_checksums(&tli_, start_lsn, end_lsn))
+            .await??;
+
+    let remote = if wal_backup::is_remote_storage_configured() {
+        Some(check_remote(&tli, &local).await?)
+    } else {
+        None
+    };
+
+    let mut peer_checks = Vec::with_capacity(peers.len());
+    for pg_addr in peers {
+        let mut peer_check = PeerCheck {
+            pg_addr,
+            ..Default::default()
+        };
+        if let Err(e) = check_peer(&tli, start_lsn, end_lsn, &local, &mut peer_check).await {
+            warn!("failed to check WAL of peer {}: {e:#}", peer_check.pg_addr);
+            peer_check.error = Some(format!("{e:#}"));
+        }
+        peer_checks.push(peer_check);
+    }
+
+    Ok(WalCheckReport {
+        start_lsn,
+        end_lsn,
+        local_segments: local.len(),
+        remote,
+        peers: peer_checks,
+    })
+}
+
+/// Compares the local segments, that are already backed up, with the archive.
+async fn check_remote(tli: &Timeline, local: &[SegmentChecksum]) -> Result<RemoteCheck> {
+    let wal_seg_size = tli.get_wal_seg_size();
+    let backed_up_segments = tli.get_wal_backup_lsn().segment_number(wal_seg_size);
+
+    let mut check = RemoteCheck::default();
+    for segment in local.iter().filter(|s| s.segno < backed_up_segments) {
+        let name = segment_name(segment.segno, wal_seg_size);
+        let remote_segment =
+            wal_backup::read_backed_up_segment(&tli.get_timeline_dir().join(&name)).await?;
+        check.checked_segments += 1;
+        WAL_CHECK_SEGMENTS.with_label_values(&["remote"]).inc();
+        match remote_segment {
+            None => {
+                WAL_CHECK_DIVERGENCES
+                    .with_label_values(&["remote_missing"])
+                    .inc();
+                check.missing_segments.push(name);
+            }
+            Some(remote_segment) if crc32c::crc32c(&remote_segment) != segment.crc32c => {
+                WAL_CHECK_DIVERGENCES
+                    .with_label_values(&["remote_mismatch"])
+                    .inc();
+                check.mismatched_segments.push(name);
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(check)
+}
+
+/// Compares the term history and the local segments with the peer's ones.
+async fn check_peer(
+    tli: &Timeline,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+    local: &[SegmentChecksum],
+    check: &mut PeerCheck,
+) -> Result<()> {
+    let wal_seg_size = tli.get_wal_seg_size();
+    let client = connect_to_peer(tli, &check.pg_addr, WAL_CHECK_APPLICATION_NAME).await?;
+
+    let peer_term_history = parse_term_history(&client.simple_query("TERM_HISTORY").await?)?;
+    if !tli
+        .get_committed_term_history()
+        .agrees_with(&peer_term_history)
+    {
+        WAL_CHECK_DIVERGENCES
+            .with_label_values(&["term_history"])
+            .inc();
+        check.term_history_diverged = true;
+    }
+
+    let response = client
+        .simple_query(&format!("WAL_CHECKSUMS {start_lsn} {end_lsn}"))
+        .await?;
+    let peer_checksums = parse_wal_checksums(&response, wal_seg_size)?;
+    // Segments are compared where both safekeepers have them committed.
+    for segment in local {
+        if let Some(peer_crc32c) = peer_checksums.get(&segment.segno) {
+            check.checked_segments += 1;
+            WAL_CHECK_SEGMENTS.with_label_values(&["peer"]).inc();
+            if *peer_crc32c != segment.crc32c {
+                WAL_CHECK_DIVERGENCES
+                    .with_label_values(&["peer_mismatch"])
+                    .inc();
+                check
+                    .mismatched_segments
+                    .push(segment_name(segment.segno, wal_seg_size));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses the response of the WAL_CHECKSUMS command, one (segment name, crc32c) row per segment.
+fn parse_wal_checksums(
+    response: &[SimpleQueryMessage],
+    wal_seg_size: usize,
+) -> Result<HashMap<XLogSegNo, u32>> {
+    let mut checksums = HashMap::new();
+    for message in response {
+        if let SimpleQueryMessage::Row(row) = message {
+            let (segno, crc32c) = parse_wal_checksum(row.get(0), row.get(1), wal_seg_size)?;
+            checksums.insert(segno, crc32c);
+        }
+    }
+    Ok(checksums)
+}
+
+fn parse_wal_checksum(
+    name: Option<&str>,
+    crc32c: Option<&str>,
+    wal_seg_size: usize,
+) -> Result<(XLogSegNo, u32)> {
+    let name = name.context("segment name is missing")?;
+    if !IsXLogFileName(name) {
+        bail!("invalid segment name {name} in WAL_CHECKSUMS row");
+    }
+    let (segno, _) = XLogFromFileName(name, wal_seg_size);
+    let crc32c = crc32c.context("crc32c is missing")?;
+    let crc32c = u32::from_str_radix(crc32c, 16)
+        .with_context(|| format!("invalid crc32c {crc32c} in WAL_CHECKSUMS row"))?;
+    Ok((segno, crc32c))
+}
+
+/// Postgres protocol addresses of the other members of the timeline, to check the WAL against.
+pub fn other_members(tli: &Timeline, my_id: NodeId) -> Vec<String> {
+    let mconf = tli.get_state().1.mconf;
+    mconf
+        .members
+        .iter()
+        .chain(mconf.new_members.iter().flatten())
+        .filter(|member| member.id != my_id)
+        .map(|member| member.pg_addr.clone())
+        .collect()
+}
+
+/// Reads the LSN the WAL of the timeline has been periodically checked up to, `None` if it was never checked.
+fn load_checked_lsn(timeline_dir: &Path) -> Result<Option<Lsn>> {
+    let path = timeline_dir.join(CHECKED_LSN_FILE_NAME);
+    match fs::read_to_string(&path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map(Some)
+            .with_context(|| format!("invalid LSN {content:?} in {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+fn persist_checked_lsn(timeline_dir: &Path, lsn: Lsn) -> Result<()> {
+    let path = timeline_dir.join(CHECKED_LSN_FILE_NAME);
+    let tmp_path = path.with_extension("lsn.partial");
+    fs::write(&tmp_path, lsn.to_string())
+        .and_then(|()| fs::rename(&tmp_path, &path))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Periodically checks the WAL committed since the previous check on all active timelines,
+/// against the archive and the other members of the timeline.
+pub fn thread_main(conf: SafeKeeperConf, interval: Duration) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to create the WAL check runtime");
+    let mut checked_up_to: HashMap<TenantTimelineId, Lsn> = HashMap::new();
+    loop {
+        thread::sleep(interval);
+        let timelines = GlobalTimelines::get_all();
+
+        // Forget the deleted timelines, offloaded ones are checked further once loaded back.
+        let existing: HashSet<TenantTimelineId> = timelines
+            .iter()
+            .map(|tli| tli.ttid)
+            .chain(
+                GlobalTimelines::get_offloaded_info()
+                    .into_iter()
+                    .map(|(ttid, _)| ttid),
+            )
+            .collect();
+        checked_up_to.retain(|ttid, _| existing.contains(ttid));
+
+        for tli in timelines {
+            if !tli.is_active() {
+                continue;
+            }
+            let ttid = tli.ttid;
+            let span =
+                info_span!("WAL check", tenant = %ttid.tenant_id, timeline = %ttid.timeline_id);
+            let start_lsn = match checked_up_to.get(&ttid) {
+                Some(lsn) => *lsn,
+                None => match load_checked_lsn(tli.get_timeline_dir()) {
+                    // Never checked timelines are checked from the start of their local WAL.
+                    Ok(lsn) => lsn.unwrap_or(Lsn(0)),
+                    Err(e) => {
+                        warn!("failed to load WAL check position of timeline {ttid}: {e:#}");
+                        continue;
+                    }
+                },
+            };
+            let (_, commit_lsn) = tli.get_local_committed_wal();
+            // Don't check the WAL, which is not backed up yet, to compare it with the archive later.
+            let end_lsn = if wal_backup::is_remote_storage_configured() && conf.wal_backup_enabled {
+                commit_lsn.min(tli.get_wal_backup_lsn())
+            } else {
+                commit_lsn
+            };
+            if end_lsn.segment_number(tli.get_wal_seg_size())
+                <= start_lsn.segment_number(tli.get_wal_seg_size())
+            {
+                continue;
+            }
+            let peers = other_members(&tli, conf.my_id);
+
+            let res = runtime.block_on(
+                check_wal_consistency(tli.clone(), start_lsn, end_lsn, peers).instrument(span),
+            );
+            match res {
+                Ok(report) => {
+                    if !report.is_consistent() {
+                        warn!("WAL of timeline {ttid} is inconsistent: {report:?}");
+                    }
+                    // Unreachable peers get the range checked on the next run.
+                    if !report.is_complete() {
+                        continue;
+                    }
+                    if let Err(e) = persist_checked_lsn(tli.get_timeline_dir(), end_lsn) {
+                        warn!("failed to persist WAL check position of timeline {ttid}: {e:#}");
+                    }
+                    checked_up_to.insert(ttid, end_lsn);
+                }
+                Err(e) => warn!("failed to check WAL of timeline {ttid}: {e:#}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safekeeper::{TermHistory, TermSwitchEntry};
+    use postgres_ffi::WAL_SEGMENT_SIZE;
+
+    fn term_history(entries: &[(u64, u64)]) -> TermHistory {
+        TermHistory(
+            entries
+                .iter()
+                .map(|&(term, lsn)| TermSwitchEntry {
+                    term,
+                    lsn: Lsn(lsn),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_term_histories_agree() {
+        let history = term_history(&[(1, 0x100), (2, 0x200), (4, 0x400)]);
+        assert!(history.agrees_with(&history));
+
+        // the peer has seen fewer or more term switches
+        let prefix = term_history(&[(1, 0x100), (2, 0x200)]);
+        assert!(history.agrees_with(&prefix));
+        assert!(prefix.agrees_with(&history));
+        assert!(history.agrees_with(&TermHistory::empty()));
+
+        // the same term started at another point
+        let diverged = term_history(&[(1, 0x100), (2, 0x180)]);
+        assert!(!history.agrees_with(&diverged));
+        assert!(!diverged.agrees_with(&history));
+        // another term started at the same point
+        let diverged = term_history(&[(1, 0x100), (2, 0x200), (3, 0x400)]);
+        assert!(!history.agrees_with(&diverged));
+    }
+
+    #[test]
+    fn test_parse_wal_checksum() {
+        assert_eq!(
+            parse_wal_checksum(
+                Some("000000010000000000000003"),
+                Some("deadbeef"),
+                WAL_SEGMENT_SIZE
+            )
+            .unwrap(),
+            (3, 0xdeadbeef)
+        );
+
+        for (name, crc32c) in [
+            (None, Some("deadbeef")),
+            (Some("000000010000000000000003"), None),
+            (Some("000000010000000000000003.partial"), Some("deadbeef")),
+            (Some("not a segment"), Some("deadbeef")),
+            (Some("000000010000000000000003"), Some("not hex")),
+            (Some("000000010000000000000003"), Some("1deadbeef")),
+        ] {
+            assert!(
+                parse_wal_checksum(name, crc32c, WAL_SEGMENT_SIZE).is_err(),
+                "{name:?} {crc32c:?} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn test_checked_lsn_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(load_checked_lsn(dir.path()).unwrap(), None);
+
+        persist_checked_lsn(dir.path(), Lsn(0x1_0000_0028)).unwrap();
+        assert_eq!(
+            load_checked_lsn(dir.path()).unwrap(),
+            Some(Lsn(0x1_0000_0028))
+        );
+        persist_checked_lsn(dir.path(), Lsn(0x2_0000_0000)).unwrap();
+        assert_eq!(
+            load_checked_lsn(dir.path()).unwrap(),
+            Some(Lsn(0x2_0000_0000))
+        );
+
+        fs::write(dir.path().join(CHECKED_LSN_FILE_NAME), "garbage").unwrap();
+        assert!(load_checked_lsn(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_full_segments() {
+        let seg = WAL_SEGMENT_SIZE as u64;
+        assert_eq!(full_segments(Lsn(0), Lsn(3 * seg), WAL_SEGMENT_SIZE), 0..3);
+        // partially covered segments are not checked
+        assert_eq!(
+            full_segments(Lsn(seg + 1), Lsn(4 * seg - 1), WAL_SEGMENT_SIZE),
+            2..3
+        );
+        assert_eq!(
+            full_segments(Lsn(seg + 1), Lsn(2 * seg - 1), WAL_SEGMENT_SIZE),
+            2..2
+        );
+        assert_eq!(
+            full_segments(Lsn(3 * seg), Lsn(seg), WAL_SEGMENT_SIZE),
+            3..3
+        );
+    }
+}
@@ -405,6 +405,27 @@ impl Storage for PhysicalStorage {
     }
 }
 
+/// Find the oldest WAL segment present in timeline_dir, if any. Segments before
+/// it were either removed or never received.
+pub fn first_segment_on_disk(
+    timeline_dir: &Path,
+    wal_seg_size: usize,
+) -> Result<Option<XLogSegNo>> {
+    let mut first_segno = None;
+    for entry in fs::read_dir(&timeline_dir)? {
+        let entry = entry?;
+        if let Some(fname_str) = entry.file_name().to_str() {
+            /* Ignore files that are not XLOG segments */
+            if !IsXLogFileName(fname_str) && !IsPartialXLogFileName(fname_str) {
+                continue;
+            }
+            let (segno, _) = XLogFromFileName(fname_str, wal_seg_size);
+            first_segno = Some(first_segno.map_or(segno, |first| min(first, segno)));
+        }
+    }
+    Ok(first_segno)
+}
+
 /// Remove all WAL segments in timeline_dir that match the given predicate.
 fn remove_segments_from_disk(
     timeline_dir: &Path,
@@ -393,6 +393,19 @@ class AuthKeys:
 
         return token
 
+    def generate_safekeeper_token(self):
+        # Safekeepers reject safekeeperdata tokens without the expiration time.
+        token = jwt.encode(
+            {"scope": "safekeeperdata", "exp": int(time.time()) + 24 * 60 * 60},
+            self.priv,
+            algorithm="RS256",
+        )
+
+        if isinstance(token, bytes):
+            token = token.decode()
+
+        return token
+
     def generate_tenant_token(self, tenant_id):
         token = jwt.encode(
             {"scope": "tenant", "tenant_id": str(tenant_id)}, self.priv, algorithm="RS256"
@@ -483,6 +496,9 @@ class S3Storage:
     secret_key: str
     endpoint: Optional[str] = None
     prefix_in_bucket: Optional[str] = None
+    multipart_upload_threshold: Optional[int] = None
+    multipart_upload_part_size: Optional[int] = None
+    multipart_upload_abandoned_after: Optional[str] = None
 
     def access_env_vars(self) -> Dict[str, str]:
         return {
@@ -507,6 +523,19 @@ def remote_storage_to_toml_inline_table(remote_storage):
 
         if remote_storage.endpoint is not None:
             remote_storage_config += f",endpoint='{remote_storage.endpoint}'"
+
+        if remote_storage.multipart_upload_threshold is not None:
+            remote_storage_config += (
+                f",multipart_upload_threshold={remote_storage.multipart_upload_threshold}"
+            )
+
+        if remote_storage.multipart_upload_part_size is not None:
+            remote_storage_config += (
+                f",multipart_upload_part_size={remote_storage.multipart_upload_part_size}"
+            )
+
+        if remote_storage.multipart_upload_abandoned_after is not None:
+            remote_storage_config += f",multipart_upload_abandoned_after='{remote_storage.multipart_upload_abandoned_after}'"
     else:
         raise Exception("invalid remote storage type")
 
@@ -544,6 +573,8 @@ class NeonEnvBuilder:
         # fsync is disabled by default to make the tests go faster
         safekeepers_enable_fsync: bool = False,
         auth_enabled: bool = False,
+        # check tokens on the safekeeper postgres protocol connections too, requires auth_enabled
+        safekeepers_enable_pg_auth: bool = False,
         rust_log_override: Optional[str] = None,
         default_branch_name=DEFAULT_BRANCH_NAME,
     ):
@@ -560,6 +591,7 @@ class NeonEnvBuilder:
         self.safekeepers_id_start = safekeepers_id_start
         self.safekeepers_enable_fsync = safekeepers_enable_fsync
         self.auth_enabled = auth_enabled
+        self.safekeepers_enable_pg_auth = safekeepers_enable_pg_auth
         self.default_branch_name = default_branch_name
         self.env: Optional[NeonEnv] = None
         self.remote_storage_prefix: Optional[str] = None
@@ -843,6 +875,12 @@ class NeonEnv:
                 auth_enabled = true
                 """
                 )
+                if config.safekeepers_enable_pg_auth:
+                    toml += textwrap.dedent(
+                        """
+                    pg_auth_enabled = true
+                    """
+                    )
             if (
                 bool(self.remote_storage_users & RemoteStorageUsers.SAFEKEEPER)
                 and self.remote_storage is not None
@@ -1036,6 +1074,26 @@ class NeonPageserverHttpClient(requests.Session):
         res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/attach")
         self.verbose_error(res)
 
+    def tenant_snapshot(
+        self,
+        tenant_id: TenantId,
+        new_tenant_id: Optional[TenantId] = None,
+        timeline_lsns: Optional[Dict[TimelineId, Lsn]] = None,
+    ) -> Dict[Any, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/snapshot",
+            json={
+                "new_tenant_id": str(new_tenant_id) if new_tenant_id else None,
+                "timeline_lsns": {
+                    str(timeline_id): str(lsn) for timeline_id, lsn in (timeline_lsns or {}).items()
+                },
+            },
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
     def tenant_detach(self, tenant_id: TenantId):
         res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/detach")
         self.verbose_error(res)
@@ -1887,10 +1945,18 @@ class PSQL:
 
 
 class NeonProxy(PgProtocol):
-    def __init__(self, proxy_port: int, http_port: int, auth_endpoint=None, mgmt_port=None):
+    def __init__(
+        self,
+        proxy_port: int,
+        http_port: int,
+        internal_http_port: int,
+        auth_endpoint=None,
+        mgmt_port=None,
+    ):
         super().__init__(dsn=auth_endpoint, port=proxy_port)
         self.host = "127.0.0.1"
         self.http_port = http_port
+        self.internal_http_port = internal_http_port
         self.proxy_port = proxy_port
         self.mgmt_port = mgmt_port
         self.auth_endpoint = auth_endpoint
@@ -1908,6 +1974,7 @@ class NeonProxy(PgProtocol):
         args = [
             os.path.join(neon_binpath, "proxy"),
             *["--http", f"{self.host}:{self.http_port}"],
+            *["--internal-http", f"{self.host}:{self.internal_http_port}"],
             *["--proxy", f"{self.host}:{self.proxy_port}"],
             *["--auth-backend", "postgres"],
             *["--auth-endpoint", self.auth_endpoint],
@@ -1925,6 +1992,7 @@ class NeonProxy(PgProtocol):
         bin_proxy = os.path.join(str(neon_binpath), "proxy")
         args = [bin_proxy]
         args.extend(["--http", f"{self.host}:{self.http_port}"])
+        args.extend(["--internal-http", f"{self.host}:{self.internal_http_port}"])
         args.extend(["--proxy", f"{self.host}:{self.proxy_port}"])
         args.extend(["--mgmt", f"{self.host}:{self.mgmt_port}"])
         args.extend(["--auth-backend", "link"])
@@ -1952,9 +2020,10 @@ class NeonProxy(PgProtocol):
 def link_proxy(port_distributor) -> Iterator[NeonProxy]:
     """Neon proxy that routes through link auth."""
     http_port = port_distributor.get_port()
+    internal_http_port = port_distributor.get_port()
     proxy_port = port_distributor.get_port()
     mgmt_port = port_distributor.get_port()
-    with NeonProxy(proxy_port, http_port, mgmt_port=mgmt_port) as proxy:
+    with NeonProxy(proxy_port, http_port, internal_http_port, mgmt_port=mgmt_port) as proxy:
         proxy.start_with_link_auth()
         yield proxy
 
@@ -1974,9 +2043,13 @@ def static_proxy(vanilla_pg, port_distributor) -> Iterator[NeonProxy]:
 
     proxy_port = port_distributor.get_port()
     http_port = port_distributor.get_port()
+    internal_http_port = port_distributor.get_port()
 
     with NeonProxy(
-        proxy_port=proxy_port, http_port=http_port, auth_endpoint=auth_endpoint
+        proxy_port=proxy_port,
+        http_port=http_port,
+        internal_http_port=internal_http_port,
+        auth_endpoint=auth_endpoint,
     ) as proxy:
         proxy.start()
         yield proxy
@@ -2376,6 +2449,18 @@ class SafekeeperHttpClient(requests.Session):
         assert isinstance(res_json, dict)
         return res_json
 
+    def timeline_membership_switch(
+        self, tenant_id: TenantId, timeline_id: TimelineId, mconf: Dict[str, Any]
+    ) -> Dict[Any, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/membership",
+            json={"mconf": mconf},
+        )
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
     def get_metrics_str(self) -> str:
         request_result = self.get(f"http://localhost:{self.port}/metrics")
         request_result.raise_for_status()
@@ -144,3 +144,72 @@ def test_remote_storage_backup_and_restore(
                 query_scalar(cur, f"SELECT secret FROM t{checkpoint_number} WHERE id = {data_id};")
                 == f"{data_secret}|{checkpoint_number}"
             )
+
+
+#
+# Tests that big layer files are uploaded with S3 multipart upload and restored correctly,
+# and that the incomplete multipart uploads, left by the previous runs, are aborted on pageserver startup.
+#
+def test_remote_storage_multipart_upload(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.enable_remote_storage(
+        remote_storage_kind=RemoteStorageKind.MOCK_S3,
+        test_name="test_remote_storage_multipart_upload",
+    )
+    # S3 does not allow parts smaller than 5MB, except the last one
+    part_size = 5 * 1024 * 1024
+    neon_env_builder.remote_storage.multipart_upload_threshold = part_size
+    neon_env_builder.remote_storage.multipart_upload_part_size = part_size
+    neon_env_builder.remote_storage.multipart_upload_abandoned_after = "1s"
+    bucket_name = neon_env_builder.remote_storage.bucket_name
+    s3_client = neon_env_builder.remote_storage_client
+
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+    pg = env.postgres.create_start("main")
+
+    tenant_id = TenantId(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = TimelineId(pg.safe_psql("show neon.timeline_id")[0][0])
+
+    with pg.cursor() as cur:
+        cur.execute(
+            "CREATE TABLE t AS SELECT g AS id, repeat('x', 100) AS filler FROM generate_series(1, 300000) g"
+        )
+        current_lsn = Lsn(query_scalar(cur, "SELECT pg_current_wal_flush_lsn()"))
+
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+    client.timeline_checkpoint(tenant_id, timeline_id)
+    wait_for_upload(client, tenant_id, timeline_id, current_lsn)
+
+    # S3 forms the ETag of the object uploaded in parts as `<md5 of part md5s>-<number of parts>`
+    objects = s3_client.list_objects_v2(Bucket=bucket_name)["Contents"]
+    multipart_objects = [o["Key"] for o in objects if "-" in o["ETag"]]
+    log.info(f"Objects uploaded in parts: {multipart_objects}")
+    assert len(multipart_objects) > 0, "Expected some layers to be uploaded in parts"
+
+    env.postgres.stop_all()
+    env.pageserver.stop()
+
+    # Leave an incomplete upload behind, as if the pageserver crashed in the middle of an upload
+    s3_client.create_multipart_upload(Bucket=bucket_name, Key="abandoned_layer")
+    assert len(s3_client.list_multipart_uploads(Bucket=bucket_name).get("Uploads", [])) == 1
+    time.sleep(2)
+
+    dir_to_clear = Path(env.repo_dir) / "tenants"
+    shutil.rmtree(dir_to_clear)
+    os.mkdir(dir_to_clear)
+
+    env.pageserver.start()
+    assert (
+        len(s3_client.list_multipart_uploads(Bucket=bucket_name).get("Uploads", [])) == 0
+    ), "Abandoned multipart upload should be aborted on startup"
+
+    client.tenant_attach(tenant_id)
+    wait_until(
+        number_of_iterations=20,
+        interval=1,
+        func=lambda: assert_timeline_local(client, tenant_id, timeline_id),
+    )
+
+    pg = env.postgres.create_start("main")
+    with pg.cursor() as cur:
+        assert query_scalar(cur, "SELECT count(*) FROM t") == 300000
@@ -0,0 +1,123 @@
+import shutil
+
+import pytest
+from fixtures.log_helper import log
+from fixtures.neon_fixtures import (
+    LocalFsStorage,
+    NeonEnvBuilder,
+    RemoteStorageKind,
+    assert_timeline_local,
+    wait_for_last_record_lsn,
+    wait_for_upload,
+    wait_until,
+)
+from fixtures.types import Lsn, TenantId, TimelineId
+from fixtures.utils import query_scalar
+
+
+#
+# Tests that a tenant snapshot gets all timelines of the source tenant, with their branch structure,
+# downloaded from the remote layers of the source tenant.
+#
+def test_tenant_snapshot(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.enable_remote_storage(
+        remote_storage_kind=RemoteStorageKind.LOCAL_FS,
+        test_name="test_tenant_snapshot",
+    )
+
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+    tenant_id = env.initial_tenant
+
+    timelines = {}
+    for branch_name, ancestor_branch_name in [("main", None), ("child", "main")]:
+        if ancestor_branch_name is not None:
+            env.neon_cli.create_branch(branch_name, ancestor_branch_name, tenant_id=tenant_id)
+        pg = env.postgres.create_start(branch_name, tenant_id=tenant_id)
+        timeline_id = TimelineId(pg.safe_psql("show neon.timeline_id")[0][0])
+        with pg.cursor() as cur:
+            cur.execute(f"CREATE TABLE {branch_name}_t AS SELECT generate_series(1, 10000) AS id")
+            current_lsn = Lsn(query_scalar(cur, "SELECT pg_current_wal_flush_lsn()"))
+        wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+        client.timeline_checkpoint(tenant_id, timeline_id)
+        wait_for_upload(client, tenant_id, timeline_id, current_lsn)
+        timelines[branch_name] = timeline_id
+
+    snapshot = client.tenant_snapshot(tenant_id)
+    log.info(f"Tenant snapshot: {snapshot}")
+    new_tenant_id = TenantId(snapshot["tenant_id"])
+    assert new_tenant_id != tenant_id
+    assert set(TimelineId(timeline_id) for timeline_id in snapshot["timeline_lsns"].keys()) == set(
+        timelines.values()
+    )
+
+    for timeline_id in timelines.values():
+        wait_until(
+            number_of_iterations=20,
+            interval=1,
+            func=lambda: assert_timeline_local(client, new_tenant_id, timeline_id),
+        )
+        detail = client.timeline_detail(new_tenant_id, timeline_id)
+        source_detail = client.timeline_detail(tenant_id, timeline_id)
+        assert Lsn(detail["local"]["last_record_lsn"]) == Lsn(
+            snapshot["timeline_lsns"][str(timeline_id)]
+        )
+        assert Lsn(detail["local"]["last_record_lsn"]) <= Lsn(
+            source_detail["remote"]["remote_consistent_lsn"]
+        )
+        assert detail["local"]["ancestor_timeline_id"] == (
+            source_detail["local"]["ancestor_timeline_id"]
+        )
+
+    with pytest.raises(Exception, match="already exists"):
+        client.tenant_snapshot(tenant_id, new_tenant_id=new_tenant_id)
+
+    with pytest.raises(Exception, match="ahead of the remote disk consistent lsn"):
+        client.tenant_snapshot(tenant_id, timeline_lsns={timelines["main"]: Lsn("FFFF/FFFFFFFF")})
+
+
+#
+# Tests that a tenant snapshot does not depend on the remote layers of the source tenant,
+# which are free to be removed by GC or the tenant deletion.
+#
+def test_tenant_snapshot_outlives_source(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.enable_remote_storage(
+        remote_storage_kind=RemoteStorageKind.LOCAL_FS,
+        test_name="test_tenant_snapshot_outlives_source",
+    )
+
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+    tenant_id = env.initial_tenant
+
+    pg = env.postgres.create_start("main", tenant_id=tenant_id)
+    timeline_id = TimelineId(pg.safe_psql("show neon.timeline_id")[0][0])
+    with pg.cursor() as cur:
+        cur.execute("CREATE TABLE t AS SELECT generate_series(1, 10000) AS id")
+        cur.execute("DELETE FROM t")
+        cur.execute("INSERT INTO t SELECT generate_series(1, 10000)")
+        current_lsn = Lsn(query_scalar(cur, "SELECT pg_current_wal_flush_lsn()"))
+    pg.stop()
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+    client.timeline_checkpoint(tenant_id, timeline_id)
+    wait_for_upload(client, tenant_id, timeline_id, current_lsn)
+
+    snapshot = client.tenant_snapshot(tenant_id)
+    new_tenant_id = TenantId(snapshot["tenant_id"])
+
+    # Remove the source layers, both the ones GC gets to and the rest, along with the tenant
+    client.timeline_compact(tenant_id, timeline_id)
+    client.timeline_gc(tenant_id, timeline_id, 0)
+    client.tenant_detach(tenant_id)
+    assert isinstance(env.remote_storage, LocalFsStorage)
+    shutil.rmtree(env.remote_storage.root / "tenants" / str(tenant_id))
+
+    wait_until(
+        number_of_iterations=20,
+        interval=1,
+        func=lambda: assert_timeline_local(client, new_tenant_id, timeline_id),
+    )
+    detail = client.timeline_detail(new_tenant_id, timeline_id)
+    assert Lsn(detail["local"]["last_record_lsn"]) == Lsn(
+        snapshot["timeline_lsns"][str(timeline_id)]
+    )
@@ -10,8 +10,9 @@ import time
 from contextlib import closing
 from dataclasses import dataclass, field
 from pathlib import Path
-from typing import Any, List, Optional
+from typing import Any, Dict, List, Optional
 
+import psycopg2
 import pytest
 from fixtures.log_helper import log
 from fixtures.neon_fixtures import (
@@ -342,11 +343,12 @@ def test_wal_removal(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
     if not auth_enabled:
         http_cli = env.safekeepers[0].http_client()
     else:
+        # recording peer info is a safekeeper-wide API, tenant tokens are not enough
         http_cli = env.safekeepers[0].http_client(
-            auth_token=env.auth_keys.generate_tenant_token(tenant_id)
+            auth_token=env.auth_keys.generate_safekeeper_token()
         )
-        http_cli_other = env.safekeepers[0].http_client(
-            auth_token=env.auth_keys.generate_tenant_token(TenantId.generate())
+        http_cli_tenant = env.safekeepers[0].http_client(
+            auth_token=env.auth_keys.generate_tenant_token(tenant_id)
         )
         http_cli_noauth = env.safekeepers[0].http_client()
 
@@ -356,7 +358,7 @@ def test_wal_removal(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
             tenant_id=tenant_id, timeline_id=timeline_id
         ).backup_lsn
         assert "FFFFFFFF/FEFFFFFF" != old_backup_lsn
-        for cli in [http_cli_other, http_cli_noauth]:
+        for cli in [http_cli_tenant, http_cli_noauth]:
             with pytest.raises(cli.HTTPError, match="Forbidden|Unauthorized"):
                 cli.record_safekeeper_info(
                     tenant_id, timeline_id, {"backup_lsn": "FFFFFFFF/FEFFFFFF"}
@@ -755,6 +757,13 @@ def test_timeline_status(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
             with pytest.raises(cli.HTTPError, match="Forbidden|Unauthorized"):
                 cli.timeline_status(tenant_id, timeline_id)
 
+        # safekeeper scope gives access to any tenant, pageserver scope is not accepted
+        wa_http_cli_sk = wa.http_client(auth_token=env.auth_keys.generate_safekeeper_token())
+        assert wa_http_cli_sk.timeline_status(tenant_id, timeline_id).acceptor_epoch == epoch
+        wa_http_cli_ps = wa.http_client(auth_token=env.auth_keys.generate_management_token())
+        with pytest.raises(wa_http_cli_ps.HTTPError, match="Forbidden"):
+            wa_http_cli_ps.timeline_status(tenant_id, timeline_id)
+
     pg.safe_psql("create table t(i int)")
 
     # ensure epoch goes up after reboot
@@ -769,6 +778,42 @@ def test_timeline_status(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
     assert tli_status.timeline_start_lsn == timeline_start_lsn
 
 
+def test_wal_streaming_with_pg_auth(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 3
+    neon_env_builder.auth_enabled = True
+    neon_env_builder.safekeepers_enable_pg_auth = True
+    env = neon_env_builder.init_start()
+
+    # compute, pageserver and safekeeper peers all present tokens to the safekeepers
+    env.neon_cli.create_branch("test_wal_streaming_with_pg_auth")
+    pg = env.postgres.create_start("test_wal_streaming_with_pg_auth")
+
+    tenant_id = TenantId(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = TimelineId(pg.safe_psql("show neon.timeline_id")[0][0])
+
+    pg.safe_psql("CREATE TABLE t(key int primary key, value text)")
+    pg.safe_psql("INSERT INTO t SELECT generate_series(1, 100000), 'payload'")
+    lsn = Lsn(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+
+    ps_http_cli = env.pageserver.http_client(auth_token=env.auth_keys.generate_management_token())
+    wait_for_last_record_lsn(ps_http_cli, tenant_id, timeline_id, lsn)
+
+    # restarted compute gets the data back from the pageserver
+    pg.stop().start()
+    assert pg.safe_psql("SELECT count(*) FROM t")[0][0] == 100000
+
+    # connections without a token or with a token of another tenant are rejected
+    sk = env.safekeepers[0]
+    connstr = f"host=localhost port={sk.port.pg} replication=0 options='-c timeline_id={timeline_id} tenant_id={tenant_id}'"
+    other_tenant_token = env.auth_keys.generate_tenant_token(TenantId.generate())
+    for password in [None, other_tenant_token]:
+        with pytest.raises(psycopg2.Error):
+            with closing(psycopg2.connect(connstr, password=password)) as conn:
+                conn.autocommit = True
+                with conn.cursor() as cur:
+                    cur.execute("IDENTIFY_SYSTEM")
+
+
 class SafekeeperEnv:
     def __init__(
         self,
@@ -985,6 +1030,56 @@ def test_replace_safekeeper(neon_env_builder: NeonEnvBuilder):
     show_statuses(env.safekeepers, tenant_id, timeline_id)
 
 
+# While the membership configuration is joint, the compute should be elected and
+# commit only with a quorum of both the old and the new member sets.
+def test_joint_membership_quorum(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 5
+    env = neon_env_builder.init_start()
+    env.neon_cli.create_branch("test_joint_membership_quorum")
+    pg = env.postgres.create_start("test_joint_membership_quorum")
+
+    tenant_id = TenantId(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = TimelineId(pg.safe_psql("show neon.timeline_id")[0][0])
+    pg.safe_psql("CREATE TABLE t(key int primary key, value text)")
+    pg.stop()
+
+    def member_set(sk_ids: List[int]) -> List[Dict[str, Any]]:
+        return [
+            {"id": sk.id, "pg_addr": f"localhost:{sk.port.pg}"}
+            for sk in env.safekeepers
+            if sk.id in sk_ids
+        ]
+
+    old_members, new_members = member_set([1, 2, 3]), member_set([3, 4, 5])
+    for mconf in [
+        {"generation": 1, "members": old_members, "new_members": None},
+        {"generation": 2, "members": old_members, "new_members": new_members},
+    ]:
+        for sk in env.safekeepers:
+            sk.http_client().timeline_membership_switch(tenant_id, timeline_id, mconf)
+
+    pg.start()
+    pg.safe_psql("INSERT INTO t VALUES (1, 'both member sets')")
+
+    log.info("Stop sk4 and sk5, leaving a quorum of the old members only")
+    # sk1, sk2 and sk3 are still a majority of neon.safekeepers, so a single
+    # majority would let the commit through.
+    env.safekeepers[3].stop()
+    env.safekeepers[4].stop()
+    insert = threading.Thread(
+        target=pg.safe_psql, args=("INSERT INTO t VALUES (2, 'new member set')",)
+    )
+    insert.start()
+    time.sleep(5)
+    assert insert.is_alive(), "commit should wait for a quorum of the new members"
+
+    log.info("Start sk4 to restore the quorum of the new members")
+    env.safekeepers[3].start()
+    insert.join(timeout=60)
+    assert not insert.is_alive(), "commit should succeed with a quorum of both member sets"
+    assert pg.safe_psql("SELECT count(*) FROM t")[0][0] == 2
+
+
 # We have `wal_keep_size=0`, so postgres should trim WAL once it's broadcasted
 # to all safekeepers. This test checks that compute WAL can fit into small number
 # of WAL segments.
@@ -1069,7 +1164,8 @@ def test_delete_force(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
         sk_http = sk.http_client()
         sk_http_other = sk_http
     else:
-        sk_http = sk.http_client(auth_token=env.auth_keys.generate_tenant_token(tenant_id))
+        # deletion is a safekeeper-wide API, tenant tokens are not enough
+        sk_http = sk.http_client(auth_token=env.auth_keys.generate_safekeeper_token())
         sk_http_other = sk.http_client(
             auth_token=env.auth_keys.generate_tenant_token(tenant_id_other)
         )
@@ -1115,8 +1211,8 @@ def test_delete_force(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
     assert (sk_data_dir / str(tenant_id_other) / str(timeline_id_other)).is_dir()
 
     if auth_enabled:
-        # Ensure we cannot delete the other tenant
-        for sk_h in [sk_http, sk_http_noauth]:
+        # Ensure we cannot delete the tenant with its own token
+        for sk_h in [sk_http_other, sk_http_noauth]:
             with pytest.raises(sk_h.HTTPError, match="Forbidden|Unauthorized"):
                 assert sk_h.timeline_delete_force(tenant_id_other, timeline_id_other)
             with pytest.raises(sk_h.HTTPError, match="Forbidden|Unauthorized"):